@@ -9,7 +9,7 @@ use core::mem;
 use defmt::{info, *};
 use embassy_executor::Spawner;
 use nrf_softdevice::ble::advertisement_builder::{
-    Flag, LegacyAdvertisementBuilder, LegacyAdvertisementPayload, ServiceList, ServiceUuid16,
+    Flag, LegacyAdvertisementBuilder, LegacyAdvertisementPayload, LegacyScanResponseBuilder, ServiceList, ServiceUuid16,
 };
 use nrf_softdevice::ble::peripheral;
 use nrf_softdevice::{raw, Softdevice};
@@ -69,7 +69,7 @@ async fn main(spawner: Spawner) {
 
     // but we can put it in the scan data
     // so the full name is visible once connected
-    static SCAN_DATA: LegacyAdvertisementPayload = LegacyAdvertisementBuilder::new().full_name("Hello, Rust!").build();
+    static SCAN_DATA: LegacyAdvertisementPayload = LegacyScanResponseBuilder::new().full_name("Hello, Rust!").build();
 
     let adv = peripheral::NonconnectableAdvertisement::ScannableUndirected {
         adv_data: &ADV_DATA,
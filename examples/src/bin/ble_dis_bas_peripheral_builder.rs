@@ -141,8 +141,8 @@ impl BatteryService {
     pub fn battery_level_set(&self, sd: &Softdevice, val: u8) -> Result<(), gatt_server::SetValueError> {
         gatt_server::set_value(sd, self.value_handle, &[val])
     }
-    pub fn battery_level_notify(&self, conn: &Connection, val: u8) -> Result<(), gatt_server::NotifyValueError> {
-        gatt_server::notify_value(conn, self.value_handle, &[val])
+    pub fn battery_level_notify(&self, conn: &Connection, val: u8) -> Result<(), gatt_server::TryNotifyValueError> {
+        gatt_server::try_notify_value(conn, self.value_handle, &[val])
     }
 
     pub fn on_write(&self, handle: u16, data: &[u8]) {
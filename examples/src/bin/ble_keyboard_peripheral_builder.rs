@@ -18,13 +18,13 @@ use core::mem;
 use defmt::{info, *};
 use embassy_executor::Spawner;
 use nrf_softdevice::ble::advertisement_builder::{
-    AdvertisementDataType, Flag, LegacyAdvertisementBuilder, LegacyAdvertisementPayload, ServiceList, ServiceUuid16,
+    Flag, LegacyAdvertisementBuilder, LegacyAdvertisementPayload, ServiceList, ServiceUuid16,
 };
 use nrf_softdevice::ble::gatt_server::builder::ServiceBuilder;
 use nrf_softdevice::ble::gatt_server::characteristic::{Attribute, Metadata, Presentation, Properties};
 use nrf_softdevice::ble::gatt_server::{CharacteristicHandles, RegisterError, WriteOp};
 use nrf_softdevice::ble::security::SecurityHandler;
-use nrf_softdevice::ble::{gatt_server, peripheral, Connection, Uuid};
+use nrf_softdevice::ble::{gatt_server, peripheral, Appearance, Connection, Uuid};
 use nrf_softdevice::{raw, Softdevice};
 
 const DEVICE_INFORMATION: Uuid = Uuid::new_16(0x180a);
@@ -258,8 +258,8 @@ impl BatteryService {
     pub fn battery_level_set(&self, sd: &Softdevice, val: u8) -> Result<(), gatt_server::SetValueError> {
         gatt_server::set_value(sd, self.value_handle, &[val])
     }
-    pub fn battery_level_notify(&self, conn: &Connection, val: u8) -> Result<(), gatt_server::NotifyValueError> {
-        gatt_server::notify_value(conn, self.value_handle, &[val])
+    pub fn battery_level_notify(&self, conn: &Connection, val: u8) -> Result<(), gatt_server::TryNotifyValueError> {
+        gatt_server::try_notify_value(conn, self.value_handle, &[val])
     }
 
     pub fn on_write(&self, handle: u16, data: &[u8]) {
@@ -368,8 +368,8 @@ impl HidService {
             0, // Reserved
             0x0E, 0, 0, 0, 0, 0, // Key code array - 0x04 is 'a' and 0x1d is 'z' - for example
         ];
-        // gatt_server::notify_value(conn, self.input_keyboard_cccd, val).unwrap();
-        // gatt_server::notify_value(conn, self.input_keyboard_descriptor, val).unwrap();
+        // gatt_server::try_notify_value(conn, self.input_keyboard_cccd, val).unwrap();
+        // gatt_server::try_notify_value(conn, self.input_keyboard_descriptor, val).unwrap();
         if handle == self.input_keyboard_cccd {
             info!("HID input keyboard notify: {:?}", data);
         } else if handle == self.output_keyboard {
@@ -377,10 +377,10 @@ impl HidService {
             info!("HID output keyboard: {:?}", data);
 
             if *data.get(0).unwrap() == 1 {
-                gatt_server::notify_value(conn, self.input_keyboard, val).unwrap();
+                gatt_server::try_notify_value(conn, self.input_keyboard, val).unwrap();
                 info!("Keyboard report sent");
             } else {
-                gatt_server::notify_value(conn, self.input_keyboard, &[0u8; 8]).unwrap();
+                gatt_server::try_notify_value(conn, self.input_keyboard, &[0u8; 8]).unwrap();
                 info!("Keyboard report cleared");
             }
         } else if handle == self.input_media_keys_cccd {
@@ -490,7 +490,7 @@ async fn main(spawner: Spawner) {
         )
         .full_name("HelloRust")
         // Change the appearance (icon of the bluetooth device) to a keyboard
-        .raw(AdvertisementDataType::APPEARANCE, &[0xC1, 0x03])
+        .appearance(Appearance::HID_KEYBOARD)
         .build();
 
     static SCAN_DATA: LegacyAdvertisementPayload = LegacyAdvertisementBuilder::new()
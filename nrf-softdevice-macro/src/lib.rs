@@ -34,7 +34,19 @@ struct CharacteristicArgs {
     #[darling(default)]
     indicate: bool,
     #[darling(default)]
+    deferred_read: bool,
+    #[darling(default)]
+    deferred_write: bool,
+    #[darling(default)]
     security: Option<SecurityMode>,
+    #[darling(default)]
+    read_security: Option<SecurityMode>,
+    #[darling(default)]
+    write_security: Option<SecurityMode>,
+    #[darling(default)]
+    cccd_security: Option<SecurityMode>,
+    #[darling(default)]
+    value: Option<syn::Expr>,
 }
 
 #[derive(Debug)]
@@ -64,29 +76,121 @@ pub fn gatt_server(_args: TokenStream, item: TokenStream) -> TokenStream {
             return TokenStream::new();
         }
     };
-    let fields = struct_fields.named.iter().cloned().collect::<Vec<syn::Field>>();
+    let mut fields = struct_fields.named.iter().cloned().collect::<Vec<syn::Field>>();
 
     let struct_name = struc.ident.clone();
     let event_enum_name = format_ident!("{}Event", struct_name);
 
     let mut code_register_init = TokenStream2::new();
+    let mut code_struct_init = TokenStream2::new();
     let mut code_on_write = TokenStream2::new();
+    let mut code_on_deferred_read = TokenStream2::new();
+    let mut code_on_deferred_write = TokenStream2::new();
     let mut code_event_enum = TokenStream2::new();
 
     let ble = quote!(::nrf_softdevice::ble);
 
-    for field in fields.iter() {
+    for field in fields.iter_mut() {
+        // Services included by this one, with `#[include(other_service, ...)]`.
+        let mut includes = Vec::new();
+        field.attrs.retain(|attr| {
+            if !attr.path.is_ident("include") {
+                return true;
+            }
+            match attr.parse_meta() {
+                Ok(syn::Meta::List(list)) => {
+                    for nested in list.nested {
+                        match nested {
+                            syn::NestedMeta::Meta(syn::Meta::Path(path)) if path.get_ident().is_some() => {
+                                includes.push(path.get_ident().unwrap().clone())
+                            }
+                            nested => ctxt.error_spanned_by(nested, "expected the name of a service field"),
+                        }
+                    }
+                }
+                _ => ctxt.error_spanned_by(attr, "expected #[include(service, ...)]"),
+            }
+            false
+        });
+
         let name = field.ident.as_ref().unwrap();
         let ty = &field.ty;
         let span = field.ty.span();
-        code_register_init.extend(quote_spanned!(span=>
-            #name: #ty::new(sd)?,
+
+        // Multiple instances of the same service can be registered with an array field.
+        let (service_ty, array_len) = match &field.ty {
+            syn::Type::Array(array) => (&*array.elem, Some(&array.len)),
+            ty => (ty, None),
+        };
+        let service_ty_ident = match service_ty {
+            syn::Type::Path(p) => &p.path.segments.last().unwrap().ident,
+            _ => {
+                ctxt.error_spanned_by(ty, "gatt_server fields must be services, or arrays of services.");
+                continue;
+            }
+        };
+
+        let code_new = if includes.is_empty() {
+            quote_spanned!(span=> #service_ty::new(sd))
+        } else {
+            quote_spanned!(span=> #service_ty::new_with_includes(sd, &[#(#includes.service_handle()),*]))
+        };
+        if let Some(len) = array_len {
+            code_register_init.extend(quote_spanned!(span=>
+                let #name: #ty = {
+                    // Registered one by one, stopping at the first error.
+                    let mut services: [Option<#service_ty>; #len] = ::core::array::from_fn(|_| None);
+                    for service in services.iter_mut() {
+                        *service = Some(#code_new?);
+                    }
+                    services.map(|service| service.unwrap())
+                };
+            ));
+        } else {
+            code_register_init.extend(quote_spanned!(span=>
+                let #name = #code_new?;
+            ));
+        }
+        code_struct_init.extend(quote_spanned!(span=>
+            #name,
         ));
 
-        if let syn::Type::Path(p) = &field.ty {
-            let name_pascal = format_ident!("{}", inflector::cases::pascalcase::to_pascal_case(&name.to_string()));
-            let event_enum_ty = p.path.get_ident().unwrap();
-            let event_enum_variant = format_ident!("{}Event", event_enum_ty);
+        let name_pascal = format_ident!("{}", inflector::cases::pascalcase::to_pascal_case(&name.to_string()));
+        let event_enum_variant = format_ident!("{}Event", service_ty_ident);
+
+        if array_len.is_some() {
+            code_event_enum.extend(quote_spanned!(span=>
+                #name_pascal(usize, #event_enum_variant),
+            ));
+
+            code_on_write.extend(quote_spanned!(span=>
+                for (i, service) in self.#name.iter().enumerate() {
+                    if let Some(e) = service.on_write(handle, data) {
+                        return Some(#event_enum_name::#name_pascal(i, e));
+                    }
+                }
+            ));
+
+            code_on_deferred_read.extend(quote_spanned!(span=>
+                let mut reply = reply;
+                for (i, service) in self.#name.iter().enumerate() {
+                    reply = match service.on_deferred_read(handle, offset, reply) {
+                        Ok(e) => return e.map(|e| #event_enum_name::#name_pascal(i, e)),
+                        Err(reply) => reply,
+                    };
+                }
+            ));
+
+            code_on_deferred_write.extend(quote_spanned!(span=>
+                let mut reply = reply;
+                for (i, service) in self.#name.iter().enumerate() {
+                    reply = match service.on_deferred_write(handle, op, offset, data, reply) {
+                        Ok(e) => return e.map(|e| #event_enum_name::#name_pascal(i, e)),
+                        Err(reply) => reply,
+                    };
+                }
+            ));
+        } else {
             code_event_enum.extend(quote_spanned!(span=>
                 #name_pascal(#event_enum_variant),
             ));
@@ -96,6 +200,20 @@ pub fn gatt_server(_args: TokenStream, item: TokenStream) -> TokenStream {
                     return Some(#event_enum_name::#name_pascal(e));
                 }
             ));
+
+            code_on_deferred_read.extend(quote_spanned!(span=>
+                let reply = match self.#name.on_deferred_read(handle, offset, reply) {
+                    Ok(e) => return e.map(#event_enum_name::#name_pascal),
+                    Err(reply) => reply,
+                };
+            ));
+
+            code_on_deferred_write.extend(quote_spanned!(span=>
+                let reply = match self.#name.on_deferred_write(handle, op, offset, data, reply) {
+                    Ok(e) => return e.map(#event_enum_name::#name_pascal),
+                    Err(reply) => reply,
+                };
+            ));
         }
     }
 
@@ -108,8 +226,10 @@ pub fn gatt_server(_args: TokenStream, item: TokenStream) -> TokenStream {
         impl #struct_name {
             #struct_vis fn new(sd: &mut ::nrf_softdevice::Softdevice) -> Result<Self, #ble::gatt_server::RegisterError>
             {
+                #code_register_init
+
                 Ok(Self {
-                    #code_register_init
+                    #code_struct_init
                 })
             }
         }
@@ -127,6 +247,31 @@ pub fn gatt_server(_args: TokenStream, item: TokenStream) -> TokenStream {
                 #code_on_write
                 None
             }
+
+            fn on_deferred_read(&self, handle: u16, offset: usize, reply: #ble::DeferredReadReply) -> Option<Self::Event> {
+                use #ble::gatt_server::Service;
+
+                #code_on_deferred_read
+                // Dropping the reply responds with an error.
+                let _ = reply;
+                None
+            }
+
+            fn on_deferred_write(
+                &self,
+                handle: u16,
+                op: #ble::gatt_server::WriteOp,
+                offset: usize,
+                data: &[u8],
+                reply: #ble::DeferredWriteReply,
+            ) -> Option<Self::Event> {
+                use #ble::gatt_server::Service;
+
+                #code_on_deferred_write
+                // Dropping the reply responds with an error.
+                let _ = reply;
+                None
+            }
         }
     };
 
@@ -213,6 +358,8 @@ pub fn gatt_service(args: TokenStream, item: TokenStream) -> TokenStream {
     let mut code_build_chars = TokenStream2::new();
     let mut code_struct_init = TokenStream2::new();
     let mut code_on_write = TokenStream2::new();
+    let mut code_on_deferred_read = TokenStream2::new();
+    let mut code_on_deferred_write = TokenStream2::new();
     let mut code_event_enum = TokenStream2::new();
 
     let ble = quote!(::nrf_softdevice::ble);
@@ -225,7 +372,9 @@ pub fn gatt_service(args: TokenStream, item: TokenStream) -> TokenStream {
         let get_fn = format_ident!("{}_get", ch.name);
         let set_fn = format_ident!("{}_set", ch.name);
         let notify_fn = format_ident!("{}_notify", ch.name);
+        let try_notify_fn = format_ident!("{}_try_notify", ch.name);
         let indicate_fn = format_ident!("{}_indicate", ch.name);
+        let try_indicate_fn = format_ident!("{}_try_indicate", ch.name);
         let fn_vis = ch.vis.clone();
 
         let uuid = ch.args.uuid;
@@ -234,6 +383,8 @@ pub fn gatt_service(args: TokenStream, item: TokenStream) -> TokenStream {
         let write_without_response = ch.args.write_without_response;
         let notify = ch.args.notify;
         let indicate = ch.args.indicate;
+        let deferred_read = ch.args.deferred_read;
+        let deferred_write = ch.args.deferred_write;
         let ty = &ch.ty;
         let ty_as_val = quote!(<#ty as #ble::GattValue>);
 
@@ -243,6 +394,21 @@ pub fn gatt_service(args: TokenStream, item: TokenStream) -> TokenStream {
         } else {
             quote!()
         };
+        let read_security = if let Some(security) = ch.args.read_security {
+            quote!(attr = attr.read_security(#security))
+        } else {
+            quote!()
+        };
+        let write_security = if let Some(security) = ch.args.write_security {
+            quote!(attr = attr.write_security(#security))
+        } else {
+            quote!()
+        };
+        let cccd_security = if let Some(security) = ch.args.cccd_security {
+            quote!(let metadata = metadata.security(#security);)
+        } else {
+            quote!()
+        };
 
         fields.push(syn::Field {
             ident: Some(value_handle.clone()),
@@ -252,14 +418,33 @@ pub fn gatt_service(args: TokenStream, item: TokenStream) -> TokenStream {
             vis: syn::Visibility::Inherited,
         });
 
+        let code_initial_value = if let Some(value) = &ch.args.value {
+            quote_spanned!(ch.span=>
+                let val: #ty = #value;
+                let val = #ty_as_val::to_gatt(&val);
+            )
+        } else {
+            quote_spanned!(ch.span=>
+                let val = &[123u8; #ty_as_val::MIN_SIZE][..];
+            )
+        };
+
         code_build_chars.extend(quote_spanned!(ch.span=>
             let #char_name = {
-                let val = [123u8; #ty_as_val::MIN_SIZE];
-                let mut attr = #ble::gatt_server::characteristic::Attribute::new(&val);
+                #code_initial_value
+                let mut attr = #ble::gatt_server::characteristic::Attribute::new(val);
                 if #ty_as_val::MAX_SIZE != #ty_as_val::MIN_SIZE {
                     attr = attr.variable_len(#ty_as_val::MAX_SIZE as u16);
                 }
                 #security;
+                #read_security;
+                #write_security;
+                if #deferred_read {
+                    attr = attr.deferred_read();
+                }
+                if #deferred_write {
+                    attr = attr.deferred_write();
+                }
                 let props = #ble::gatt_server::characteristic::Properties {
                     read: #read,
                     write: #write,
@@ -269,6 +454,7 @@ pub fn gatt_service(args: TokenStream, item: TokenStream) -> TokenStream {
                     ..Default::default()
                 };
                 let metadata = #ble::gatt_server::characteristic::Metadata::new(props);
+                #cccd_security
                 service_builder.add_characteristic(#uuid, attr, metadata)?.build()
             };
         ));
@@ -303,6 +489,41 @@ pub fn gatt_service(args: TokenStream, item: TokenStream) -> TokenStream {
             code_struct_init.extend(quote_spanned!(ch.span=>
                 #cccd_handle: #char_name.cccd_handle,
             ));
+
+            let is_subscribed_fn = format_ident!("{}_is_subscribed", ch.name);
+            code_impl.extend(quote_spanned!(ch.span=>
+                #fn_vis fn #is_subscribed_fn(&self, conn: &#ble::Connection) -> bool {
+                    #ble::gatt_server::is_subscribed(conn, self.#cccd_handle)
+                }
+            ));
+        }
+
+        if deferred_read {
+            let case_read = format_ident!("{}Read", name_pascal);
+            code_event_enum.extend(quote_spanned!(ch.span=>
+                #case_read{offset: usize, reply: #ble::DeferredReadReply},
+            ));
+            code_on_deferred_read.extend(quote_spanned!(ch.span=>
+                if handle == self.#value_handle {
+                    return Ok(Some(#event_enum_name::#case_read{offset, reply}));
+                }
+            ));
+        }
+
+        if deferred_write {
+            let case_write_request = format_ident!("{}WriteRequest", name_pascal);
+            code_event_enum.extend(quote_spanned!(ch.span=>
+                #case_write_request{value: #ty, reply: #ble::DeferredWriteReply},
+            ));
+            code_on_deferred_write.extend(quote_spanned!(ch.span=>
+                if handle == self.#value_handle {
+                    if offset != 0 || data.len() < #ty_as_val::MIN_SIZE || data.len() > #ty_as_val::MAX_SIZE {
+                        let _ = reply.reject(#ble::GattError::ATTERR_INVALID_ATT_VAL_LENGTH);
+                        return Ok(None);
+                    }
+                    return Ok(Some(#event_enum_name::#case_write_request{value: #ty_as_val::from_gatt(data), reply}));
+                }
+            ));
         }
 
         if write || write_without_response {
@@ -323,13 +544,22 @@ pub fn gatt_service(args: TokenStream, item: TokenStream) -> TokenStream {
 
         if notify {
             code_impl.extend(quote_spanned!(ch.span=>
-                #fn_vis fn #notify_fn(
+                #fn_vis async fn #notify_fn(
                     &self,
                     conn: &#ble::Connection,
                     val: &#ty,
                 ) -> Result<(), #ble::gatt_server::NotifyValueError> {
                     let buf = #ty_as_val::to_gatt(val);
-                    #ble::gatt_server::notify_value(conn, self.#value_handle, buf)
+                    #ble::gatt_server::notify_value(conn, self.#value_handle, buf).await
+                }
+
+                #fn_vis fn #try_notify_fn(
+                    &self,
+                    conn: &#ble::Connection,
+                    val: &#ty,
+                ) -> Result<(), #ble::gatt_server::TryNotifyValueError> {
+                    let buf = #ty_as_val::to_gatt(val);
+                    #ble::gatt_server::try_notify_value(conn, self.#value_handle, buf)
                 }
             ));
 
@@ -353,13 +583,22 @@ pub fn gatt_service(args: TokenStream, item: TokenStream) -> TokenStream {
 
         if indicate {
             code_impl.extend(quote_spanned!(ch.span=>
-                #fn_vis fn #indicate_fn(
+                #fn_vis async fn #indicate_fn(
                     &self,
                     conn: &#ble::Connection,
                     val: &#ty,
                 ) -> Result<(), #ble::gatt_server::IndicateValueError> {
                     let buf = #ty_as_val::to_gatt(val);
-                    #ble::gatt_server::indicate_value(conn, self.#value_handle, buf)
+                    #ble::gatt_server::indicate_value(conn, self.#value_handle, buf).await
+                }
+
+                #fn_vis fn #try_indicate_fn(
+                    &self,
+                    conn: &#ble::Connection,
+                    val: &#ty,
+                ) -> Result<(), #ble::gatt_server::TryIndicateValueError> {
+                    let buf = #ty_as_val::to_gatt(val);
+                    #ble::gatt_server::try_indicate_value(conn, self.#value_handle, buf)
                 }
             ));
 
@@ -401,6 +640,15 @@ pub fn gatt_service(args: TokenStream, item: TokenStream) -> TokenStream {
         }
     }
 
+    // Reserved name, so it can't clash with the user's fields.
+    fields.push(syn::Field {
+        ident: Some(format_ident!("__service_handle")),
+        ty: syn::Type::Verbatim(quote!(#ble::gatt_server::ServiceHandle)),
+        attrs: Vec::new(),
+        colon_token: Default::default(),
+        vis: syn::Visibility::Inherited,
+    });
+
     let uuid = args.uuid;
     struct_fields.named = syn::punctuated::Punctuated::from_iter(fields);
     let struc_vis = struc.vis.clone();
@@ -411,18 +659,35 @@ pub fn gatt_service(args: TokenStream, item: TokenStream) -> TokenStream {
         #[allow(unused)]
         impl #struct_name {
             #struct_vis fn new(sd: &mut ::nrf_softdevice::Softdevice) -> Result<Self, #ble::gatt_server::RegisterError>
+            {
+                Self::new_with_includes(sd, &[])
+            }
+
+            #struct_vis fn new_with_includes(
+                sd: &mut ::nrf_softdevice::Softdevice,
+                includes: &[#ble::gatt_server::ServiceHandle],
+            ) -> Result<Self, #ble::gatt_server::RegisterError>
             {
                 let mut service_builder = #ble::gatt_server::builder::ServiceBuilder::new(sd, #uuid)?;
 
+                for service in includes {
+                    service_builder.include_service(service)?;
+                }
+
                 #code_build_chars
 
-                let _ = service_builder.build();
+                let service_handle = service_builder.build();
 
                 Ok(Self {
+                    __service_handle: service_handle,
                     #code_struct_init
                 })
             }
 
+            #struct_vis fn service_handle(&self) -> #ble::gatt_server::ServiceHandle {
+                self.__service_handle
+            }
+
             #code_impl
         }
 
@@ -433,6 +698,23 @@ pub fn gatt_service(args: TokenStream, item: TokenStream) -> TokenStream {
                 #code_on_write
                 None
             }
+
+            fn on_deferred_read(&self, handle: u16, offset: usize, reply: #ble::DeferredReadReply) -> Result<Option<Self::Event>, #ble::DeferredReadReply> {
+                #code_on_deferred_read
+                Err(reply)
+            }
+
+            fn on_deferred_write(
+                &self,
+                handle: u16,
+                op: #ble::gatt_server::WriteOp,
+                offset: usize,
+                data: &[u8],
+                reply: #ble::DeferredWriteReply,
+            ) -> Result<Option<Self::Event>, #ble::DeferredWriteReply> {
+                #code_on_deferred_write
+                Err(reply)
+            }
         }
 
         #[allow(unused)]
@@ -510,6 +792,7 @@ pub fn gatt_client(args: TokenStream, item: TokenStream) -> TokenStream {
 
     //panic!("chars {:?}", chars);
     let struct_name = struc.ident.clone();
+    let struc_vis = struc.vis.clone();
     let event_enum_name = format_ident!("{}Event", struct_name);
 
     let mut code_impl = TokenStream2::new();
@@ -573,8 +856,8 @@ pub fn gatt_client(args: TokenStream, item: TokenStream) -> TokenStream {
         let mut code_descs = TokenStream2::new();
         if indicate || notify {
             code_descs.extend(quote_spanned!(ch.span=>
-                if _desc_uuid == #ble::Uuid::new_16(::nrf_softdevice::raw::BLE_UUID_DESCRIPTOR_CLIENT_CHAR_CONFIG as u16) {
-                    self.#cccd_handle = desc.handle;
+                if let Some(cccd_handle) = #ble::gatt_client::find_cccd(descriptors) {
+                    self.#cccd_handle = cccd_handle;
                 }
             ));
         }
@@ -584,11 +867,7 @@ pub fn gatt_client(args: TokenStream, item: TokenStream) -> TokenStream {
                 if char_uuid == self.#uuid_field {
                     // TODO maybe check the char_props have the necessary operations allowed? read/write/notify/etc
                     self.#value_handle = characteristic.handle_value;
-                    for desc in descriptors {
-                        if let Some(_desc_uuid) = desc.uuid {
-                            #code_descs
-                        }
-                    }
+                    #code_descs
                 }
             }
         ));
@@ -604,6 +883,9 @@ pub fn gatt_client(args: TokenStream, item: TokenStream) -> TokenStream {
                 #fn_vis async fn #read_fn(&self) -> Result<#ty, #ble::gatt_client::ReadError> {
                     let mut buf = [0; #ty_as_val::MAX_SIZE];
                     let len = #ble::gatt_client::read(&self.conn, self.#value_handle, &mut buf).await?;
+                    if len < #ty_as_val::MIN_SIZE {
+                        return Err(#ble::gatt_client::ReadError::InvalidLength);
+                    }
                     Ok(#ty_as_val::from_gatt(&buf[..len]))
                 }
             ));
@@ -638,7 +920,7 @@ pub fn gatt_client(args: TokenStream, item: TokenStream) -> TokenStream {
                 #cccd_handle: 0,
             ));
             code_disc_done.extend(quote_spanned!(ch.span=>
-                if self.#value_handle == 0 {
+                if self.#cccd_handle == 0 {
                     return Err(#ble::gatt_client::DiscoverError::ServiceIncomplete);
                 }
             ));
@@ -662,7 +944,7 @@ pub fn gatt_client(args: TokenStream, item: TokenStream) -> TokenStream {
             if !indicate {
                 code_impl.extend(quote_spanned!(ch.span=>
                     #fn_vis async fn #cccd_write_fn(&self, notifications: bool) -> Result<(), #ble::gatt_client::WriteError> {
-                        #ble::gatt_client::write(&self.conn, self.#cccd_handle, &[if notifications { 0x01 } else { 0x00 }, 0x00]).await
+                        #ble::gatt_client::write_cccd(&self.conn, self.#cccd_handle, false, notifications).await
                     }
                 ));
             }
@@ -686,7 +968,7 @@ pub fn gatt_client(args: TokenStream, item: TokenStream) -> TokenStream {
             if !notify {
                 code_impl.extend(quote_spanned!(ch.span=>
                     #fn_vis async fn #cccd_write_fn(&self, indications: bool) -> Result<(), #ble::gatt_client::WriteError> {
-                        #ble::gatt_client::write(&self.conn, self.#cccd_handle, &[if indications { 0x02 } else { 0x00 }, 0x00]).await
+                        #ble::gatt_client::write_cccd(&self.conn, self.#cccd_handle, indications, false).await
                     }
                 ));
             }
@@ -695,7 +977,7 @@ pub fn gatt_client(args: TokenStream, item: TokenStream) -> TokenStream {
         if indicate && notify {
             code_impl.extend(quote_spanned!(ch.span=>
                 #fn_vis async fn #cccd_write_fn(&self, indications: bool, notifications: bool) -> Result<(), #ble::gatt_client::WriteError> {
-                    #ble::gatt_client::write(&self.conn, self.#cccd_handle, &[if indications { 0x02 } else { 0x00 } | if notifications { 0x01 } else { 0x00 }, 0x00]).await
+                    #ble::gatt_client::write_cccd(&self.conn, self.#cccd_handle, indications, notifications).await
                 }
             ));
         }
@@ -747,7 +1029,7 @@ pub fn gatt_client(args: TokenStream, item: TokenStream) -> TokenStream {
             }
         }
 
-        enum #event_enum_name {
+        #struc_vis enum #event_enum_name {
             #code_event_enum
         }
     };
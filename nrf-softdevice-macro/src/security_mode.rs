@@ -19,13 +19,13 @@ impl FromMeta for SecurityMode {
         match value.trim().to_lowercase().as_str() {
             "noaccess" => Ok(SecurityMode::NoAccess),
             "open" => Ok(SecurityMode::Open),
-            "justworks" => Ok(SecurityMode::JustWorks),
+            "justworks" | "encrypted" => Ok(SecurityMode::JustWorks),
             "mitm" => Ok(SecurityMode::Mitm),
-            "lescmitm" => Ok(SecurityMode::LescMitm),
+            "lescmitm" | "lesc" => Ok(SecurityMode::LescMitm),
             "signed" => Ok(SecurityMode::Signed),
             "signedmitm" => Ok(SecurityMode::SignedMitm),
             _ => Err(Error::unknown_value(format!(
-                "SecurityMode {} is invalid. Expected one of: NoAccess, Open, JustWorks, Mitm, LescMitm, Signed, SignedMitm",
+                "SecurityMode {} is invalid. Expected one of: NoAccess, Open, JustWorks (or Encrypted), Mitm, LescMitm (or Lesc), Signed, SignedMitm",
                 value)
                 .as_str())),
         }
@@ -1,13 +1,26 @@
 #[cfg(feature = "defmt")]
 use defmt::Format;
 
+use crate::ble::Appearance;
+
 const LEGACY_PAYLOAD_LEN: usize = 31;
-const EXTENDED_PAYLOAD_LEN: usize = 254;
+const EXTENDED_PAYLOAD_LEN: usize = 255;
+
+/// Maximum length of the data of an AD structure, so that the length byte (which includes the type) fits in a `u8`.
+const AD_DATA_MAX_LEN: usize = u8::MAX as usize - 1;
 
 #[derive(Debug, Clone, Copy)]
 #[cfg_attr(feature = "defmt", derive(Format))]
 pub enum Error {
-    Oversize { expected: usize },
+    Oversize {
+        expected: usize,
+    },
+    /// The data of an AD structure is longer than the 254 bytes its length byte allows.
+    AdStructureTooLong {
+        len: usize,
+    },
+    /// The AD type is not allowed in scan response data.
+    NotAllowedInScanResponse(AdvertisementDataType),
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -179,9 +192,70 @@ impl From<ServiceUuid16> for u16 {
     }
 }
 
+/// Apple's Bluetooth SIG company identifier, used by iBeacon.
+const APPLE_COMPANY_ID: u16 = 0x004c;
+
+/// An iBeacon advertisement.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct IBeacon {
+    /// Proximity UUID, in the usual big-endian order.
+    pub uuid: [u8; 16],
+    pub major: u16,
+    pub minor: u16,
+    /// Measured power at 1 meter, in dBm.
+    pub tx_power: i8,
+}
+
+impl IBeacon {
+    const TYPE: u8 = 0x02;
+    const LEN: u8 = 0x15;
+
+    /// Encode the manufacturer specific data of the beacon, including the company identifier.
+    pub const fn to_bytes(&self) -> [u8; 25] {
+        let company = APPLE_COMPANY_ID.to_le_bytes();
+        let major = self.major.to_be_bytes();
+        let minor = self.minor.to_be_bytes();
+        let mut buf = [0; 25];
+        buf[0] = company[0];
+        buf[1] = company[1];
+        buf[2] = Self::TYPE;
+        buf[3] = Self::LEN;
+        let mut i = 0;
+        while i < 16 {
+            buf[4 + i] = self.uuid[i];
+            i += 1;
+        }
+        buf[20] = major[0];
+        buf[21] = major[1];
+        buf[22] = minor[0];
+        buf[23] = minor[1];
+        buf[24] = self.tx_power as u8;
+        buf
+    }
+
+    /// Decode the manufacturer specific data of an iBeacon, including the company identifier.
+    ///
+    /// Returns None if the data is not an iBeacon.
+    pub fn from_bytes(data: &[u8]) -> Option<Self> {
+        let data: &[u8; 25] = data.try_into().ok()?;
+        if u16::from_le_bytes([data[0], data[1]]) != APPLE_COMPANY_ID || data[2] != Self::TYPE || data[3] != Self::LEN {
+            return None;
+        }
+        Some(Self {
+            uuid: unwrap!(data[4..20].try_into()),
+            major: u16::from_be_bytes([data[20], data[21]]),
+            minor: u16::from_be_bytes([data[22], data[23]]),
+            tx_power: data[24] as i8,
+        })
+    }
+}
+
 pub struct AdvertisementBuilder<const N: usize> {
     buf: [u8; N],
     ptr: usize,
+    // First error other than an overflow of the buffer, which is tracked by `ptr`.
+    error: Option<Error>,
 }
 
 pub struct AdvertisementPayload<const N: usize> {
@@ -205,7 +279,11 @@ impl<const N: usize> core::ops::Deref for AdvertisementPayload<N> {
 
 impl<const K: usize> AdvertisementBuilder<K> {
     pub const fn new() -> Self {
-        Self { buf: [0; K], ptr: 0 }
+        Self {
+            buf: [0; K],
+            ptr: 0,
+            error: None,
+        }
     }
 
     const fn write(mut self, data: &[u8]) -> Self {
@@ -224,6 +302,21 @@ impl<const K: usize> AdvertisementBuilder<K> {
         self
     }
 
+    const fn fail(mut self, error: Error) -> Self {
+        if self.error.is_none() {
+            self.error = Some(error);
+        }
+        self
+    }
+
+    /// Write the header of an AD structure with `len` bytes of data.
+    const fn header(self, ad: AdvertisementDataType, len: usize) -> Self {
+        if len > AD_DATA_MAX_LEN {
+            return self.fail(Error::AdStructureTooLong { len });
+        }
+        self.write(&[len as u8 + 1, ad.to_u8()])
+    }
+
     pub const fn capacity() -> usize {
         K
     }
@@ -236,13 +329,17 @@ impl<const K: usize> AdvertisementBuilder<K> {
     ///
     /// *Note: The length is automatically computed and prepended.*
     pub const fn raw(self, ad: AdvertisementDataType, data: &[u8]) -> Self {
-        self.write(&[data.len() as u8 + 1, ad.to_u8()]).write(data)
+        self.header(ad, data.len()).write(data)
     }
 
     /// Get the resulting advertisement payload.
     ///
-    /// Returns `Error::Oversize` if more than `K` bytes were written to the builder.
+    /// Returns `Error::Oversize` if more than `K` bytes were written to the builder, or
+    /// `Error::AdStructureTooLong` if the data of an AD structure doesn't fit in its length byte.
     pub const fn try_build(self) -> Result<AdvertisementPayload<K>, Error> {
+        if let Some(error) = self.error {
+            return Err(error);
+        }
         if self.ptr <= K {
             Ok(AdvertisementPayload {
                 buf: self.buf,
@@ -255,9 +352,11 @@ impl<const K: usize> AdvertisementBuilder<K> {
 
     /// Get the resulting advertisement payload.
     ///
-    /// Panics if more than `K` bytes were written to the builder.
+    /// Panics if more than `K` bytes were written to the builder, or if the data of an AD structure
+    /// doesn't fit in its length byte.
     pub const fn build(self) -> AdvertisementPayload<K> {
         // Use core::assert! even if defmt is enabled because it is const
+        core::assert!(self.error.is_none(), "invalid advertisement data");
         core::assert!(self.ptr <= K, "advertisement exceeded buffer length");
 
         AdvertisementPayload {
@@ -285,7 +384,7 @@ impl<const K: usize> AdvertisementBuilder<K> {
             ServiceList::Complete => AdvertisementDataType::COMPLETE_16_SERVICE_LIST,
         };
 
-        let mut res = self.write(&[(services.len() * 2) as u8 + 1, ad_type.to_u8()]);
+        let mut res = self.header(ad_type, services.len() * 2);
         let mut i = 0;
         while i < services.len() {
             res = res.write(&(services[i].to_u16()).to_le_bytes());
@@ -304,7 +403,7 @@ impl<const K: usize> AdvertisementBuilder<K> {
             ServiceList::Complete => AdvertisementDataType::COMPLETE_128_SERVICE_LIST,
         };
 
-        let mut res = self.write(&[(services.len() * 16) as u8 + 1, ad_type.to_u8()]);
+        let mut res = self.header(ad_type, services.len() * 16);
         let mut i = 0;
         while i < services.len() {
             res = res.write(&services[i]);
@@ -313,6 +412,63 @@ impl<const K: usize> AdvertisementBuilder<K> {
         res
     }
 
+    /// Add manufacturer specific data to the advertisement data.
+    ///
+    /// `company_id` is the Bluetooth SIG assigned company identifier, which is prepended to the payload.
+    pub const fn manufacturer_data(self, company_id: u16, payload: &[u8]) -> Self {
+        self.header(AdvertisementDataType::MANUFACTURER_SPECIFIC_DATA, payload.len() + 2)
+            .write(&company_id.to_le_bytes())
+            .write(payload)
+    }
+
+    /// Add service data for a 16-bit service uuid to the advertisement data.
+    pub const fn service_data_16(self, uuid: ServiceUuid16, payload: &[u8]) -> Self {
+        self.header(AdvertisementDataType::SERVICE_DATA_16, payload.len() + 2)
+            .write(&uuid.to_u16().to_le_bytes())
+            .write(payload)
+    }
+
+    /// Add service data for a 32-bit service uuid to the advertisement data.
+    pub const fn service_data_32(self, uuid: u32, payload: &[u8]) -> Self {
+        self.header(AdvertisementDataType::SERVICE_DATA_32, payload.len() + 4)
+            .write(&uuid.to_le_bytes())
+            .write(payload)
+    }
+
+    /// Add service data for a 128-bit service uuid to the advertisement data.
+    ///
+    /// Note that the UUID needs to be in little-endian format, i.e. opposite to what you would
+    /// normally write UUIDs.
+    pub const fn service_data_128(self, uuid: [u8; 16], payload: &[u8]) -> Self {
+        self.header(AdvertisementDataType::SERVICE_DATA_128, payload.len() + 16)
+            .write(&uuid)
+            .write(payload)
+    }
+
+    /// Add the preferred connection interval range to the advertisement data.
+    ///
+    /// Both limits are in 1.25ms units. Use `0xFFFF` for a limit with no specific preference.
+    pub const fn peripheral_conn_interval_range(self, min: u16, max: u16) -> Self {
+        let min = min.to_le_bytes();
+        let max = max.to_le_bytes();
+        self.raw(
+            AdvertisementDataType::PERIPHERAL_CONNECTION_INTERVAL_RANGE,
+            &[min[0], min[1], max[0], max[1]],
+        )
+    }
+
+    /// Add the appearance of the device to the advertisement data.
+    pub const fn appearance(self, appearance: Appearance) -> Self {
+        self.raw(AdvertisementDataType::APPEARANCE, &appearance.to_u16().to_le_bytes())
+    }
+
+    /// Add an iBeacon to the advertisement data.
+    ///
+    /// *Note: iBeacon advertisements are expected to also contain flags and nothing else.*
+    pub const fn ibeacon(self, beacon: &IBeacon) -> Self {
+        self.raw(AdvertisementDataType::MANUFACTURER_SPECIFIC_DATA, &beacon.to_bytes())
+    }
+
     /// Add a name to the advertisement data.
     pub const fn short_name(self, name: &str) -> Self {
         self.raw(AdvertisementDataType::SHORT_NAME, name.as_bytes())
@@ -344,8 +500,140 @@ impl<const K: usize> AdvertisementBuilder<K> {
     }
 }
 
+/// Builder for scan response data.
+///
+/// Works like [`AdvertisementBuilder`], but doesn't allow adding AD types that are only valid in
+/// advertising data, such as [`AdvertisementDataType::FLAGS`].
+pub struct ScanResponseBuilder<const N: usize> {
+    inner: AdvertisementBuilder<N>,
+}
+
+impl<const K: usize> ScanResponseBuilder<K> {
+    pub const fn new() -> Self {
+        Self {
+            inner: AdvertisementBuilder::new(),
+        }
+    }
+
+    pub const fn capacity() -> usize {
+        K
+    }
+
+    pub const fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    /// Write raw bytes to the scan response data.
+    ///
+    /// If `ad` is [`AdvertisementDataType::FLAGS`], which is not allowed in scan responses, nothing is written
+    /// and building fails with `Error::NotAllowedInScanResponse`.
+    ///
+    /// *Note: The length is automatically computed and prepended.*
+    pub const fn raw(self, ad: AdvertisementDataType, data: &[u8]) -> Self {
+        if ad.to_u8() == AdvertisementDataType::FLAGS.to_u8() {
+            return Self {
+                inner: self.inner.fail(Error::NotAllowedInScanResponse(ad)),
+            };
+        }
+
+        Self {
+            inner: self.inner.raw(ad, data),
+        }
+    }
+
+    /// Get the resulting scan response payload.
+    ///
+    /// Returns an error if the data is invalid, see [`AdvertisementBuilder::try_build`] and [`Self::raw`].
+    pub const fn try_build(self) -> Result<AdvertisementPayload<K>, Error> {
+        self.inner.try_build()
+    }
+
+    /// Get the resulting scan response payload.
+    ///
+    /// Panics if the data is invalid, see [`Self::try_build`].
+    pub const fn build(self) -> AdvertisementPayload<K> {
+        self.inner.build()
+    }
+
+    /// Add a list of 16-bit service uuids to the scan response data.
+    pub const fn services_16(self, complete: ServiceList, services: &[ServiceUuid16]) -> Self {
+        Self {
+            inner: self.inner.services_16(complete, services),
+        }
+    }
+
+    /// Add a list of 128-bit service uuids to the scan response data.
+    ///
+    /// Note that each UUID in the list needs to be in little-endian format, i.e. opposite to what you would
+    /// normally write UUIDs.
+    pub const fn services_128(self, complete: ServiceList, services: &[[u8; 16]]) -> Self {
+        Self {
+            inner: self.inner.services_128(complete, services),
+        }
+    }
+
+    /// Add manufacturer specific data to the scan response data.
+    ///
+    /// `company_id` is the Bluetooth SIG assigned company identifier, which is prepended to the payload.
+    pub const fn manufacturer_data(self, company_id: u16, payload: &[u8]) -> Self {
+        Self {
+            inner: self.inner.manufacturer_data(company_id, payload),
+        }
+    }
+
+    /// Add service data for a 16-bit service uuid to the scan response data.
+    pub const fn service_data_16(self, uuid: ServiceUuid16, payload: &[u8]) -> Self {
+        Self {
+            inner: self.inner.service_data_16(uuid, payload),
+        }
+    }
+
+    /// Add service data for a 32-bit service uuid to the scan response data.
+    pub const fn service_data_32(self, uuid: u32, payload: &[u8]) -> Self {
+        Self {
+            inner: self.inner.service_data_32(uuid, payload),
+        }
+    }
+
+    /// Add service data for a 128-bit service uuid to the scan response data.
+    ///
+    /// Note that the UUID needs to be in little-endian format, i.e. opposite to what you would
+    /// normally write UUIDs.
+    pub const fn service_data_128(self, uuid: [u8; 16], payload: &[u8]) -> Self {
+        Self {
+            inner: self.inner.service_data_128(uuid, payload),
+        }
+    }
+
+    /// Add a name to the scan response data.
+    pub const fn short_name(self, name: &str) -> Self {
+        Self {
+            inner: self.inner.short_name(name),
+        }
+    }
+
+    /// Add a name to the scan response data.
+    pub const fn full_name(self, name: &str) -> Self {
+        Self {
+            inner: self.inner.full_name(name),
+        }
+    }
+
+    /// Adds the provided string as a name, truncating and typing as needed.
+    ///
+    /// *Note: This modifier should be placed last.*
+    pub const fn adapt_name(self, name: &str) -> Self {
+        Self {
+            inner: self.inner.adapt_name(name),
+        }
+    }
+}
+
 pub type LegacyAdvertisementBuilder = AdvertisementBuilder<LEGACY_PAYLOAD_LEN>;
 pub type ExtendedAdvertisementBuilder = AdvertisementBuilder<EXTENDED_PAYLOAD_LEN>;
 
+pub type LegacyScanResponseBuilder = ScanResponseBuilder<LEGACY_PAYLOAD_LEN>;
+pub type ExtendedScanResponseBuilder = ScanResponseBuilder<EXTENDED_PAYLOAD_LEN>;
+
 pub type LegacyAdvertisementPayload = AdvertisementPayload<LEGACY_PAYLOAD_LEN>;
 pub type ExtendedAdvertisementPayload = AdvertisementPayload<EXTENDED_PAYLOAD_LEN>;
@@ -0,0 +1,454 @@
+//! Parser for received advertising data, the counterpart of [`advertisement_builder`](super::advertisement_builder).
+
+#[cfg(feature = "defmt")]
+use defmt::Format;
+
+use super::advertisement_builder::{AdvertisementDataType, ServiceList, ServiceUuid16};
+use super::Appearance;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(Format))]
+pub enum Error {
+    /// The AD structure length runs past the end of the data. Parsing stops after this error.
+    Truncated,
+    /// The AD structure payload has an invalid length for its type.
+    InvalidLength(AdvertisementDataType),
+}
+
+/// A single decoded AD structure.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(Format))]
+pub enum AdStructure<'a> {
+    /// Raw flags bits, see [`Flag`](super::advertisement_builder::Flag).
+    Flags(u8),
+    Services16(ServiceList, ServiceUuids16<'a>),
+    Services32(ServiceList, ServiceUuids32<'a>),
+    /// 128-bit service UUIDs, each in little-endian format as sent over the air.
+    Services128(ServiceList, ServiceUuids128<'a>),
+    /// Shortened local name. Invalid UTF-8 is reported as [`AdStructure::Unknown`].
+    ShortName(&'a str),
+    /// Complete local name. Invalid UTF-8 is reported as [`AdStructure::Unknown`].
+    FullName(&'a str),
+    /// TX power level in dBm.
+    TxPowerLevel(i8),
+    ServiceData16 {
+        uuid: ServiceUuid16,
+        data: &'a [u8],
+    },
+    ServiceData32 {
+        uuid: u32,
+        data: &'a [u8],
+    },
+    /// Service data for a 128-bit UUID, in little-endian format as sent over the air.
+    ServiceData128 {
+        uuid: [u8; 16],
+        data: &'a [u8],
+    },
+    /// Preferred connection interval range, in 1.25ms units. `0xFFFF` means no specific preference.
+    PeripheralConnIntervalRange {
+        min: u16,
+        max: u16,
+    },
+    Appearance(Appearance),
+    ManufacturerSpecificData {
+        company_identifier: u16,
+        data: &'a [u8],
+    },
+    /// Any AD type without a typed representation.
+    Unknown {
+        ad_type: AdvertisementDataType,
+        data: &'a [u8],
+    },
+}
+
+impl<'a> AdStructure<'a> {
+    /// Decode the payload of a single AD structure.
+    pub fn parse(ad_type: AdvertisementDataType, data: &'a [u8]) -> Result<Self, Error> {
+        let invalid = Err(Error::InvalidLength(ad_type));
+        let list = |incomplete: AdvertisementDataType| {
+            if ad_type == incomplete {
+                ServiceList::Incomplete
+            } else {
+                ServiceList::Complete
+            }
+        };
+
+        let res = match ad_type {
+            AdvertisementDataType::FLAGS => match data {
+                [flags] => AdStructure::Flags(*flags),
+                _ => return invalid,
+            },
+            AdvertisementDataType::INCOMPLETE_16_SERVICE_LIST | AdvertisementDataType::COMPLETE_16_SERVICE_LIST => {
+                if data.len() % 2 != 0 {
+                    return invalid;
+                }
+                AdStructure::Services16(
+                    list(AdvertisementDataType::INCOMPLETE_16_SERVICE_LIST),
+                    ServiceUuids16(data),
+                )
+            }
+            AdvertisementDataType::INCOMPLETE_32_SERVICE_LIST | AdvertisementDataType::COMPLETE_32_SERVICE_LIST => {
+                if data.len() % 4 != 0 {
+                    return invalid;
+                }
+                AdStructure::Services32(
+                    list(AdvertisementDataType::INCOMPLETE_32_SERVICE_LIST),
+                    ServiceUuids32(data),
+                )
+            }
+            AdvertisementDataType::INCOMPLETE_128_SERVICE_LIST | AdvertisementDataType::COMPLETE_128_SERVICE_LIST => {
+                if data.len() % 16 != 0 {
+                    return invalid;
+                }
+                AdStructure::Services128(
+                    list(AdvertisementDataType::INCOMPLETE_128_SERVICE_LIST),
+                    ServiceUuids128(data),
+                )
+            }
+            AdvertisementDataType::SHORT_NAME | AdvertisementDataType::FULL_NAME => match core::str::from_utf8(data) {
+                Ok(name) if ad_type == AdvertisementDataType::SHORT_NAME => AdStructure::ShortName(name),
+                Ok(name) => AdStructure::FullName(name),
+                Err(_) => AdStructure::Unknown { ad_type, data },
+            },
+            AdvertisementDataType::TXPOWER_LEVEL => match data {
+                [level] => AdStructure::TxPowerLevel(*level as i8),
+                _ => return invalid,
+            },
+            AdvertisementDataType::SERVICE_DATA_16 => match data {
+                [a, b, data @ ..] => AdStructure::ServiceData16 {
+                    uuid: ServiceUuid16::from_u16(u16::from_le_bytes([*a, *b])),
+                    data,
+                },
+                _ => return invalid,
+            },
+            AdvertisementDataType::SERVICE_DATA_32 => match data {
+                [a, b, c, d, data @ ..] => AdStructure::ServiceData32 {
+                    uuid: u32::from_le_bytes([*a, *b, *c, *d]),
+                    data,
+                },
+                _ => return invalid,
+            },
+            AdvertisementDataType::SERVICE_DATA_128 => {
+                if data.len() < 16 {
+                    return invalid;
+                }
+                let (uuid, data) = data.split_at(16);
+                AdStructure::ServiceData128 {
+                    uuid: unwrap!(uuid.try_into()),
+                    data,
+                }
+            }
+            AdvertisementDataType::PERIPHERAL_CONNECTION_INTERVAL_RANGE => match data {
+                [a, b, c, d] => AdStructure::PeripheralConnIntervalRange {
+                    min: u16::from_le_bytes([*a, *b]),
+                    max: u16::from_le_bytes([*c, *d]),
+                },
+                _ => return invalid,
+            },
+            AdvertisementDataType::APPEARANCE => match data {
+                [a, b] => AdStructure::Appearance(Appearance::from_u16(u16::from_le_bytes([*a, *b]))),
+                _ => return invalid,
+            },
+            AdvertisementDataType::MANUFACTURER_SPECIFIC_DATA => match data {
+                [a, b, data @ ..] => AdStructure::ManufacturerSpecificData {
+                    company_identifier: u16::from_le_bytes([*a, *b]),
+                    data,
+                },
+                _ => return invalid,
+            },
+            _ => AdStructure::Unknown { ad_type, data },
+        };
+        Ok(res)
+    }
+}
+
+/// List of 16-bit service UUIDs in an AD structure.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(Format))]
+pub struct ServiceUuids16<'a>(&'a [u8]);
+
+impl<'a> ServiceUuids16<'a> {
+    pub fn iter(&self) -> impl Iterator<Item = ServiceUuid16> + 'a {
+        self.0
+            .chunks_exact(2)
+            .map(|c| ServiceUuid16::from_u16(u16::from_le_bytes([c[0], c[1]])))
+    }
+}
+
+/// List of 32-bit service UUIDs in an AD structure.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(Format))]
+pub struct ServiceUuids32<'a>(&'a [u8]);
+
+impl<'a> ServiceUuids32<'a> {
+    pub fn iter(&self) -> impl Iterator<Item = u32> + 'a {
+        self.0
+            .chunks_exact(4)
+            .map(|c| u32::from_le_bytes([c[0], c[1], c[2], c[3]]))
+    }
+}
+
+/// List of 128-bit service UUIDs in an AD structure.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(Format))]
+pub struct ServiceUuids128<'a>(&'a [u8]);
+
+impl<'a> ServiceUuids128<'a> {
+    /// Iterate the UUIDs, each in little-endian format as sent over the air.
+    pub fn iter(&self) -> impl Iterator<Item = [u8; 16]> + 'a {
+        self.0.chunks_exact(16).map(|c| unwrap!(c.try_into()))
+    }
+}
+
+/// Advertising or scan response data, as received from a peer.
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(Format))]
+pub struct AdvData<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> AdvData<'a> {
+    pub const fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    pub const fn as_bytes(&self) -> &'a [u8] {
+        self.data
+    }
+
+    /// Iterate the AD structures, yielding the type and raw payload of each.
+    pub fn raw(&self) -> RawIter<'a> {
+        RawIter { data: self.data }
+    }
+
+    /// Iterate the decoded AD structures.
+    pub fn iter(&self) -> Iter<'a> {
+        Iter { raw: self.raw() }
+    }
+
+    /// Get the raw payload of the first AD structure of the given type.
+    pub fn find(&self, ad_type: AdvertisementDataType) -> Option<&'a [u8]> {
+        self.raw()
+            .filter_map(Result::ok)
+            .find_map(|(t, data)| (t == ad_type).then_some(data))
+    }
+
+    /// Get the payload of the first manufacturer specific data with the given company identifier.
+    ///
+    /// The company identifier itself is not included in the returned payload.
+    pub fn manufacturer_data(&self, company_id: u16) -> Option<&'a [u8]> {
+        self.iter().filter_map(Result::ok).find_map(|s| match s {
+            AdStructure::ManufacturerSpecificData {
+                company_identifier,
+                data,
+            } if company_identifier == company_id => Some(data),
+            _ => None,
+        })
+    }
+
+    /// Get the local name, preferring the complete name over the shortened one.
+    pub fn name(&self) -> Option<&'a str> {
+        let mut short = None;
+        for s in self.iter().filter_map(Result::ok) {
+            match s {
+                AdStructure::FullName(name) => return Some(name),
+                AdStructure::ShortName(name) if short.is_none() => short = Some(name),
+                _ => {}
+            }
+        }
+        short
+    }
+}
+
+impl<'a> IntoIterator for AdvData<'a> {
+    type Item = Result<AdStructure<'a>, Error>;
+    type IntoIter = Iter<'a>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+/// Iterator over the type and raw payload of each AD structure.
+pub struct RawIter<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Iterator for RawIter<'a> {
+    type Item = Result<(AdvertisementDataType, &'a [u8]), Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (&len, rest) = self.data.split_first()?;
+        let len = len as usize;
+
+        // A zero length marks the end of the significant part of the data.
+        if len == 0 {
+            self.data = &[];
+            return None;
+        }
+        if rest.len() < len {
+            self.data = &[];
+            return Some(Err(Error::Truncated));
+        }
+
+        let (ad, rest) = rest.split_at(len);
+        self.data = rest;
+        Some(Ok((AdvertisementDataType::from_u8(ad[0]), &ad[1..])))
+    }
+}
+
+/// Iterator over the decoded AD structures.
+pub struct Iter<'a> {
+    raw: RawIter<'a>,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = Result<AdStructure<'a>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.raw
+            .next()
+            .map(|r| r.and_then(|(ad_type, data)| AdStructure::parse(ad_type, data)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn raw_well_formed() {
+        let data = [0x02, 0x01, 0x06, 0x05, 0x09, b'a', b'b', b'c', b'd', 0x01, 0xff];
+        let mut iter = AdvData::new(&data).raw();
+
+        assert_eq!(iter.next(), Some(Ok((AdvertisementDataType::FLAGS, &[0x06][..]))));
+        assert_eq!(iter.next(), Some(Ok((AdvertisementDataType::FULL_NAME, &b"abcd"[..]))));
+        assert_eq!(iter.next(), Some(Ok((AdvertisementDataType::from_u8(0xff), &[][..]))));
+        assert_eq!(iter.next(), None);
+    }
+
+    #[test]
+    fn iter_well_formed() {
+        #[rustfmt::skip]
+        let data = [
+            0x02, 0x01, 0x06,
+            0x05, 0x03, 0x0f, 0x18, 0x0d, 0x18,
+            0x03, 0x08, b'a', b'b',
+            0x05, 0xff, 0x59, 0x00, 0x01, 0x02,
+        ];
+        let adv = AdvData::new(&data);
+        let mut iter = adv.iter();
+
+        assert_eq!(iter.next(), Some(Ok(AdStructure::Flags(0x06))));
+        match iter.next() {
+            Some(Ok(AdStructure::Services16(ServiceList::Complete, uuids))) => {
+                assert!(uuids.iter().eq([ServiceUuid16::BATTERY, ServiceUuid16::HEART_RATE]));
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(iter.next(), Some(Ok(AdStructure::ShortName("ab"))));
+        assert_eq!(
+            iter.next(),
+            Some(Ok(AdStructure::ManufacturerSpecificData {
+                company_identifier: 0x0059,
+                data: &[0x01, 0x02],
+            }))
+        );
+        assert_eq!(iter.next(), None);
+
+        assert_eq!(adv.name(), Some("ab"));
+        assert_eq!(adv.manufacturer_data(0x0059), Some(&[0x01, 0x02][..]));
+        assert_eq!(adv.manufacturer_data(0x004c), None);
+        assert_eq!(adv.find(AdvertisementDataType::FLAGS), Some(&[0x06][..]));
+    }
+
+    #[test]
+    fn zero_length_ends_data() {
+        let data = [0x02, 0x01, 0x06, 0x00, 0x03, 0x09, b'a', b'b'];
+        let mut iter = AdvData::new(&data).raw();
+
+        assert_eq!(iter.next(), Some(Ok((AdvertisementDataType::FLAGS, &[0x06][..]))));
+        assert_eq!(iter.next(), None);
+        assert_eq!(iter.next(), None);
+
+        // Zero padding, as in fixed-size buffers.
+        assert_eq!(AdvData::new(&[0x00; 31]).iter().next(), None);
+        assert_eq!(AdvData::new(&[]).iter().next(), None);
+    }
+
+    #[test]
+    fn truncated_final_structure() {
+        let data = [0x02, 0x01, 0x06, 0x05, 0x09, b'a', b'b'];
+
+        let mut raw = AdvData::new(&data).raw();
+        assert_eq!(raw.next(), Some(Ok((AdvertisementDataType::FLAGS, &[0x06][..]))));
+        assert_eq!(raw.next(), Some(Err(Error::Truncated)));
+        assert_eq!(raw.next(), None);
+
+        let mut iter = AdvData::new(&data).iter();
+        assert_eq!(iter.next(), Some(Ok(AdStructure::Flags(0x06))));
+        assert_eq!(iter.next(), Some(Err(Error::Truncated)));
+        assert_eq!(iter.next(), None);
+
+        // The name is in the truncated structure.
+        assert_eq!(AdvData::new(&data).name(), None);
+    }
+
+    #[test]
+    fn uuid_list_invalid_length() {
+        #[rustfmt::skip]
+        let data = [
+            0x04, 0x02, 0x0f, 0x18, 0x0d,
+            0x07, 0x05, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06,
+            0x12, 0x07, 0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d, 0x0e, 0x0f, 0x10,
+            0x02, 0x01, 0x06,
+        ];
+        let mut iter = AdvData::new(&data).iter();
+
+        assert_eq!(
+            iter.next(),
+            Some(Err(Error::InvalidLength(
+                AdvertisementDataType::INCOMPLETE_16_SERVICE_LIST
+            )))
+        );
+        assert_eq!(
+            iter.next(),
+            Some(Err(Error::InvalidLength(
+                AdvertisementDataType::COMPLETE_32_SERVICE_LIST
+            )))
+        );
+        assert_eq!(
+            iter.next(),
+            Some(Err(Error::InvalidLength(
+                AdvertisementDataType::COMPLETE_128_SERVICE_LIST
+            )))
+        );
+        // Parsing continues after an invalid structure.
+        assert_eq!(iter.next(), Some(Ok(AdStructure::Flags(0x06))));
+        assert_eq!(iter.next(), None);
+    }
+
+    #[test]
+    fn uuid_lists() {
+        let uuid128: [u8; 16] = core::array::from_fn(|i| i as u8);
+        let mut data = [0; 2 + 8 + 2 + 16];
+        data[..10].copy_from_slice(&[0x09, 0x04, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08]);
+        data[10..12].copy_from_slice(&[0x11, 0x06]);
+        data[12..].copy_from_slice(&uuid128);
+        let mut iter = AdvData::new(&data).iter();
+
+        match iter.next() {
+            Some(Ok(AdStructure::Services32(ServiceList::Incomplete, uuids))) => {
+                assert!(uuids.iter().eq([0x04030201, 0x08070605]));
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        match iter.next() {
+            Some(Ok(AdStructure::Services128(ServiceList::Incomplete, uuids))) => {
+                assert!(uuids.iter().eq([uuid128]));
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(iter.next(), None);
+    }
+}
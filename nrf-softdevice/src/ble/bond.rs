@@ -0,0 +1,479 @@
+//! Flash-backed bond storage.
+//!
+//! [`BondStore`] keeps the keys and GATT server system attributes of bonded peers in RAM, and persists them to
+//! two pages of flash. Use it as the [`SecurityHandler`] of the connections that should be bonded, call
+//! [`BondStore::load`] at boot and run [`BondStore::run`] in a task to write changes back to flash.
+
+use core::cell::{Cell, RefCell};
+use core::marker::PhantomData;
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+use embedded_storage_async::nor_flash::NorFlash;
+use heapless::Vec;
+
+#[cfg(feature = "ble-peripheral")]
+use crate::ble::replies::RepairingReply;
+use crate::ble::replies::{ConfirmPasskeyReply, DhKeyReply, OutOfBandReply, PasskeyReply};
+use crate::ble::security::{IoCapabilities, Keypress, SecurityHandler};
+#[cfg(feature = "ble-central")]
+use crate::ble::security::{SecurityRequest, SecurityRequestAction};
+use crate::ble::{
+    set_whitelist_from_identities, Address, Connection, EncryptionInfo, IdentityKey, IdentityResolutionKey, MasterId,
+    SecurityMode,
+};
+use crate::{raw, RawError, Softdevice};
+
+/// Maximum length of the GATT server system attributes stored for each bond.
+pub const SYS_ATTRS_MAX_LEN: usize = 64;
+
+const MAGIC: u32 = 0x444e_4f42; // "BOND"
+const HEADER_LEN: usize = 12;
+
+// irk(16) addr_flags(1) addr(6) ediv(2) rand(8) ltk(16) enc_flags(1) sys_attrs_len(1) sys_attrs(64), padded to
+// a multiple of the flash word size.
+const RECORD_LEN: usize = 120;
+
+/// Checks at compile time that `N` bonds fit in a page of `F`.
+struct PageFits<F, const N: usize>(PhantomData<F>);
+
+impl<F: NorFlash, const N: usize> PageFits<F, N> {
+    const OK: () = core::assert!(
+        HEADER_LEN + N * RECORD_LEN <= F::ERASE_SIZE,
+        "bond store: N bonds don't fit in a flash page"
+    );
+}
+
+/// Keys and state kept for a bonded peer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bond {
+    /// Identity of the peer, used to recognize it when it reconnects.
+    pub peer_id: IdentityKey,
+    /// Identifies the long term key when the peer requests encryption.
+    pub master_id: MasterId,
+    /// Long term key.
+    pub key: EncryptionInfo,
+    /// GATT server system attributes, such as the CCCD values written by the peer.
+    pub sys_attrs: Vec<u8, SYS_ATTRS_MAX_LEN>,
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for Bond {
+    fn format(&self, fmt: defmt::Formatter) {
+        defmt::write!(
+            fmt,
+            "Bond {{ peer_id: {:?}, master_id: {:?} }}",
+            self.peer_id,
+            self.master_id
+        )
+    }
+}
+
+impl Bond {
+    fn to_record(&self) -> [u8; RECORD_LEN] {
+        let mut buf = [0xff; RECORD_LEN];
+        buf[0..16].copy_from_slice(&self.peer_id.irk.as_raw().irk);
+        buf[16] = self.peer_id.addr.flags;
+        buf[17..23].copy_from_slice(&self.peer_id.addr.bytes);
+        buf[23..25].copy_from_slice(&self.master_id.ediv.to_le_bytes());
+        buf[25..33].copy_from_slice(&self.master_id.rand);
+        buf[33..49].copy_from_slice(&self.key.ltk);
+        buf[49] = self.key.flags;
+        buf[50] = self.sys_attrs.len() as u8;
+        buf[51..51 + self.sys_attrs.len()].copy_from_slice(&self.sys_attrs);
+        buf
+    }
+
+    fn from_record(buf: &[u8; RECORD_LEN]) -> Option<Self> {
+        let sys_attrs_len = buf[50] as usize;
+        if sys_attrs_len > SYS_ATTRS_MAX_LEN {
+            return None;
+        }
+
+        Some(Self {
+            peer_id: IdentityKey {
+                irk: IdentityResolutionKey::from_raw(raw::ble_gap_irk_t {
+                    irk: unwrap!(buf[0..16].try_into()),
+                }),
+                addr: Address {
+                    flags: buf[16],
+                    bytes: unwrap!(buf[17..23].try_into()),
+                },
+            },
+            master_id: MasterId {
+                ediv: u16::from_le_bytes([buf[23], buf[24]]),
+                rand: unwrap!(buf[25..33].try_into()),
+            },
+            key: EncryptionInfo {
+                ltk: unwrap!(buf[33..49].try_into()),
+                flags: buf[49],
+            },
+            sys_attrs: unwrap!(Vec::from_slice(&buf[51..51 + sys_attrs_len])),
+        })
+    }
+}
+
+#[repr(align(4))]
+struct Aligned<const N: usize>([u8; N]);
+
+/// Bond storage for up to `N` peers.
+///
+/// When the store is full, bonding with a new peer replaces the oldest bond. All `N` bonds must fit in a flash
+/// page, which is checked at compile time by [`load`](Self::load) and [`save`](Self::save).
+///
+/// The GATT server system attributes are stored up to [`SYS_ATTRS_MAX_LEN`] bytes per bond, enough for the
+/// CCCDs of about 10 characteristics. For larger servers they don't fit, so they aren't persisted and a warning
+/// is logged instead.
+pub struct BondStore<const N: usize> {
+    handler: Option<&'static dyn SecurityHandler>,
+    bonds: RefCell<Vec<Bond, N>>,
+    dirty: Signal<CriticalSectionRawMutex, ()>,
+    whitelisted: Cell<bool>,
+}
+
+impl<const N: usize> BondStore<N> {
+    /// Create an empty store, which pairs without user interaction.
+    pub const fn new() -> Self {
+        Self {
+            handler: None,
+            bonds: RefCell::new(Vec::new()),
+            dirty: Signal::new(),
+            whitelisted: Cell::new(false),
+        }
+    }
+
+    /// Create an empty store, delegating everything but bond storage (IO capabilities, passkeys, LESC keys...)
+    /// to `handler`.
+    pub const fn with_handler(handler: &'static dyn SecurityHandler) -> Self {
+        Self {
+            handler: Some(handler),
+            bonds: RefCell::new(Vec::new()),
+            dirty: Signal::new(),
+            whitelisted: Cell::new(false),
+        }
+    }
+
+    /// Run `f` with the current bonds, oldest first.
+    pub fn with_bonds<R>(&self, f: impl FnOnce(&[Bond]) -> R) -> R {
+        f(&self.bonds.borrow())
+    }
+
+    /// Find the bond of the peer with the given address, resolving private addresses with the stored IRKs.
+    pub fn find(&self, addr: Address) -> Option<Bond> {
+        self.bonds.borrow().iter().find(|b| b.peer_id.is_match(addr)).cloned()
+    }
+
+    /// Set the whitelist and device identities list to the bonded peers.
+    ///
+    /// Advertising with a [`FilterPolicy`](crate::ble::peripheral::FilterPolicy) filtering on the whitelist then
+    /// only accepts the bonded peers, including those using resolvable private addresses. If there are more
+    /// bonds than the SoftDevice lists can hold, the most recent ones are used. Must be called again when the
+    /// bonds change, while not advertising, scanning or connecting.
+    pub fn set_whitelist(&self, sd: &Softdevice) -> Result<(), RawError> {
+        const MAX_LEN: usize = raw::BLE_GAP_WHITELIST_ADDR_MAX_COUNT as usize;
+
+        let mut id_keys: Vec<IdentityKey, MAX_LEN> = Vec::new();
+        for bond in self.bonds.borrow().iter().rev().take(MAX_LEN) {
+            unwrap!(id_keys.push(bond.peer_id));
+        }
+        set_whitelist_from_identities(sd, &id_keys)?;
+        self.whitelisted.set(true);
+        Ok(())
+    }
+
+    /// Delete the bond of the peer with the given address, returning it.
+    ///
+    /// The flash copy is updated by [`run`](Self::run). If [`set_whitelist`](Self::set_whitelist) was used,
+    /// the SoftDevice lists are updated too, which fails while advertising, scanning or connecting.
+    pub fn remove(&self, sd: &Softdevice, addr: Address) -> Result<Option<Bond>, RawError> {
+        let index = self.bonds.borrow().iter().position(|b| b.peer_id.is_match(addr));
+        let Some(index) = index else {
+            return Ok(None);
+        };
+
+        let bond = self.bonds.borrow_mut().remove(index);
+        self.dirty.signal(());
+        self.on_bond_deleted(&bond.peer_id);
+
+        if self.whitelisted.get() {
+            self.set_whitelist(sd)?;
+        }
+        Ok(Some(bond))
+    }
+
+    /// Delete all bonds, e.g. for a factory reset.
+    ///
+    /// The flash copy is updated by [`run`](Self::run), or can be written immediately with [`save`](Self::save).
+    /// The SoftDevice whitelist and device identities list are cleared, which fails while advertising, scanning
+    /// or connecting.
+    pub fn clear(&self, sd: &Softdevice) -> Result<(), RawError> {
+        let bonds = core::mem::take(&mut *self.bonds.borrow_mut());
+        self.dirty.signal(());
+        for bond in bonds.iter() {
+            self.on_bond_deleted(&bond.peer_id);
+        }
+
+        self.whitelisted.set(false);
+        set_whitelist_from_identities(sd, &[])
+    }
+
+    fn modify(&self, f: impl FnOnce(&mut Vec<Bond, N>)) {
+        f(&mut self.bonds.borrow_mut());
+        self.dirty.signal(());
+    }
+
+    /// Restore the bonds from flash.
+    ///
+    /// The store uses the two pages of `flash` starting at offset `start`.
+    pub async fn load<F: NorFlash>(&self, flash: &mut F, start: u32) -> Result<(), F::Error> {
+        let () = PageFits::<F, N>::OK;
+
+        let mut bonds = Vec::new();
+        if let Some((page, _, count)) = active_page(flash, start).await? {
+            let mut buf = Aligned([0; RECORD_LEN]);
+            for i in 0..count.min(N) {
+                flash
+                    .read(page + (HEADER_LEN + i * RECORD_LEN) as u32, &mut buf.0)
+                    .await?;
+                match Bond::from_record(&buf.0) {
+                    Some(bond) => unwrap!(bonds.push(bond)),
+                    None => warn!("bond store: skipping invalid record {}", i),
+                }
+            }
+        }
+
+        *self.bonds.borrow_mut() = bonds;
+        Ok(())
+    }
+
+    /// Write the bonds to flash.
+    ///
+    /// The previous copy is kept until the new one is completely written, so a reset while saving doesn't
+    /// lose the bonds.
+    pub async fn save<F: NorFlash>(&self, flash: &mut F, start: u32) -> Result<(), F::Error> {
+        let () = PageFits::<F, N>::OK;
+
+        let bonds = self.bonds.borrow().clone();
+        let (page, sequence) = match active_page(flash, start).await? {
+            Some((page, sequence, _)) if page == start => (start + F::ERASE_SIZE as u32, sequence.wrapping_add(1)),
+            Some((_, sequence, _)) => (start, sequence.wrapping_add(1)),
+            None => (start, 0),
+        };
+
+        flash.erase(page, page + F::ERASE_SIZE as u32).await?;
+        for (i, bond) in bonds.iter().enumerate() {
+            let buf = Aligned(bond.to_record());
+            flash.write(page + (HEADER_LEN + i * RECORD_LEN) as u32, &buf.0).await?;
+        }
+
+        // The header is written last, marking the page as valid.
+        let mut header = Aligned([0; HEADER_LEN]);
+        header.0[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+        header.0[4..8].copy_from_slice(&sequence.to_le_bytes());
+        header.0[8..12].copy_from_slice(&(bonds.len() as u32).to_le_bytes());
+        flash.write(page, &header.0).await
+    }
+
+    /// Save the bonds to flash whenever they change. Never returns.
+    pub async fn run<F: NorFlash>(&self, flash: &mut F, start: u32) -> ! {
+        loop {
+            self.dirty.wait().await;
+            if self.save(flash, start).await.is_err() {
+                warn!("bond store: saving to flash failed");
+            }
+        }
+    }
+}
+
+impl<const N: usize> Default for BondStore<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Find the page holding the latest copy of the bonds, returning its offset, sequence number and bond count.
+async fn active_page<F: NorFlash>(flash: &mut F, start: u32) -> Result<Option<(u32, u32, usize)>, F::Error> {
+    let mut active: Option<(u32, u32, usize)> = None;
+    for page in [start, start + F::ERASE_SIZE as u32] {
+        let mut header = Aligned([0; HEADER_LEN]);
+        flash.read(page, &mut header.0).await?;
+
+        let word = |i: usize| u32::from_le_bytes(unwrap!(header.0[i * 4..i * 4 + 4].try_into()));
+        if word(0) != MAGIC {
+            continue;
+        }
+
+        let (sequence, count) = (word(1), word(2) as usize);
+        match active {
+            Some((_, active_sequence, _)) if (sequence.wrapping_sub(active_sequence) as i32) <= 0 => {}
+            _ => active = Some((page, sequence, count)),
+        }
+    }
+    Ok(active)
+}
+
+impl<const N: usize> SecurityHandler for BondStore<N> {
+    fn io_capabilities(&self) -> IoCapabilities {
+        self.handler.map_or(IoCapabilities::None, |h| h.io_capabilities())
+    }
+
+    fn keypress_notifications(&self) -> bool {
+        self.handler.map_or(false, |h| h.keypress_notifications())
+    }
+
+    fn can_recv_out_of_band(&self, conn: &Connection) -> bool {
+        self.handler.map_or(false, |h| h.can_recv_out_of_band(conn))
+    }
+
+    fn can_bond(&self, _conn: &Connection) -> bool {
+        true
+    }
+
+    fn on_bond_deleted(&self, peer_id: &IdentityKey) {
+        debug!("bond store: deleted bond for {:?}", peer_id.addr);
+        if let Some(h) = self.handler {
+            h.on_bond_deleted(peer_id)
+        }
+    }
+
+    fn display_passkey(&self, passkey: &[u8; 6]) {
+        if let Some(h) = self.handler {
+            h.display_passkey(passkey)
+        }
+    }
+
+    fn confirm_passkey(&self, passkey: &[u8; 6], reply: ConfirmPasskeyReply) {
+        if let Some(h) = self.handler {
+            h.confirm_passkey(passkey, reply)
+        }
+    }
+
+    fn enter_passkey(&self, reply: PasskeyReply) {
+        if let Some(h) = self.handler {
+            h.enter_passkey(reply)
+        }
+    }
+
+    fn on_keypress(&self, conn: &Connection, keypress: Keypress) {
+        if let Some(h) = self.handler {
+            h.on_keypress(conn, keypress)
+        }
+    }
+
+    fn recv_out_of_band(&self, reply: OutOfBandReply) {
+        if let Some(h) = self.handler {
+            h.recv_out_of_band(reply)
+        }
+    }
+
+    fn lesc_public_key(&self, conn: &Connection) -> Option<[u8; 64]> {
+        self.handler.and_then(|h| h.lesc_public_key(conn))
+    }
+
+    fn compute_dhkey(&self, peer_public_key: &[u8; 64], reply: DhKeyReply) {
+        if let Some(h) = self.handler {
+            h.compute_dhkey(peer_public_key, reply)
+        }
+    }
+
+    fn on_security_update(&self, conn: &Connection, security_mode: SecurityMode) {
+        if let Some(h) = self.handler {
+            h.on_security_update(conn, security_mode)
+        }
+    }
+
+    fn on_bonded(&self, conn: &Connection, master_id: MasterId, key: EncryptionInfo, peer_id: IdentityKey) {
+        debug!("bond store: storing bond for {:?}", peer_id.addr);
+
+        // A re-pairing peer may distribute a different identity address than the one it was bonded with, so
+        // drop the bond the connection was recognized by as well.
+        let addr = conn.peer_address();
+        self.modify(|bonds| {
+            bonds.retain(|b| b.peer_id.addr != peer_id.addr && !b.peer_id.is_match(addr));
+            if bonds.is_full() {
+                bonds.remove(0);
+            }
+            unwrap!(bonds.push(Bond {
+                peer_id,
+                master_id,
+                key,
+                sys_attrs: Vec::new(),
+            }));
+        });
+    }
+
+    fn is_bonded(&self, conn: &Connection) -> bool {
+        self.find(conn.peer_address()).is_some()
+    }
+
+    #[cfg(feature = "ble-peripheral")]
+    fn on_repairing_request(&self, conn: &Connection, reply: RepairingReply) {
+        match self.handler {
+            Some(h) => h.on_repairing_request(conn, reply),
+            None => {
+                if let Err(_err) = reply.reply(true) {
+                    warn!("bond store: failed to accept repairing: {:?}", _err);
+                }
+            }
+        }
+    }
+
+    #[cfg(feature = "ble-central")]
+    fn get_peer_key(&self, conn: &Connection) -> Option<(MasterId, EncryptionInfo)> {
+        self.find(conn.peer_address()).map(|b| (b.master_id, b.key))
+    }
+
+    #[cfg(feature = "ble-central")]
+    fn on_security_request(&self, conn: &Connection, request: SecurityRequest) -> SecurityRequestAction {
+        match self.handler {
+            Some(h) => h.on_security_request(conn, request),
+            None => SecurityRequestAction::Ignore,
+        }
+    }
+
+    fn get_key(&self, conn: &Connection, master_id: MasterId) -> Option<EncryptionInfo> {
+        // LESC bonds have no master id, they're found by the peer's identity.
+        if master_id == MasterId::default() {
+            return self.find(conn.peer_address()).map(|b| b.key);
+        }
+
+        self.bonds
+            .borrow()
+            .iter()
+            .find(|b| b.master_id == master_id)
+            .map(|b| b.key)
+    }
+
+    #[cfg(feature = "ble-gatt-server")]
+    fn save_sys_attrs(&self, conn: &Connection) {
+        let addr = conn.peer_address();
+        let Some(index) = self.bonds.borrow().iter().position(|b| b.peer_id.is_match(addr)) else {
+            return;
+        };
+
+        let mut buf = [0; SYS_ATTRS_MAX_LEN];
+        let sys_attrs = match crate::ble::gatt_server::get_sys_attrs(conn, &mut buf) {
+            Ok(len) => &buf[..len],
+            Err(_e) => {
+                warn!("bond store: get_sys_attrs err {:?}", _e);
+                return;
+            }
+        };
+
+        if self.bonds.borrow()[index].sys_attrs != sys_attrs {
+            self.modify(|bonds| bonds[index].sys_attrs = unwrap!(Vec::from_slice(sys_attrs)));
+        }
+    }
+
+    #[cfg(feature = "ble-gatt-server")]
+    fn load_sys_attrs(&self, conn: &Connection) {
+        let sys_attrs = self
+            .find(conn.peer_address())
+            .map(|b| b.sys_attrs)
+            .filter(|s| !s.is_empty());
+
+        if let Err(_e) = crate::ble::gatt_server::set_sys_attrs(conn, sys_attrs.as_deref()) {
+            warn!("bond store: set_sys_attrs err {:?}", _e);
+        }
+    }
+}
@@ -3,10 +3,18 @@
 //! Typically the Central device is the higher-powered device, such as a smartphone or laptop, since scanning is more
 //! power-hungry than advertising.
 
+use core::cell::Cell;
 use core::{mem, ptr};
 
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use futures::stream::{self, Stream};
+use heapless::Vec;
+
+use crate::ble::advertisement_builder::ServiceUuid16;
+use crate::ble::advertisement_parser::{AdStructure, AdvData};
 use crate::ble::types::*;
-use crate::ble::{Address, Connection};
+use crate::ble::{Address, Connection, OutOfConnsError};
 use crate::util::{get_union_field, OnDrop, Portal};
 use crate::{raw, RawError, Softdevice};
 
@@ -28,7 +36,28 @@ impl From<RawError> for ConnectError {
 pub(crate) static CONNECT_PORTAL: Portal<*const raw::ble_evt_t> = Portal::new();
 
 // Begins an ATT MTU exchange procedure, followed by a data length update request as necessary.
-pub async fn connect(_sd: &Softdevice, config: &ConnectConfig<'_>) -> Result<Connection, ConnectError> {
+pub async fn connect(sd: &Softdevice, config: &ConnectConfig<'_>) -> Result<Connection, ConnectError> {
+    if let Some(w) = config.scan_config.whitelist {
+        if w.len() == 0 {
+            return Err(ConnectError::NoAddresses);
+        }
+    } else {
+        return Err(ConnectError::NoAddresses);
+    }
+
+    let scan_params = config.scan_config.to_raw()?;
+    connect_inner(sd, ptr::null(), &scan_params, config, Connection::new).await
+}
+
+/// Like [`connect`], but the connection uses `security_handler` for pairing and bonding.
+///
+/// Pairing can then be started with [`authenticate`](crate::ble::security::authenticate).
+#[cfg(feature = "ble-sec")]
+pub async fn connect_with_security(
+    sd: &Softdevice,
+    config: &ConnectConfig<'_>,
+    security_handler: &'static dyn crate::ble::security::SecurityHandler,
+) -> Result<Connection, ConnectError> {
     if let Some(w) = config.scan_config.whitelist {
         if w.len() == 0 {
             return Err(ConnectError::NoAddresses);
@@ -38,7 +67,46 @@ pub async fn connect(_sd: &Softdevice, config: &ConnectConfig<'_>) -> Result<Con
     }
 
     let scan_params = config.scan_config.to_raw()?;
+    connect_inner(
+        sd,
+        ptr::null(),
+        &scan_params,
+        config,
+        |conn_handle, role, peer_address, conn_params| {
+            Connection::with_security_handler(conn_handle, role, peer_address, conn_params, security_handler)
+        },
+    )
+    .await
+}
+
+/// Connect to the peer with the given address.
+///
+/// Unlike [`connect`], this doesn't use the whitelist, so `config.scan_config.whitelist` is ignored.
+///
+/// Begins an ATT MTU exchange procedure, followed by a data length update request as necessary.
+pub async fn connect_to_address(
+    sd: &Softdevice,
+    address: &Address,
+    config: &ConnectConfig<'_>,
+) -> Result<Connection, ConnectError> {
+    let scan_config = ScanConfig {
+        whitelist: None,
+        ..config.scan_config
+    };
+    let scan_params = scan_config.to_raw()?;
+    connect_inner(sd, address.as_raw(), &scan_params, config, Connection::new).await
+}
 
+async fn connect_inner<F>(
+    _sd: &Softdevice,
+    peer_addr: *const raw::ble_gap_addr_t,
+    scan_params: &raw::ble_gap_scan_params_t,
+    config: &ConnectConfig<'_>,
+    mut f: F,
+) -> Result<Connection, ConnectError>
+where
+    F: FnMut(u16, Role, Address, raw::ble_gap_conn_params_t) -> Result<Connection, OutOfConnsError>,
+{
     let d = OnDrop::new(|| {
         let ret = unsafe { raw::sd_ble_gap_connect_cancel() };
         if let Err(_e) = RawError::convert(ret) {
@@ -46,7 +114,7 @@ pub async fn connect(_sd: &Softdevice, config: &ConnectConfig<'_>) -> Result<Con
         }
     });
 
-    let ret = unsafe { raw::sd_ble_gap_connect(ptr::null(), &scan_params, &config.conn_params, 1) };
+    let ret = unsafe { raw::sd_ble_gap_connect(peer_addr, scan_params, &config.conn_params, 1) };
     if let Err(err) = RawError::convert(ret) {
         warn!("sd_ble_gap_connect err {:?}", err);
         return Err(err.into());
@@ -66,10 +134,10 @@ pub async fn connect(_sd: &Softdevice, config: &ConnectConfig<'_>) -> Result<Con
                     let conn_params = params.conn_params;
                     debug!("connected role={:?} peer_addr={:?}", role, peer_address);
 
-                    match Connection::new(conn_handle, role, peer_address, conn_params) {
+                    match f(conn_handle, role, peer_address, conn_params) {
                         Ok(conn) => {
                             #[cfg(any(feature = "s113", feature = "s132", feature = "s140"))]
-                            crate::ble::gap::do_data_length_update(conn_handle, ptr::null());
+                            let _ = crate::ble::gap::do_data_length_update(conn_handle, ptr::null());
 
                             Ok(conn)
                         }
@@ -99,6 +167,24 @@ pub async fn connect(_sd: &Softdevice, config: &ConnectConfig<'_>) -> Result<Con
     Ok(conn)
 }
 
+/// Handler for connection parameter update requests from peripherals.
+///
+/// Returns the connection params to use, or `None` to reject the request.
+pub type ConnParamUpdateRequestHandler =
+    fn(conn: &Connection, requested: &raw::ble_gap_conn_params_t) -> Option<raw::ble_gap_conn_params_t>;
+
+pub(crate) static CONN_PARAM_UPDATE_REQUEST_HANDLER: Mutex<
+    CriticalSectionRawMutex,
+    Cell<Option<ConnParamUpdateRequestHandler>>,
+> = Mutex::new(Cell::new(None));
+
+/// Set the handler deciding whether to accept connection parameter update requests from peripherals.
+///
+/// If no handler is set, all requests are accepted as-is.
+pub fn set_conn_param_update_request_handler(_sd: &Softdevice, handler: Option<ConnParamUpdateRequestHandler>) {
+    CONN_PARAM_UPDATE_REQUEST_HANDLER.lock(|h| h.set(handler));
+}
+
 #[derive(Copy, Clone)]
 pub struct ConnectConfig<'a> {
     /// Requested ATT_MTU size for the next connection that is established.
@@ -106,6 +192,8 @@ pub struct ConnectConfig<'a> {
     pub att_mtu: Option<u16>,
 
     pub scan_config: ScanConfig<'a>,
+    /// Connection params used to establish the connection: interval range, slave latency and supervision
+    /// timeout. The interval is picked from the range by the SoftDevice.
     pub conn_params: raw::ble_gap_conn_params_t,
 }
 
@@ -140,27 +228,36 @@ impl From<RawError> for ScanError {
 
 pub(crate) static SCAN_PORTAL: Portal<*const raw::ble_evt_t> = Portal::new();
 
+// Buffer to store received advertisement data.
+const BUF_LEN: usize = 256;
+
+// Both of these are intentionally static because Softdevice will,
+// sometimes, write to the buffer after scan_stop() has been
+// called, somewhere around evt_get().
+//
+// This can result in UB as a use-after-free, given the buffer
+// has been dropped and the scanning has been stopped.
+static mut BUF: [u8; BUF_LEN] = [0u8; BUF_LEN];
+static mut BUF_DATA: raw::ble_data_t = raw::ble_data_t {
+    p_data: unsafe { BUF.as_mut_ptr() },
+    len: BUF_LEN as u16,
+};
+
+fn scan_stop() {
+    let ret = unsafe { raw::sd_ble_gap_scan_stop() };
+    match RawError::convert(ret) {
+        Ok(_) => {}
+        Err(RawError::InvalidState) => {} // scan stopped itself due to timeout, erroring is normal.
+        Err(_e) => warn!("sd_ble_gap_scan_stop: {:?}", _e),
+    }
+}
+
 pub async fn scan<'a, F, R>(_sd: &Softdevice, config: &ScanConfig<'a>, mut f: F) -> Result<R, ScanError>
 where
     F: for<'b> FnMut(&'b raw::ble_gap_evt_adv_report_t) -> Option<R>,
 {
     let scan_params = config.to_raw()?;
 
-    // Buffer to store received advertisement data.
-    const BUF_LEN: usize = 256;
-
-    // Both of these are intentionally static because Softdevice will,
-    // sometimes, write to the buffer after scan_stop() has been
-    // called, somewhere around evt_get().
-    //
-    // This can result in UB as a use-after-free, given the buffer
-    // has been dropped and the scanning has been stopped.
-    static mut BUF: [u8; BUF_LEN] = [0u8; BUF_LEN];
-    static mut BUF_DATA: raw::ble_data_t = raw::ble_data_t {
-        p_data: unsafe { BUF.as_mut_ptr() },
-        len: BUF_LEN as u16,
-    };
-
     let ret = unsafe { raw::sd_ble_gap_scan_start(&scan_params, &BUF_DATA) };
     match RawError::convert(ret) {
         Ok(()) => {}
@@ -170,14 +267,7 @@ where
         }
     }
 
-    let _d = OnDrop::new(|| {
-        let ret = unsafe { raw::sd_ble_gap_scan_stop() };
-        match RawError::convert(ret) {
-            Ok(_) => {}
-            Err(RawError::InvalidState) => {} // scan stopped itself due to timeout, erroring is normal.
-            Err(_e) => warn!("sd_ble_gap_scan_stop: {:?}", _e),
-        }
-    });
+    let _d = OnDrop::new(scan_stop);
 
     debug!("Scan started");
     let res = SCAN_PORTAL
@@ -214,6 +304,239 @@ where
     Ok(res)
 }
 
+/// Maximum length of the advertising data kept in a [`ScanReport`].
+pub const SCAN_REPORT_DATA_MAX_LEN: usize = BUF_LEN - 1;
+
+/// An advertising report received while scanning.
+#[derive(Debug, Clone)]
+pub struct ScanReport {
+    /// Address of the advertiser, with [`AddressType::Anonymous`] for anonymous advertisements.
+    pub address: Address,
+    /// Received signal strength, in dBm.
+    pub rssi: i8,
+    /// True if the advertiser accepts connections.
+    pub connectable: bool,
+    /// True if the advertiser accepts scan requests.
+    pub scannable: bool,
+    /// True if `data` holds a scan response instead of advertising data.
+    pub scan_response: bool,
+    /// True if the report was received on an extended advertising PDU.
+    pub extended: bool,
+    /// Raw AD structures, as sent by the advertiser.
+    pub data: Vec<u8, SCAN_REPORT_DATA_MAX_LEN>,
+}
+
+impl ScanReport {
+    /// Copy a raw advertising report.
+    ///
+    /// The data is truncated to [`SCAN_REPORT_DATA_MAX_LEN`] bytes.
+    pub fn from_raw(report: &raw::ble_gap_evt_adv_report_t) -> Self {
+        let data = unsafe { core::slice::from_raw_parts(report.data.p_data, report.data.len as usize) };
+        let data = &data[..data.len().min(SCAN_REPORT_DATA_MAX_LEN)];
+        Self {
+            address: Address::from_raw(report.peer_addr),
+            rssi: report.rssi,
+            connectable: report.type_.connectable() != 0,
+            scannable: report.type_.scannable() != 0,
+            scan_response: report.type_.scan_response() != 0,
+            extended: report.type_.extended_pdu() != 0,
+            data: unwrap!(Vec::from_slice(data)),
+        }
+    }
+
+    /// Parse the AD structures in the report data.
+    pub fn adv_data(&self) -> AdvData<'_> {
+        AdvData::new(&self.data)
+    }
+}
+
+struct ScanStreamState<'a> {
+    filter: ScanFilter<'a>,
+    started: bool,
+    done: bool,
+    _stop: OnDrop<fn()>,
+}
+
+/// Filter for advertising reports received while scanning.
+///
+/// All the criteria that are set must match for a report to be accepted. The default filter accepts everything.
+///
+/// Each report is matched on its own, so criteria on data that the advertiser only puts in its scan response
+/// (commonly the name) won't match the advertising report, and vice versa.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ScanFilter<'a> {
+    /// Only accept reports from this address.
+    pub address: Option<Address>,
+    /// Only accept reports with at least this RSSI, in dBm.
+    pub min_rssi: Option<i8>,
+    /// Only accept reports listing this 16-bit service UUID, in a complete or incomplete list.
+    pub service_uuid_16: Option<ServiceUuid16>,
+    /// Only accept reports listing this 128-bit service UUID (in little-endian format), in a complete or
+    /// incomplete list.
+    pub service_uuid_128: Option<[u8; 16]>,
+    /// Only accept reports whose complete or shortened local name starts with this prefix.
+    pub name_prefix: Option<&'a str>,
+    /// Only accept reports with manufacturer specific data for this company identifier.
+    pub manufacturer_id: Option<u16>,
+}
+
+impl<'a> ScanFilter<'a> {
+    /// Check if a raw advertising report matches the filter.
+    pub fn matches(&self, report: &raw::ble_gap_evt_adv_report_t) -> bool {
+        if let Some(rssi) = self.min_rssi {
+            if report.rssi < rssi {
+                return false;
+            }
+        }
+        if let Some(address) = self.address {
+            if Address::from_raw(report.peer_addr) != address {
+                return false;
+            }
+        }
+
+        if self.service_uuid_16.is_none()
+            && self.service_uuid_128.is_none()
+            && self.name_prefix.is_none()
+            && self.manufacturer_id.is_none()
+        {
+            return true;
+        }
+
+        let data = unsafe { core::slice::from_raw_parts(report.data.p_data, report.data.len as usize) };
+        self.matches_data(AdvData::new(data))
+    }
+
+    fn matches_data(&self, data: AdvData<'_>) -> bool {
+        let mut service_16 = self.service_uuid_16.is_none();
+        let mut service_128 = self.service_uuid_128.is_none();
+        let mut name = self.name_prefix.is_none();
+        let mut manufacturer = self.manufacturer_id.is_none();
+
+        for s in data.iter().filter_map(Result::ok) {
+            match s {
+                AdStructure::Services16(_, uuids) if !service_16 => {
+                    service_16 = uuids.iter().any(|u| Some(u) == self.service_uuid_16);
+                }
+                AdStructure::Services128(_, uuids) if !service_128 => {
+                    service_128 = uuids.iter().any(|u| Some(u) == self.service_uuid_128);
+                }
+                AdStructure::ShortName(n) | AdStructure::FullName(n) if !name => {
+                    name = self.name_prefix.map_or(true, |p| n.starts_with(p));
+                }
+                AdStructure::ManufacturerSpecificData { company_identifier, .. } if !manufacturer => {
+                    manufacturer = Some(company_identifier) == self.manufacturer_id;
+                }
+                _ => {}
+            }
+        }
+
+        service_16 && service_128 && name && manufacturer
+    }
+}
+
+/// Scan for advertising reports, returning them as an async stream.
+///
+/// Scanning starts immediately and is stopped when the stream is dropped. The SoftDevice pauses
+/// scanning after each report until the report buffer is re-armed, which happens when the next
+/// item is polled, so advertisements are missed while the previous report is being processed.
+///
+/// The stream yields `Err(ScanError::Timeout)` and then ends when the scan times out.
+pub fn scan_stream<'a>(
+    sd: &'a Softdevice,
+    config: &ScanConfig<'_>,
+) -> Result<impl Stream<Item = Result<ScanReport, ScanError>> + 'a, ScanError> {
+    scan_stream_filtered(sd, config, ScanFilter::default())
+}
+
+/// Like [`scan_stream`], but only yields reports that match `filter`.
+///
+/// The filter is evaluated in the event handler, and scanning is resumed right away for reports
+/// that don't match, so they never wake the task polling the stream.
+pub fn scan_stream_filtered<'a>(
+    _sd: &'a Softdevice,
+    config: &ScanConfig<'_>,
+    filter: ScanFilter<'a>,
+) -> Result<impl Stream<Item = Result<ScanReport, ScanError>> + 'a, ScanError> {
+    let scan_params = config.to_raw()?;
+
+    let ret = unsafe { raw::sd_ble_gap_scan_start(&scan_params, &BUF_DATA) };
+    if let Err(err) = RawError::convert(ret) {
+        warn!("sd_ble_gap_scan_start err {:?}", err);
+        return Err(ScanError::Raw(err));
+    }
+
+    debug!("Scan started");
+
+    let state = ScanStreamState {
+        filter,
+        started: false,
+        done: false,
+        _stop: OnDrop::new(scan_stop),
+    };
+
+    Ok(stream::unfold(state, |mut state| async move {
+        if state.done {
+            return None;
+        }
+
+        // Resume scan, the first report is received without having to re-arm the buffer.
+        if state.started {
+            let ret = unsafe { raw::sd_ble_gap_scan_start(ptr::null(), &BUF_DATA) };
+            match RawError::convert(ret) {
+                Ok(()) => {}
+
+                // "The scanner has timed out when this function is called to continue scanning"
+                Err(RawError::InvalidState) => {
+                    state.done = true;
+                    return Some((Err(ScanError::Timeout), state));
+                }
+
+                Err(err) => {
+                    warn!("sd_ble_gap_scan_start resume err {:?}", err);
+                    state.done = true;
+                    return Some((Err(ScanError::Raw(err)), state));
+                }
+            }
+        }
+        state.started = true;
+
+        let res = SCAN_PORTAL
+            .wait_many(|ble_evt| unsafe {
+                match (*ble_evt).header.evt_id as u32 {
+                    raw::BLE_GAP_EVTS_BLE_GAP_EVT_TIMEOUT => Some(Err(ScanError::Timeout)),
+                    raw::BLE_GAP_EVTS_BLE_GAP_EVT_ADV_REPORT => {
+                        let gap_evt = get_union_field(ble_evt, &(*ble_evt).evt.gap_evt);
+                        let report = &gap_evt.params.adv_report;
+                        if state.filter.matches(report) {
+                            return Some(Ok(ScanReport::from_raw(report)));
+                        }
+
+                        // Resume scan
+                        let ret = raw::sd_ble_gap_scan_start(ptr::null(), &BUF_DATA);
+                        match RawError::convert(ret) {
+                            Ok(()) => None,
+
+                            // "The scanner has timed out when this function is called to continue scanning"
+                            Err(RawError::InvalidState) => Some(Err(ScanError::Timeout)),
+
+                            Err(err) => {
+                                warn!("sd_ble_gap_scan_start resume err {:?}", err);
+                                Some(Err(ScanError::Raw(err)))
+                            }
+                        }
+                    }
+                    _ => None,
+                }
+            })
+            .await;
+
+        if res.is_err() {
+            state.done = true;
+        }
+        Some((res, state))
+    }))
+}
+
 #[derive(Copy, Clone)]
 pub struct ScanConfig<'a> {
     /// Whitelist of addresses to scan. If None, all advertisements
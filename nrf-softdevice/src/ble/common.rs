@@ -1,4 +1,5 @@
-use crate::raw;
+use crate::util::get_union_field;
+use crate::{raw, RawError};
 
 pub(crate) unsafe fn on_evt(ble_evt: *const raw::ble_evt_t) {
     match (*ble_evt).header.evt_id as u32 {
@@ -8,9 +9,26 @@ pub(crate) unsafe fn on_evt(ble_evt: *const raw::ble_evt_t) {
     }
 }
 
-fn on_user_mem_request(_ble_evt: *const raw::ble_evt_t) {
-    trace!("on_user_mem_request");
+unsafe fn on_user_mem_request(ble_evt: *const raw::ble_evt_t) {
+    let common_evt = get_union_field(ble_evt, &(*ble_evt).evt.common_evt);
+    trace!("on_user_mem_request conn={:?}", common_evt.conn_handle);
+
+    #[cfg(feature = "ble-gatt-server")]
+    let block = crate::ble::gatt_server::queued_writes_mem_request(common_evt.conn_handle);
+    #[cfg(not(feature = "ble-gatt-server"))]
+    let block = core::ptr::null();
+
+    // Replying with no memory block makes the softdevice reject queued writes.
+    let ret = raw::sd_ble_user_mem_reply(common_evt.conn_handle, block);
+    if let Err(_err) = RawError::convert(ret) {
+        warn!("sd_ble_user_mem_reply err {:?}", _err);
+    }
 }
-fn on_user_mem_release(_ble_evt: *const raw::ble_evt_t) {
-    trace!("on_user_mem_release");
+
+unsafe fn on_user_mem_release(ble_evt: *const raw::ble_evt_t) {
+    let common_evt = get_union_field(ble_evt, &(*ble_evt).evt.common_evt);
+    trace!("on_user_mem_release conn={:?}", common_evt.conn_handle);
+
+    #[cfg(feature = "ble-gatt-server")]
+    crate::ble::gatt_server::queued_writes_mem_release(common_evt.conn_handle);
 }
@@ -3,10 +3,11 @@ use core::iter::FusedIterator;
 
 use raw::ble_gap_conn_params_t;
 
-use super::PhySet;
+use super::{Phy, PhySet};
 #[cfg(feature = "ble-sec")]
 use crate::ble::security::SecurityHandler;
-use crate::ble::types::{Address, AddressType, Role, SecurityMode};
+use crate::ble::types::{Address, AddressType, ConnectionSecurity, Role, SecurityMode};
+use crate::util::get_union_field;
 use crate::{raw, RawError};
 
 #[cfg(any(feature = "s113", feature = "s132", feature = "s140"))]
@@ -61,8 +62,31 @@ impl From<RawError> for IgnoreSlaveLatencyError {
     }
 }
 
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SetAuthPayloadTimeoutError {
+    Disconnected,
+    Raw(RawError),
+}
+
+impl From<DisconnectedError> for SetAuthPayloadTimeoutError {
+    fn from(_err: DisconnectedError) -> Self {
+        Self::Disconnected
+    }
+}
+
+impl From<RawError> for SetAuthPayloadTimeoutError {
+    fn from(err: RawError) -> Self {
+        Self::Raw(err)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum PhyUpdateError {
     Disconnected,
+    /// The procedure failed with the given HCI status code.
+    Failed(u8),
     Raw(RawError),
 }
 
@@ -78,6 +102,28 @@ impl From<RawError> for PhyUpdateError {
     }
 }
 
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg(any(feature = "s113", feature = "s132", feature = "s140"))]
+pub enum DataLengthUpdateError {
+    Disconnected,
+    Raw(RawError),
+}
+
+#[cfg(any(feature = "s113", feature = "s132", feature = "s140"))]
+impl From<DisconnectedError> for DataLengthUpdateError {
+    fn from(_err: DisconnectedError) -> Self {
+        Self::Disconnected
+    }
+}
+
+#[cfg(any(feature = "s113", feature = "s132", feature = "s140"))]
+impl From<RawError> for DataLengthUpdateError {
+    fn from(err: RawError) -> Self {
+        Self::Raw(err)
+    }
+}
+
 // Highest ever the softdevice can support.
 pub(crate) const CONNS_MAX: usize = 20;
 
@@ -89,6 +135,12 @@ pub(crate) struct EncryptionState {
     pub own_enc_key: raw::ble_gap_enc_key_t,
     pub peer_enc_key: raw::ble_gap_enc_key_t,
     pub peer_id: raw::ble_gap_id_key_t,
+
+    pub own_pk: raw::ble_gap_lesc_p256_pk_t,
+    pub peer_pk: raw::ble_gap_lesc_p256_pk_t,
+
+    pub own_sign_key: raw::ble_gap_sign_info_t,
+    pub peer_sign_key: raw::ble_gap_sign_info_t,
 }
 
 #[cfg(feature = "ble-sec")]
@@ -115,6 +167,10 @@ const NEW_ENCRYPTION_STATE: EncryptionState = EncryptionState {
     own_enc_key: NEW_GAP_ENC_KEY,
     peer_enc_key: NEW_GAP_ENC_KEY,
     peer_id: NEW_GAP_ID_KEY,
+    own_pk: raw::ble_gap_lesc_p256_pk_t { pk: [0; 64] },
+    peer_pk: raw::ble_gap_lesc_p256_pk_t { pk: [0; 64] },
+    own_sign_key: raw::ble_gap_sign_info_t { csrk: [0; 16] },
+    peer_sign_key: raw::ble_gap_sign_info_t { csrk: [0; 16] },
 };
 
 // We could make the public Connection type simply hold the softdevice's conn_handle.
@@ -141,14 +197,23 @@ pub(crate) struct ConnectionState {
     pub role: Role,
     pub peer_address: Address,
     pub security_mode: SecurityMode,
+    pub encryption_key_size: u8, // 0 while the link is not encrypted.
 
     pub conn_params: ble_gap_conn_params_t,
 
+    // Current PHYs, as BLE_GAP_PHY_* values.
+    pub tx_phy: u8,
+    pub rx_phy: u8,
+
     #[cfg(feature = "ble-rssi")]
     pub rssi: Option<i8>,
 
     #[cfg(feature = "ble-gatt")]
     pub att_mtu: u16, // Effective ATT_MTU size (in bytes).
+    #[cfg(feature = "ble-gatt-client")]
+    pub gattc_pending: bool, // A GATT client procedure is in progress in the softdevice.
+    #[cfg(feature = "ble-gatt-server")]
+    pub hvn_tx_pending: u8, // Notifications queued in the softdevice and not transmitted yet.
     #[cfg(any(feature = "s113", feature = "s132", feature = "s140"))]
     pub data_length_effective: u8, // Effective data length (in bytes).
 
@@ -169,6 +234,7 @@ impl ConnectionState {
             role: Role::Peripheral,
             peer_address: Address::new(AddressType::Public, [0; 6]),
             security_mode: SecurityMode::NoAccess,
+            encryption_key_size: 0,
             disconnecting: false,
             conn_params: ble_gap_conn_params_t {
                 conn_sup_timeout: 0,
@@ -176,10 +242,16 @@ impl ConnectionState {
                 min_conn_interval: 0,
                 slave_latency: 0,
             },
+            tx_phy: 0,
+            rx_phy: 0,
             #[cfg(feature = "ble-rssi")]
             rssi: None,
             #[cfg(feature = "ble-gatt")]
             att_mtu: 0,
+            #[cfg(feature = "ble-gatt-client")]
+            gattc_pending: false,
+            #[cfg(feature = "ble-gatt-server")]
+            hvn_tx_pending: 0,
             #[cfg(any(feature = "s113", feature = "s132", feature = "s140"))]
             data_length_effective: 0,
             #[cfg(feature = "ble-sec")]
@@ -226,8 +298,16 @@ impl ConnectionState {
         crate::ble::gatt_client::portal(conn_handle).call(_ble_evt);
         #[cfg(feature = "ble-gatt-server")]
         crate::ble::gatt_server::portal(conn_handle).call(_ble_evt);
+        #[cfg(feature = "ble-gatt-server")]
+        crate::ble::gatt_server::on_disconnected(conn_handle);
         #[cfg(feature = "ble-l2cap")]
-        crate::ble::l2cap::portal(conn_handle).call(_ble_evt);
+        crate::ble::l2cap::on_disconnected(conn_handle, _ble_evt);
+        crate::ble::gap::conn_params_portal(conn_handle).call(_ble_evt);
+        crate::ble::gap::phy_portal(conn_handle).call(_ble_evt);
+        #[cfg(any(feature = "s113", feature = "s132", feature = "s140"))]
+        crate::ble::gap::data_length_portal(conn_handle).call(_ble_evt);
+        #[cfg(feature = "ble-sec")]
+        crate::ble::security::portal(conn_handle).call(_ble_evt);
 
         trace!("conn {:?}: disconnected", _index);
     }
@@ -238,14 +318,14 @@ impl ConnectionState {
             keys_own: raw::ble_gap_sec_keys_t {
                 p_enc_key: &mut self.security.own_enc_key,
                 p_id_key: core::ptr::null_mut(),
-                p_sign_key: core::ptr::null_mut(),
-                p_pk: core::ptr::null_mut(),
+                p_sign_key: &mut self.security.own_sign_key,
+                p_pk: &mut self.security.own_pk,
             },
             keys_peer: raw::ble_gap_sec_keys_t {
                 p_enc_key: &mut self.security.peer_enc_key,
                 p_id_key: &mut self.security.peer_id,
-                p_sign_key: core::ptr::null_mut(),
-                p_pk: core::ptr::null_mut(),
+                p_sign_key: &mut self.security.peer_sign_key,
+                p_pk: &mut self.security.peer_pk,
             },
         };
         #[cfg(not(feature = "ble-sec"))]
@@ -308,6 +388,10 @@ impl Connection {
         self.with_state(|state| state.role)
     }
 
+    /// Address of the peer.
+    ///
+    /// If the peer connected with a resolvable private address that was resolved using the device identities
+    /// list, this is its identity address and [`Address::is_resolved_peer_id`] is set.
     pub fn peer_address(&self) -> Address {
         self.with_state(|state| state.peer_address)
     }
@@ -343,16 +427,24 @@ impl Connection {
                 role,
                 peer_address,
                 security_mode: SecurityMode::Open,
+                encryption_key_size: 0,
 
                 disconnecting: false,
 
                 conn_params,
 
+                tx_phy: raw::BLE_GAP_PHY_1MBPS as u8,
+                rx_phy: raw::BLE_GAP_PHY_1MBPS as u8,
+
                 #[cfg(feature = "ble-rssi")]
                 rssi: None,
 
                 #[cfg(feature = "ble-gatt")]
                 att_mtu: raw::BLE_GATT_ATT_MTU_DEFAULT as _,
+                #[cfg(feature = "ble-gatt-client")]
+                gattc_pending: false,
+                #[cfg(feature = "ble-gatt-server")]
+                hvn_tx_pending: 0,
 
                 #[cfg(any(feature = "s113", feature = "s132", feature = "s140"))]
                 data_length_effective: BLE_GAP_DATA_LENGTH_DEFAULT,
@@ -419,6 +511,16 @@ impl Connection {
         with_state(self.index, |s| s.security_mode)
     }
 
+    /// Get the current security mode and encryption key size of the link.
+    ///
+    /// This is updated when the link is encrypted, and can be used to gate sensitive operations.
+    pub fn security(&self) -> ConnectionSecurity {
+        with_state(self.index, |s| ConnectionSecurity {
+            mode: s.security_mode,
+            key_size: s.encryption_key_size,
+        })
+    }
+
     #[cfg(feature = "ble-sec")]
     pub fn security_handler(&self) -> Option<&dyn SecurityHandler> {
         with_state(self.index, |s| s.security.handler)
@@ -444,6 +546,40 @@ impl Connection {
         Ok(())
     }
 
+    /// Update the connection params, waiting for the procedure to complete.
+    ///
+    /// Returns the connection params in use after the update, which may differ from the requested ones
+    /// within the requested interval range.
+    ///
+    /// For peripheral connections the central may reject the request, in which case this never completes,
+    /// so it should be used with a timeout. See [`Connection::set_conn_params`] for details.
+    ///
+    /// Fails with `Raw(RawError::Busy)` if a connection parameter update is already in progress.
+    pub async fn update_conn_params(
+        &self,
+        conn_params: ble_gap_conn_params_t,
+    ) -> Result<ble_gap_conn_params_t, SetConnParamsError> {
+        let conn_handle = self.with_state(|state| state.check_connected())?;
+        let portal = crate::ble::gap::conn_params_portal(conn_handle);
+        if portal.is_waiting() {
+            return Err(RawError::Busy.into());
+        }
+        self.set_conn_params(conn_params)?;
+
+        portal
+            .wait_many(|ble_evt| unsafe {
+                match (*ble_evt).header.evt_id as u32 {
+                    raw::BLE_GAP_EVTS_BLE_GAP_EVT_DISCONNECTED => Some(Err(SetConnParamsError::Disconnected)),
+                    raw::BLE_GAP_EVTS_BLE_GAP_EVT_CONN_PARAM_UPDATE => {
+                        let gap_evt = get_union_field(ble_evt, &(*ble_evt).evt.gap_evt);
+                        Some(Ok(gap_evt.params.conn_param_update.conn_params))
+                    }
+                    _ => None,
+                }
+            })
+            .await
+    }
+
     /// Temporarily ignore slave latency for peripehral connections.
     ///
     /// "Slave latency" is a setting in the conn params that allows the peripheral
@@ -485,6 +621,36 @@ impl Connection {
         Ok(())
     }
 
+    /// Set the authenticated payload timeout, in 10ms units.
+    ///
+    /// While the link is encrypted, the peer must send a packet with a valid MIC within this time, otherwise
+    /// the controller pings it (LE Ping). If the timeout still expires, the connection's
+    /// [`SecurityHandler::on_auth_payload_timeout`] is called. Defaults to 30 s, and must be larger than the
+    /// connection supervision timeout and at least the connection interval times `(1 + slave_latency)`.
+    pub fn set_auth_payload_timeout(&self, timeout: u16) -> Result<(), SetAuthPayloadTimeoutError> {
+        let conn_handle = self.with_state(|state| state.check_connected())?;
+
+        let ret = unsafe {
+            raw::sd_ble_opt_set(
+                raw::BLE_GAP_OPTS_BLE_GAP_OPT_AUTH_PAYLOAD_TIMEOUT,
+                &raw::ble_opt_t {
+                    gap_opt: raw::ble_gap_opt_t {
+                        auth_payload_timeout: raw::ble_gap_opt_auth_payload_timeout_t {
+                            conn_handle,
+                            auth_payload_timeout: timeout,
+                        },
+                    },
+                },
+            )
+        };
+        if let Err(err) = RawError::convert(ret) {
+            warn!("set_auth_payload_timeout sd_ble_opt_set err {:?}", err);
+            return Err(err.into());
+        }
+
+        Ok(())
+    }
+
     pub(crate) fn with_state<T>(&self, f: impl FnOnce(&mut ConnectionState) -> T) -> T {
         with_state(self.index, f)
     }
@@ -493,6 +659,58 @@ impl Connection {
         ConnectionIter(0)
     }
 
+    /// Get the effective maximum TX data length of the link layer, in bytes.
+    ///
+    /// This is the maximum payload of a single link layer packet, which bounds the throughput of the
+    /// connection. It defaults to 27 bytes and can be raised up to 251 bytes with [`Self::update_data_length`].
+    #[cfg(any(feature = "s113", feature = "s132", feature = "s140"))]
+    pub fn data_length_effective(&self) -> u8 {
+        self.with_state(|state| state.data_length_effective)
+    }
+
+    /// Start a data length update procedure, waiting for it to complete.
+    ///
+    /// If `params` is None, the SoftDevice picks the largest values allowed by the configuration.
+    /// Returns the effective data length parameters after the update.
+    ///
+    /// Fails with `Raw(RawError::Busy)` if a data length update is already in progress.
+    #[cfg(any(feature = "s113", feature = "s132", feature = "s140"))]
+    pub async fn update_data_length(
+        &self,
+        params: Option<&raw::ble_gap_data_length_params_t>,
+    ) -> Result<raw::ble_gap_data_length_params_t, DataLengthUpdateError> {
+        let conn_handle = self.with_state(|state| state.check_connected())?;
+        let portal = crate::ble::gap::data_length_portal(conn_handle);
+        if portal.is_waiting() {
+            return Err(RawError::Busy.into());
+        }
+        let p_params = params.map(|p| p as *const _).unwrap_or(core::ptr::null());
+        unsafe { crate::ble::gap::do_data_length_update(conn_handle, p_params) }?;
+
+        portal
+            .wait_many(|ble_evt| unsafe {
+                match (*ble_evt).header.evt_id as u32 {
+                    raw::BLE_GAP_EVTS_BLE_GAP_EVT_DISCONNECTED => Some(Err(DataLengthUpdateError::Disconnected)),
+                    raw::BLE_GAP_EVTS_BLE_GAP_EVT_DATA_LENGTH_UPDATE => {
+                        let gap_evt = get_union_field(ble_evt, &(*ble_evt).evt.gap_evt);
+                        Some(Ok(gap_evt.params.data_length_update.effective_params))
+                    }
+                    _ => None,
+                }
+            })
+            .await
+    }
+
+    /// Get the PHY currently used in the TX direction.
+    pub fn tx_phy(&self) -> Phy {
+        Phy::from_raw(self.with_state(|state| state.tx_phy))
+    }
+
+    /// Get the PHY currently used in the RX direction.
+    pub fn rx_phy(&self) -> Phy {
+        Phy::from_raw(self.with_state(|state| state.rx_phy))
+    }
+
     /// Send a request to the connected device to change the PHY.
     ///
     /// Note that this just initiates the PHY change, it does not wait for completion.
@@ -512,6 +730,38 @@ impl Connection {
 
         Ok(())
     }
+
+    /// Change the PHY, waiting for the procedure to complete.
+    ///
+    /// Returns the TX and RX PHYs in use after the update. The peer may choose PHYs other than the
+    /// preferred ones, for example if it doesn't support them.
+    ///
+    /// Fails with `Raw(RawError::Busy)` if a PHY update is already in progress.
+    pub async fn update_phy(&mut self, tx_phys: PhySet, rx_phys: PhySet) -> Result<(Phy, Phy), PhyUpdateError> {
+        let conn_handle = self.with_state(|state| state.check_connected())?;
+        let portal = crate::ble::gap::phy_portal(conn_handle);
+        if portal.is_waiting() {
+            return Err(RawError::Busy.into());
+        }
+        self.phy_update(tx_phys, rx_phys)?;
+
+        portal
+            .wait_many(|ble_evt| unsafe {
+                match (*ble_evt).header.evt_id as u32 {
+                    raw::BLE_GAP_EVTS_BLE_GAP_EVT_DISCONNECTED => Some(Err(PhyUpdateError::Disconnected)),
+                    raw::BLE_GAP_EVTS_BLE_GAP_EVT_PHY_UPDATE => {
+                        let gap_evt = get_union_field(ble_evt, &(*ble_evt).evt.gap_evt);
+                        let params = &gap_evt.params.phy_update;
+                        if params.status as u32 != raw::BLE_HCI_STATUS_CODE_SUCCESS {
+                            return Some(Err(PhyUpdateError::Failed(params.status)));
+                        }
+                        Some(Ok((Phy::from_raw(params.tx_phy), Phy::from_raw(params.rx_phy))))
+                    }
+                    _ => None,
+                }
+            })
+            .await
+    }
 }
 
 pub struct ConnectionIter(u8);
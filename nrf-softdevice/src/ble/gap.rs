@@ -1,5 +1,12 @@
+use core::cell::Cell;
+use core::ptr;
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+
+use crate::ble::connection::CONNS_MAX;
 use crate::ble::*;
-use crate::util::get_union_field;
+use crate::util::{get_union_field, Portal};
 use crate::{raw, RawError};
 
 pub(crate) unsafe fn on_evt(ble_evt: *const raw::ble_evt_t) {
@@ -45,6 +52,7 @@ pub(crate) unsafe fn on_evt(ble_evt: *const raw::ble_evt_t) {
             connection::with_state_by_conn_handle(gap_evt.conn_handle, |state| {
                 state.conn_params = conn_params;
             });
+            conn_params_portal(gap_evt.conn_handle).call(ble_evt);
         }
         #[cfg(feature = "ble-central")]
         raw::BLE_GAP_EVTS_BLE_GAP_EVT_CONN_PARAM_UPDATE_REQUEST => {
@@ -59,7 +67,17 @@ pub(crate) unsafe fn on_evt(ble_evt: *const raw::ble_evt_t) {
                 conn_params.slave_latency,
             );
 
-            let ret = raw::sd_ble_gap_conn_param_update(conn_handle, &conn_params);
+            let reply = match central::CONN_PARAM_UPDATE_REQUEST_HANDLER.lock(|h| h.get()) {
+                Some(handler) => match Connection::from_handle(conn_handle) {
+                    Some(conn) => handler(&conn, &conn_params),
+                    None => None,
+                },
+                None => Some(conn_params),
+            };
+
+            // Passing null rejects the request.
+            let p_conn_params = reply.as_ref().map(|p| p as *const _).unwrap_or(ptr::null());
+            let ret = raw::sd_ble_gap_conn_param_update(conn_handle, p_conn_params);
             if let Err(err) = RawError::convert(ret) {
                 warn!("sd_ble_gap_conn_param_update err {:?}", err);
             }
@@ -73,6 +91,16 @@ pub(crate) unsafe fn on_evt(ble_evt: *const raw::ble_evt_t) {
                 raw::BLE_GAP_TIMEOUT_SRC_CONN => central::CONNECT_PORTAL.call(ble_evt),
                 #[cfg(feature = "ble-central")]
                 raw::BLE_GAP_TIMEOUT_SRC_SCAN => central::SCAN_PORTAL.call(ble_evt),
+                raw::BLE_GAP_TIMEOUT_SRC_AUTH_PAYLOAD => {
+                    warn!("authenticated payload timeout conn_handle={:?}", gap_evt.conn_handle);
+                    #[cfg(feature = "ble-sec")]
+                    if let Some(conn) = Connection::from_handle(gap_evt.conn_handle) {
+                        if let Some(handler) = conn.with_state(|state| state.security.handler) {
+                            handler.on_auth_payload_timeout(&conn);
+                        }
+                    }
+                    true
+                }
                 x => panic!("unknown timeout src {:?}", x),
             };
         }
@@ -97,9 +125,15 @@ pub(crate) unsafe fn on_evt(ble_evt: *const raw::ble_evt_t) {
                 peer_preferred_phys.tx_phys
             );
 
-            let phys = raw::ble_gap_phys_t {
-                rx_phys: peer_preferred_phys.rx_phys,
-                tx_phys: peer_preferred_phys.tx_phys,
+            let phys = match PHY_UPDATE_REQUEST_HANDLER.lock(|h| h.get()) {
+                Some(handler) => match Connection::from_handle(conn_handle) {
+                    Some(conn) => handler(&conn, &peer_preferred_phys),
+                    None => peer_preferred_phys,
+                },
+                None => raw::ble_gap_phys_t {
+                    rx_phys: peer_preferred_phys.rx_phys,
+                    tx_phys: peer_preferred_phys.tx_phys,
+                },
             };
 
             let ret = raw::sd_ble_gap_phy_update(conn_handle, &phys as *const raw::ble_gap_phys_t);
@@ -109,15 +143,23 @@ pub(crate) unsafe fn on_evt(ble_evt: *const raw::ble_evt_t) {
             }
         }
         raw::BLE_GAP_EVTS_BLE_GAP_EVT_PHY_UPDATE => {
-            let _phy_update = gap_evt.params.phy_update;
+            let phy_update = gap_evt.params.phy_update;
 
             trace!(
                 "on_phy_update conn_handle={:?} status={:?} rx_phy={:?} tx_phy={:?}",
                 gap_evt.conn_handle,
-                _phy_update.status,
-                _phy_update.rx_phy,
-                _phy_update.tx_phy
+                phy_update.status,
+                phy_update.rx_phy,
+                phy_update.tx_phy
             );
+
+            if phy_update.status as u32 == raw::BLE_HCI_STATUS_CODE_SUCCESS {
+                connection::with_state_by_conn_handle(gap_evt.conn_handle, |state| {
+                    state.tx_phy = phy_update.tx_phy;
+                    state.rx_phy = phy_update.rx_phy;
+                });
+            }
+            phy_portal(gap_evt.conn_handle).call(ble_evt);
         }
         #[cfg(any(feature = "s113", feature = "s132", feature = "s140"))]
         raw::BLE_GAP_EVTS_BLE_GAP_EVT_DATA_LENGTH_UPDATE_REQUEST => {
@@ -133,7 +175,7 @@ pub(crate) unsafe fn on_evt(ble_evt: *const raw::ble_evt_t) {
             );
 
             let conn_handle = gap_evt.conn_handle;
-            do_data_length_update(conn_handle, core::ptr::null());
+            let _ = do_data_length_update(conn_handle, core::ptr::null());
         }
         #[cfg(any(feature = "s113", feature = "s132", feature = "s140"))]
         raw::BLE_GAP_EVTS_BLE_GAP_EVT_DATA_LENGTH_UPDATE => {
@@ -151,6 +193,8 @@ pub(crate) unsafe fn on_evt(ble_evt: *const raw::ble_evt_t) {
                 effective_params.max_tx_octets,
                 effective_params.max_tx_time_us,
             );
+
+            data_length_portal(gap_evt.conn_handle).call(ble_evt);
         }
         #[cfg(feature = "ble-rssi")]
         raw::BLE_GAP_EVTS_BLE_GAP_EVT_RSSI_CHANGED => {
@@ -169,54 +213,43 @@ pub(crate) unsafe fn on_evt(ble_evt: *const raw::ble_evt_t) {
                     gap_evt.conn_handle, peer_params.bond(), peer_params.io_caps(), peer_params.keypress(), peer_params.lesc(), peer_params.mitm(), peer_params.oob(),
                     peer_params.min_key_size, peer_params.max_key_size);
 
-            let (sec_params, keyset) = connection::with_state_by_conn_handle(gap_evt.conn_handle, |state| {
-                let mut sec_params: raw::ble_gap_sec_params_t = core::mem::zeroed();
-
-                sec_params.min_key_size = 7;
-                sec_params.max_key_size = 16;
-
-                sec_params.kdist_own.set_enc(1);
-                sec_params.kdist_own.set_id(1);
-                sec_params.kdist_peer.set_enc(1);
-                sec_params.kdist_peer.set_id(1);
-                sec_params.set_io_caps(raw::BLE_GAP_IO_CAPS_NONE as u8);
-
-                #[cfg(feature = "ble-sec")]
-                if let Some(handler) = state.security.handler {
-                    sec_params.set_io_caps(handler.io_capabilities().to_io_caps());
-                    if let Some(conn) = Connection::from_handle(gap_evt.conn_handle) {
-                        sec_params.set_bond(handler.can_bond(&conn) as u8);
-                        sec_params.set_oob(handler.can_recv_out_of_band(&conn) as u8);
+            #[cfg(all(feature = "ble-sec", feature = "ble-peripheral"))]
+            if let Some(conn) = Connection::from_handle(gap_evt.conn_handle) {
+                let handler = conn.with_state(|state| match state.role {
+                    #[cfg(feature = "ble-central")]
+                    Role::Central => None,
+                    Role::Peripheral => state.security.handler,
+                });
+                if let Some(handler) = handler {
+                    if handler.is_bonded(&conn) {
+                        // The sec params reply is sent by the `RepairingReply`.
+                        handler.on_repairing_request(&conn, RepairingReply::new(conn.clone()));
+                        return;
                     }
                 }
+            }
 
-                (sec_params, state.keyset())
-            });
-
-            let ret = raw::sd_ble_gap_sec_params_reply(
-                gap_evt.conn_handle,
-                raw::BLE_GAP_SEC_STATUS_SUCCESS as u8,
-                &sec_params,
-                &keyset,
-            );
-
-            if let Err(_err) = RawError::convert(ret) {
+            if let Err(_err) = sec_params_reply(gap_evt.conn_handle) {
                 warn!("sd_ble_gap_sec_params_reply err {:?}", _err);
             }
         }
         raw::BLE_GAP_EVTS_BLE_GAP_EVT_PASSKEY_DISPLAY => {
             let params = &gap_evt.params.passkey_display;
-            debug_assert_eq!(params.match_request(), 0);
             trace!(
-                "on_passkey_display passkey={}",
-                core::str::from_utf8_unchecked(&params.passkey)
+                "on_passkey_display passkey={} match_request={}",
+                core::str::from_utf8_unchecked(&params.passkey),
+                params.match_request()
             );
             #[cfg(feature = "ble-sec")]
-            connection::with_state_by_conn_handle(gap_evt.conn_handle, |state| {
-                if let Some(handler) = state.security.handler {
-                    handler.display_passkey(&params.passkey)
+            if let Some(conn) = Connection::from_handle(gap_evt.conn_handle) {
+                if let Some(handler) = conn.with_state(|state| state.security.handler) {
+                    if params.match_request() != 0 {
+                        handler.confirm_passkey(&params.passkey, ConfirmPasskeyReply::new(conn));
+                    } else {
+                        handler.display_passkey(&params.passkey);
+                    }
                 }
-            });
+            }
         }
         raw::BLE_GAP_EVTS_BLE_GAP_EVT_AUTH_KEY_REQUEST => {
             let params = &gap_evt.params.auth_key_request;
@@ -251,6 +284,41 @@ pub(crate) unsafe fn on_evt(ble_evt: *const raw::ble_evt_t) {
                 }
             }
         }
+        raw::BLE_GAP_EVTS_BLE_GAP_EVT_KEY_PRESSED => {
+            let params = &gap_evt.params.key_pressed;
+            trace!("on_key_pressed kp_not={}", params.kp_not);
+
+            #[cfg(feature = "ble-sec")]
+            if let (Some(conn), Some(keypress)) = (
+                Connection::from_handle(gap_evt.conn_handle),
+                security::Keypress::from_raw(params.kp_not),
+            ) {
+                if let Some(handler) = conn.with_state(|state| state.security.handler) {
+                    handler.on_keypress(&conn, keypress);
+                }
+            }
+        }
+        raw::BLE_GAP_EVTS_BLE_GAP_EVT_LESC_DHKEY_REQUEST => {
+            let params = &gap_evt.params.lesc_dhkey_request;
+            trace!("on_lesc_dhkey_request oobd_req={}", params.oobd_req());
+
+            #[cfg(feature = "ble-sec")]
+            if let Some(conn) = Connection::from_handle(gap_evt.conn_handle) {
+                match conn.with_state(|state| state.security.handler) {
+                    Some(handler) => handler.compute_dhkey(&(*params.p_pk_peer).pk, DhKeyReply::new(conn)),
+                    None => drop(DhKeyReply::new(conn)),
+                }
+            }
+            #[cfg(not(feature = "ble-sec"))]
+            {
+                // LESC is never enabled without `ble-sec`, so this shouldn't happen. Fail the pairing.
+                let dhkey = raw::ble_gap_lesc_dhkey_t { key: [0; 32] };
+                let ret = raw::sd_ble_gap_lesc_dhkey_reply(gap_evt.conn_handle, &dhkey);
+                if let Err(_err) = RawError::convert(ret) {
+                    warn!("sd_ble_gap_lesc_dhkey_reply err {:?}", _err);
+                }
+            }
+        }
         #[cfg(feature = "ble-peripheral")]
         raw::BLE_GAP_EVTS_BLE_GAP_EVT_SEC_INFO_REQUEST => {
             let params = &gap_evt.params.sec_info_request;
@@ -285,12 +353,16 @@ pub(crate) unsafe fn on_evt(ble_evt: *const raw::ble_evt_t) {
             if let Some(conn) = Connection::from_handle(gap_evt.conn_handle) {
                 conn.with_state(|state| {
                     state.security_mode = SecurityMode::try_from_raw(params.conn_sec.sec_mode).unwrap_or_default();
+                    state.encryption_key_size = params.conn_sec.encr_key_size;
                     #[cfg(feature = "ble-sec")]
                     if let Some(handler) = state.security.handler {
                         handler.on_security_update(&conn, state.security_mode);
                     }
                 });
             }
+
+            #[cfg(feature = "ble-sec")]
+            security::portal(gap_evt.conn_handle).call(ble_evt);
         }
         raw::BLE_GAP_EVTS_BLE_GAP_EVT_AUTH_STATUS => {
             let params = &gap_evt.params.auth_status;
@@ -302,6 +374,19 @@ pub(crate) unsafe fn on_evt(ble_evt: *const raw::ble_evt_t) {
                 params.kdist_own._bitfield_1.get(0, 8),
                 params.kdist_peer._bitfield_1.get(0, 8)
             );
+            #[cfg(feature = "ble-sec")]
+            if u32::from(params.auth_status) == raw::BLE_GAP_SEC_STATUS_SUCCESS {
+                if let Some(conn) = Connection::from_handle(gap_evt.conn_handle) {
+                    let (handler, (own_keys, peer_keys)) = conn.with_state(|state| {
+                        let keys = security::KeySet::from_state(&state.security, params.kdist_own, params.kdist_peer);
+                        (state.security.handler, keys)
+                    });
+                    if let Some(handler) = handler {
+                        handler.on_keys_exchanged(&conn, &own_keys, &peer_keys);
+                    }
+                }
+            }
+
             #[cfg(feature = "ble-sec")]
             if u32::from(params.auth_status) == raw::BLE_GAP_SEC_STATUS_SUCCESS && params.bonded() != 0 {
                 if let Some(conn) = Connection::from_handle(gap_evt.conn_handle) {
@@ -314,33 +399,189 @@ pub(crate) unsafe fn on_evt(ble_evt: *const raw::ble_evt_t) {
                                 IdentityKey::from_addr(state.peer_address)
                             };
 
+                            // The LTK used to encrypt later connections is the one distributed by the peripheral,
+                            // except with LESC where both sides derive the same key, found in the own keys.
+                            let enc_key = match state.role {
+                                #[cfg(feature = "ble-central")]
+                                Role::Central if params.lesc() == 0 => state.security.peer_enc_key,
+                                _ => state.security.own_enc_key,
+                            };
+
                             handler.on_bonded(
                                 &conn,
-                                MasterId::from_raw(state.security.own_enc_key.master_id),
-                                EncryptionInfo::from_raw(state.security.own_enc_key.enc_info),
+                                MasterId::from_raw(enc_key.master_id),
+                                EncryptionInfo::from_raw(enc_key.enc_info),
                                 peer_id,
                             );
                         }
                     });
                 }
             }
+
+            #[cfg(feature = "ble-sec")]
+            security::portal(gap_evt.conn_handle).call(ble_evt);
+        }
+        #[cfg(feature = "ble-peripheral")]
+        raw::BLE_GAP_EVTS_BLE_GAP_EVT_SCAN_REQ_REPORT => {
+            trace!("peripheral on_scan_req_report");
+            peripheral::SCAN_REQ_PORTAL.call(ble_evt);
+        }
+        #[cfg(any(feature = "s132", feature = "s140"))]
+        raw::BLE_GAP_EVTS_BLE_GAP_EVT_QOS_CHANNEL_SURVEY_REPORT => {
+            trace!("on_qos_channel_survey_report");
+            CHANNEL_SURVEY_PORTAL.call(ble_evt);
+        }
+        #[cfg(all(feature = "ble-sec", feature = "ble-central"))]
+        raw::BLE_GAP_EVTS_BLE_GAP_EVT_SEC_REQUEST => {
+            let params = &gap_evt.params.sec_request;
+            trace!(
+                "ble evt sec request conn={:x} bond={:?} mitm={:?} lesc={:?}",
+                gap_evt.conn_handle,
+                params.bond(),
+                params.mitm(),
+                params.lesc()
+            );
+            security::on_security_request(gap_evt.conn_handle, params);
         }
-        // BLE_GAP_EVTS_BLE_GAP_EVT_KEY_PRESSED (LESC central pairing)
-        // BLE_GAP_EVTS_BLE_GAP_EVT_LESC_DHKEY_REQUEST (LESC key calculation)
-        // BLE_GAP_EVTS_BLE_GAP_EVT_SEC_REQUEST (Peripheral-initiated security request)
         // BLE_GAP_EVTS_BLE_GAP_EVT_RSSI_CHANGED
-        // BLE_GAP_EVTS_BLE_GAP_EVT_SCAN_REQ_REPORT
-        // BLE_GAP_EVTS_BLE_GAP_EVT_QOS_CHANNEL_SURVEY_REPORT
         _ => {}
     }
 }
 
+/// Handler for PHY update requests from the peer.
+///
+/// Returns the preferred PHYs to answer with. The SoftDevice then picks the PHYs to use from these and the
+/// peer's preferred PHYs.
+pub type PhyUpdateRequestHandler = fn(conn: &Connection, peer_preferred: &raw::ble_gap_phys_t) -> raw::ble_gap_phys_t;
+
+static PHY_UPDATE_REQUEST_HANDLER: Mutex<CriticalSectionRawMutex, Cell<Option<PhyUpdateRequestHandler>>> =
+    Mutex::new(Cell::new(None));
+
+/// Set the handler deciding how to answer PHY update requests from the peer.
+///
+/// If no handler is set, the peer's preferred PHYs are accepted.
+pub fn set_phy_update_request_handler(_sd: &Softdevice, handler: Option<PhyUpdateRequestHandler>) {
+    PHY_UPDATE_REQUEST_HANDLER.lock(|h| h.set(handler));
+}
+
+/// Accept a pairing request, replying with the security parameters and the connection's keyset.
+pub(crate) unsafe fn sec_params_reply(conn_handle: u16) -> Result<(), RawError> {
+    let (sec_params, keyset) = connection::with_state_by_conn_handle(conn_handle, |state| {
+        let sec_params = match state.role {
+            // As central, the params were already given to `sd_ble_gap_authenticate`.
+            #[cfg(feature = "ble-central")]
+            Role::Central => {
+                #[cfg(feature = "ble-sec")]
+                load_lesc_public_key(conn_handle, state);
+                None
+            }
+            #[cfg(feature = "ble-peripheral")]
+            Role::Peripheral => Some(peripheral_sec_params(conn_handle, state)),
+        };
+        (sec_params, state.keyset())
+    });
+    let sec_params_ptr = sec_params.as_ref().map_or(ptr::null(), |p| p as *const _);
+
+    let ret = raw::sd_ble_gap_sec_params_reply(
+        conn_handle,
+        raw::BLE_GAP_SEC_STATUS_SUCCESS as u8,
+        sec_params_ptr,
+        &keyset,
+    );
+
+    RawError::convert(ret)
+}
+
+#[cfg(feature = "ble-peripheral")]
+unsafe fn peripheral_sec_params(
+    _conn_handle: u16,
+    _state: &mut connection::ConnectionState,
+) -> raw::ble_gap_sec_params_t {
+    let mut sec_params: raw::ble_gap_sec_params_t = core::mem::zeroed();
+
+    sec_params.min_key_size = 7;
+    sec_params.max_key_size = 16;
+
+    sec_params.kdist_own.set_enc(1);
+    sec_params.kdist_own.set_id(1);
+    sec_params.kdist_peer.set_enc(1);
+    sec_params.kdist_peer.set_id(1);
+    sec_params.set_io_caps(raw::BLE_GAP_IO_CAPS_NONE as u8);
+
+    #[cfg(feature = "ble-sec")]
+    if let Some(handler) = _state.security.handler {
+        sec_params.set_io_caps(handler.io_capabilities().to_io_caps());
+        if let Some(conn) = Connection::from_handle(_conn_handle) {
+            sec_params.set_bond(handler.can_bond(&conn) as u8);
+            sec_params.set_oob(handler.can_recv_out_of_band(&conn) as u8);
+
+            let (own_keys, peer_keys) = handler.key_distribution(&conn);
+            sec_params.kdist_own = own_keys.into_raw();
+            sec_params.kdist_peer = peer_keys.into_raw();
+        }
+        sec_params.set_keypress(handler.keypress_notifications() as u8);
+    }
+
+    #[cfg(feature = "ble-sec")]
+    sec_params.set_lesc(load_lesc_public_key(_conn_handle, _state) as u8);
+
+    sec_params
+}
+
+/// Copy the handler's LESC public key to the keyset, returning whether there's one.
+#[cfg(feature = "ble-sec")]
+fn load_lesc_public_key(conn_handle: u16, state: &mut connection::ConnectionState) -> bool {
+    let pk = state
+        .security
+        .handler
+        .zip(Connection::from_handle(conn_handle))
+        .and_then(|(handler, conn)| handler.lesc_public_key(&conn));
+
+    match pk {
+        Some(pk) => {
+            #[cfg(feature = "ble-sec-lesc-debug")]
+            #[allow(deprecated)]
+            if pk == security::LESC_DEBUG_PUBLIC_KEY {
+                warn!(
+                    "pairing conn={:x} with the LESC debug key, the link will not be secure",
+                    conn_handle
+                );
+            }
+            state.security.own_pk.pk = pk;
+            true
+        }
+        None => false,
+    }
+}
+
+const PORTAL_NEW: Portal<*const raw::ble_evt_t> = Portal::new();
+
+static CONN_PARAMS_PORTALS: [Portal<*const raw::ble_evt_t>; CONNS_MAX] = [PORTAL_NEW; CONNS_MAX];
+pub(crate) fn conn_params_portal(conn_handle: u16) -> &'static Portal<*const raw::ble_evt_t> {
+    &CONN_PARAMS_PORTALS[conn_handle as usize]
+}
+
+static PHY_PORTALS: [Portal<*const raw::ble_evt_t>; CONNS_MAX] = [PORTAL_NEW; CONNS_MAX];
+pub(crate) fn phy_portal(conn_handle: u16) -> &'static Portal<*const raw::ble_evt_t> {
+    &PHY_PORTALS[conn_handle as usize]
+}
+
+#[cfg(any(feature = "s113", feature = "s132", feature = "s140"))]
+static DATA_LENGTH_PORTALS: [Portal<*const raw::ble_evt_t>; CONNS_MAX] = [PORTAL_NEW; CONNS_MAX];
+#[cfg(any(feature = "s113", feature = "s132", feature = "s140"))]
+pub(crate) fn data_length_portal(conn_handle: u16) -> &'static Portal<*const raw::ble_evt_t> {
+    &DATA_LENGTH_PORTALS[conn_handle as usize]
+}
+
 #[cfg(any(feature = "s113", feature = "s132", feature = "s140"))]
-pub(crate) unsafe fn do_data_length_update(conn_handle: u16, params: *const raw::ble_gap_data_length_params_t) {
+pub(crate) unsafe fn do_data_length_update(
+    conn_handle: u16,
+    params: *const raw::ble_gap_data_length_params_t,
+) -> Result<(), RawError> {
     let mut dl_limitation = core::mem::zeroed();
     let ret = raw::sd_ble_gap_data_length_update(conn_handle, params, &mut dl_limitation);
-    if let Err(_err) = RawError::convert(ret) {
-        warn!("sd_ble_gap_data_length_update err {:?}", _err);
+    RawError::convert(ret).map_err(|err| {
+        warn!("sd_ble_gap_data_length_update err {:?}", err);
 
         if dl_limitation.tx_payload_limited_octets != 0 || dl_limitation.rx_payload_limited_octets != 0 {
             warn!(
@@ -355,7 +596,9 @@ pub(crate) unsafe fn do_data_length_update(conn_handle: u16, params: *const raw:
                 dl_limitation.tx_rx_time_limited_us
             );
         }
-    }
+
+        err
+    })
 }
 
 pub fn set_device_identities_list(
@@ -421,3 +664,258 @@ pub fn set_whitelist(sd: &Softdevice, addrs: &[Address]) -> Result<(), RawError>
     };
     RawError::convert(ret)
 }
+
+/// Set the whitelist to the identity addresses of the given peers, typically loaded from stored bonds.
+///
+/// Peers with a non-zero IRK are also added to the device identities list, so that they are accepted
+/// when connecting or advertising with a resolvable private address.
+pub fn set_whitelist_from_identities(sd: &Softdevice, id_keys: &[IdentityKey]) -> Result<(), RawError> {
+    const MAX_LEN: usize = raw::BLE_GAP_WHITELIST_ADDR_MAX_COUNT as usize;
+    assert!(id_keys.len() <= MAX_LEN);
+
+    let mut addrs = [Address::new(AddressType::Public, [0; 6]); MAX_LEN];
+    for (a, k) in addrs.iter_mut().zip(id_keys) {
+        *a = k.addr;
+    }
+
+    let mut resolvable: heapless::Vec<IdentityKey, { raw::BLE_GAP_DEVICE_IDENTITIES_MAX_COUNT as usize }> =
+        heapless::Vec::new();
+    for k in id_keys.iter().filter(|k| k.irk != IdentityResolutionKey::default()) {
+        resolvable.push(*k).map_err(|_| RawError::NoMem)?;
+    }
+
+    set_device_identities_list(sd, &resolvable, None)?;
+    set_whitelist(sd, &addrs[..id_keys.len()])
+}
+
+#[repr(u8)]
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum PrivacyMode {
+    /// Use the identity address.
+    #[default]
+    Off = raw::BLE_GAP_PRIVACY_MODE_OFF as u8,
+    /// Use a private address, and accept peers using their identity address even if their IRK is known.
+    Device = raw::BLE_GAP_PRIVACY_MODE_DEVICE_PRIVACY as u8,
+    /// Use a private address, and reject peers using their identity address if their IRK is known.
+    Network = raw::BLE_GAP_PRIVACY_MODE_NETWORK_PRIVACY as u8,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PrivacyConfig {
+    pub mode: PrivacyMode,
+    /// Must be [`AddressType::RandomPrivateResolvable`] or [`AddressType::RandomPrivateNonResolvable`].
+    pub address_type: AddressType,
+    /// How often the private address is changed, in seconds.
+    pub address_cycle_s: u16,
+    /// IRK used to generate resolvable private addresses. If None, the device IRK is used.
+    pub irk: Option<IdentityResolutionKey>,
+}
+
+impl Default for PrivacyConfig {
+    fn default() -> Self {
+        Self {
+            mode: PrivacyMode::Off,
+            address_type: AddressType::RandomPrivateResolvable,
+            address_cycle_s: raw::BLE_GAP_DEFAULT_PRIVATE_ADDR_CYCLE_INTERVAL_S as u16,
+            irk: None,
+        }
+    }
+}
+
+/// Configure the privacy of the local device.
+///
+/// With privacy enabled, the local device advertises, scans and connects with a private address that is
+/// regenerated every `address_cycle_s` seconds. This can't be changed while advertising, scanning or
+/// connecting.
+///
+/// Peers using resolvable private addresses are resolved with the list set by [`set_device_identities_list`],
+/// and show up with [`Address::is_resolved_peer_id`] set.
+pub fn set_privacy(_sd: &Softdevice, config: &PrivacyConfig) -> Result<(), RawError> {
+    let mut irk = config.irk.map(|irk| *irk.as_raw());
+    let params = raw::ble_gap_privacy_params_t {
+        privacy_mode: config.mode as u8,
+        private_addr_type: config.address_type as u8,
+        private_addr_cycle_s: config.address_cycle_s,
+        p_device_irk: irk.as_mut().map(|x| x as *mut _).unwrap_or(ptr::null_mut()),
+    };
+
+    let ret = unsafe { raw::sd_ble_gap_privacy_set(&params) };
+    RawError::convert(ret).map_err(|err| {
+        warn!("sd_ble_gap_privacy_set err {:?}", err);
+        err
+    })
+}
+
+/// Get the current privacy configuration. The returned IRK is the one currently in use.
+pub fn get_privacy(_sd: &Softdevice) -> Result<PrivacyConfig, RawError> {
+    let mut irk = raw::ble_gap_irk_t { irk: [0; 16] };
+    let mut params = raw::ble_gap_privacy_params_t {
+        privacy_mode: 0,
+        private_addr_type: 0,
+        private_addr_cycle_s: 0,
+        p_device_irk: &mut irk,
+    };
+
+    let ret = unsafe { raw::sd_ble_gap_privacy_get(&mut params) };
+    RawError::convert(ret).map_err(|err| {
+        warn!("sd_ble_gap_privacy_get err {:?}", err);
+        err
+    })?;
+
+    let mode = match params.privacy_mode as u32 {
+        raw::BLE_GAP_PRIVACY_MODE_DEVICE_PRIVACY => PrivacyMode::Device,
+        raw::BLE_GAP_PRIVACY_MODE_NETWORK_PRIVACY => PrivacyMode::Network,
+        _ => PrivacyMode::Off,
+    };
+
+    Ok(PrivacyConfig {
+        mode,
+        address_type: params
+            .private_addr_type
+            .try_into()
+            .unwrap_or(AddressType::RandomPrivateResolvable),
+        address_cycle_s: params.private_addr_cycle_s,
+        irk: Some(IdentityResolutionKey::from_raw(irk)),
+    })
+}
+
+/// Energy measured on each radio channel by a channel survey.
+#[cfg(any(feature = "s132", feature = "s140"))]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ChannelSurveyReport {
+    channel_energy: [i8; 40],
+}
+
+#[cfg(any(feature = "s132", feature = "s140"))]
+impl ChannelSurveyReport {
+    /// Energy measured on the given channel index (0-39), in dBm, or None if there's no measurement.
+    pub fn energy(&self, channel: u8) -> Option<i8> {
+        let energy = *self.channel_energy.get(channel as usize)?;
+        (energy != raw::BLE_GAP_POWER_LEVEL_INVALID as i8).then_some(energy)
+    }
+}
+
+#[cfg(any(feature = "s132", feature = "s140"))]
+static CHANNEL_SURVEY_PORTAL: Portal<*const raw::ble_evt_t> = Portal::new();
+
+/// Start surveying the energy on the radio channels, returning the reports as an async stream.
+///
+/// `interval_us` is the time between measurements, from 7500 to 4000000 us, or 0 to measure continuously
+/// whenever the radio is idle. Reports generated while the stream isn't being polled are dropped. The survey
+/// is stopped when the stream is dropped.
+///
+/// The QoS channel survey role must be enabled in the `gap_role_count` config.
+#[cfg(any(feature = "s132", feature = "s140"))]
+pub fn channel_survey(
+    _sd: &Softdevice,
+    interval_us: u32,
+) -> Result<impl futures::Stream<Item = ChannelSurveyReport>, RawError> {
+    let ret = unsafe { raw::sd_ble_gap_qos_channel_survey_start(interval_us) };
+    RawError::convert(ret).map_err(|err| {
+        warn!("sd_ble_gap_qos_channel_survey_start err {:?}", err);
+        err
+    })?;
+
+    let stop = crate::util::OnDrop::new(|| {
+        let ret = unsafe { raw::sd_ble_gap_qos_channel_survey_stop() };
+        if let Err(_e) = RawError::convert(ret) {
+            warn!("sd_ble_gap_qos_channel_survey_stop: {:?}", _e);
+        }
+    });
+
+    Ok(futures::stream::unfold(stop, |stop| async move {
+        let report = CHANNEL_SURVEY_PORTAL
+            .wait_many(|ble_evt| unsafe {
+                match (*ble_evt).header.evt_id as u32 {
+                    raw::BLE_GAP_EVTS_BLE_GAP_EVT_QOS_CHANNEL_SURVEY_REPORT => {
+                        let gap_evt = get_union_field(ble_evt, &(*ble_evt).evt.gap_evt);
+                        Some(ChannelSurveyReport {
+                            channel_energy: gap_evt.params.qos_channel_survey_report.channel_energy,
+                        })
+                    }
+                    _ => None,
+                }
+            })
+            .await;
+        Some((report, stop))
+    }))
+}
+
+/// Set the value of the Appearance characteristic in the GAP service.
+pub fn set_appearance(_sd: &Softdevice, appearance: Appearance) -> Result<(), RawError> {
+    let ret = unsafe { raw::sd_ble_gap_appearance_set(appearance.to_u16()) };
+    RawError::convert(ret).map_err(|err| {
+        warn!("sd_ble_gap_appearance_set err {:?}", err);
+        err
+    })
+}
+
+/// Get the value of the Appearance characteristic in the GAP service.
+pub fn get_appearance(_sd: &Softdevice) -> Result<Appearance, RawError> {
+    let mut appearance = 0;
+    let ret = unsafe { raw::sd_ble_gap_appearance_get(&mut appearance) };
+    RawError::convert(ret).map_err(|err| {
+        warn!("sd_ble_gap_appearance_get err {:?}", err);
+        err
+    })?;
+    Ok(Appearance::from_u16(appearance))
+}
+
+/// Set the value of the Peripheral Preferred Connection Parameters characteristic in the GAP service.
+///
+/// Centrals may read it to pick the connection parameters. Unknown values can be set to
+/// `BLE_GAP_CP_MIN_CONN_INTVL_NONE` and similar.
+#[cfg(feature = "ble-peripheral")]
+pub fn set_ppcp(_sd: &Softdevice, conn_params: &raw::ble_gap_conn_params_t) -> Result<(), RawError> {
+    let ret = unsafe { raw::sd_ble_gap_ppcp_set(conn_params) };
+    RawError::convert(ret).map_err(|err| {
+        warn!("sd_ble_gap_ppcp_set err {:?}", err);
+        err
+    })
+}
+
+/// Get the value of the Peripheral Preferred Connection Parameters characteristic in the GAP service.
+#[cfg(feature = "ble-peripheral")]
+pub fn get_ppcp(_sd: &Softdevice) -> Result<raw::ble_gap_conn_params_t, RawError> {
+    let mut conn_params: raw::ble_gap_conn_params_t = unsafe { core::mem::zeroed() };
+    let ret = unsafe { raw::sd_ble_gap_ppcp_get(&mut conn_params) };
+    RawError::convert(ret).map_err(|err| {
+        warn!("sd_ble_gap_ppcp_get err {:?}", err);
+        err
+    })?;
+    Ok(conn_params)
+}
+
+/// Set the value of the Device Name characteristic in the GAP service.
+///
+/// `write_perm` is the security required for peers to write the name. Use [`SecurityMode::NoAccess`] to make it
+/// read-only. Peer writes are stored by the SoftDevice, so use [`get_device_name`] to read back the current name,
+/// for example to build advertising data with
+/// [`AdvertisementBuilder::adapt_name`](crate::ble::advertisement_builder::AdvertisementBuilder::adapt_name).
+///
+/// The name is limited to the `max_len` set in the `gap_device_name` config.
+pub fn set_device_name(_sd: &Softdevice, name: &[u8], write_perm: SecurityMode) -> Result<(), RawError> {
+    assert!(name.len() <= u16::MAX as usize);
+    let write_perm = write_perm.into_raw();
+    let ret = unsafe { raw::sd_ble_gap_device_name_set(&write_perm, name.as_ptr(), name.len() as u16) };
+    RawError::convert(ret).map_err(|err| {
+        warn!("sd_ble_gap_device_name_set err {:?}", err);
+        err
+    })
+}
+
+/// Get the value of the Device Name characteristic in the GAP service.
+///
+/// The name may have been written by a peer, so it's not guaranteed to be valid UTF-8.
+pub fn get_device_name<'a>(_sd: &Softdevice, buf: &'a mut [u8]) -> Result<&'a [u8], RawError> {
+    let mut len = buf.len().min(u16::MAX as usize) as u16;
+    let ret = unsafe { raw::sd_ble_gap_device_name_get(buf.as_mut_ptr(), &mut len) };
+    RawError::convert(ret).map_err(|err| {
+        warn!("sd_ble_gap_device_name_get err {:?}", err);
+        err
+    })?;
+    Ok(&buf[..len as usize])
+}
@@ -6,6 +6,15 @@ use crate::ble::*;
 use crate::util::{get_flexarray, get_union_field, Portal};
 use crate::{raw, RawError};
 
+pub mod characteristic;
+
+/// Discovered service
+pub struct Service {
+    pub uuid: Option<Uuid>,
+    pub start_handle: u16,
+    pub end_handle: u16,
+}
+
 /// Discovered characteristic
 pub struct Characteristic {
     pub uuid: Option<Uuid>,
@@ -21,6 +30,19 @@ pub struct Descriptor {
     pub handle: u16,
 }
 
+impl Descriptor {
+    /// Returns whether this is a Client Characteristic Configuration Descriptor (CCCD).
+    pub fn is_cccd(&self) -> bool {
+        self.uuid == Some(Uuid::new_16(raw::BLE_UUID_DESCRIPTOR_CLIENT_CHAR_CONFIG as u16))
+    }
+}
+
+/// Find the handle of the Client Characteristic Configuration Descriptor (CCCD) among the
+/// descriptors of a discovered characteristic.
+pub fn find_cccd(descriptors: &[Descriptor]) -> Option<u16> {
+    descriptors.iter().find(|desc| desc.is_cccd()).map(|desc| desc.handle)
+}
+
 #[repr(u8)]
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
@@ -63,6 +85,9 @@ pub trait Client {
     /// Called by [`discover`] for every discovered characteristic. Implementations must
     /// check if they're interested in the UUID of the characteristic, and save their
     /// handles if needed.
+    ///
+    /// Up to 6 descriptors are passed. Discovery fails with [`DiscoverError::TooManyDescriptors`] if a
+    /// characteristic has more.
     fn discovered_characteristic(&mut self, characteristic: &Characteristic, descriptors: &[Descriptor]);
 
     /// Called by [`discover`] at the end of the discovery procedure. Implementations must check
@@ -84,6 +109,10 @@ pub enum DiscoverError {
     ServiceNotFound,
     /// Service with the given UUID found, but it's missing some required characteristics.
     ServiceIncomplete,
+    /// The server didn't respond to a discovery request in time.
+    Timeout,
+    /// A characteristic has more descriptors than can be passed to [`Client::discovered_characteristic`].
+    TooManyDescriptors,
     Gatt(GattError),
     Raw(RawError),
 }
@@ -109,34 +138,50 @@ impl From<RawError> for DiscoverError {
 const DISC_CHARS_MAX: usize = 6;
 const DISC_DESCS_MAX: usize = 6;
 
+const DISC_SRVCS_MAX: usize = 6;
+
 pub(crate) async fn discover_service(conn: &Connection, uuid: Uuid) -> Result<raw::ble_gattc_service_t, DiscoverError> {
-    let conn_handle = conn.with_state(|state| state.check_connected())?;
-    let ret = unsafe { raw::sd_ble_gattc_primary_services_discover(conn_handle, 1, uuid.as_raw_ptr()) };
+    let v = discover_services_from(conn, 1, Some(&uuid)).await?;
+
+    match v.len() {
+        0 => Err(DiscoverError::ServiceNotFound),
+        1 => Ok(v[0]),
+        _n => {
+            warn!("Found {:?} services with the same UUID, using the first one", v.len());
+            Ok(v[0])
+        }
+    }
+}
+
+/// Discover the primary services starting at `start_handle`, optionally filtered by UUID.
+///
+/// If the server returns more services than fit in the result, the rest are dropped. Callers
+/// can continue discovery from the end handle of the last returned service.
+async fn discover_services_from(
+    conn: &Connection,
+    start_handle: u16,
+    uuid: Option<&Uuid>,
+) -> Result<Vec<raw::ble_gattc_service_t, DISC_SRVCS_MAX>, DiscoverError> {
+    let conn_handle = wait_idle(conn).await?;
+    let uuid = uuid.map(|uuid| uuid.as_raw_ptr()).unwrap_or(core::ptr::null());
+    let ret = unsafe { raw::sd_ble_gattc_primary_services_discover(conn_handle, start_handle, uuid) };
     RawError::convert(ret).map_err(|err| {
         warn!("sd_ble_gattc_primary_services_discover err {:?}", err);
         err
     })?;
+    conn.with_state(|state| state.gattc_pending = true);
 
     portal(conn_handle)
         .wait_once(|ble_evt| unsafe {
             match (*ble_evt).header.evt_id as u32 {
                 raw::BLE_GAP_EVTS_BLE_GAP_EVT_DISCONNECTED => return Err(DiscoverError::Disconnected),
+                raw::BLE_GATTC_EVTS_BLE_GATTC_EVT_TIMEOUT => return Err(DiscoverError::Timeout),
                 raw::BLE_GATTC_EVTS_BLE_GATTC_EVT_PRIM_SRVC_DISC_RSP => {
                     let gattc_evt = check_status(ble_evt)?;
                     let params = get_union_field(ble_evt, &gattc_evt.params.prim_srvc_disc_rsp);
                     let v = get_flexarray(ble_evt, &params.services, params.count as usize);
-
-                    match v.len() {
-                        0 => Err(DiscoverError::ServiceNotFound),
-                        1 => Ok(v[0]),
-                        _n => {
-                            warn!(
-                                "Found {:?} services with the same UUID, using the first one",
-                                params.count
-                            );
-                            Ok(v[0])
-                        }
-                    }
+                    let len = core::cmp::min(v.len(), DISC_SRVCS_MAX);
+                    Ok(unwrap!(Vec::from_slice(&v[..len])))
                 }
                 e => panic!("unexpected event {}", e),
             }
@@ -151,7 +196,7 @@ async fn discover_characteristics(
     start_handle: u16,
     end_handle: u16,
 ) -> Result<Vec<raw::ble_gattc_char_t, DISC_CHARS_MAX>, DiscoverError> {
-    let conn_handle = conn.with_state(|state| state.check_connected())?;
+    let conn_handle = wait_idle(conn).await?;
 
     let ret = unsafe {
         raw::sd_ble_gattc_characteristics_discover(
@@ -166,18 +211,20 @@ async fn discover_characteristics(
         warn!("sd_ble_gattc_characteristics_discover err {:?}", err);
         err
     })?;
+    conn.with_state(|state| state.gattc_pending = true);
 
     portal(conn_handle)
         .wait_once(|ble_evt| unsafe {
             match (*ble_evt).header.evt_id as u32 {
                 raw::BLE_GAP_EVTS_BLE_GAP_EVT_DISCONNECTED => return Err(DiscoverError::Disconnected),
+                raw::BLE_GATTC_EVTS_BLE_GATTC_EVT_TIMEOUT => return Err(DiscoverError::Timeout),
                 raw::BLE_GATTC_EVTS_BLE_GATTC_EVT_CHAR_DISC_RSP => {
                     let gattc_evt = check_status(ble_evt)?;
                     let params = get_union_field(ble_evt, &gattc_evt.params.char_disc_rsp);
                     let v = get_flexarray(ble_evt, &params.chars, params.count as usize);
-                    let v = Vec::from_slice(v)
-                        .unwrap_or_else(|_| panic!("too many gatt chars, increase DiscCharsMax: {:?}", v.len()));
-                    Ok(v)
+                    // If there are more, the caller continues discovery after the last one returned.
+                    let len = core::cmp::min(v.len(), DISC_CHARS_MAX);
+                    Ok(unwrap!(Vec::from_slice(&v[..len])))
                 }
                 e => panic!("unexpected event {}", e),
             }
@@ -192,7 +239,7 @@ async fn discover_descriptors(
     start_handle: u16,
     end_handle: u16,
 ) -> Result<Vec<raw::ble_gattc_desc_t, DISC_DESCS_MAX>, DiscoverError> {
-    let conn_handle = conn.with_state(|state| state.check_connected())?;
+    let conn_handle = wait_idle(conn).await?;
 
     let ret = unsafe {
         raw::sd_ble_gattc_descriptors_discover(
@@ -207,18 +254,20 @@ async fn discover_descriptors(
         warn!("sd_ble_gattc_descriptors_discover err {:?}", err);
         err
     })?;
+    conn.with_state(|state| state.gattc_pending = true);
 
     portal(conn_handle)
         .wait_once(|ble_evt| unsafe {
             match (*ble_evt).header.evt_id as u32 {
                 raw::BLE_GAP_EVTS_BLE_GAP_EVT_DISCONNECTED => return Err(DiscoverError::Disconnected),
+                raw::BLE_GATTC_EVTS_BLE_GATTC_EVT_TIMEOUT => return Err(DiscoverError::Timeout),
                 raw::BLE_GATTC_EVTS_BLE_GATTC_EVT_DESC_DISC_RSP => {
                     let gattc_evt = check_status(ble_evt)?;
                     let params = get_union_field(ble_evt, &gattc_evt.params.desc_disc_rsp);
                     let v = get_flexarray(ble_evt, &params.descs, params.count as usize);
-                    let v = Vec::from_slice(v)
-                        .unwrap_or_else(|_| panic!("too many gatt descs, increase DiscDescsMax: {:?}", v.len()));
-                    Ok(v)
+                    // If there are more, the caller continues discovery after the last one returned.
+                    let len = core::cmp::min(v.len(), DISC_DESCS_MAX);
+                    Ok(unwrap!(Vec::from_slice(&v[..len])))
                 }
                 e => panic!("unexpected event {}", e),
             }
@@ -228,6 +277,58 @@ async fn discover_descriptors(
 
 // =============================
 
+/// Find the full UUID of an attribute whose UUID is unknown to the SoftDevice (a 128-bit UUID
+/// whose base hasn't been added with [`Uuid::new_128`]).
+///
+/// The base is added to the SoftDevice, so that the returned UUID can be compared with other UUIDs.
+/// Returns `None` if it can't be added (no vendor-specific UUID slots left).
+async fn discover_attr_uuid(conn: &Connection, handle: u16) -> Result<Option<Uuid>, DiscoverError> {
+    let conn_handle = wait_idle(conn).await?;
+
+    let ret = unsafe {
+        raw::sd_ble_gattc_attr_info_discover(
+            conn_handle,
+            &raw::ble_gattc_handle_range_t {
+                start_handle: handle,
+                end_handle: handle,
+            },
+        )
+    };
+    RawError::convert(ret).map_err(|err| {
+        warn!("sd_ble_gattc_attr_info_discover err {:?}", err);
+        err
+    })?;
+    conn.with_state(|state| state.gattc_pending = true);
+
+    let uuid128 = portal(conn_handle)
+        .wait_once(|ble_evt| unsafe {
+            match (*ble_evt).header.evt_id as u32 {
+                raw::BLE_GAP_EVTS_BLE_GAP_EVT_DISCONNECTED => return Err(DiscoverError::Disconnected),
+                raw::BLE_GATTC_EVTS_BLE_GATTC_EVT_TIMEOUT => return Err(DiscoverError::Timeout),
+                raw::BLE_GATTC_EVTS_BLE_GATTC_EVT_ATTR_INFO_DISC_RSP => {
+                    let gattc_evt = check_status(ble_evt)?;
+                    let params = get_union_field(ble_evt, &gattc_evt.params.attr_info_disc_rsp);
+                    if params.count == 0 || params.format != raw::BLE_GATTC_ATTR_INFO_FORMAT_128BIT as u8 {
+                        return Ok(None);
+                    }
+                    let info = get_union_field(ble_evt, &params.info.attr_info128);
+                    let info = core::slice::from_raw_parts(info.as_ptr(), params.count as usize);
+                    Ok(Some(info[0].uuid.uuid128))
+                }
+                e => panic!("unexpected event {}", e),
+            }
+        })
+        .await?;
+
+    Ok(uuid128.and_then(|uuid128| match Uuid::try_new_128(&uuid128) {
+        Ok(uuid) => Some(uuid),
+        Err(err) => {
+            warn!("sd_ble_uuid_vs_add err {:?}", err);
+            None
+        }
+    }))
+}
+
 async fn discover_inner<T: Client>(
     conn: &Connection,
     client: &mut T,
@@ -239,8 +340,13 @@ async fn discover_inner<T: Client>(
     let start_handle = curr.handle_value + 1;
     let end_handle = next.map(|c| c.handle_decl - 1).unwrap_or(svc.handle_range.end_handle);
 
+    let uuid = match Uuid::from_raw(curr.uuid) {
+        Some(uuid) => Some(uuid),
+        None => discover_attr_uuid(conn, curr.handle_value).await?,
+    };
+
     let characteristic = Characteristic {
-        uuid: Uuid::from_raw(curr.uuid),
+        uuid,
         handle_decl: curr.handle_decl,
         handle_value: curr.handle_value,
         has_ext_props: curr.char_ext_props() != 0,
@@ -250,21 +356,39 @@ async fn discover_inner<T: Client>(
     let mut descriptors: Vec<Descriptor, DISC_DESCS_MAX> = Vec::new();
 
     // Only if range is non-empty, discover. (if it's empty there must be no descriptors)
-    if start_handle <= end_handle {
-        let descs = {
-            match discover_descriptors(conn, start_handle, end_handle).await {
-                Ok(descs) => descs,
-                Err(DiscoverError::Gatt(GattError::ATTERR_ATTRIBUTE_NOT_FOUND)) => Vec::new(),
-                Err(err) => return Err(err),
-            }
+    let mut curr_handle = start_handle;
+    while curr_handle <= end_handle {
+        let descs = match discover_descriptors(conn, curr_handle, end_handle).await {
+            Ok(descs) => descs,
+            Err(DiscoverError::Gatt(GattError::ATTERR_ATTRIBUTE_NOT_FOUND)) => break,
+            Err(err) => return Err(err),
         };
-        for desc in descs {
+        if descs.is_empty() {
+            break;
+        }
+        for desc in &descs {
+            if descriptors.is_full() {
+                warn!(
+                    "too many descriptors for characteristic {:?}, max {:?}",
+                    characteristic.handle_value, DISC_DESCS_MAX
+                );
+                return Err(DiscoverError::TooManyDescriptors);
+            }
+            let uuid = match Uuid::from_raw(desc.uuid) {
+                Some(uuid) => Some(uuid),
+                None => discover_attr_uuid(conn, desc.handle).await?,
+            };
             descriptors
                 .push(Descriptor {
-                    uuid: Uuid::from_raw(desc.uuid),
+                    uuid,
                     handle: desc.handle,
                 })
-                .unwrap_or_else(|_| panic!("no size in descriptors"));
+                .unwrap_or_else(|_| unreachable!());
+        }
+
+        match unwrap!(descs.last()).handle.checked_add(1) {
+            Some(handle) => curr_handle = handle,
+            None => break,
         }
     }
 
@@ -275,14 +399,118 @@ async fn discover_inner<T: Client>(
 
 /// Discover a service in the peer's GATT server and construct a Client instance
 /// to use it.
+///
+/// If the server has multiple instances of the service, the first one is used. Use [`discover_all`]
+/// to use all of them.
 pub async fn discover<T: Client>(conn: &Connection) -> Result<T, DiscoverError> {
-    // TODO handle drop. Probably doable gracefully (no DropBomb)
-
     let svc = match discover_service(conn, T::uuid()).await {
         Err(DiscoverError::Gatt(GattError::ATTERR_ATTRIBUTE_NOT_FOUND)) => Err(DiscoverError::ServiceNotFound),
         x => x,
     }?;
 
+    discover_client(conn, &svc).await
+}
+
+/// Discover all instances of a service in the peer's GATT server, and construct a Client instance
+/// to use each one of them.
+///
+/// At most `N` instances are returned. If the server has no instances of the service, an empty
+/// `Vec` is returned.
+pub async fn discover_all<T: Client, const N: usize>(conn: &Connection) -> Result<Vec<T, N>, DiscoverError> {
+    let uuid = T::uuid();
+    let mut clients = Vec::new();
+
+    let mut start_handle = 1;
+    loop {
+        let svcs = match discover_services_from(conn, start_handle, Some(&uuid)).await {
+            Err(DiscoverError::Gatt(GattError::ATTERR_ATTRIBUTE_NOT_FOUND)) => break,
+            x => x,
+        }?;
+        if svcs.is_empty() {
+            break;
+        }
+
+        for svc in &svcs {
+            if clients.is_full() {
+                return Ok(clients);
+            }
+            let client = discover_client(conn, svc).await?;
+            clients.push(client).unwrap_or_else(|_| unreachable!());
+        }
+
+        match unwrap!(svcs.last()).handle_range.end_handle.checked_add(1) {
+            Some(handle) => start_handle = handle,
+            None => break,
+        }
+    }
+
+    Ok(clients)
+}
+
+/// Discover all primary services in the peer's GATT server, without filtering by UUID.
+///
+/// At most `N` services are returned, in handle order.
+pub async fn discover_services<const N: usize>(conn: &Connection) -> Result<Vec<Service, N>, DiscoverError> {
+    let mut services = Vec::new();
+
+    let mut start_handle = 1;
+    loop {
+        let svcs = match discover_services_from(conn, start_handle, None).await {
+            Err(DiscoverError::Gatt(GattError::ATTERR_ATTRIBUTE_NOT_FOUND)) => break,
+            x => x,
+        }?;
+        if svcs.is_empty() {
+            break;
+        }
+
+        for svc in &svcs {
+            if services.is_full() {
+                return Ok(services);
+            }
+            let uuid = match Uuid::from_raw(svc.uuid) {
+                Some(uuid) => Some(uuid),
+                None => discover_service_uuid(conn, svc.handle_range.start_handle).await?,
+            };
+            let service = Service {
+                uuid,
+                start_handle: svc.handle_range.start_handle,
+                end_handle: svc.handle_range.end_handle,
+            };
+            services.push(service).unwrap_or_else(|_| unreachable!());
+        }
+
+        match unwrap!(svcs.last()).handle_range.end_handle.checked_add(1) {
+            Some(handle) => start_handle = handle,
+            None => break,
+        }
+    }
+
+    Ok(services)
+}
+
+/// Find the full UUID of a service whose UUID is unknown to the SoftDevice, by reading its
+/// service declaration.
+async fn discover_service_uuid(conn: &Connection, handle: u16) -> Result<Option<Uuid>, DiscoverError> {
+    let mut uuid128 = [0; 16];
+    match read(conn, handle, &mut uuid128).await {
+        Ok(16) => {}
+        Ok(_) | Err(ReadError::Truncated) | Err(ReadError::InvalidLength) => return Ok(None),
+        Err(ReadError::Disconnected) => return Err(DiscoverError::Disconnected),
+        Err(ReadError::Timeout) => return Err(DiscoverError::Timeout),
+        Err(ReadError::Gatt(err)) => return Err(err.into()),
+        Err(ReadError::Raw(err)) => return Err(err.into()),
+    }
+
+    match Uuid::try_new_128(&uuid128) {
+        Ok(uuid) => Ok(Some(uuid)),
+        Err(err) => {
+            warn!("sd_ble_uuid_vs_add err {:?}", err);
+            Ok(None)
+        }
+    }
+}
+
+async fn discover_client<T: Client>(conn: &Connection, svc: &raw::ble_gattc_service_t) -> Result<T, DiscoverError> {
     let mut client = T::new_undiscovered(conn.clone());
 
     let mut curr_handle = svc.handle_range.start_handle;
@@ -297,14 +525,14 @@ pub async fn discover<T: Client>(conn: &Connection) -> Result<T, DiscoverError>
         assert_ne!(chars.len(), 0);
         for curr in chars {
             if let Some(prev) = prev_char {
-                discover_inner(conn, &mut client, &svc, prev, Some(curr)).await?;
+                discover_inner(conn, &mut client, svc, prev, Some(curr)).await?;
             }
             prev_char = Some(curr);
             curr_handle = curr.handle_value + 1;
         }
     }
     if let Some(prev) = prev_char {
-        discover_inner(conn, &mut client, &svc, prev, None).await?;
+        discover_inner(conn, &mut client, svc, prev, None).await?;
     }
 
     client.discovery_complete()?;
@@ -312,11 +540,19 @@ pub async fn discover<T: Client>(conn: &Connection) -> Result<T, DiscoverError>
     Ok(client)
 }
 
+/// Error type for [`read`], [`read_long`] and [`read_by_uuid`]
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum ReadError {
+    /// Connection is disconnected.
     Disconnected,
+    /// The value didn't fit in the provided buffer. The buffer has been filled with
+    /// as much of the value as fits.
     Truncated,
+    /// The server didn't respond to the read request in time.
+    Timeout,
+    /// The value returned by the server is too short for the expected type.
+    InvalidLength,
     Gatt(GattError),
     Raw(RawError),
 }
@@ -339,19 +575,118 @@ impl From<RawError> for ReadError {
     }
 }
 
+/// Read the value of the attribute with the given handle from the peer's GATT server.
+///
+/// The value is copied into `buf`, and the number of bytes read is returned. Only the first
+/// `ATT_MTU - 1` bytes of the value are returned by the server, use [`read_long`] to read longer values.
 pub async fn read(conn: &Connection, handle: u16, buf: &mut [u8]) -> Result<usize, ReadError> {
-    let conn_handle = conn.with_state(|state| state.check_connected())?;
+    read_inner(conn, handle, 0, buf).await
+}
+
+/// Read the whole value of the attribute with the given handle from the peer's GATT server,
+/// even if it's longer than `ATT_MTU - 1` bytes.
+///
+/// This issues successive reads with increasing offsets (Read Blob Requests) until the server
+/// returns a short response. The value is copied into `buf`, and the number of bytes read is returned.
+pub async fn read_long(conn: &Connection, handle: u16, buf: &mut [u8]) -> Result<usize, ReadError> {
+    assert!(buf.len() <= u16::MAX as usize);
 
-    let ret = unsafe { raw::sd_ble_gattc_read(conn_handle, handle, 0) };
+    let mut offset = 0;
+    loop {
+        let chunk_len = (conn.att_mtu() - 1) as usize;
+        match read_inner(conn, handle, offset as u16, &mut buf[offset..]).await {
+            Ok(n) => {
+                offset += n;
+                if n < chunk_len {
+                    return Ok(offset);
+                }
+            }
+            // The value length was an exact multiple of the chunk length.
+            Err(ReadError::Gatt(GattError::ATTERR_INVALID_OFFSET)) if offset > 0 => return Ok(offset),
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Read the value of the first characteristic with the given UUID in the given handle range
+/// from the peer's GATT server, without doing discovery first.
+///
+/// The value is copied into `buf`. The handle of the characteristic value and the number of
+/// bytes read are returned.
+pub async fn read_by_uuid(
+    conn: &Connection,
+    uuid: &Uuid,
+    start_handle: u16,
+    end_handle: u16,
+    buf: &mut [u8],
+) -> Result<(u16, usize), ReadError> {
+    let conn_handle = wait_idle(conn).await?;
+
+    let ret = unsafe {
+        raw::sd_ble_gattc_char_value_by_uuid_read(
+            conn_handle,
+            uuid.as_raw_ptr(),
+            &raw::ble_gattc_handle_range_t {
+                start_handle,
+                end_handle,
+            },
+        )
+    };
+    RawError::convert(ret).map_err(|err| {
+        warn!("sd_ble_gattc_char_value_by_uuid_read err {:?}", err);
+        err
+    })?;
+    conn.with_state(|state| state.gattc_pending = true);
+
+    portal(conn_handle)
+        .wait_many(|ble_evt| unsafe {
+            match (*ble_evt).header.evt_id as u32 {
+                raw::BLE_GAP_EVTS_BLE_GAP_EVT_DISCONNECTED => return Some(Err(ReadError::Disconnected)),
+                raw::BLE_GATTC_EVTS_BLE_GATTC_EVT_TIMEOUT => return Some(Err(ReadError::Timeout)),
+                raw::BLE_GATTC_EVTS_BLE_GATTC_EVT_CHAR_VAL_BY_UUID_READ_RSP => {
+                    let gattc_evt = match check_status(ble_evt) {
+                        Ok(evt) => evt,
+                        Err(e) => return Some(Err(e.into())),
+                    };
+                    let params = get_union_field(ble_evt, &gattc_evt.params.char_val_by_uuid_read_rsp);
+                    if params.count == 0 {
+                        return Some(Err(GattError::ATTERR_ATTRIBUTE_NOT_FOUND.into()));
+                    }
+
+                    // The list contains `count` handle-value pairs, each one is a 2-byte handle
+                    // followed by `value_len` bytes of value. Only the first one is returned.
+                    let v = get_flexarray(ble_evt, &params.handle_value, 2 + params.value_len as usize);
+                    let handle = u16::from_le_bytes([v[0], v[1]]);
+                    let v = &v[2..];
+                    let len = core::cmp::min(v.len(), buf.len());
+                    buf[..len].copy_from_slice(&v[..len]);
+
+                    if v.len() > buf.len() {
+                        return Some(Err(ReadError::Truncated));
+                    }
+                    Some(Ok((handle, len)))
+                }
+                _ => None,
+            }
+        })
+        .await
+}
+
+async fn read_inner(conn: &Connection, handle: u16, offset: u16, buf: &mut [u8]) -> Result<usize, ReadError> {
+    let conn_handle = wait_idle(conn).await?;
+
+    let ret = unsafe { raw::sd_ble_gattc_read(conn_handle, handle, offset) };
     RawError::convert(ret).map_err(|err| {
         warn!("sd_ble_gattc_read err {:?}", err);
         err
     })?;
+    conn.with_state(|state| state.gattc_pending = true);
 
     portal(conn_handle)
         .wait_many(|ble_evt| unsafe {
             match (*ble_evt).header.evt_id as u32 {
                 raw::BLE_GAP_EVTS_BLE_GAP_EVT_DISCONNECTED => return Some(Err(ReadError::Disconnected)),
+                raw::BLE_GATTC_EVTS_BLE_GATTC_EVT_TIMEOUT => return Some(Err(ReadError::Timeout)),
                 raw::BLE_GATTC_EVTS_BLE_GATTC_EVT_READ_RSP => {
                     let gattc_evt = match check_status(ble_evt) {
                         Ok(evt) => evt,
@@ -373,10 +708,13 @@ pub async fn read(conn: &Connection, handle: u16, buf: &mut [u8]) -> Result<usiz
         .await
 }
 
+/// Error type for [`write`], [`write_long`] and [`write_without_response`]
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum WriteError {
+    /// Connection is disconnected.
     Disconnected,
+    /// The server didn't respond to the write request in time.
     Timeout,
     Gatt(GattError),
     Raw(RawError),
@@ -400,9 +738,10 @@ impl From<RawError> for WriteError {
     }
 }
 
+/// Write the value of the attribute with the given handle in the peer's GATT server.
+///
+/// This issues a Write Request, and waits for the server to respond with a Write Response.
 pub async fn write(conn: &Connection, handle: u16, buf: &[u8]) -> Result<(), WriteError> {
-    let conn_handle = conn.with_state(|state| state.check_connected())?;
-
     assert!(buf.len() <= u16::MAX as usize);
     let params = raw::ble_gattc_write_params_t {
         write_op: raw::BLE_GATT_OP_WRITE_REQ as u8,
@@ -413,11 +752,86 @@ pub async fn write(conn: &Connection, handle: u16, buf: &[u8]) -> Result<(), Wri
         offset: 0,
     };
 
-    let ret = unsafe { raw::sd_ble_gattc_write(conn_handle, &params) };
+    write_inner(conn, &params).await
+}
+
+/// Write the Client Characteristic Configuration Descriptor (CCCD) with the given handle, to
+/// enable or disable indications and notifications for its characteristic.
+///
+/// The CCCD handle can be found with [`find_cccd`] when the characteristic is discovered.
+pub async fn write_cccd(
+    conn: &Connection,
+    cccd_handle: u16,
+    indications: bool,
+    notifications: bool,
+) -> Result<(), WriteError> {
+    let mut cccd: u16 = 0;
+    if notifications {
+        cccd |= raw::BLE_GATT_HVX_NOTIFICATION as u16;
+    }
+    if indications {
+        cccd |= raw::BLE_GATT_HVX_INDICATION as u16;
+    }
+    write(conn, cccd_handle, &cccd.to_le_bytes()).await
+}
+
+/// Write the whole value of the attribute with the given handle in the peer's GATT server,
+/// even if it's longer than what fits in a single Write Request.
+///
+/// The value is split in chunks that are queued in the server with Prepare Write Requests, and
+/// then written atomically with an Execute Write Request. If any chunk fails, the queue is cancelled.
+pub async fn write_long(conn: &Connection, handle: u16, buf: &[u8]) -> Result<(), WriteError> {
+    assert!(buf.len() <= u16::MAX as usize);
+
+    // Prepare Write Request has a 5-byte header: opcode, handle and offset.
+    let chunk_len = (conn.att_mtu() - 5) as usize;
+
+    let mut offset = 0;
+    for chunk in buf.chunks(chunk_len) {
+        let params = raw::ble_gattc_write_params_t {
+            write_op: raw::BLE_GATT_OP_PREP_WRITE_REQ as u8,
+            flags: 0,
+            handle,
+            p_value: chunk.as_ptr(),
+            len: chunk.len() as u16,
+            offset: offset as u16,
+        };
+
+        if let Err(err) = write_inner(conn, &params).await {
+            if !matches!(err, WriteError::Disconnected | WriteError::Timeout) {
+                let _ = execute_write(conn, handle, raw::BLE_GATT_EXEC_WRITE_FLAG_PREPARED_CANCEL).await;
+            }
+            return Err(err);
+        }
+
+        offset += chunk.len();
+    }
+
+    execute_write(conn, handle, raw::BLE_GATT_EXEC_WRITE_FLAG_PREPARED_WRITE).await
+}
+
+async fn execute_write(conn: &Connection, handle: u16, flags: u32) -> Result<(), WriteError> {
+    let params = raw::ble_gattc_write_params_t {
+        write_op: raw::BLE_GATT_OP_EXEC_WRITE_REQ as u8,
+        flags: flags as u8,
+        handle,
+        p_value: core::ptr::null(),
+        len: 0,
+        offset: 0,
+    };
+
+    write_inner(conn, &params).await
+}
+
+async fn write_inner(conn: &Connection, params: &raw::ble_gattc_write_params_t) -> Result<(), WriteError> {
+    let conn_handle = wait_idle(conn).await?;
+
+    let ret = unsafe { raw::sd_ble_gattc_write(conn_handle, params) };
     RawError::convert(ret).map_err(|err| {
         warn!("sd_ble_gattc_write err {:?}", err);
         err
     })?;
+    conn.with_state(|state| state.gattc_pending = true);
 
     portal(conn_handle)
         .wait_many(|ble_evt| unsafe {
@@ -439,6 +853,11 @@ pub async fn write(conn: &Connection, handle: u16, buf: &[u8]) -> Result<(), Wri
         .await
 }
 
+/// Write the value of the attribute with the given handle in the peer's GATT server,
+/// without waiting for a response (Write Command).
+///
+/// If the SoftDevice's TX queue is full, this waits for a Write Command TX complete event
+/// and retries, so it's suitable for streaming data to the peer as fast as the link allows.
 pub async fn write_without_response(conn: &Connection, handle: u16, buf: &[u8]) -> Result<(), WriteError> {
     loop {
         let conn_handle = conn.with_state(|state| state.check_connected())?;
@@ -472,10 +891,13 @@ pub async fn write_without_response(conn: &Connection, handle: u16, buf: &[u8])
     }
 }
 
+/// Error type for [`try_write_without_response`]
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum TryWriteError {
+    /// Connection is disconnected.
     Disconnected,
+    /// The SoftDevice's TX queue is full.
     BufferFull,
     Gatt(GattError),
     Raw(RawError),
@@ -499,6 +921,8 @@ impl From<RawError> for TryWriteError {
     }
 }
 
+/// Like [`write_without_response`], but returns [`TryWriteError::BufferFull`] instead of
+/// waiting if the SoftDevice's TX queue is full.
 pub fn try_write_without_response(conn: &Connection, handle: u16, buf: &[u8]) -> Result<(), TryWriteError> {
     let conn_handle = conn.with_state(|state| state.check_connected())?;
 
@@ -525,16 +949,47 @@ unsafe fn check_status(ble_evt: *const raw::ble_evt_t) -> Result<&'static raw::b
     GattStatus::from(gattc_evt.gatt_status).to_result().and(Ok(gattc_evt))
 }
 
+/// Wait until there's no GATT client procedure in progress in the connection.
+///
+/// The softdevice only allows one procedure at a time. If the future of a previous procedure
+/// was dropped before its response arrived, this waits for the response and discards it, so
+/// that it's not mistaken for the response of the new procedure.
+async fn wait_idle(conn: &Connection) -> Result<u16, DisconnectedError> {
+    let conn_handle = conn.with_state(|state| state.check_connected())?;
+    if !conn.with_state(|state| state.gattc_pending) {
+        return Ok(conn_handle);
+    }
+
+    debug!("gattc: waiting for the response of a cancelled procedure");
+    portal(conn_handle)
+        .wait_many(|ble_evt| unsafe {
+            match (*ble_evt).header.evt_id as u32 {
+                raw::BLE_GAP_EVTS_BLE_GAP_EVT_DISCONNECTED => Some(Err(DisconnectedError)),
+                _ if !conn.with_state(|state| state.gattc_pending) => Some(Ok(conn_handle)),
+                _ => None,
+            }
+        })
+        .await
+}
+
 pub(crate) unsafe fn on_evt(ble_evt: *const raw::ble_evt_t) {
     let gattc_evt = get_union_field(ble_evt, &(*ble_evt).evt.gattc_evt);
+    match (*ble_evt).header.evt_id as u32 {
+        raw::BLE_GATTC_EVTS_BLE_GATTC_EVT_HVX | raw::BLE_GATTC_EVTS_BLE_GATTC_EVT_WRITE_CMD_TX_COMPLETE => {}
+        // Every other event is the response to a procedure (or a procedure timeout).
+        _ => with_state_by_conn_handle(gattc_evt.conn_handle, |state| state.gattc_pending = false),
+    }
     portal(gattc_evt.conn_handle).call(ble_evt);
 }
 
+/// Error type for [`att_mtu_exchange`]
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum MtuExchangeError {
     /// Connection is disconnected.
     Disconnected,
+    /// The server didn't respond to the MTU exchange request in time.
+    Timeout,
     Gatt(GattError),
     Raw(RawError),
 }
@@ -557,9 +1012,12 @@ impl From<RawError> for MtuExchangeError {
     }
 }
 
-#[cfg(feature = "ble-central")]
-pub(crate) async fn att_mtu_exchange(conn: &Connection, mtu: u16) -> Result<(), MtuExchangeError> {
-    let conn_handle = conn.with_state(|state| state.check_connected())?;
+/// Perform an ATT MTU exchange with the peer's GATT server, requesting the given MTU.
+///
+/// The effective ATT MTU (the minimum of ours and the server's) is stored in the connection
+/// state and returned. If the current ATT MTU is already `mtu` or higher, this does nothing.
+pub async fn att_mtu_exchange(conn: &Connection, mtu: u16) -> Result<u16, MtuExchangeError> {
+    let conn_handle = wait_idle(conn).await?;
 
     let current_mtu = conn.with_state(|state| state.att_mtu);
 
@@ -568,7 +1026,7 @@ pub(crate) async fn att_mtu_exchange(conn: &Connection, mtu: u16) -> Result<(),
             "att mtu exchange: want mtu {:?}, already got {:?}. Doing nothing.",
             mtu, current_mtu
         );
-        return Ok(());
+        return Ok(current_mtu);
     }
 
     debug!(
@@ -581,22 +1039,24 @@ pub(crate) async fn att_mtu_exchange(conn: &Connection, mtu: u16) -> Result<(),
         warn!("sd_ble_gattc_exchange_mtu_request err {:?}", err);
         return Err(err.into());
     }
+    conn.with_state(|state| state.gattc_pending = true);
 
     portal(conn_handle)
         .wait_once(|ble_evt| unsafe {
             match (*ble_evt).header.evt_id as u32 {
                 raw::BLE_GAP_EVTS_BLE_GAP_EVT_DISCONNECTED => return Err(MtuExchangeError::Disconnected),
+                raw::BLE_GATTC_EVTS_BLE_GATTC_EVT_TIMEOUT => return Err(MtuExchangeError::Timeout),
                 raw::BLE_GATTC_EVTS_BLE_GATTC_EVT_EXCHANGE_MTU_RSP => {
                     let gattc_evt = match check_status(ble_evt) {
                         Ok(evt) => evt,
                         Err(e) => return Err(e.into()),
                     };
                     let params = get_union_field(ble_evt, &gattc_evt.params.exchange_mtu_rsp);
-                    let mtu = params.server_rx_mtu;
+                    let mtu = core::cmp::min(mtu, params.server_rx_mtu);
                     debug!("att mtu exchange: got mtu {:?}", mtu);
                     conn.with_state(|state| state.att_mtu = mtu);
 
-                    Ok(())
+                    Ok(mtu)
                 }
                 e => panic!("unexpected event {}", e),
             }
@@ -610,6 +1070,14 @@ pub(crate) fn portal(conn_handle: u16) -> &'static Portal<*const raw::ble_evt_t>
     &PORTALS[conn_handle as usize]
 }
 
+/// Run the GATT client for the given connection, receiving notifications and indications
+/// from the peer's GATT server.
+///
+/// Each received value is passed to [`Client::on_hvx`], and the resulting event (if any) is passed
+/// to `f`. This only returns once the connection is disconnected, so it's usually run concurrently
+/// with other tasks using the connection (e.g. with `embassy_futures::select`).
+///
+/// Indications are confirmed to the server once `f` returns.
 pub async fn run<'a, F, C>(conn: &Connection, client: &C, mut f: F) -> DisconnectedError
 where
     F: FnMut(C::Event),
@@ -629,31 +1097,37 @@ where
 
             // We have a GATTC event
             let gattc_evt = get_union_field(ble_evt, &ble_evt.evt.gattc_evt);
-            let conn = unwrap!(Connection::from_handle(gattc_evt.conn_handle));
-            let evt = match ble_evt.header.evt_id as u32 {
-                raw::BLE_GATTC_EVTS_BLE_GATTC_EVT_HVX => {
-                    let params = get_union_field(ble_evt, &gattc_evt.params.hvx);
-                    let v = get_flexarray(ble_evt, &params.data, params.len as usize);
-                    trace!(
-                        "GATT_HVX write handle={:?} type={:?} data={:?}",
-                        params.handle,
-                        params.type_,
-                        v
-                    );
-
-                    match params.type_.try_into() {
-                        Ok(type_) => client.on_hvx(&conn, type_, params.handle, v),
-                        Err(_) => {
-                            error!("gatt_client invalid hvx type: {}", params.type_);
-                            None
-                        }
+            if u32::from(ble_evt.header.evt_id) == raw::BLE_GATTC_EVTS_BLE_GATTC_EVT_HVX {
+                let conn = unwrap!(Connection::from_handle(gattc_evt.conn_handle));
+                let params = get_union_field(ble_evt, &gattc_evt.params.hvx);
+                let v = get_flexarray(ble_evt, &params.data, params.len as usize);
+                trace!(
+                    "GATT_HVX write handle={:?} type={:?} data={:?}",
+                    params.handle,
+                    params.type_,
+                    v
+                );
+
+                let evt = match params.type_.try_into() {
+                    Ok(type_) => client.on_hvx(&conn, type_, params.handle, v),
+                    Err(_) => {
+                        error!("gatt_client invalid hvx type: {}", params.type_);
+                        None
                     }
+                };
+
+                if let Some(evt) = evt {
+                    f(evt);
                 }
-                _ => None,
-            };
 
-            if let Some(evt) = evt {
-                f(evt);
+                // Indications must be confirmed, otherwise the server won't send any more.
+                // This is done after `f` has processed the event.
+                if u32::from(params.type_) == raw::BLE_GATT_HVX_INDICATION {
+                    let ret = raw::sd_ble_gattc_hv_confirm(gattc_evt.conn_handle, params.handle);
+                    if let Err(err) = RawError::convert(ret) {
+                        warn!("sd_ble_gattc_hv_confirm err {:?}", err);
+                    }
+                }
             }
 
             None
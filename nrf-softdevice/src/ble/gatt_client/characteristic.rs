@@ -0,0 +1,161 @@
+//! Typed wrappers around the handles of a discovered characteristic.
+
+use core::marker::PhantomData;
+
+use super::{
+    find_cccd, read, read_long, try_write_without_response, write, write_cccd, write_without_response, Characteristic,
+    Descriptor, HvxType, ReadError, TryWriteError, WriteError,
+};
+use crate::ble::{Connection, GattValue};
+
+/// Maximum length of an attribute value.
+const ATTR_VALUE_MAX_LEN: usize = 512;
+
+/// A characteristic whose value can be read.
+pub struct ReadableCharacteristic<T: GattValue> {
+    conn: Connection,
+    value_handle: u16,
+    _phantom: PhantomData<T>,
+}
+
+impl<T: GattValue> ReadableCharacteristic<T> {
+    pub fn new(conn: Connection, value_handle: u16) -> Self {
+        Self {
+            conn,
+            value_handle,
+            _phantom: PhantomData,
+        }
+    }
+
+    pub fn from_discovered(conn: Connection, characteristic: &Characteristic) -> Self {
+        Self::new(conn, characteristic.handle_value)
+    }
+
+    pub fn value_handle(&self) -> u16 {
+        self.value_handle
+    }
+
+    /// Read the value of the characteristic from the peer's GATT server.
+    ///
+    /// Values longer than `ATT_MTU - 1` are read with [`read_long`].
+    pub async fn read(&self) -> Result<T, ReadError> {
+        let mut buf = [0; ATTR_VALUE_MAX_LEN];
+        let buf = &mut buf[..core::cmp::min(T::MAX_SIZE, ATTR_VALUE_MAX_LEN)];
+
+        let len = if buf.len() < self.conn.att_mtu() as usize {
+            read(&self.conn, self.value_handle, buf).await?
+        } else {
+            read_long(&self.conn, self.value_handle, buf).await?
+        };
+
+        if len < T::MIN_SIZE {
+            return Err(ReadError::InvalidLength);
+        }
+        Ok(T::from_gatt(&buf[..len]))
+    }
+}
+
+/// A characteristic whose value can be written.
+pub struct WritableCharacteristic<T: GattValue> {
+    conn: Connection,
+    value_handle: u16,
+    _phantom: PhantomData<T>,
+}
+
+impl<T: GattValue> WritableCharacteristic<T> {
+    pub fn new(conn: Connection, value_handle: u16) -> Self {
+        Self {
+            conn,
+            value_handle,
+            _phantom: PhantomData,
+        }
+    }
+
+    pub fn from_discovered(conn: Connection, characteristic: &Characteristic) -> Self {
+        Self::new(conn, characteristic.handle_value)
+    }
+
+    pub fn value_handle(&self) -> u16 {
+        self.value_handle
+    }
+
+    /// Write the value of the characteristic in the peer's GATT server, waiting for the response.
+    pub async fn write(&self, val: &T) -> Result<(), WriteError> {
+        write(&self.conn, self.value_handle, val.to_gatt()).await
+    }
+
+    /// Write the value of the characteristic in the peer's GATT server, without response.
+    pub async fn write_without_response(&self, val: &T) -> Result<(), WriteError> {
+        write_without_response(&self.conn, self.value_handle, val.to_gatt()).await
+    }
+
+    /// Like [`Self::write_without_response`], but fails instead of waiting if the TX queue is full.
+    pub fn try_write_without_response(&self, val: &T) -> Result<(), TryWriteError> {
+        try_write_without_response(&self.conn, self.value_handle, val.to_gatt())
+    }
+}
+
+/// A characteristic that can be notified or indicated by the peer's GATT server.
+pub struct NotifiableCharacteristic<T: GattValue> {
+    conn: Connection,
+    value_handle: u16,
+    cccd_handle: u16,
+    _phantom: PhantomData<T>,
+}
+
+impl<T: GattValue> NotifiableCharacteristic<T> {
+    pub fn new(conn: Connection, value_handle: u16, cccd_handle: u16) -> Self {
+        Self {
+            conn,
+            value_handle,
+            cccd_handle,
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Returns `None` if the characteristic has no CCCD.
+    pub fn from_discovered(
+        conn: Connection,
+        characteristic: &Characteristic,
+        descriptors: &[Descriptor],
+    ) -> Option<Self> {
+        let cccd_handle = find_cccd(descriptors)?;
+        Some(Self::new(conn, characteristic.handle_value, cccd_handle))
+    }
+
+    pub fn value_handle(&self) -> u16 {
+        self.value_handle
+    }
+
+    pub fn cccd_handle(&self) -> u16 {
+        self.cccd_handle
+    }
+
+    /// Enable notifications for this characteristic.
+    pub async fn subscribe(&self) -> Result<(), WriteError> {
+        write_cccd(&self.conn, self.cccd_handle, false, true).await
+    }
+
+    /// Enable indications for this characteristic.
+    pub async fn subscribe_indications(&self) -> Result<(), WriteError> {
+        write_cccd(&self.conn, self.cccd_handle, true, false).await
+    }
+
+    /// Disable both notifications and indications for this characteristic.
+    pub async fn unsubscribe(&self) -> Result<(), WriteError> {
+        write_cccd(&self.conn, self.cccd_handle, false, false).await
+    }
+
+    /// Decode a notification or indication received in [`super::Client::on_hvx`].
+    ///
+    /// Returns `None` if it's not for this characteristic, or if it has an invalid length.
+    pub fn on_hvx(&self, type_: HvxType, handle: u16, data: &[u8]) -> Option<T> {
+        if handle != self.value_handle || !matches!(type_, HvxType::Notification | HvxType::Indication) {
+            return None;
+        }
+        if data.len() < T::MIN_SIZE || data.len() > T::MAX_SIZE {
+            return None;
+        }
+        Some(T::from_gatt(data))
+    }
+}
@@ -3,7 +3,14 @@
 //! Typically the peripheral device is the GATT server, but it is not necessary.
 //! In a connection any device can be server and client, and even both can be both at the same time.
 
+use core::cell::{Cell, RefCell};
 use core::convert::TryFrom;
+use core::task::Poll;
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::waitqueue::MultiWakerRegistration;
+use futures::future::poll_fn;
 
 use crate::ble::*;
 use crate::util::{get_flexarray, get_union_field, Portal};
@@ -30,6 +37,18 @@ pub struct CharacteristicHandles {
     pub sccd_handle: u16,
 }
 
+impl CharacteristicHandles {
+    /// Get the current value of the characteristic. See [`get_value`].
+    pub fn get_value(&self, sd: &Softdevice, buf: &mut [u8]) -> Result<usize, GetValueError> {
+        get_value(sd, self.value_handle, buf)
+    }
+
+    /// Set the value of the characteristic, without notifying clients. See [`set_value`].
+    pub fn set_value(&self, sd: &Softdevice, val: &[u8]) -> Result<(), SetValueError> {
+        set_value(sd, self.value_handle, val)
+    }
+}
+
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct ServiceHandle(u16);
@@ -91,9 +110,15 @@ impl TryFrom<u8> for WriteOp {
     }
 }
 
+/// Trait for implementing GATT servers.
+///
+/// Services are registered in the softdevice with [`builder::ServiceBuilder`] (or the
+/// `#[gatt_server]` and `#[gatt_service]` macros), and events for them are dispatched
+/// to the server by [`run`].
 pub trait Server: Sized {
     type Event;
 
+    /// Handles writes from the client to the attributes of this server.
     fn on_write(&self, conn: &Connection, handle: u16, op: WriteOp, offset: usize, data: &[u8]) -> Option<Self::Event>;
 
     /// Handle reads of characteristics built with the
@@ -121,6 +146,15 @@ pub trait Server: Sized {
         panic!("on_deferred_write needs to be implemented for this gatt server");
     }
 
+    /// Callback to indicate that the client has written a Client Characteristic Configuration Descriptor (CCCD),
+    /// enabling or disabling notifications and indications of a characteristic.
+    ///
+    /// This is called before [`Server::on_write`] is called for the same write.
+    fn on_cccd_write(&self, conn: &Connection, cccd_handle: u16, value: CccdValue) -> Option<Self::Event> {
+        let _ = (conn, cccd_handle, value);
+        None
+    }
+
     /// Callback to indicate that one or more characteristic notifications have been transmitted.
     fn on_notify_tx_complete(&self, conn: &Connection, count: u8) -> Option<Self::Event> {
         let _ = (conn, count);
@@ -139,6 +173,7 @@ pub trait Server: Sized {
         None
     }
 
+    /// Callback to indicate that the client didn't confirm an indication in time.
     fn on_timeout(&self, conn: &Connection) -> Option<Self::Event> {
         let _ = conn;
         None
@@ -149,20 +184,73 @@ pub trait Service: Sized {
     type Event;
 
     fn on_write(&self, handle: u16, data: &[u8]) -> Option<Self::Event>;
+
+    /// Handle a deferred read of one of the characteristics of this service.
+    ///
+    /// Returns the `reply` back if `handle` doesn't belong to this service, so that it can be
+    /// offered to the other services of the server. Returns `Ok(None)` if the read has already been
+    /// replied to without producing an event.
+    fn on_deferred_read(
+        &self,
+        handle: u16,
+        offset: usize,
+        reply: DeferredReadReply,
+    ) -> Result<Option<Self::Event>, DeferredReadReply> {
+        let _ = (handle, offset);
+        Err(reply)
+    }
+
+    /// Handle a deferred write of one of the characteristics of this service.
+    ///
+    /// Same as [`Service::on_deferred_read`], for writes.
+    fn on_deferred_write(
+        &self,
+        handle: u16,
+        op: WriteOp,
+        offset: usize,
+        data: &[u8],
+        reply: DeferredWriteReply,
+    ) -> Result<Option<Self::Event>, DeferredWriteReply> {
+        let _ = (handle, op, offset, data);
+        Err(reply)
+    }
 }
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum RegisterError {
+    /// The attribute table is full.
+    ///
+    /// Its size is set by [`Config::gatts_attr_tab_size`][crate::Config::gatts_attr_tab_size],
+    /// see [`configured_attr_tab_size`] for the current value.
+    AttTableFull,
+    /// Services can't be registered once there are connections, as the clients wouldn't know
+    /// about the new attributes.
+    TooLate,
     Raw(RawError),
 }
 
 impl From<RawError> for RegisterError {
     fn from(err: RawError) -> Self {
-        RegisterError::Raw(err)
+        match err {
+            RawError::NoMem => RegisterError::AttTableFull,
+            err => RegisterError::Raw(err),
+        }
     }
 }
 
+/// Returns the total size of the attribute table in bytes, as configured when enabling the softdevice.
+///
+/// This is not the space left for registering more services: the softdevice doesn't report how much of
+/// the table is in use, so registering just fails with [`RegisterError::AttTableFull`] once it's full.
+pub fn configured_attr_tab_size(sd: &Softdevice) -> u32 {
+    sd.attr_tab_size
+}
+
+/// Run the GATT server for the given connection.
+///
+/// Events from the client are dispatched to the `server` callbacks, and the resulting events (if any)
+/// are passed to `f`. This only returns once the connection is disconnected.
 pub async fn run<'m, F, S>(conn: &Connection, server: &S, mut f: F) -> DisconnectedError
 where
     F: FnMut(S::Event),
@@ -184,23 +272,34 @@ where
             let gatts_evt = get_union_field(ble_evt, &ble_evt.evt.gatts_evt);
             let conn = unwrap!(Connection::from_handle(gatts_evt.conn_handle));
             let evt = match ble_evt.header.evt_id as u32 {
-                raw::BLE_GATTS_EVTS_BLE_GATTS_EVT_SYS_ATTR_MISSING => {
-                    let _params = get_union_field(ble_evt, &gatts_evt.params.sys_attr_missing);
-                    trace!("gatts sys attr missing conn={:?}", gatts_evt.conn_handle);
-
-                    if let Some(conn) = Connection::from_handle(gatts_evt.conn_handle) {
-                        #[cfg(feature = "ble-sec")]
-                        if let Some(handler) = conn.security_handler() {
-                            handler.load_sys_attrs(&conn);
-                        } else if let Err(err) = set_sys_attrs(&conn, None) {
-                            warn!("gatt_server failed to set sys attrs: {:?}", err);
+                raw::BLE_GATTS_EVTS_BLE_GATTS_EVT_WRITE
+                    if u32::from(get_union_field(ble_evt, &gatts_evt.params.write).op)
+                        == raw::BLE_GATTS_OP_EXEC_WRITE_REQ_NOW =>
+                {
+                    trace!("gatts execute queued writes");
+
+                    // The softdevice has already written the queued values to the attributes,
+                    // deliver the assembled value of each of them.
+                    let mut buf = [0u8; ATTR_VALUE_MAX_LEN];
+                    for_each_queued_write(gatts_evt.conn_handle, |handle| {
+                        let mut value = raw::ble_gatts_value_t {
+                            p_value: buf.as_mut_ptr(),
+                            len: buf.len() as _,
+                            offset: 0,
+                        };
+                        let ret = raw::sd_ble_gatts_value_get(gatts_evt.conn_handle, handle, &mut value);
+                        if let Err(_err) = RawError::convert(ret) {
+                            warn!("sd_ble_gatts_value_get err {:?}", _err);
+                            return;
                         }
 
-                        #[cfg(not(feature = "ble-sec"))]
-                        if let Err(err) = set_sys_attrs(&conn, None) {
-                            warn!("gatt_server failed to set sys attrs: {:?}", err);
+                        let len = core::cmp::min(usize::from(value.len), buf.len());
+                        if let Some(evt) =
+                            server.on_write(&conn, handle, WriteOp::ExecutePreparedWrites, 0, &buf[..len])
+                        {
+                            f(evt)
                         }
-                    }
+                    });
 
                     None
                 }
@@ -210,6 +309,14 @@ where
                     let v = get_flexarray(ble_evt, &params.data, params.len as usize);
                     trace!("gatts write handle={:?} data={:?}", params.handle, v);
 
+                    let is_cccd = u32::from(params.uuid.type_) == raw::BLE_UUID_TYPE_BLE
+                        && u32::from(params.uuid.uuid) == raw::BLE_UUID_DESCRIPTOR_CLIENT_CHAR_CONFIG;
+                    if is_cccd && offset == 0 && !v.is_empty() {
+                        if let Some(evt) = server.on_cccd_write(&conn, params.handle, CccdValue::from_raw(v[0])) {
+                            f(evt)
+                        }
+                    }
+
                     match params.op.try_into() {
                         Ok(op) => server.on_write(&conn, params.handle, op, offset, v),
                         Err(_) => {
@@ -280,6 +387,9 @@ impl From<RawError> for GetValueError {
     }
 }
 
+/// Get the current value of a local attribute, for example the last value written by a client.
+///
+/// Returns the length of the value, or [`GetValueError::Truncated`] if it doesn't fit in `buf`.
 pub fn get_value(_sd: &Softdevice, handle: u16, buf: &mut [u8]) -> Result<usize, GetValueError> {
     let mut value = raw::ble_gatts_value_t {
         p_value: buf.as_mut_ptr(),
@@ -296,6 +406,72 @@ pub fn get_value(_sd: &Softdevice, handle: u16, buf: &mut [u8]) -> Result<usize,
     Ok(value.len as _)
 }
 
+/// Value of a Client Characteristic Configuration Descriptor (CCCD).
+#[derive(Default, Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct CccdValue {
+    pub notifications: bool,
+    pub indications: bool,
+}
+
+impl CccdValue {
+    fn from_raw(value: u8) -> Self {
+        Self {
+            notifications: value & 0x01 != 0,
+            indications: value & 0x02 != 0,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum GetCccdError {
+    Disconnected,
+    Raw(RawError),
+}
+
+impl From<RawError> for GetCccdError {
+    fn from(err: RawError) -> Self {
+        Self::Raw(err)
+    }
+}
+
+impl From<DisconnectedError> for GetCccdError {
+    fn from(_: DisconnectedError) -> Self {
+        Self::Disconnected
+    }
+}
+
+/// Get the value of a Client Characteristic Configuration Descriptor (CCCD) for the given connection.
+///
+/// The softdevice keeps a separate CCCD value for every connection.
+pub fn get_cccd(conn: &Connection, cccd_handle: u16) -> Result<CccdValue, GetCccdError> {
+    let conn_handle = conn.with_state(|state| state.check_connected())?;
+
+    let mut buf = [0u8; 2];
+    let mut value = raw::ble_gatts_value_t {
+        p_value: buf.as_mut_ptr(),
+        len: buf.len() as _,
+        offset: 0,
+    };
+    let ret = unsafe { raw::sd_ble_gatts_value_get(conn_handle, cccd_handle, &mut value) };
+    match RawError::convert(ret) {
+        Ok(()) => Ok(CccdValue::from_raw(buf[0])),
+        // The client hasn't written any CCCD yet.
+        Err(RawError::BleGattsSysAttrMissing) => Ok(CccdValue::default()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Returns whether the client of the given connection has enabled notifications or indications
+/// in the Client Characteristic Configuration Descriptor (CCCD) `cccd_handle`.
+pub fn is_subscribed(conn: &Connection, cccd_handle: u16) -> bool {
+    match get_cccd(conn, cccd_handle) {
+        Ok(value) => value.notifications || value.indications,
+        Err(_) => false,
+    }
+}
+
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum SetValueError {
@@ -308,6 +484,10 @@ impl From<RawError> for SetValueError {
     }
 }
 
+/// Set the value of a local attribute.
+///
+/// This doesn't notify or indicate the new value to clients, use [`notify_value`] or
+/// [`indicate_value`] for that.
 pub fn set_value(_sd: &Softdevice, handle: u16, val: &[u8]) -> Result<(), SetValueError> {
     let mut value = raw::ble_gatts_value_t {
         p_value: val.as_ptr() as _,
@@ -320,16 +500,25 @@ pub fn set_value(_sd: &Softdevice, handle: u16, val: &[u8]) -> Result<(), SetVal
     Ok(())
 }
 
+/// Error type for [`notify_value`]
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum NotifyValueError {
+    /// Connection is disconnected.
     Disconnected,
+    /// The client has not enabled notifications for this characteristic in its CCCD.
+    NotEnabled,
+    /// The value is longer than `ATT_MTU - 3`, the maximum that fits in a notification.
+    TooLong,
     Raw(RawError),
 }
 
 impl From<RawError> for NotifyValueError {
     fn from(err: RawError) -> Self {
-        Self::Raw(err)
+        match err {
+            RawError::InvalidState | RawError::BleGattsSysAttrMissing => Self::NotEnabled,
+            err => Self::Raw(err),
+        }
     }
 }
 
@@ -339,10 +528,12 @@ impl From<DisconnectedError> for NotifyValueError {
     }
 }
 
-/// Multiple notifications can be queued. Will fail when the queue is full.
-pub fn notify_value(conn: &Connection, handle: u16, val: &[u8]) -> Result<(), NotifyValueError> {
-    let conn_handle = conn.with_state(|state| state.check_connected())?;
+/// Maximum length of a notified or indicated value on the connection.
+fn max_hvx_len(conn: &Connection) -> usize {
+    usize::from(conn.att_mtu().saturating_sub(3))
+}
 
+fn hvx_notify(conn_handle: u16, handle: u16, val: &[u8]) -> Result<(), RawError> {
     let mut len: u16 = val.len() as _;
     let params = raw::ble_gatts_hvx_params_t {
         handle,
@@ -354,19 +545,139 @@ pub fn notify_value(conn: &Connection, handle: u16, val: &[u8]) -> Result<(), No
     let ret = unsafe { raw::sd_ble_gatts_hvx(conn_handle, &params) };
     RawError::convert(ret)?;
 
+    connection::with_state_by_conn_handle(conn_handle, |state| {
+        state.hvn_tx_pending = state.hvn_tx_pending.saturating_add(1);
+    });
+    Ok(())
+}
+
+/// Returns how many more notifications can be queued in the softdevice for the connection
+/// before its TX queue is full.
+///
+/// The size of the queue is set by `hvn_tx_queue_size` in [`Config::conn_gatts`][crate::Config::conn_gatts].
+pub fn hvn_tx_free(conn: &Connection) -> u8 {
+    let size = unsafe { Softdevice::steal() }.hvn_tx_queue_size;
+    conn.with_state(|state| size.saturating_sub(state.hvn_tx_pending))
+}
+
+/// Wait until all the notifications queued for the connection have been transmitted.
+///
+/// Any number of tasks can wait for the TX queue of a connection, including tasks blocked in
+/// [`notify_value`].
+pub async fn wait_hvn_tx_drained(conn: &Connection) -> Result<(), DisconnectedError> {
+    let conn_handle = conn.with_state(|state| state.check_connected())?;
+    hvx_wait(conn, conn_handle, |_| {
+        (conn.with_state(|state| state.hvn_tx_pending) == 0).then_some(())
+    })
+    .await
+}
+
+/// Send a notification of a characteristic value to the client.
+///
+/// Multiple notifications can be queued. If the SoftDevice's TX queue is full, this waits
+/// until a queued notification has been transmitted and tries again. Any number of tasks
+/// can wait for the TX queue of a connection.
+pub async fn notify_value(conn: &Connection, handle: u16, val: &[u8]) -> Result<(), NotifyValueError> {
+    loop {
+        let conn_handle = conn.with_state(|state| state.check_connected())?;
+        if val.len() > max_hvx_len(conn) {
+            return Err(NotifyValueError::TooLong);
+        }
+
+        // Taken before trying, so a transmission completing in between isn't missed.
+        let tx_complete = hvx_state(conn_handle, |state| state.tx_complete);
+        match hvx_notify(conn_handle, handle, val) {
+            Err(RawError::Resources) => {}
+            Err(e) => return Err(e.into()),
+            Ok(()) => return Ok(()),
+        }
+
+        hvx_wait(conn, conn_handle, |state| {
+            (state.tx_complete != tx_complete).then_some(())
+        })
+        .await?;
+    }
+}
+
+/// Send notifications for a sequence of `(handle, value)` pairs.
+///
+/// Notifications are queued in the softdevice as long as there is space in the TX queue, waiting
+/// for transmissions to complete only when it is full, so the link is kept busy.
+///
+/// Stops at the first error.
+pub async fn notify_batch<'a, I>(conn: &Connection, notifications: I) -> Result<(), NotifyValueError>
+where
+    I: IntoIterator<Item = (u16, &'a [u8])>,
+{
+    for (handle, val) in notifications {
+        notify_value(conn, handle, val).await?;
+    }
+    Ok(())
+}
+
+/// Error type for [`try_notify_value`]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum TryNotifyValueError {
+    /// Connection is disconnected.
+    Disconnected,
+    /// The client has not enabled notifications for this characteristic in its CCCD.
+    NotEnabled,
+    /// The value is longer than `ATT_MTU - 3`, the maximum that fits in a notification.
+    TooLong,
+    /// The SoftDevice's TX queue is full.
+    BufferFull,
+    Raw(RawError),
+}
+
+impl From<RawError> for TryNotifyValueError {
+    fn from(err: RawError) -> Self {
+        match err {
+            RawError::InvalidState | RawError::BleGattsSysAttrMissing => Self::NotEnabled,
+            RawError::Resources => Self::BufferFull,
+            err => Self::Raw(err),
+        }
+    }
+}
+
+impl From<DisconnectedError> for TryNotifyValueError {
+    fn from(_: DisconnectedError) -> Self {
+        Self::Disconnected
+    }
+}
+
+/// Like [`notify_value`], but fails with [`TryNotifyValueError::BufferFull`] instead of waiting
+/// if the TX queue is full.
+pub fn try_notify_value(conn: &Connection, handle: u16, val: &[u8]) -> Result<(), TryNotifyValueError> {
+    let conn_handle = conn.with_state(|state| state.check_connected())?;
+    if val.len() > max_hvx_len(conn) {
+        return Err(TryNotifyValueError::TooLong);
+    }
+    hvx_notify(conn_handle, handle, val)?;
     Ok(())
 }
 
+/// Error type for [`indicate_value`]
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum IndicateValueError {
+    /// Connection is disconnected.
     Disconnected,
+    /// The client has not enabled indications for this characteristic in its CCCD.
+    NotEnabled,
+    /// The value is longer than `ATT_MTU - 3`, the maximum that fits in an indication.
+    TooLong,
+    /// The client did not confirm the indication in time.
+    Timeout,
     Raw(RawError),
 }
 
 impl From<RawError> for IndicateValueError {
     fn from(err: RawError) -> Self {
-        Self::Raw(err)
+        match err {
+            RawError::InvalidState | RawError::BleGattsSysAttrMissing => Self::NotEnabled,
+            err => Self::Raw(err),
+        }
     }
 }
 
@@ -376,10 +687,7 @@ impl From<DisconnectedError> for IndicateValueError {
     }
 }
 
-/// This will fail if an indication is already in progress
-pub fn indicate_value(conn: &Connection, handle: u16, val: &[u8]) -> Result<(), IndicateValueError> {
-    let conn_handle = conn.with_state(|state| state.check_connected())?;
-
+fn hvx_indicate(conn_handle: u16, handle: u16, val: &[u8]) -> Result<(), RawError> {
     let mut len: u16 = val.len() as _;
     let params = raw::ble_gatts_hvx_params_t {
         handle,
@@ -389,11 +697,128 @@ pub fn indicate_value(conn: &Connection, handle: u16, val: &[u8]) -> Result<(),
         p_len: &mut len,
     };
     let ret = unsafe { raw::sd_ble_gatts_hvx(conn_handle, &params) };
-    RawError::convert(ret)?;
+    RawError::convert(ret)
+}
+
+/// Send an indication of a characteristic value to the client, and wait for the client to confirm it.
+///
+/// Only one indication can be in progress per connection at a time. This fails with
+/// `Raw(RawError::Busy)` if another one is still waiting for its confirmation.
+pub async fn indicate_value(conn: &Connection, handle: u16, val: &[u8]) -> Result<(), IndicateValueError> {
+    let conn_handle = conn.with_state(|state| state.check_connected())?;
+    if val.len() > max_hvx_len(conn) {
+        return Err(IndicateValueError::TooLong);
+    }
+    hvx_state(conn_handle, |state| state.indication = None);
+    hvx_indicate(conn_handle, handle, val)?;
+
+    // The SoftDevice refuses a second indication until this one completes, so the next
+    // outcome is this one's.
+    match hvx_wait(conn, conn_handle, |state| state.indication.take()).await? {
+        IndicationOutcome::Timeout => Err(IndicateValueError::Timeout),
+        IndicationOutcome::Confirmed | IndicationOutcome::ServicesChangedConfirmed => Ok(()),
+    }
+}
+
+/// Error type for [`try_indicate_value`]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum TryIndicateValueError {
+    /// Connection is disconnected.
+    Disconnected,
+    /// The client has not enabled indications for this characteristic in its CCCD.
+    NotEnabled,
+    /// The value is longer than `ATT_MTU - 3`, the maximum that fits in an indication.
+    TooLong,
+    /// Another indication is still waiting for its confirmation.
+    Busy,
+    Raw(RawError),
+}
+
+impl From<RawError> for TryIndicateValueError {
+    fn from(err: RawError) -> Self {
+        match err {
+            RawError::InvalidState | RawError::BleGattsSysAttrMissing => Self::NotEnabled,
+            RawError::Busy => Self::Busy,
+            err => Self::Raw(err),
+        }
+    }
+}
+
+impl From<DisconnectedError> for TryIndicateValueError {
+    fn from(_: DisconnectedError) -> Self {
+        Self::Disconnected
+    }
+}
 
+/// Like [`indicate_value`], but returns as soon as the indication has been queued, without
+/// waiting for the client's confirmation.
+///
+/// The confirmation is reported through [`Server::on_indicate_confirm`].
+pub fn try_indicate_value(conn: &Connection, handle: u16, val: &[u8]) -> Result<(), TryIndicateValueError> {
+    let conn_handle = conn.with_state(|state| state.check_connected())?;
+    if val.len() > max_hvx_len(conn) {
+        return Err(TryIndicateValueError::TooLong);
+    }
+    hvx_indicate(conn_handle, handle, val)?;
     Ok(())
 }
 
+/// Error type for [`indicate_services_changed`]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ServicesChangedError {
+    /// Connection is disconnected.
+    Disconnected,
+    /// The Service Changed characteristic was not enabled in [`Config::gatts_service_changed`][crate::Config::gatts_service_changed].
+    NotSupported,
+    /// The client has not enabled indications of the Service Changed characteristic.
+    NotEnabled,
+    /// The client did not confirm the indication in time.
+    Timeout,
+    Raw(RawError),
+}
+
+impl From<RawError> for ServicesChangedError {
+    fn from(err: RawError) -> Self {
+        match err {
+            RawError::NotSupported => Self::NotSupported,
+            RawError::InvalidState | RawError::BleGattsSysAttrMissing => Self::NotEnabled,
+            err => Self::Raw(err),
+        }
+    }
+}
+
+impl From<DisconnectedError> for ServicesChangedError {
+    fn from(_: DisconnectedError) -> Self {
+        Self::Disconnected
+    }
+}
+
+/// Indicate to the client that the attributes between `start_handle` and `end_handle` have changed,
+/// and wait for the client to confirm it.
+///
+/// Bonded clients cache the attribute table of the server, so this must be sent when the table
+/// changes (for example after a firmware update) for them to discover it again.
+///
+/// This fails with `Raw(RawError::Busy)` if an indication is still waiting for its confirmation.
+pub async fn indicate_services_changed(
+    conn: &Connection,
+    start_handle: u16,
+    end_handle: u16,
+) -> Result<(), ServicesChangedError> {
+    let conn_handle = conn.with_state(|state| state.check_connected())?;
+    hvx_state(conn_handle, |state| state.indication = None);
+    let ret = unsafe { raw::sd_ble_gatts_service_changed(conn_handle, start_handle, end_handle) };
+    RawError::convert(ret)?;
+
+    // Like for `indicate_value`, the SoftDevice refuses other indications until this one completes.
+    match hvx_wait(conn, conn_handle, |state| state.indication.take()).await? {
+        IndicationOutcome::Timeout => Err(ServicesChangedError::Timeout),
+        IndicationOutcome::Confirmed | IndicationOutcome::ServicesChangedConfirmed => Ok(()),
+    }
+}
+
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum GetSysAttrsError {
@@ -408,6 +833,13 @@ impl From<DisconnectedError> for GetSysAttrsError {
     }
 }
 
+/// Get the system attributes of the connection, which hold the values of its CCCDs.
+///
+/// For bonded peers, these should be stored (typically in `SecurityHandler::save_sys_attrs`)
+/// and restored with [`set_sys_attrs`] on reconnection, so that the peer doesn't have to subscribe again.
+///
+/// Returns the length of the system attributes, or [`GetSysAttrsError::DataSize`] with the
+/// required length if they don't fit in `buf`.
 pub fn get_sys_attrs(conn: &Connection, buf: &mut [u8]) -> Result<usize, GetSysAttrsError> {
     let conn_handle = conn.with_state(|state| state.check_connected())?;
 
@@ -433,6 +865,9 @@ impl From<DisconnectedError> for SetSysAttrsError {
     }
 }
 
+/// Set the system attributes of the connection, as previously returned by [`get_sys_attrs`].
+///
+/// With `None`, the system attributes are reset, leaving all CCCDs disabled.
 pub fn set_sys_attrs(conn: &Connection, sys_attrs: Option<&[u8]>) -> Result<(), SetSysAttrsError> {
     let conn_handle = conn.with_state(|state| state.check_connected())?;
     let ptr = sys_attrs.map(|x| x.as_ptr()).unwrap_or(core::ptr::null());
@@ -441,6 +876,121 @@ pub fn set_sys_attrs(conn: &Connection, sys_attrs: Option<&[u8]>) -> Result<(),
     RawError::convert(ret).map_err(SetSysAttrsError::Raw)
 }
 
+/// Maximum length of an attribute value.
+const ATTR_VALUE_MAX_LEN: usize = 512;
+
+/// Error type for [`set_queued_writes_mem`]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SetQueuedWritesMemError {
+    /// A client has prepared writes queued in the current memory.
+    Busy,
+    /// The memory is longer than 65535 bytes, the most the SoftDevice can use.
+    TooLong,
+}
+
+/// Memory for queued writes, and the connection currently using it.
+#[derive(Clone, Copy)]
+struct QueuedWrites {
+    block: raw::ble_user_mem_block_t,
+    conn: Option<u16>,
+}
+
+// Safety: the memory is `&'static mut`, and only used by the connection that holds it.
+unsafe impl Send for QueuedWrites {}
+
+static QUEUED_WRITES: Mutex<CriticalSectionRawMutex, Cell<QueuedWrites>> = Mutex::new(Cell::new(QueuedWrites {
+    block: raw::ble_user_mem_block_t {
+        p_mem: core::ptr::null_mut(),
+        len: 0,
+    },
+    conn: None,
+}));
+
+/// Set the memory used to hold the prepared writes of a client until they are executed.
+///
+/// This allows clients to write values longer than the ATT MTU. Once the client executes the
+/// queued writes, the assembled value of each written attribute is passed to [`Server::on_write`]
+/// with [`WriteOp::ExecutePreparedWrites`].
+///
+/// There is a single block of memory, shared by all connections. While a client has prepared
+/// writes queued in it, prepared writes from the clients of other connections are refused until
+/// that client executes or cancels its writes. Without this memory, all queued writes are rejected.
+///
+/// Fails with [`SetQueuedWritesMemError::Busy`] while a client has prepared writes queued in the
+/// current memory.
+pub fn set_queued_writes_mem(_sd: &Softdevice, mem: &'static mut [u8]) -> Result<(), SetQueuedWritesMemError> {
+    let len = mem.len().try_into().map_err(|_| SetQueuedWritesMemError::TooLong)?;
+    QUEUED_WRITES.lock(|queued| {
+        if queued.get().conn.is_some() {
+            return Err(SetQueuedWritesMemError::Busy);
+        }
+        queued.set(QueuedWrites {
+            block: raw::ble_user_mem_block_t {
+                p_mem: mem.as_mut_ptr(),
+                len,
+            },
+            conn: None,
+        });
+        Ok(())
+    })
+}
+
+/// Take the queued writes memory for the connection, if it's set and not used by another connection.
+///
+/// The returned block stays valid and unchanged until the connection releases it.
+pub(crate) fn queued_writes_mem_request(conn_handle: u16) -> *const raw::ble_user_mem_block_t {
+    QUEUED_WRITES.lock(|queued| {
+        let mut q = queued.get();
+        if q.block.p_mem.is_null() || q.conn.is_some() {
+            return core::ptr::null();
+        }
+
+        q.conn = Some(conn_handle);
+        queued.set(q);
+        // Safety: the block is in a static, and only replaced while no connection holds it.
+        unsafe { core::ptr::addr_of!((*queued.as_ptr()).block) }
+    })
+}
+
+pub(crate) fn queued_writes_mem_release(conn_handle: u16) {
+    QUEUED_WRITES.lock(|queued| {
+        let mut q = queued.get();
+        if q.conn == Some(conn_handle) {
+            q.conn = None;
+            queued.set(q);
+        }
+    })
+}
+
+/// Calls `f` with the handle of each attribute written by the queued writes of the connection.
+///
+/// The memory block holds a list of `handle`, `offset`, `len` and `data` entries, terminated by an invalid handle.
+unsafe fn for_each_queued_write(conn_handle: u16, mut f: impl FnMut(u16)) {
+    let q = QUEUED_WRITES.lock(|queued| queued.get());
+    if q.conn != Some(conn_handle) {
+        return;
+    }
+
+    // The memory can't be replaced while the connection holds it.
+    let mem = core::slice::from_raw_parts(q.block.p_mem, usize::from(q.block.len));
+    let mut last_handle = raw::BLE_GATT_HANDLE_INVALID as u16;
+    let mut pos = 0;
+    while pos + 6 <= mem.len() {
+        let handle = u16::from_le_bytes([mem[pos], mem[pos + 1]]);
+        let len = usize::from(u16::from_le_bytes([mem[pos + 4], mem[pos + 5]]));
+        if handle == raw::BLE_GATT_HANDLE_INVALID as u16 {
+            break;
+        }
+
+        if handle != last_handle {
+            f(handle);
+            last_handle = handle;
+        }
+        pos += 6 + len;
+    }
+}
+
 pub(crate) unsafe fn on_evt(ble_evt: *const raw::ble_evt_t) {
     let gatts_evt = get_union_field(ble_evt, &(*ble_evt).evt.gatts_evt);
     match (*ble_evt).header.evt_id as u32 {
@@ -462,6 +1012,54 @@ pub(crate) unsafe fn on_evt(ble_evt: *const raw::ble_evt_t) {
                 state.att_mtu = mtu;
             });
         }
+        raw::BLE_GATTS_EVTS_BLE_GATTS_EVT_SYS_ATTR_MISSING => {
+            trace!("gatts sys attr missing conn={:?}", gatts_evt.conn_handle);
+
+            // Handled here instead of in `run`, so that notifications work even for
+            // connections that don't run a server.
+            if let Some(conn) = Connection::from_handle(gatts_evt.conn_handle) {
+                #[cfg(feature = "ble-sec")]
+                if let Some(handler) = conn.security_handler() {
+                    handler.load_sys_attrs(&conn);
+                } else if let Err(err) = set_sys_attrs(&conn, None) {
+                    warn!("gatt_server failed to set sys attrs: {:?}", err);
+                }
+
+                #[cfg(not(feature = "ble-sec"))]
+                if let Err(err) = set_sys_attrs(&conn, None) {
+                    warn!("gatt_server failed to set sys attrs: {:?}", err);
+                }
+            }
+        }
+        raw::BLE_GATTS_EVTS_BLE_GATTS_EVT_HVN_TX_COMPLETE => {
+            let params = get_union_field(ble_evt, &gatts_evt.params.hvn_tx_complete);
+            if let Some(conn) = Connection::from_handle(gatts_evt.conn_handle) {
+                conn.with_state(|state| {
+                    state.hvn_tx_pending = state.hvn_tx_pending.saturating_sub(params.count);
+                });
+            }
+            hvx_state(gatts_evt.conn_handle, |state| {
+                state.tx_complete = state.tx_complete.wrapping_add(1);
+                state.wakers.wake();
+            });
+
+            portal(gatts_evt.conn_handle).call(ble_evt);
+        }
+        raw::BLE_GATTS_EVTS_BLE_GATTS_EVT_HVC
+        | raw::BLE_GATTS_EVTS_BLE_GATTS_EVT_SC_CONFIRM
+        | raw::BLE_GATTS_EVTS_BLE_GATTS_EVT_TIMEOUT => {
+            let outcome = match (*ble_evt).header.evt_id as u32 {
+                raw::BLE_GATTS_EVTS_BLE_GATTS_EVT_HVC => IndicationOutcome::Confirmed,
+                raw::BLE_GATTS_EVTS_BLE_GATTS_EVT_SC_CONFIRM => IndicationOutcome::ServicesChangedConfirmed,
+                _ => IndicationOutcome::Timeout,
+            };
+            hvx_state(gatts_evt.conn_handle, |state| {
+                state.indication = Some(outcome);
+                state.wakers.wake();
+            });
+
+            portal(gatts_evt.conn_handle).call(ble_evt);
+        }
         _ => {
             portal(gatts_evt.conn_handle).call(ble_evt);
         }
@@ -473,3 +1071,71 @@ static PORTALS: [Portal<*const raw::ble_evt_t>; CONNS_MAX] = [PORTAL_NEW; CONNS_
 pub(crate) fn portal(conn_handle: u16) -> &'static Portal<*const raw::ble_evt_t> {
     &PORTALS[conn_handle as usize]
 }
+
+/// Number of tasks that can wait on the notifications of a connection before they all
+/// get woken up to make room. Woken up tasks that still have to wait register again.
+const HVX_WAKERS_MAX: usize = 4;
+
+/// How the indication in progress on a connection completed.
+#[derive(Clone, Copy)]
+enum IndicationOutcome {
+    Confirmed,
+    ServicesChangedConfirmed,
+    Timeout,
+}
+
+/// Notification and indication state of a connection, which any number of tasks can wait on.
+struct HvxState {
+    /// Number of `HVN_TX_COMPLETE` events received, wrapping.
+    tx_complete: u32,
+    /// Outcome of the last indication, until its waiter takes it.
+    indication: Option<IndicationOutcome>,
+    wakers: MultiWakerRegistration<HVX_WAKERS_MAX>,
+}
+
+const HVX_STATE_NEW: Mutex<CriticalSectionRawMutex, RefCell<HvxState>> = Mutex::new(RefCell::new(HvxState {
+    tx_complete: 0,
+    indication: None,
+    wakers: MultiWakerRegistration::new(),
+}));
+static HVX_STATES: [Mutex<CriticalSectionRawMutex, RefCell<HvxState>>; CONNS_MAX] = [HVX_STATE_NEW; CONNS_MAX];
+
+fn hvx_state<R>(conn_handle: u16, f: impl FnOnce(&mut HvxState) -> R) -> R {
+    HVX_STATES[conn_handle as usize].lock(|state| f(&mut state.borrow_mut()))
+}
+
+/// Wait until `f` returns `Some`, checking it again on every notification or indication event
+/// of the connection.
+async fn hvx_wait<R>(
+    conn: &Connection,
+    conn_handle: u16,
+    mut f: impl FnMut(&mut HvxState) -> Option<R>,
+) -> Result<R, DisconnectedError> {
+    poll_fn(|cx| {
+        let res = hvx_state(conn_handle, |state| {
+            let res = f(state);
+            if res.is_none() {
+                state.wakers.register(cx.waker());
+            }
+            res
+        });
+        if let Some(res) = res {
+            return Poll::Ready(Ok(res));
+        }
+
+        // Checked after registering, as the disconnection wakes up the waiters after
+        // marking the connection as disconnected.
+        match conn.with_state(|state| state.check_connected()) {
+            Ok(_) => Poll::Pending,
+            Err(err) => Poll::Ready(Err(err)),
+        }
+    })
+    .await
+}
+
+pub(crate) fn on_disconnected(conn_handle: u16) {
+    hvx_state(conn_handle, |state| {
+        state.indication = None;
+        state.wakers.wake();
+    });
+}
@@ -12,7 +12,7 @@ use alloc::boxed::Box;
 
 use super::characteristic::{self, AttributeMetadata, Presentation};
 use super::{CharacteristicHandles, DescriptorHandle, IncludedServiceHandle, RegisterError, ServiceHandle};
-use crate::ble::Uuid;
+use crate::ble::{Connection, Uuid};
 use crate::{raw, RawError, Softdevice};
 
 pub struct ServiceBuilder<'a> {
@@ -25,8 +25,41 @@ pub struct CharacteristicBuilder<'a> {
     sb: PhantomData<&'a ServiceBuilder<'a>>,
 }
 
+/// An attribute value, as passed to the softdevice.
+enum Value<'a> {
+    /// Copied into the softdevice's own memory when the attribute is added.
+    Stack(&'a [u8]),
+    /// Kept in the buffer, which the softdevice reads and writes from then on.
+    User(&'static mut [u8]),
+}
+
+impl Value<'_> {
+    fn len(&self) -> usize {
+        match self {
+            Value::Stack(value) => value.len(),
+            Value::User(value) => value.len(),
+        }
+    }
+
+    fn as_raw(&mut self) -> *mut u8 {
+        match self {
+            // The softdevice only reads stack values, to copy them.
+            Value::Stack(value) => value.as_ptr() as *mut _,
+            Value::User(value) => value.as_mut_ptr(),
+        }
+    }
+}
+
 impl<'a> ServiceBuilder<'a> {
+    /// Start registering a primary service.
+    ///
+    /// Services can be registered at any time after enabling the softdevice, as long as there
+    /// are no connections. Otherwise this fails with [`RegisterError::TooLate`].
     pub fn new(_sd: &'a mut Softdevice, uuid: Uuid) -> Result<Self, RegisterError> {
+        if Connection::iter().next().is_some() {
+            return Err(RegisterError::TooLate);
+        }
+
         let mut service_handle: u16 = 0;
         let ret = unsafe {
             raw::sd_ble_gatts_service_add(
@@ -49,11 +82,31 @@ impl<'a> ServiceBuilder<'a> {
         attr: characteristic::Attribute<T>,
         md: characteristic::Metadata,
     ) -> Result<CharacteristicBuilder<'_>, RegisterError> {
-        let value = attr.value.as_ref();
+        let value = Value::Stack(attr.value.as_ref());
         let attr_md = attr.metadata.into_raw();
         self.add_characteristic_inner(uuid, value, attr.max_len, &attr_md, md)
     }
 
+    /// Like [`Self::add_characteristic`], but the softdevice keeps the value in `attr.value`
+    /// instead of copying it into its own memory.
+    ///
+    /// The value is stored in the first `attr.max_len` bytes of the buffer. Fails with
+    /// `Raw(RawError::InvalidLength)` if the buffer is shorter than that.
+    pub fn add_characteristic_static(
+        &mut self,
+        uuid: Uuid,
+        attr: characteristic::Attribute<&'static mut [u8]>,
+        md: characteristic::Metadata,
+    ) -> Result<CharacteristicBuilder<'_>, RegisterError> {
+        let value = attr
+            .value
+            .get_mut(..usize::from(attr.max_len))
+            .ok_or(RawError::InvalidLength)?;
+        let value = Value::User(value);
+        let attr_md = attr.metadata.into_raw_user();
+        self.add_characteristic_inner(uuid, value, attr.max_len, &attr_md, md)
+    }
+
     #[cfg(feature = "alloc")]
     pub fn add_characteristic_app(
         &mut self,
@@ -61,7 +114,7 @@ impl<'a> ServiceBuilder<'a> {
         attr: characteristic::Attribute<Box<[u8]>>,
         md: characteristic::Metadata,
     ) -> Result<CharacteristicBuilder<'_>, RegisterError> {
-        let value = Box::leak(attr.value);
+        let value = Value::User(Box::leak(attr.value));
         let attr_md = attr.metadata.into_raw_user();
         self.add_characteristic_inner(uuid, value, attr.max_len, &attr_md, md)
     }
@@ -69,7 +122,7 @@ impl<'a> ServiceBuilder<'a> {
     fn add_characteristic_inner(
         &mut self,
         uuid: Uuid,
-        value: &[u8],
+        mut value: Value<'_>,
         max_len: u16,
         attr_md: &raw::ble_gatts_attr_md_t,
         char_md: characteristic::Metadata,
@@ -105,7 +158,7 @@ impl<'a> ServiceBuilder<'a> {
             init_len: unwrap!(value.len().try_into()),
             init_offs: 0,
             max_len,
-            p_value: value.as_ptr() as *mut _,
+            p_value: value.as_raw(),
         };
 
         let mut handles: raw::ble_gatts_char_handles_t = unsafe { mem::zeroed() };
@@ -147,18 +200,37 @@ impl<'a> CharacteristicBuilder<'a> {
         uuid: Uuid,
         attr: characteristic::Attribute<T>,
     ) -> Result<DescriptorHandle, RegisterError> {
-        let value = attr.value.as_ref();
+        let value = Value::Stack(attr.value.as_ref());
         let attr_md = attr.metadata.into_raw();
         self.add_descriptor_inner(uuid, value, attr.max_len, &attr_md)
     }
 
+    /// Like [`Self::add_descriptor`], but the softdevice keeps the value in `attr.value`
+    /// instead of copying it into its own memory.
+    ///
+    /// The value is stored in the first `attr.max_len` bytes of the buffer. Fails with
+    /// `Raw(RawError::InvalidLength)` if the buffer is shorter than that.
+    pub fn add_descriptor_static(
+        &mut self,
+        uuid: Uuid,
+        attr: characteristic::Attribute<&'static mut [u8]>,
+    ) -> Result<DescriptorHandle, RegisterError> {
+        let value = attr
+            .value
+            .get_mut(..usize::from(attr.max_len))
+            .ok_or(RawError::InvalidLength)?;
+        let value = Value::User(value);
+        let attr_md = attr.metadata.into_raw_user();
+        self.add_descriptor_inner(uuid, value, attr.max_len, &attr_md)
+    }
+
     #[cfg(feature = "alloc")]
     pub fn add_descriptor_app(
         &mut self,
         uuid: Uuid,
         attr: characteristic::Attribute<Box<[u8]>>,
     ) -> Result<DescriptorHandle, RegisterError> {
-        let value = Box::leak(attr.value);
+        let value = Value::User(Box::leak(attr.value));
         let attr_md = attr.metadata.into_raw_user();
         self.add_descriptor_inner(uuid, value, attr.max_len, &attr_md)
     }
@@ -166,7 +238,7 @@ impl<'a> CharacteristicBuilder<'a> {
     fn add_descriptor_inner(
         &mut self,
         uuid: Uuid,
-        value: &[u8],
+        mut value: Value<'_>,
         max_len: u16,
         attr_md: &raw::ble_gatts_attr_md_t,
     ) -> Result<DescriptorHandle, RegisterError> {
@@ -176,7 +248,7 @@ impl<'a> CharacteristicBuilder<'a> {
             init_len: unwrap!(value.len().try_into()),
             init_offs: 0,
             max_len,
-            p_value: value.as_ptr() as *mut _,
+            p_value: value.as_raw(),
         };
 
         let mut handle = 0;
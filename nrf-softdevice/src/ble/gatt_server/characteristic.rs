@@ -1,9 +1,6 @@
 use crate::ble::SecurityMode;
 use crate::raw;
 
-// Missing:
-// - Characteristic presentation format
-
 #[derive(Default, Debug, PartialEq, Eq, Clone, Copy)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct AttributeMetadata {
@@ -37,7 +34,6 @@ impl AttributeMetadata {
         self.into_raw_inner(raw::BLE_GATTS_VLOC_STACK as u8)
     }
 
-    #[cfg(feature = "alloc")]
     pub(crate) fn into_raw_user(self) -> raw::ble_gatts_attr_md_t {
         self.into_raw_inner(raw::BLE_GATTS_VLOC_USER as u8)
     }
@@ -121,6 +117,26 @@ pub struct UserDescription {
     pub max_len: u16,
 }
 
+impl UserDescription {
+    /// A read-only Characteristic User Description.
+    pub fn new(value: &'static [u8]) -> Self {
+        UserDescription {
+            metadata: None,
+            value,
+            max_len: unwrap!(value.len().try_into()),
+        }
+    }
+
+    /// Allow clients to write the user description, up to `max_len` bytes.
+    pub fn writable(mut self, write_security: SecurityMode, max_len: u16) -> Self {
+        let mut metadata = AttributeMetadata::default().write_security(write_security);
+        metadata.variable_len = true;
+        self.metadata = Some(metadata);
+        self.max_len = max_len;
+        self
+    }
+}
+
 #[derive(Default, Debug, PartialEq, Eq, Clone, Copy)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct Properties {
@@ -229,6 +245,19 @@ pub struct Presentation {
 }
 
 impl Presentation {
+    /// A Characteristic Presentation Format with a format and unit defined by the Bluetooth SIG.
+    ///
+    /// The value is `value * 10^exponent` in the given `unit`.
+    pub fn new(format: u8, exponent: i8, unit: u16) -> Self {
+        Presentation {
+            format,
+            exponent,
+            unit,
+            name_space: raw::BLE_GATT_CPF_NAMESPACE_BTSIG as u8,
+            description: raw::BLE_GATT_CPF_NAMESPACE_DESCRIPTION_UNKNOWN as u16,
+        }
+    }
+
     pub(crate) fn into_raw(self) -> raw::ble_gatts_char_pf_t {
         raw::ble_gatts_char_pf_t {
             format: self.format.into(),
@@ -294,6 +323,19 @@ impl Metadata {
         }
     }
 
+    /// Add a Characteristic User Description descriptor.
+    ///
+    /// If the description is [writable](UserDescription::writable), the `write_user_description`
+    /// extended property is set as well.
+    pub fn user_description(mut self, user_description: UserDescription) -> Self {
+        self.properties.write_user_description = user_description
+            .metadata
+            .map_or(false, |md| md.write != SecurityMode::NoAccess);
+        self.user_description = Some(user_description);
+        self
+    }
+
+    /// Add a Characteristic Presentation Format descriptor.
     pub fn presentation(self, presentation: Presentation) -> Self {
         let cpfd = Some(presentation);
         Metadata { cpfd, ..self }
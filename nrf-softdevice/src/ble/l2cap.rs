@@ -3,17 +3,30 @@
 //! This module allows you to establish L2CAP connection oriented channels
 //! with the peer.
 //!
+//! Several channels can be open on the same connection, with the same or different PSMs. Events are
+//! dispatched to each channel by its local CID, so each channel can be used from its own task. Up to 16
+//! channels can be open at the same time over all connections.
+//!
 //! Unless configured with the `"ble-l2cap-credit-workaround"` feature, the
 //! driver will use credit based control flow, giving the peer a limited number
 //! of messages they can send. Only if the receive buffer has enough space
 //! more credits will be issued to the peer. Otherwise the peer has to wait
 //! before it can send more messages.
 
+use core::cell::{RefCell, UnsafeCell};
 use core::marker::PhantomData;
 use core::ptr::NonNull;
-use core::sync::atomic::{AtomicBool, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use core::task::{Context, Poll};
 use core::{ptr, u16};
 
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::waitqueue::MultiWakerRegistration;
+use futures::future::poll_fn;
+use futures::stream::{self, Stream};
+use heapless::Deque;
+
 use crate::ble::*;
 use crate::util::{get_union_field, Portal};
 use crate::{raw, RawError, Softdevice};
@@ -53,7 +66,6 @@ fn credit_hack_refill(conn: u16, cid: u16) {
 pub(crate) unsafe fn on_evt(ble_evt: *const raw::ble_evt_t) {
     let l2cap_evt = get_union_field(ble_evt, &(*ble_evt).evt.l2cap_evt);
     match (*ble_evt).header.evt_id as u32 {
-        raw::BLE_L2CAP_EVTS_BLE_L2CAP_EVT_CH_CREDIT => {}
         raw::BLE_L2CAP_EVTS_BLE_L2CAP_EVT_CH_SDU_BUF_RELEASED => {
             let params = &l2cap_evt.params.ch_sdu_buf_released;
             let pkt = unwrap!(NonNull::new(params.sdu_buf.p_data));
@@ -62,20 +74,66 @@ pub(crate) unsafe fn on_evt(ble_evt: *const raw::ble_evt_t) {
         raw::BLE_L2CAP_EVTS_BLE_L2CAP_EVT_CH_TX => {
             let params = &l2cap_evt.params.tx;
             let pkt = unwrap!(NonNull::new(params.sdu_buf.p_data));
-            portal(l2cap_evt.conn_handle).call(ble_evt);
+            if let Some(slot) = channel_slot(l2cap_evt.conn_handle, l2cap_evt.local_cid) {
+                slot.tx_progress();
+            }
             (unwrap!(PACKET_FREE))(pkt)
         }
+        raw::BLE_L2CAP_EVTS_BLE_L2CAP_EVT_CH_RX => {
+            let params = &l2cap_evt.params.rx;
+            let pkt = unwrap!(NonNull::new(params.sdu_buf.p_data));
+            match channel_slot(l2cap_evt.conn_handle, l2cap_evt.local_cid) {
+                Some(slot) => slot.push_rx(pkt, params.sdu_len as usize),
+                None => (unwrap!(PACKET_FREE))(pkt),
+            }
+        }
+        raw::BLE_L2CAP_EVTS_BLE_L2CAP_EVT_CH_CREDIT => {
+            let params = &l2cap_evt.params.credit;
+            if let Some(slot) = channel_slot(l2cap_evt.conn_handle, l2cap_evt.local_cid) {
+                slot.push_event(ChannelEvent::Credit(params.credits));
+                slot.tx_progress();
+            }
+        }
+        raw::BLE_L2CAP_EVTS_BLE_L2CAP_EVT_CH_RELEASED => {
+            if let Some(slot) = channel_slot(l2cap_evt.conn_handle, l2cap_evt.local_cid) {
+                slot.push_event(ChannelEvent::Released);
+            }
+            dispatch(l2cap_evt.conn_handle, l2cap_evt.local_cid, ble_evt);
+            unregister_channel(l2cap_evt.conn_handle, l2cap_evt.local_cid);
+        }
         _ => {
-            portal(l2cap_evt.conn_handle).call(ble_evt);
+            dispatch(l2cap_evt.conn_handle, l2cap_evt.local_cid, ble_evt);
         }
     };
 }
 
+/// Send an event to the channel it belongs to, or to the connection if no channel is registered for it.
+fn dispatch(conn_handle: u16, cid: u16, ble_evt: *const raw::ble_evt_t) {
+    match channel_slot(conn_handle, cid) {
+        Some(slot) => slot.portal.call(ble_evt),
+        None => portal(conn_handle).call(ble_evt),
+    };
+}
+
+/// Signal the disconnection to the connection and all its channels, and free their slots.
+pub(crate) fn on_disconnected(conn_handle: u16, ble_evt: *const raw::ble_evt_t) {
+    portal(conn_handle).call(ble_evt);
+    for slot in &CHANNELS {
+        let key = slot.key.load(Ordering::Acquire);
+        if key != SLOT_FREE && (key >> 16) as u16 == conn_handle {
+            slot.push_event(ChannelEvent::Disconnected);
+            slot.portal.call(ble_evt);
+            slot.free();
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum TxError<P: Packet> {
     Disconnected,
     TxQueueFull(P),
+    AllocateFailed,
     Raw(RawError),
 }
 
@@ -130,12 +188,215 @@ impl From<RawError> for SetupError {
     }
 }
 
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ReleaseError {
+    Disconnected,
+    Raw(RawError),
+}
+
+impl From<DisconnectedError> for ReleaseError {
+    fn from(_err: DisconnectedError) -> Self {
+        ReleaseError::Disconnected
+    }
+}
+
+impl From<RawError> for ReleaseError {
+    fn from(err: RawError) -> Self {
+        ReleaseError::Raw(err)
+    }
+}
+
 const PORTAL_NEW: Portal<*const raw::ble_evt_t> = Portal::new();
 static PORTALS: [Portal<*const raw::ble_evt_t>; CONNS_MAX] = [PORTAL_NEW; CONNS_MAX];
 pub(crate) fn portal(conn_handle: u16) -> &'static Portal<*const raw::ble_evt_t> {
     &PORTALS[conn_handle as usize]
 }
 
+/// Maximum number of L2CAP channels open at the same time, over all connections.
+const CHANNELS_MAX: usize = 16;
+const SLOT_FREE: u32 = u32::MAX;
+
+/// Maximum number of buffers loaned to a channel with [`Channel::loan_rx`] and not yet returned.
+const RX_LOANS_MAX: usize = 8;
+
+/// Maximum number of lifecycle events queued per channel, see [`Channel::next_event`].
+const EVENTS_MAX: usize = 4;
+
+/// Number of tasks that can wait on each direction of a channel before they all get woken up to make room.
+/// Woken up tasks that still have to wait register again.
+const WAKERS_MAX: usize = 4;
+
+/// Portal, transmit progress, received SDUs and lifecycle events for one channel, keyed by connection
+/// handle and local CID.
+///
+/// The portal is only used while setting the channel up. Transmission and reception wait on their own
+/// wakers, so a channel can send and receive from different tasks at the same time.
+struct ChannelSlot {
+    key: AtomicU32,
+    /// Incremented every time the slot is taken, so `Channel`s of a released channel don't use the next one.
+    generation: AtomicU32,
+    /// Number of `Channel` objects referring to the channel.
+    refs: AtomicU32,
+    portal: Portal<*const raw::ble_evt_t>,
+    tx: Mutex<CriticalSectionRawMutex, RefCell<TxState>>,
+    rx: Mutex<CriticalSectionRawMutex, RefCell<RxState>>,
+    events: embassy_sync::channel::Channel<CriticalSectionRawMutex, ChannelEvent, EVENTS_MAX>,
+}
+
+struct TxState {
+    /// Number of completed transmissions and credit grants, wrapping.
+    progress: u32,
+    wakers: MultiWakerRegistration<WAKERS_MAX>,
+}
+
+struct RxState {
+    /// Number of buffers given to the SoftDevice or waiting in `filled`.
+    loaned: usize,
+    /// Buffers and lengths of SDUs received but not yet taken by the channel.
+    filled: Deque<(NonNull<u8>, usize), RX_LOANS_MAX>,
+    wakers: MultiWakerRegistration<WAKERS_MAX>,
+}
+
+unsafe impl Sync for ChannelSlot {}
+
+const SLOT_NEW: ChannelSlot = ChannelSlot {
+    key: AtomicU32::new(SLOT_FREE),
+    generation: AtomicU32::new(0),
+    refs: AtomicU32::new(0),
+    portal: Portal::new(),
+    tx: Mutex::new(RefCell::new(TxState {
+        progress: 0,
+        wakers: MultiWakerRegistration::new(),
+    })),
+    rx: Mutex::new(RefCell::new(RxState {
+        loaned: 0,
+        filled: Deque::new(),
+        wakers: MultiWakerRegistration::new(),
+    })),
+    events: embassy_sync::channel::Channel::new(),
+};
+static CHANNELS: [ChannelSlot; CHANNELS_MAX] = [SLOT_NEW; CHANNELS_MAX];
+
+impl ChannelSlot {
+    /// Reserve room for a buffer loaned to the SoftDevice. Returns `false` if `RX_LOANS_MAX` are loaned already.
+    fn reserve_rx(&self) -> bool {
+        self.rx.lock(|rx| {
+            let mut rx = rx.borrow_mut();
+            if rx.loaned >= RX_LOANS_MAX {
+                return false;
+            }
+            rx.loaned += 1;
+            true
+        })
+    }
+
+    /// Undo `reserve_rx` when the SoftDevice didn't take the buffer.
+    fn unreserve_rx(&self) {
+        self.rx.lock(|rx| rx.borrow_mut().loaned -= 1)
+    }
+
+    fn push_rx(&self, ptr: NonNull<u8>, len: usize) {
+        let res = self.rx.lock(|rx| {
+            let mut rx = rx.borrow_mut();
+            rx.wakers.wake();
+            rx.filled.push_back((ptr, len))
+        });
+        if let Err((ptr, _)) = res {
+            // Not reachable, as at most `RX_LOANS_MAX` buffers are loaned.
+            warn!("l2cap rx queue full, dropping sdu");
+            unsafe { (unwrap!(PACKET_FREE))(ptr) }
+        }
+    }
+
+    /// Queue a lifecycle event, dropping the oldest one if the queue is full.
+    fn push_event(&self, event: ChannelEvent) {
+        if self.events.try_send(event).is_err() {
+            let _ = self.events.try_receive();
+            let _ = self.events.try_send(event);
+        }
+    }
+
+    fn pop_rx(&self) -> Option<(NonNull<u8>, usize)> {
+        self.poll_rx(None)
+    }
+
+    /// Take the oldest received SDU, or register `cx` to be woken up when one is received.
+    fn poll_rx(&self, cx: Option<&Context<'_>>) -> Option<(NonNull<u8>, usize)> {
+        self.rx.lock(|rx| {
+            let mut rx = rx.borrow_mut();
+            let res = rx.filled.pop_front();
+            match (&res, cx) {
+                (Some(_), _) => rx.loaned -= 1,
+                (None, Some(cx)) => rx.wakers.register(cx.waker()),
+                (None, None) => {}
+            }
+            res
+        })
+    }
+
+    /// Number of loaned buffers the SoftDevice hasn't filled yet.
+    fn rx_outstanding(&self) -> usize {
+        self.rx.lock(|rx| {
+            let rx = rx.borrow();
+            rx.loaned - rx.filled.len()
+        })
+    }
+
+    /// Wake up the transmitting tasks after a transmission completed or the peer gave more credits.
+    fn tx_progress(&self) {
+        self.tx.lock(|tx| {
+            let mut tx = tx.borrow_mut();
+            tx.progress = tx.progress.wrapping_add(1);
+            tx.wakers.wake();
+        })
+    }
+
+    /// Free the received SDUs nobody took, and the slot itself.
+    fn free(&self) {
+        while let Some((ptr, _)) = self.pop_rx() {
+            unsafe { (unwrap!(PACKET_FREE))(ptr) }
+        }
+        // Buffers still held by the SoftDevice are returned with `CH_SDU_BUF_RELEASED`.
+        self.rx.lock(|rx| rx.borrow_mut().loaned = 0);
+        self.key.store(SLOT_FREE, Ordering::Release);
+
+        // Waiting tasks see the channel is gone once woken up.
+        self.tx.lock(|tx| tx.borrow_mut().wakers.wake());
+        self.rx.lock(|rx| rx.borrow_mut().wakers.wake());
+    }
+}
+
+fn channel_key(conn_handle: u16, cid: u16) -> u32 {
+    (conn_handle as u32) << 16 | cid as u32
+}
+
+fn channel_slot(conn_handle: u16, cid: u16) -> Option<&'static ChannelSlot> {
+    let key = channel_key(conn_handle, cid);
+    CHANNELS.iter().find(|slot| slot.key.load(Ordering::Acquire) == key)
+}
+
+/// Take a free slot for the channel. Returns `None` if `CHANNELS_MAX` channels are already open.
+fn register_channel(conn_handle: u16, cid: u16) -> Option<&'static ChannelSlot> {
+    let key = channel_key(conn_handle, cid);
+    let slot = CHANNELS.iter().find(|slot| {
+        slot.key
+            .compare_exchange(SLOT_FREE, key, Ordering::AcqRel, Ordering::Acquire)
+            .is_ok()
+    })?;
+
+    slot.generation.fetch_add(1, Ordering::AcqRel);
+    slot.refs.store(0, Ordering::Release);
+    while slot.events.try_receive().is_ok() {}
+    Some(slot)
+}
+
+fn unregister_channel(conn_handle: u16, cid: u16) {
+    if let Some(slot) = channel_slot(conn_handle, cid) {
+        slot.free();
+    }
+}
+
 /// A Packet is a byte buffer for packet data.
 /// Similar to a `Vec<u8>` it has a length and a capacity.
 /// The capacity however is the fixed value `MTU`.
@@ -212,8 +473,8 @@ impl<P: Packet> L2cap<P> {
             le_psm: psm,
             status: 0, // only used when responding
             rx_params: raw::ble_l2cap_ch_rx_params_t {
-                rx_mps: sd.l2cap_rx_mps,
-                rx_mtu: P::MTU as u16,
+                rx_mps: config.rx_mps(sd)?,
+                rx_mtu: config.rx_mtu::<P>()?,
                 sdu_buf: raw::ble_data_t {
                     len: 0,
                     p_data: ptr::null_mut(),
@@ -227,7 +488,17 @@ impl<P: Packet> L2cap<P> {
         }
         debug!("cid {:?}", cid);
 
-        portal(conn_handle)
+        let Some(slot) = register_channel(conn_handle, cid) else {
+            warn!("too many l2cap channels");
+            let ret = unsafe { raw::sd_ble_l2cap_ch_release(conn_handle, cid) };
+            if let Err(_err) = RawError::convert(ret) {
+                warn!("sd_ble_l2cap_ch_release err {:?}", _err);
+            }
+            return Err(SetupError::Raw(RawError::NoMem));
+        };
+
+        let res = slot
+            .portal
             .wait_once(|ble_evt| unsafe {
                 match (*ble_evt).header.evt_id as u32 {
                     raw::BLE_GAP_EVTS_BLE_GAP_EVT_DISCONNECTED => return Err(SetupError::Disconnected),
@@ -253,11 +524,7 @@ impl<P: Packet> L2cap<P> {
                             }
                         }
 
-                        Ok(Channel {
-                            conn: conn.clone(),
-                            cid,
-                            _private: PhantomData,
-                        })
+                        Ok(Channel::new(conn.clone(), cid, slot))
                     }
                     raw::BLE_L2CAP_EVTS_BLE_L2CAP_EVT_CH_SETUP_REFUSED => {
                         let l2cap_evt = get_union_field(ble_evt, &(*ble_evt).evt.l2cap_evt);
@@ -267,7 +534,13 @@ impl<P: Packet> L2cap<P> {
                     e => panic!("unexpected event {}", e),
                 }
             })
-            .await
+            .await;
+
+        // A released channel's slot is freed when the event is received.
+        if let Err(SetupError::Refused) = res {
+            unregister_channel(conn_handle, cid);
+        }
+        res
     }
 
     /// Listen for setup requests of the peer.
@@ -290,6 +563,8 @@ impl<P: Packet> L2cap<P> {
     ) -> Result<(u16, Channel<P>), SetupError> {
         let sd = unsafe { Softdevice::steal() };
         let conn_handle = conn.with_state(|state| state.check_connected())?;
+        let rx_mps = config.rx_mps(sd)?;
+        let rx_mtu = config.rx_mtu::<P>()?;
 
         portal(conn_handle)
             .wait_many(|ble_evt| unsafe {
@@ -301,12 +576,26 @@ impl<P: Packet> L2cap<P> {
 
                         let mut cid: u16 = l2cap_evt.local_cid;
                         if accept_psm(evt.le_psm) {
+                            let Some(slot) = register_channel(conn_handle, cid) else {
+                                warn!("too many l2cap channels");
+                                let params = raw::ble_l2cap_ch_setup_params_t {
+                                    le_psm: evt.le_psm,
+                                    status: raw::BLE_L2CAP_CH_STATUS_CODE_NO_RESOURCES as _,
+                                    rx_params: mem::zeroed(),
+                                };
+                                let ret = raw::sd_ble_l2cap_ch_setup(conn_handle, &mut cid, &params);
+                                if let Err(_err) = RawError::convert(ret) {
+                                    warn!("sd_ble_l2cap_ch_setup err {:?}", _err);
+                                }
+                                return Some(Err(SetupError::Raw(RawError::NoMem)));
+                            };
+
                             let params = raw::ble_l2cap_ch_setup_params_t {
                                 le_psm: evt.le_psm,
                                 status: raw::BLE_L2CAP_CH_STATUS_CODE_SUCCESS as _,
                                 rx_params: raw::ble_l2cap_ch_rx_params_t {
-                                    rx_mps: sd.l2cap_rx_mps,
-                                    rx_mtu: P::MTU as u16,
+                                    rx_mps,
+                                    rx_mtu,
                                     sdu_buf: raw::ble_data_t {
                                         len: 0,
                                         p_data: ptr::null_mut(),
@@ -317,6 +606,7 @@ impl<P: Packet> L2cap<P> {
                             let ret = raw::sd_ble_l2cap_ch_setup(conn_handle, &mut cid, &params);
                             if let Err(err) = RawError::convert(ret) {
                                 warn!("sd_ble_l2cap_ch_setup err {:?}", err);
+                                unregister_channel(conn_handle, cid);
                                 return Some(Err(err.into()));
                             }
 
@@ -336,14 +626,7 @@ impl<P: Packet> L2cap<P> {
                                 }
                             }
 
-                            Some(Ok((
-                                evt.le_psm,
-                                Channel {
-                                    _private: PhantomData,
-                                    cid,
-                                    conn: conn.clone(),
-                                },
-                            )))
+                            Some(Ok((evt.le_psm, Channel::new(conn.clone(), cid, slot))))
                         } else {
                             let params = raw::ble_l2cap_ch_setup_params_t {
                                 le_psm: evt.le_psm,
@@ -359,7 +642,8 @@ impl<P: Packet> L2cap<P> {
                             None
                         }
                     }
-                    e => panic!("unexpected event {}", e),
+                    // Events of channels already established on this connection.
+                    _ => None,
                 }
             })
             .await
@@ -367,35 +651,264 @@ impl<P: Packet> L2cap<P> {
 }
 
 /// Configuration for an L2CAP channel.
+///
+/// The connection-wide limits are set when enabling the SoftDevice, with
+/// [`Config::conn_l2cap`](crate::Config::conn_l2cap): the maximum number of channels, the maximum
+/// MPS in each direction, and the number of SDU buffers that can be queued for reception and transmission.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct Config {
     /// Number of credits that the SoftDevice will make sure the peer
     /// has every time it starts using a new reception buffer.
     pub credits: u16,
+    /// Maximum SDU size the peer may send. Defaults to, and must not be larger than, `P::MTU`.
+    ///
+    /// Setting up a channel fails with `SetupError::Raw(RawError::InvalidParam)` if it's larger.
+    pub rx_mtu: Option<u16>,
+    /// Maximum PDU payload size the peer may send. Defaults to, and must not be larger than, the `rx_mps`
+    /// the SoftDevice was enabled with.
+    ///
+    /// Setting up a channel fails with `SetupError::Raw(RawError::InvalidParam)` if it's larger.
+    pub rx_mps: Option<u16>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            credits: 1,
+            rx_mtu: None,
+            rx_mps: None,
+        }
+    }
+}
+
+impl Config {
+    fn rx_mtu<P: Packet>(&self) -> Result<u16, SetupError> {
+        let mtu = self.rx_mtu.unwrap_or(P::MTU as u16);
+        if mtu as usize > P::MTU {
+            warn!("l2cap rx_mtu {:?} larger than the packet MTU {:?}", mtu, P::MTU);
+            return Err(SetupError::Raw(RawError::InvalidParam));
+        }
+        Ok(mtu)
+    }
+
+    fn rx_mps(&self, sd: &Softdevice) -> Result<u16, SetupError> {
+        let mps = self.rx_mps.unwrap_or(sd.l2cap_rx_mps);
+        if mps > sd.l2cap_rx_mps {
+            warn!(
+                "l2cap rx_mps {:?} larger than the configured {:?}",
+                mps, sd.l2cap_rx_mps
+            );
+            return Err(SetupError::Raw(RawError::InvalidParam));
+        }
+        Ok(mps)
+    }
+}
+
+/// A pool of `N` packet buffers of `MTU` bytes each, for implementing [`Packet`] without a heap.
+///
+/// The memory is reserved statically, so the L2CAP memory usage is known at compile time. `N` is at most 32.
+///
+/// ```ignore
+/// static POOL: PacketPool<512, 8> = PacketPool::new();
+///
+/// impl l2cap::Packet for MyPacket {
+///     const MTU: usize = 512;
+///     fn allocate() -> Option<NonNull<u8>> {
+///         POOL.allocate()
+///     }
+///     // `MyPacket`'s `Drop` calls `POOL.free(ptr)`.
+///     ...
+/// }
+/// ```
+pub struct PacketPool<const MTU: usize, const N: usize> {
+    buffers: UnsafeCell<[[u8; MTU]; N]>,
+    used: AtomicU32,
+}
+
+unsafe impl<const MTU: usize, const N: usize> Sync for PacketPool<MTU, N> {}
+
+impl<const MTU: usize, const N: usize> PacketPool<MTU, N> {
+    pub const fn new() -> Self {
+        core::assert!(N <= 32);
+        Self {
+            buffers: UnsafeCell::new([[0; MTU]; N]),
+            used: AtomicU32::new(0),
+        }
+    }
+
+    /// Take a free buffer from the pool, or return `None` if all are in use.
+    pub fn allocate(&self) -> Option<NonNull<u8>> {
+        let mut used = self.used.load(Ordering::Acquire);
+        loop {
+            let index = (!used).trailing_zeros() as usize;
+            if index >= N {
+                return None;
+            }
+
+            match self
+                .used
+                .compare_exchange_weak(used, used | (1 << index), Ordering::AcqRel, Ordering::Acquire)
+            {
+                Ok(_) => {
+                    let buffers = self.buffers.get() as *mut [u8; MTU];
+                    return NonNull::new(unsafe { buffers.add(index) } as *mut u8);
+                }
+                Err(actual) => used = actual,
+            }
+        }
+    }
+
+    /// Return a buffer to the pool.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must have been returned by [`allocate`](Self::allocate) on this pool, and must not be used
+    /// afterwards.
+    pub unsafe fn free(&self, ptr: NonNull<u8>) {
+        let offset = ptr.as_ptr() as usize - self.buffers.get() as usize;
+        let index = offset / MTU;
+        assert!(offset % MTU == 0 && index < N);
+        self.used.fetch_and(!(1 << index), Ordering::AcqRel);
+    }
+
+    /// Number of buffers currently free.
+    pub fn available(&self) -> usize {
+        N - self.used.load(Ordering::Acquire).count_ones() as usize
+    }
+}
+
+/// A lifecycle event of an L2CAP channel, see [`Channel::next_event`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ChannelEvent {
+    /// The peer gave this many additional credits to send SDUs.
+    Credit(u16),
+    /// The channel was released, by either side. The connection stays up.
+    Released,
+    /// The connection was disconnected, releasing the channel.
+    Disconnected,
 }
 
 /// An L2CAP connection oriented channel.
+///
+/// The channel is released when the last `Channel` referring to it, including clones, is dropped.
 pub struct Channel<P: Packet> {
     _private: PhantomData<*mut P>,
     conn: Connection,
     cid: u16,
+    slot: &'static ChannelSlot,
+    generation: u32,
+    /// Buffer, length and read offset of an SDU partially consumed by `embedded_io_async::Read`.
+    #[cfg(feature = "embedded-io-async")]
+    rx_pending: Option<(NonNull<u8>, usize, usize)>,
 }
 
 impl<P: Packet> Clone for Channel<P> {
     fn clone(&self) -> Self {
+        if self.slot().is_ok() {
+            self.slot.refs.fetch_add(1, Ordering::AcqRel);
+        }
         Self {
             _private: PhantomData,
             conn: self.conn.clone(),
             cid: self.cid,
+            slot: self.slot,
+            generation: self.generation,
+            #[cfg(feature = "embedded-io-async")]
+            rx_pending: None,
+        }
+    }
+}
+
+impl<P: Packet> Drop for Channel<P> {
+    fn drop(&mut self) {
+        #[cfg(feature = "embedded-io-async")]
+        if let Some((ptr, len, _)) = self.rx_pending.take() {
+            // Safety: the buffer was received from the SoftDevice and is owned by this channel.
+            drop(unsafe { P::from_raw_parts(ptr, len) });
+        }
+
+        if self.slot().is_ok() && self.slot.refs.fetch_sub(1, Ordering::AcqRel) == 1 {
+            if let Err(_err) = self.release() {
+                warn!("l2cap channel release on drop err {:?}", _err);
+            }
         }
     }
 }
 
 impl<P: Packet> Channel<P> {
+    fn new(conn: Connection, cid: u16, slot: &'static ChannelSlot) -> Self {
+        slot.refs.fetch_add(1, Ordering::AcqRel);
+        Self {
+            _private: PhantomData,
+            conn,
+            cid,
+            slot,
+            generation: slot.generation.load(Ordering::Acquire),
+            #[cfg(feature = "embedded-io-async")]
+            rx_pending: None,
+        }
+    }
+
     /// Get the underlying connection.
     pub fn connection(&self) -> &Connection {
         &self.conn
     }
 
+    /// Get the local channel identifier.
+    pub fn cid(&self) -> u16 {
+        self.cid
+    }
+
+    /// Get the slot of this channel, or `Disconnected` if the channel has been released.
+    fn slot(&self) -> Result<&'static ChannelSlot, DisconnectedError> {
+        let conn_handle = self.conn.with_state(|s| s.check_connected())?;
+        if self.slot.key.load(Ordering::Acquire) != channel_key(conn_handle, self.cid)
+            || self.slot.generation.load(Ordering::Acquire) != self.generation
+        {
+            return Err(DisconnectedError);
+        }
+        Ok(self.slot)
+    }
+
+    /// Wait for the next lifecycle event of the channel.
+    ///
+    /// Events are queued from when the channel is established, so none are missed between calls. Only the
+    /// latest 4 are kept. Returns `None` once the channel is released and its events have been taken.
+    pub async fn next_event(&self) -> Option<ChannelEvent> {
+        if self.slot.generation.load(Ordering::Acquire) != self.generation {
+            return None;
+        }
+        if let Ok(event) = self.slot.events.try_receive() {
+            return Some(event);
+        }
+        if self.slot.key.load(Ordering::Acquire) == SLOT_FREE {
+            return None;
+        }
+        Some(self.slot.events.receive().await)
+    }
+
+    /// Get the lifecycle events of the channel as an async stream, see [`next_event`](Self::next_event).
+    pub fn events(&self) -> impl Stream<Item = ChannelEvent> + '_ {
+        stream::unfold(self, |ch| async move { ch.next_event().await.map(|event| (event, ch)) })
+    }
+
+    /// Release the channel, sending a disconnection request to the peer.
+    ///
+    /// Pending transmissions and receptions on the channel complete with a `Disconnected` error, and the
+    /// buffers given to the SoftDevice are freed. The connection itself stays up.
+    pub fn release(&self) -> Result<(), ReleaseError> {
+        let conn_handle = self.conn.with_state(|s| s.check_connected())?;
+
+        let ret = unsafe { raw::sd_ble_l2cap_ch_release(conn_handle, self.cid) };
+        if let Err(err) = RawError::convert(ret) {
+            warn!("sd_ble_l2cap_ch_release err {:?}", err);
+            return Err(err.into());
+        }
+        Ok(())
+    }
+
     /// Try to queue a packet for transmission.
     ///
     /// This takes ownership of the packet but you will get it back in the
@@ -425,26 +938,69 @@ impl<P: Packet> Channel<P> {
         }
     }
 
+    /// Get the number of credits the peer currently has to send SDUs to us.
+    pub fn credits(&self) -> Result<u16, RxError> {
+        let conn_handle = self.conn.with_state(|s| s.check_connected())?;
+
+        let mut credits = 0;
+        let ret = unsafe { raw::sd_ble_l2cap_ch_flow_control(conn_handle, self.cid, 0, &mut credits) };
+        if let Err(err) = RawError::convert(ret) {
+            warn!("sd_ble_l2cap_ch_flow_control err {:?}", err);
+            return Err(err.into());
+        }
+        Ok(credits)
+    }
+
+    /// Set the number of credits the SoftDevice makes sure the peer has whenever a reception buffer is
+    /// available, like [`Config::credits`].
+    ///
+    /// Credits only let the peer send while reception buffers are provided with [`rx`](Self::rx), so
+    /// this bounds how many SDUs can be in flight.
+    pub fn set_credits(&self, credits: u16) -> Result<(), RxError> {
+        let conn_handle = self.conn.with_state(|s| s.check_connected())?;
+
+        let ret = unsafe { raw::sd_ble_l2cap_ch_flow_control(conn_handle, self.cid, credits, ptr::null_mut()) };
+        if let Err(err) = RawError::convert(ret) {
+            warn!("sd_ble_l2cap_ch_flow_control err {:?}", err);
+            return Err(err.into());
+        }
+        Ok(())
+    }
+
     /// Asynchronously transmit a packet.
+    ///
+    /// Waits while the transmit queue is full or the peer has no credits left.
     pub async fn tx(&self, mut sdu: P) -> Result<(), TxError<P>> {
-        let conn_handle = self.conn.with_state(|s| s.check_connected())?;
+        let slot = self.slot()?;
 
         loop {
+            // Taken before trying, so a transmission completing in between isn't missed.
+            let progress = slot.tx.lock(|tx| tx.borrow().progress);
             match self.try_tx(sdu) {
                 Ok(()) => {
                     return Ok(());
                 }
                 Err(TxError::TxQueueFull(ret_sdu)) => {
                     sdu = ret_sdu;
-                    portal(conn_handle)
-                        .wait_once(|ble_evt| unsafe {
-                            match (*ble_evt).header.evt_id as u32 {
-                                raw::BLE_L2CAP_EVTS_BLE_L2CAP_EVT_CH_TX => (),
-                                raw::BLE_L2CAP_EVTS_BLE_L2CAP_EVT_CH_RELEASED => (),
-                                _ => unreachable!("Invalid event"),
+                    poll_fn(|cx| {
+                        let progressed = slot.tx.lock(|tx| {
+                            let mut tx = tx.borrow_mut();
+                            if tx.progress != progress {
+                                return true;
                             }
-                        })
-                        .await;
+                            tx.wakers.register(cx.waker());
+                            false
+                        });
+                        if progressed {
+                            return Poll::Ready(Ok(()));
+                        }
+                        // Checked after registering, as the slot is freed before waking up the waiters.
+                        match self.slot() {
+                            Ok(_) => Poll::Pending,
+                            Err(err) => Poll::Ready(Err(err)),
+                        }
+                    })
+                    .await?;
                     continue;
                 }
                 Err(e) => {
@@ -455,10 +1011,45 @@ impl<P: Packet> Channel<P> {
     }
 
     /// Asynchronously receive a packet.
+    ///
+    /// Returns an SDU already received if there is one. Otherwise, a new buffer is allocated and loaned to
+    /// the SoftDevice unless it holds one already, and the next received SDU is returned. If buffers were
+    /// loaned with [`loan_rx`](Self::loan_rx) before, the SDU is received into the oldest of them.
+    ///
+    /// Cancelling the returned future leaves its buffer loaned, to be used by the next call.
     pub async fn rx(&self) -> Result<P, RxError> {
+        let slot = self.slot()?;
+        if let Some((ptr, len)) = slot.pop_rx() {
+            // Safety: the buffer was loaned by `loan_rx` and filled with `len` bytes by the SoftDevice.
+            return Ok(unsafe { P::from_raw_parts(ptr, len) });
+        }
+
+        if slot.rx_outstanding() == 0 {
+            let ptr = P::allocate().ok_or(RxError::AllocateFailed)?;
+            // Safety: the allocated buffer has space for `MTU` bytes.
+            self.loan_rx(unsafe { P::from_raw_parts(ptr, 0) })?;
+        }
+        self.rx_loaned().await
+    }
+
+    /// Loan a buffer to the SoftDevice to receive an SDU into, without waiting.
+    ///
+    /// The SoftDevice writes the SDU directly into the buffer, which is returned by
+    /// [`rx_loaned`](Self::rx_loaned) once filled. Loaning several buffers up front lets the peer send
+    /// SDUs back to back, up to the `rx_queue_size` the SoftDevice was enabled with, and at most 8 per
+    /// channel. Buffers still loaned when the channel is released are freed by the driver.
+    ///
+    /// On error the buffer is dropped.
+    pub fn loan_rx(&self, buf: P) -> Result<(), RxError> {
         let conn_handle = self.conn.with_state(|s| s.check_connected())?;
+        let slot = self.slot()?;
+
+        let (ptr, _) = buf.into_raw_parts();
+        if !slot.reserve_rx() {
+            unsafe { P::from_raw_parts(ptr, 0) };
+            return Err(RxError::Raw(RawError::Resources));
+        }
 
-        let ptr = P::allocate().ok_or(RxError::AllocateFailed)?;
         let data = raw::ble_data_t {
             p_data: ptr.as_ptr(),
             len: P::MTU as u16,
@@ -467,6 +1058,7 @@ impl<P: Packet> Channel<P> {
         let ret = unsafe { raw::sd_ble_l2cap_ch_rx(conn_handle, self.cid, &data) };
         if let Err(err) = RawError::convert(ret) {
             warn!("sd_ble_l2cap_ch_rx err {:?}", err);
+            slot.unreserve_rx();
             // The SD didn't take ownership of the buffer, so it's on us to free it.
             // Reconstruct the P and let it get dropped.
             unsafe { P::from_raw_parts(ptr, 0) };
@@ -476,23 +1068,167 @@ impl<P: Packet> Channel<P> {
         #[cfg(feature = "ble-l2cap-credit-workaround")]
         credit_hack_refill(conn_handle, self.cid);
 
-        portal(conn_handle)
-            .wait_many(|ble_evt| unsafe {
-                match (*ble_evt).header.evt_id as u32 {
-                    raw::BLE_GAP_EVTS_BLE_GAP_EVT_DISCONNECTED => Some(Err(RxError::Disconnected)),
-                    raw::BLE_L2CAP_EVTS_BLE_L2CAP_EVT_CH_RELEASED => Some(Err(RxError::Disconnected)),
-                    raw::BLE_L2CAP_EVTS_BLE_L2CAP_EVT_CH_RX => {
-                        let l2cap_evt = get_union_field(ble_evt, &(*ble_evt).evt.l2cap_evt);
-                        let evt = &l2cap_evt.params.rx;
+        Ok(())
+    }
 
-                        let ptr = unwrap!(NonNull::new(evt.sdu_buf.p_data));
-                        let len = evt.sdu_len;
-                        let pkt = Packet::from_raw_parts(ptr, len as usize);
-                        Some(Ok(pkt))
-                    }
-                    _ => None,
+    /// Asynchronously wait for the next SDU received into a buffer loaned with [`loan_rx`](Self::loan_rx),
+    /// and take the buffer back.
+    ///
+    /// SDUs are returned in the order they were received. This waits forever if no buffers are loaned.
+    pub async fn rx_loaned(&self) -> Result<P, RxError> {
+        let slot = self.slot()?;
+
+        poll_fn(|cx| {
+            if let Some((ptr, len)) = slot.poll_rx(Some(cx)) {
+                // Safety: the buffer was loaned by `loan_rx` and filled with `len` bytes by the SoftDevice.
+                return Poll::Ready(Ok(unsafe { P::from_raw_parts(ptr, len) }));
+            }
+            // Checked after registering, as the slot is freed before waking up the waiters.
+            match self.slot() {
+                Ok(_) => Poll::Pending,
+                Err(_) => Poll::Ready(Err(RxError::Disconnected)),
+            }
+        })
+        .await
+    }
+
+    /// Asynchronously transmit `data` as a single SDU, copying it to a newly allocated packet.
+    ///
+    /// `data` must not be longer than `P::MTU`. Waits while the transmit queue is full or the peer has no
+    /// credits left.
+    pub async fn send(&self, data: &[u8]) -> Result<(), TxError<P>> {
+        if data.len() > P::MTU {
+            return Err(TxError::Raw(RawError::DataSize));
+        }
+
+        let ptr = P::allocate().ok_or(TxError::AllocateFailed)?;
+        // Safety: the allocated buffer has space for `MTU` bytes.
+        let sdu = unsafe {
+            ptr::copy_nonoverlapping(data.as_ptr(), ptr.as_ptr(), data.len());
+            P::from_raw_parts(ptr, data.len())
+        };
+        self.tx(sdu).await
+    }
+
+    /// Asynchronously receive a single SDU into `buf`, returning its length.
+    ///
+    /// If the SDU doesn't fit in `buf`, it is dropped and [`RawError::DataSize`] is returned. Use a buffer
+    /// of `P::MTU` bytes to avoid this.
+    pub async fn recv(&self, buf: &mut [u8]) -> Result<usize, RxError> {
+        let (ptr, len) = self.rx().await?.into_raw_parts();
+        // Safety: `ptr` holds the `len` bytes received, and is freed when the packet is dropped.
+        let pkt = unsafe { P::from_raw_parts(ptr, len) };
+        if len > buf.len() {
+            drop(pkt);
+            return Err(RxError::Raw(RawError::DataSize));
+        }
+
+        unsafe { ptr::copy_nonoverlapping(ptr.as_ptr(), buf.as_mut_ptr(), len) };
+        drop(pkt);
+        Ok(len)
+    }
+}
+
+/// Error returned by the `embedded-io-async` implementations of [`Channel`].
+#[cfg(feature = "embedded-io-async")]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum IoError {
+    Disconnected,
+    AllocateFailed,
+    Raw(RawError),
+}
+
+#[cfg(feature = "embedded-io-async")]
+impl From<RxError> for IoError {
+    fn from(err: RxError) -> Self {
+        match err {
+            RxError::Disconnected => IoError::Disconnected,
+            RxError::AllocateFailed => IoError::AllocateFailed,
+            RxError::Raw(err) => IoError::Raw(err),
+        }
+    }
+}
+
+#[cfg(feature = "embedded-io-async")]
+impl<P: Packet> From<TxError<P>> for IoError {
+    fn from(err: TxError<P>) -> Self {
+        match err {
+            TxError::Disconnected => IoError::Disconnected,
+            TxError::AllocateFailed => IoError::AllocateFailed,
+            // `tx` waits while the queue is full instead of returning this.
+            TxError::TxQueueFull(_) => IoError::Raw(RawError::Resources),
+            TxError::Raw(err) => IoError::Raw(err),
+        }
+    }
+}
+
+#[cfg(feature = "embedded-io-async")]
+impl embedded_io_async::Error for IoError {
+    fn kind(&self) -> embedded_io_async::ErrorKind {
+        match self {
+            IoError::Disconnected => embedded_io_async::ErrorKind::NotConnected,
+            IoError::AllocateFailed => embedded_io_async::ErrorKind::OutOfMemory,
+            IoError::Raw(_) => embedded_io_async::ErrorKind::Other,
+        }
+    }
+}
+
+#[cfg(feature = "embedded-io-async")]
+impl<P: Packet> embedded_io_async::ErrorType for Channel<P> {
+    type Error = IoError;
+}
+
+/// Reads the channel as a byte stream. SDU boundaries are not preserved: an SDU larger than the read buffer
+/// is returned over several reads.
+#[cfg(feature = "embedded-io-async")]
+impl<P: Packet> embedded_io_async::Read for Channel<P> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, IoError> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let (ptr, len, offset) = match self.rx_pending.take() {
+            Some(pending) => pending,
+            None => loop {
+                // Skip empty SDUs, a read of 0 bytes would mean end of stream.
+                let pkt = self.rx().await?;
+                let (ptr, len) = pkt.into_raw_parts();
+                if len != 0 {
+                    break (ptr, len, 0);
                 }
-            })
-            .await
+                drop(unsafe { P::from_raw_parts(ptr, len) });
+            },
+        };
+
+        let n = buf.len().min(len - offset);
+        // Safety: the buffer holds `len` received bytes.
+        unsafe { ptr::copy_nonoverlapping(ptr.as_ptr().add(offset), buf.as_mut_ptr(), n) };
+
+        if offset + n < len {
+            self.rx_pending = Some((ptr, len, offset + n));
+        } else {
+            drop(unsafe { P::from_raw_parts(ptr, len) });
+        }
+        Ok(n)
+    }
+}
+
+/// Writes to the channel, sending each write of up to `P::MTU` bytes as one SDU.
+#[cfg(feature = "embedded-io-async")]
+impl<P: Packet> embedded_io_async::Write for Channel<P> {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, IoError> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let n = buf.len().min(P::MTU);
+        self.send(&buf[..n]).await?;
+        Ok(n)
+    }
+
+    /// SDUs are handed to the SoftDevice by `write`, so there's nothing to flush.
+    async fn flush(&mut self) -> Result<(), IoError> {
+        Ok(())
     }
 }
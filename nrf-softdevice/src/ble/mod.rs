@@ -16,11 +16,16 @@ mod common;
 #[cfg(feature = "ble-sec")]
 pub mod security;
 
+#[cfg(feature = "ble-sec")]
+pub mod bond;
+
 #[cfg(feature = "ble-central")]
 pub mod central;
 
-#[cfg(feature = "ble-peripheral")]
+#[cfg(any(feature = "ble-central", feature = "ble-peripheral"))]
 pub mod advertisement_builder;
+#[cfg(feature = "ble-central")]
+pub mod advertisement_parser;
 #[cfg(feature = "ble-peripheral")]
 pub mod peripheral;
 
@@ -64,9 +69,43 @@ pub fn get_address(_sd: &Softdevice) -> Address {
     }
 }
 
-pub fn set_address(_sd: &Softdevice, addr: &Address) {
-    unsafe {
-        let ret = raw::sd_ble_gap_addr_set(addr.as_raw());
-        unwrap!(RawError::convert(ret), "sd_ble_gap_addr_set");
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SetAddressError {
+    /// The address doesn't follow the rules for its type, see [`Address::is_valid`].
+    InvalidAddress,
+    Raw(RawError),
+}
+
+impl From<RawError> for SetAddressError {
+    fn from(err: RawError) -> Self {
+        SetAddressError::Raw(err)
+    }
+}
+
+/// Set the local identity address.
+///
+/// Only [`AddressType::Public`] and [`AddressType::RandomStatic`] addresses can be set. Use
+/// [`set_privacy`] for private addresses. This can't be done while advertising, scanning or connecting.
+///
+/// Panics on failure, see [`try_set_address`].
+pub fn set_address(sd: &Softdevice, addr: &Address) {
+    unwrap!(try_set_address(sd, addr), "sd_ble_gap_addr_set");
+}
+
+/// Set the local identity address, returning an error instead of panicking on failure.
+pub fn try_set_address(_sd: &Softdevice, addr: &Address) -> Result<(), SetAddressError> {
+    if !addr.is_valid() || !matches!(addr.address_type(), AddressType::Public | AddressType::RandomStatic) {
+        return Err(SetAddressError::InvalidAddress);
+    }
+
+    let ret = unsafe { raw::sd_ble_gap_addr_set(addr.as_raw()) };
+    match RawError::convert(ret) {
+        Ok(()) => Ok(()),
+        Err(RawError::BleGapInvalidBleAddr) => Err(SetAddressError::InvalidAddress),
+        Err(err) => {
+            warn!("sd_ble_gap_addr_set err {:?}", err);
+            Err(err.into())
+        }
     }
 }
@@ -2,6 +2,8 @@
 
 use core::{mem, ptr};
 
+use futures::stream::{self, Stream};
+
 use crate::ble::*;
 use crate::util::{get_union_field, OnDrop, Portal};
 use crate::{raw, RawError, Softdevice};
@@ -23,9 +25,20 @@ pub enum ConnectableAdvertisement<'a> {
         adv_data: &'a [u8],
         scan_data: &'a [u8],
     },
+    /// Low duty cycle directed advertisement, sent at the configured interval until the timeout expires.
+    ///
+    /// `peer` may be the identity address of a bonded peer that uses resolvable private addresses. In that
+    /// case its identity must be in the list set with [`set_device_identities_list`], so the SoftDevice can
+    /// recognize the connection request.
     NonscannableDirected {
         peer: Address,
     },
+    /// High duty cycle directed advertisement, for fast reconnection to a known peer.
+    ///
+    /// The advertising interval is ignored, and the timeout is limited to 1.28 s by the Bluetooth
+    /// specification. If no timeout is configured, the maximum is used.
+    ///
+    /// See [`ConnectableAdvertisement::NonscannableDirected`] for peers using resolvable private addresses.
     NonscannableDirectedHighDuty {
         peer: Address,
     },
@@ -117,12 +130,16 @@ pub enum NonconnectableAdvertisement<'a> {
     #[cfg(any(feature = "s132", feature = "s140"))]
     ExtendedNonscannableUndirected {
         set_id: u8,
+        /// Omit the advertiser address from the packets, so the advertisement can't be traced back to this
+        /// device. Scanners report anonymous advertisements with [`AddressType::Anonymous`].
         anonymous: bool,
         adv_data: &'a [u8],
     },
     #[cfg(any(feature = "s132", feature = "s140"))]
     ExtendedNonscannableDirected {
         set_id: u8,
+        /// Omit the advertiser address from the packets. See
+        /// [`NonconnectableAdvertisement::ExtendedNonscannableUndirected`].
         anonymous: bool,
         peer: Address,
         adv_data: &'a [u8],
@@ -207,6 +224,11 @@ impl<'a> From<NonconnectableAdvertisement<'a>> for RawAdvertisement<'a> {
 pub enum AdvertiseError {
     Timeout,
     NoFreeConn,
+    /// The advertising or scan response data is longer than the advertisement type allows.
+    ///
+    /// Legacy advertisements carry up to 31 bytes, extended connectable advertisements up to 238 bytes
+    /// and other extended advertisements up to 255 bytes.
+    DataTooLong,
     Raw(RawError),
 }
 
@@ -219,7 +241,32 @@ impl From<RawError> for AdvertiseError {
 static mut ADV_HANDLE: u8 = raw::BLE_GAP_ADV_SET_HANDLE_NOT_SET as u8;
 pub(crate) static ADV_PORTAL: Portal<*const raw::ble_evt_t> = Portal::new();
 
+impl RawAdvertisement<'_> {
+    fn max_data_len(&self) -> usize {
+        match self.kind as u32 {
+            #[cfg(any(feature = "s132", feature = "s140"))]
+            raw::BLE_GAP_ADV_TYPE_EXTENDED_CONNECTABLE_NONSCANNABLE_UNDIRECTED
+            | raw::BLE_GAP_ADV_TYPE_EXTENDED_CONNECTABLE_NONSCANNABLE_DIRECTED => {
+                raw::BLE_GAP_ADV_SET_DATA_SIZE_EXTENDED_CONNECTABLE_MAX_SUPPORTED as usize
+            }
+            #[cfg(any(feature = "s132", feature = "s140"))]
+            raw::BLE_GAP_ADV_TYPE_EXTENDED_NONCONNECTABLE_SCANNABLE_UNDIRECTED
+            | raw::BLE_GAP_ADV_TYPE_EXTENDED_NONCONNECTABLE_SCANNABLE_DIRECTED
+            | raw::BLE_GAP_ADV_TYPE_EXTENDED_NONCONNECTABLE_NONSCANNABLE_UNDIRECTED
+            | raw::BLE_GAP_ADV_TYPE_EXTENDED_NONCONNECTABLE_NONSCANNABLE_DIRECTED => {
+                raw::BLE_GAP_ADV_SET_DATA_SIZE_EXTENDED_MAX_SUPPORTED as usize
+            }
+            _ => raw::BLE_GAP_ADV_SET_DATA_SIZE_MAX as usize,
+        }
+    }
+}
+
 fn start_adv(adv: RawAdvertisement<'_>, config: &Config) -> Result<(), AdvertiseError> {
+    let max_len = adv.max_data_len();
+    if adv.adv_data.map_or(0, |d| d.len()) > max_len || adv.scan_data.map_or(0, |d| d.len()) > max_len {
+        return Err(AdvertiseError::DataTooLong);
+    }
+
     let mut adv_params: raw::ble_gap_adv_params_t = unsafe { mem::zeroed() };
 
     adv_params.properties.type_ = adv.kind;
@@ -233,11 +280,21 @@ fn start_adv(adv: RawAdvertisement<'_>, config: &Config) -> Result<(), Advertise
     adv_params.primary_phy = config.primary_phy as u8;
     adv_params.secondary_phy = config.secondary_phy as u8;
     adv_params.duration = config.timeout.map(|t| t.max(1)).unwrap_or(0);
+    if adv.kind as u32 == raw::BLE_GAP_ADV_TYPE_CONNECTABLE_NONSCANNABLE_DIRECTED_HIGH_DUTY_CYCLE {
+        // High duty cycle directed advertising can't run forever.
+        const MAX: u16 = raw::BLE_GAP_ADV_TIMEOUT_HIGH_DUTY_MAX as u16;
+        adv_params.duration = if adv_params.duration == 0 {
+            MAX
+        } else {
+            adv_params.duration.min(MAX)
+        };
+    }
     adv_params.max_adv_evts = config.max_events.map(|t| t.max(1)).unwrap_or(0);
     adv_params.interval = config.interval;
     adv_params.filter_policy = config.filter_policy as u8;
     adv_params.set_set_id(adv.set_id);
-    // Unsupported: channel_mask and scan_req_notification
+    adv_params.channel_mask = config.channel_mask.into_raw();
+    adv_params.set_scan_req_notification(u8::from(config.scan_request_notification));
 
     let map_data = |data: Option<&[u8]>| {
         if let Some(data) = data {
@@ -354,6 +411,10 @@ where
         }
     });
 
+    if let Some(conn_params) = &config.conn_params {
+        gap::set_ppcp(_sd, conn_params)?;
+    }
+
     start_adv(adv.into(), config)?;
 
     // The advertising data needs to be kept alive for the entire duration of the advertising procedure.
@@ -372,7 +433,15 @@ where
                     match f(conn_handle, role, peer_address, conn_params) {
                         Ok(conn) => {
                             #[cfg(any(feature = "s113", feature = "s132", feature = "s140"))]
-                            gap::do_data_length_update(conn_handle, ptr::null());
+                            let _ = gap::do_data_length_update(conn_handle, ptr::null());
+
+                            if let Some(wanted) = config.conn_params {
+                                if !conn_params_match(&conn_params, &wanted) {
+                                    if let Err(_e) = conn.set_conn_params(wanted) {
+                                        warn!("set_conn_params after connect err {:?}", _e);
+                                    }
+                                }
+                            }
 
                             Ok(conn)
                         }
@@ -396,6 +465,12 @@ where
     res
 }
 
+fn conn_params_match(actual: &raw::ble_gap_conn_params_t, wanted: &raw::ble_gap_conn_params_t) -> bool {
+    (wanted.min_conn_interval..=wanted.max_conn_interval).contains(&actual.max_conn_interval)
+        && actual.slave_latency == wanted.slave_latency
+        && actual.conn_sup_timeout == wanted.conn_sup_timeout
+}
+
 #[repr(u8)]
 #[derive(Debug, Default, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
@@ -409,18 +484,43 @@ pub enum FilterPolicy {
 
 #[derive(Copy, Clone)]
 pub struct Config {
+    /// PHY used for the advertising packets on the primary advertising channels.
+    ///
+    /// Legacy advertisements must use [`Phy::M1`]. Extended advertisements may also use `Phy::Coded`.
     pub primary_phy: Phy,
+    /// PHY used for the auxiliary packets carrying the data of extended advertisements.
+    ///
+    /// Ignored for legacy advertisements.
     pub secondary_phy: Phy,
     pub tx_power: TxPower,
 
     /// Timeout duration, in 10ms units
     pub timeout: Option<u16>,
+    /// Maximum number of advertising events, after which advertising stops with a timeout.
     pub max_events: Option<u8>,
 
-    /// Advertising interval, in 0.625us units
+    /// Advertising interval, in 0.625ms units
     pub interval: u32,
 
     pub filter_policy: FilterPolicy,
+
+    /// Channels not to advertise on.
+    ///
+    /// For legacy advertisements only the primary advertising channels (37 to 39) can be excluded. At least
+    /// one of them must be left enabled.
+    pub channel_mask: ChannelMask,
+
+    /// Connection params to request once a connection is established by connectable advertising.
+    ///
+    /// They are also set as the Peripheral Preferred Connection Parameters before advertising starts, so
+    /// centrals reading them can connect with these params right away. Otherwise a connection parameter
+    /// update is requested right after connecting.
+    pub conn_params: Option<raw::ble_gap_conn_params_t>,
+
+    /// Generate a report for every scan request received while advertising, see [`scan_requests`].
+    ///
+    /// Ignored for advertisement types that aren't scannable.
+    pub scan_request_notification: bool,
 }
 
 impl Default for Config {
@@ -433,6 +533,47 @@ impl Default for Config {
             max_events: None,
             interval: 400, // 250ms
             filter_policy: FilterPolicy::default(),
+            channel_mask: ChannelMask::NONE,
+            conn_params: None,
+            scan_request_notification: false,
         }
     }
 }
+
+/// A scan request received while advertising.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ScanRequestReport {
+    /// Address of the scanner.
+    pub address: Address,
+    /// Received signal strength, in dBm.
+    pub rssi: i8,
+}
+
+pub(crate) static SCAN_REQ_PORTAL: Portal<*const raw::ble_evt_t> = Portal::new();
+
+/// Get the scan requests received while advertising as an async stream.
+///
+/// Reports are only generated when [`Config::scan_request_notification`] is set, and the stream has to be
+/// polled concurrently with the advertising future. Reports received while the stream isn't being polled
+/// are dropped.
+pub fn scan_requests(_sd: &Softdevice) -> impl Stream<Item = ScanRequestReport> + '_ {
+    stream::unfold((), |()| async {
+        let report = SCAN_REQ_PORTAL
+            .wait_many(|ble_evt| unsafe {
+                match (*ble_evt).header.evt_id as u32 {
+                    raw::BLE_GAP_EVTS_BLE_GAP_EVT_SCAN_REQ_REPORT => {
+                        let gap_evt = get_union_field(ble_evt, &(*ble_evt).evt.gap_evt);
+                        let params = &gap_evt.params.scan_req_report;
+                        Some(ScanRequestReport {
+                            address: Address::from_raw(params.peer_addr),
+                            rssi: params.rssi,
+                        })
+                    }
+                    _ => None,
+                }
+            })
+            .await;
+        Some((report, ()))
+    })
+}
@@ -28,6 +28,16 @@ impl PasskeyReply {
         }
     }
 
+    /// Send a keypress notification to the remote device while the user enters the passkey.
+    ///
+    /// Only has an effect if keypress notifications were negotiated during pairing.
+    pub fn notify_keypress(&self, keypress: super::security::Keypress) -> Result<(), RawError> {
+        let conn_handle = self.conn.handle().ok_or(RawError::InvalidState)?;
+        let ret = unsafe { raw::sd_ble_gap_keypress_notify(conn_handle, keypress as u8) };
+        RawError::convert(ret)
+    }
+
+    /// Reply with the passkey entered by the user, as 6 ASCII digits, or `None` to cancel pairing.
     pub fn reply(mut self, passkey: Option<&[u8; 6]>) -> Result<(), RawError> {
         let res = unsafe { self.finalize(passkey) };
         core::mem::forget(self); // Prevent Drop from finalizing a second time
@@ -98,6 +108,109 @@ impl OutOfBandReply {
     }
 }
 
+#[cfg(feature = "ble-sec")]
+pub struct ConfirmPasskeyReply {
+    conn: ManuallyDrop<Connection>,
+}
+
+#[cfg(feature = "ble-sec")]
+impl Drop for ConfirmPasskeyReply {
+    fn drop(&mut self) {
+        if let Err(_err) = unsafe { self.finalize(false) } {
+            warn!("sd_ble_gap_auth_key_reply err {:?}", _err);
+        }
+    }
+}
+
+#[cfg(feature = "ble-sec")]
+impl ConfirmPasskeyReply {
+    pub(crate) fn new(conn: Connection) -> Self {
+        Self {
+            conn: ManuallyDrop::new(conn),
+        }
+    }
+
+    /// Reply whether the user confirmed that the passkey matches the one displayed on the remote device.
+    pub fn reply(mut self, matches: bool) -> Result<(), RawError> {
+        let res = unsafe { self.finalize(matches) };
+        core::mem::forget(self); // Prevent Drop from finalizing a second time
+        res
+    }
+
+    /// # Safety
+    ///
+    /// This method must be called exactly once
+    unsafe fn finalize(&mut self, matches: bool) -> Result<(), RawError> {
+        let res = if let Some(conn_handle) = self.conn.handle() {
+            let key_type = if matches {
+                raw::BLE_GAP_AUTH_KEY_TYPE_PASSKEY
+            } else {
+                raw::BLE_GAP_AUTH_KEY_TYPE_NONE
+            };
+            let ret = raw::sd_ble_gap_auth_key_reply(conn_handle, key_type as u8, core::ptr::null());
+            RawError::convert(ret)
+        } else {
+            Err(RawError::InvalidState)
+        };
+
+        // Since conn is ManuallyDrop, we must drop it here
+        ManuallyDrop::drop(&mut self.conn);
+        res
+    }
+}
+
+#[cfg(feature = "ble-sec")]
+pub struct DhKeyReply {
+    conn: ManuallyDrop<Connection>,
+}
+
+#[cfg(feature = "ble-sec")]
+impl Drop for DhKeyReply {
+    fn drop(&mut self) {
+        if let Err(_err) = unsafe { self.finalize(None) } {
+            warn!("sd_ble_gap_lesc_dhkey_reply err {:?}", _err);
+        }
+    }
+}
+
+#[cfg(feature = "ble-sec")]
+impl DhKeyReply {
+    pub(crate) fn new(conn: Connection) -> Self {
+        Self {
+            conn: ManuallyDrop::new(conn),
+        }
+    }
+
+    /// Reply with the computed DHKey, in little-endian format.
+    ///
+    /// Replying with `None` makes the pairing procedure fail.
+    pub fn reply(mut self, dhkey: Option<&[u8; 32]>) -> Result<(), RawError> {
+        let res = unsafe { self.finalize(dhkey) };
+        core::mem::forget(self); // Prevent Drop from finalizing a second time
+        res
+    }
+
+    /// # Safety
+    ///
+    /// This method must be called exactly once
+    unsafe fn finalize(&mut self, dhkey: Option<&[u8; 32]>) -> Result<(), RawError> {
+        let res = if let Some(conn_handle) = self.conn.handle() {
+            // The SoftDevice doesn't accept a null key. An all-zero key fails the DHKey check, aborting pairing.
+            let dhkey = raw::ble_gap_lesc_dhkey_t {
+                key: dhkey.copied().unwrap_or([0; 32]),
+            };
+            let ret = raw::sd_ble_gap_lesc_dhkey_reply(conn_handle, &dhkey);
+            RawError::convert(ret)
+        } else {
+            Err(RawError::InvalidState)
+        };
+
+        // Since conn is ManuallyDrop, we must drop it here
+        ManuallyDrop::drop(&mut self.conn);
+        res
+    }
+}
+
 #[cfg(feature = "ble-gatt-server")]
 const DEFERRED_TYPE_READ: u8 = raw::BLE_GATTS_AUTHORIZE_TYPE_READ as u8;
 #[cfg(feature = "ble-gatt-server")]
@@ -187,6 +300,18 @@ impl DeferredWriteReply {
     pub fn reply(self, res: Result<&[u8], super::GattError>) -> Result<(), RawError> {
         self.0.reply(res.map(Some))
     }
+
+    /// Accepts the write, updating the attribute with `data`.
+    ///
+    /// `data` should be the data of the write request.
+    pub fn accept(self, data: &[u8]) -> Result<(), RawError> {
+        self.reply(Ok(data))
+    }
+
+    /// Rejects the write with `err`, leaving the attribute unchanged.
+    pub fn reject(self, err: super::GattError) -> Result<(), RawError> {
+        self.reply(Err(err))
+    }
 }
 
 #[cfg(feature = "ble-gatt-server")]
@@ -212,3 +337,62 @@ impl DeferredReadReply {
         self.0.reply(res)
     }
 }
+
+#[cfg(all(feature = "ble-sec", feature = "ble-peripheral"))]
+pub struct RepairingReply {
+    conn: ManuallyDrop<Connection>,
+}
+
+#[cfg(all(feature = "ble-sec", feature = "ble-peripheral"))]
+impl Drop for RepairingReply {
+    fn drop(&mut self) {
+        if let Err(_err) = unsafe { self.finalize(false) } {
+            warn!("sd_ble_gap_sec_params_reply err {:?}", _err);
+        }
+    }
+}
+
+#[cfg(all(feature = "ble-sec", feature = "ble-peripheral"))]
+impl RepairingReply {
+    pub(crate) fn new(conn: Connection) -> Self {
+        Self {
+            conn: ManuallyDrop::new(conn),
+        }
+    }
+
+    pub fn connection(&self) -> &Connection {
+        &self.conn
+    }
+
+    /// Reply whether pairing with the already bonded peer may proceed. Dropping the reply rejects it.
+    pub fn reply(mut self, accept: bool) -> Result<(), RawError> {
+        let res = unsafe { self.finalize(accept) };
+        core::mem::forget(self); // Prevent Drop from finalizing a second time
+        res
+    }
+
+    /// # Safety
+    ///
+    /// This method must be called exactly once
+    unsafe fn finalize(&mut self, accept: bool) -> Result<(), RawError> {
+        let res = if let Some(conn_handle) = self.conn.handle() {
+            if accept {
+                super::gap::sec_params_reply(conn_handle)
+            } else {
+                let ret = raw::sd_ble_gap_sec_params_reply(
+                    conn_handle,
+                    raw::BLE_GAP_SEC_STATUS_PAIRING_NOT_SUPP as u8,
+                    core::ptr::null(),
+                    core::ptr::null(),
+                );
+                RawError::convert(ret)
+            }
+        } else {
+            Err(RawError::InvalidState)
+        };
+
+        // Since conn is ManuallyDrop, we must drop it here
+        ManuallyDrop::drop(&mut self.conn);
+        res
+    }
+}
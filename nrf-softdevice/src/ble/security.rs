@@ -1,7 +1,11 @@
-use crate::ble::replies::{OutOfBandReply, PasskeyReply};
+use crate::ble::connection::{EncryptionState, CONNS_MAX};
+#[cfg(feature = "ble-peripheral")]
+use crate::ble::replies::RepairingReply;
+use crate::ble::replies::{ConfirmPasskeyReply, DhKeyReply, OutOfBandReply, PasskeyReply};
 use crate::ble::types::{EncryptionInfo, IdentityKey, MasterId, SecurityMode};
-use crate::ble::Connection;
-use crate::raw;
+use crate::ble::{Connection, DisconnectedError};
+use crate::util::{get_union_field, Portal};
+use crate::{raw, RawError, Softdevice};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
@@ -26,11 +30,79 @@ impl IoCapabilities {
     }
 }
 
+/// Keypress notification sent while entering a passkey.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Keypress {
+    PasskeyStart = raw::BLE_GAP_KP_NOT_TYPE_PASSKEY_START as u8,
+    DigitIn = raw::BLE_GAP_KP_NOT_TYPE_PASSKEY_DIGIT_IN as u8,
+    DigitOut = raw::BLE_GAP_KP_NOT_TYPE_PASSKEY_DIGIT_OUT as u8,
+    Clear = raw::BLE_GAP_KP_NOT_TYPE_PASSKEY_CLEAR as u8,
+    PasskeyEnd = raw::BLE_GAP_KP_NOT_TYPE_PASSKEY_END as u8,
+}
+
+impl Keypress {
+    pub(crate) fn from_raw(raw: u8) -> Option<Self> {
+        Some(match u32::from(raw) {
+            raw::BLE_GAP_KP_NOT_TYPE_PASSKEY_START => Keypress::PasskeyStart,
+            raw::BLE_GAP_KP_NOT_TYPE_PASSKEY_DIGIT_IN => Keypress::DigitIn,
+            raw::BLE_GAP_KP_NOT_TYPE_PASSKEY_DIGIT_OUT => Keypress::DigitOut,
+            raw::BLE_GAP_KP_NOT_TYPE_PASSKEY_CLEAR => Keypress::Clear,
+            raw::BLE_GAP_KP_NOT_TYPE_PASSKEY_END => Keypress::PasskeyEnd,
+            _ => return None,
+        })
+    }
+}
+
+/// Private key of the LE Secure Connections debug key pair defined by the Bluetooth Core specification, in
+/// little-endian format.
+///
+/// Pairing with this key lets a sniffer derive the link keys and decrypt the traffic, which is useful during
+/// development. It provides no security at all and must never be used in production.
+#[cfg(feature = "ble-sec-lesc-debug")]
+#[deprecated = "the LESC debug key provides no security, it must never ship in production firmware"]
+pub const LESC_DEBUG_PRIVATE_KEY: [u8; 32] = [
+    0xbd, 0x1a, 0x3c, 0xcd, 0xa6, 0xb8, 0x99, 0x58, 0x99, 0xb7, 0x40, 0xeb, 0x7b, 0x60, 0xff, 0x4a, 0x50, 0x3f, 0x10,
+    0xd2, 0xe3, 0xb3, 0xc9, 0x74, 0x38, 0x5f, 0xc5, 0xa3, 0xd4, 0xf6, 0x49, 0x3f,
+];
+
+/// Public key of the LE Secure Connections debug key pair, in the format returned by
+/// [`SecurityHandler::lesc_public_key`].
+///
+/// See [`LESC_DEBUG_PRIVATE_KEY`].
+#[cfg(feature = "ble-sec-lesc-debug")]
+#[deprecated = "the LESC debug key provides no security, it must never ship in production firmware"]
+pub const LESC_DEBUG_PUBLIC_KEY: [u8; 64] = [
+    0xe6, 0x9d, 0x35, 0x0e, 0x48, 0x01, 0x03, 0xcc, 0xdb, 0xfd, 0xf4, 0xac, 0x11, 0x91, 0xf4, 0xef, 0xb9, 0xa5, 0xf9,
+    0xe9, 0xa7, 0x83, 0x2c, 0x5e, 0x2c, 0xbe, 0x97, 0xf2, 0xd2, 0x03, 0xb0, 0x20, 0x8b, 0xd2, 0x89, 0x15, 0xd0, 0x8e,
+    0x1c, 0x74, 0x24, 0x30, 0xed, 0x8f, 0xc2, 0x45, 0x63, 0x76, 0x5c, 0x15, 0x52, 0x5a, 0xbf, 0x9a, 0x32, 0x63, 0x6d,
+    0xeb, 0x2a, 0x65, 0x49, 0x9c, 0x80, 0xdc,
+];
+
 pub trait SecurityHandler {
     fn io_capabilities(&self) -> IoCapabilities {
         IoCapabilities::None
     }
 
+    /// Returns `true` if keypress notifications should be exchanged during passkey entry.
+    ///
+    /// See [`PasskeyReply::notify_keypress`] and [`on_keypress()`][Self::on_keypress].
+    fn keypress_notifications(&self) -> bool {
+        false
+    }
+
+    /// Keys offered to the peer and requested from it when pairing as peripheral, as
+    /// `(own_keys, peer_keys)`.
+    ///
+    /// As central these come from the [`SecurityParams`] given to [`authenticate`].
+    fn key_distribution(&self, _conn: &Connection) -> (KeyDistribution, KeyDistribution) {
+        (KeyDistribution::ENC_ID, KeyDistribution::ENC_ID)
+    }
+
+    /// Called after a successful pairing procedure with the keys exchanged, whether bonding or not.
+    fn on_keys_exchanged(&self, _conn: &Connection, _own_keys: &KeySet, _peer_keys: &KeySet) {}
+
     /// Returns `true` if the device can receive out-of-band authentication data.
     fn can_recv_out_of_band(&self, _conn: &Connection) -> bool {
         false
@@ -48,6 +120,15 @@ pub trait SecurityHandler {
         panic!("SecurityHandler::display_passkey is not implemented");
     }
 
+    /// Display `passkey` to the user, and ask them to confirm that it matches the one displayed on the remote
+    /// device (LE Secure Connections numeric comparison).
+    ///
+    /// Must be implemented if [`io_capabilities()`][Self::io_capabilities] is one of `DisplayYesNo` or
+    /// `KeyboardDisplay` and LE Secure Connections is supported.
+    fn confirm_passkey(&self, _passkey: &[u8; 6], _reply: ConfirmPasskeyReply) {
+        panic!("SecurityHandler::confirm_passkey is not implemented");
+    }
+
     /// Allow the user to enter a passkey displayed on the remote device.
     ///
     /// Must be implemented if [`io_capabilities()`][Self::io_capabilities] is one of `KeyboardOnly` or `KeyboardDisplay`.
@@ -55,6 +136,9 @@ pub trait SecurityHandler {
         panic!("SecurityHandler::enter_passkey is not implemented");
     }
 
+    /// Called when a keypress notification is received from the remote device while it's entering the passkey.
+    fn on_keypress(&self, _conn: &Connection, _keypress: Keypress) {}
+
     /// Receive out-of-band authentication data.
     ///
     /// Must be implemented if [`can_recv_out_of_band()`][Self::can_recv_out_of_band] ever returns `true`.
@@ -62,21 +146,104 @@ pub trait SecurityHandler {
         panic!("SecurityHandler::recv_out_of_band is not implemented");
     }
 
+    /// Returns the P-256 backend used for LE Secure Connections pairing.
+    ///
+    /// The default implementations of [`lesc_public_key()`][Self::lesc_public_key] and
+    /// [`compute_dhkey()`][Self::compute_dhkey] use it, so implementing this is enough to support LE Secure
+    /// Connections. If `None` and those methods are not overridden, only legacy pairing is supported.
+    fn ecdh(&self) -> Option<&dyn EcdhBackend> {
+        None
+    }
+
+    /// Returns the local P-256 public key to use for LE Secure Connections pairing on `_conn`.
+    ///
+    /// The key is in the SMP format: X and Y coordinates, both little-endian. If `None`, only legacy
+    /// pairing is supported. Defaults to the public key of the [`ecdh()`][Self::ecdh] backend.
+    fn lesc_public_key(&self, _conn: &Connection) -> Option<[u8; 64]> {
+        self.ecdh().map(|ecdh| ecdh.public_key())
+    }
+
+    /// Compute the LE Secure Connections Diffie-Hellman key from the peer's public key and the local private key.
+    ///
+    /// The computation may be done asynchronously, replying once done. Defaults to the [`ecdh()`][Self::ecdh]
+    /// backend, must be implemented otherwise if [`lesc_public_key()`][Self::lesc_public_key] ever returns `Some`.
+    fn compute_dhkey(&self, peer_public_key: &[u8; 64], reply: DhKeyReply) {
+        match self.ecdh() {
+            Some(ecdh) => ecdh.compute_shared_secret(peer_public_key, reply),
+            None => panic!("SecurityHandler::compute_dhkey is not implemented"),
+        }
+    }
+
     /// Called when the [`SecurityMode`] of a [`Connection`] has changed.
     fn on_security_update(&self, _conn: &Connection, _security_mode: SecurityMode) {}
 
+    /// Called when no packet with a valid MIC was received from the peer within the authenticated payload
+    /// timeout of an encrypted link, see [`Connection::set_auth_payload_timeout`].
+    ///
+    /// The link may be stalled or under attack, and is usually disconnected.
+    fn on_auth_payload_timeout(&self, _conn: &Connection) {}
+
     /// The connection has been bonded and its encryption keys should now be stored.
     ///
+    /// `_key` is the long term key used to re-encrypt later connections with this peer: provided to the
+    /// SoftDevice by [`get_key()`][Self::get_key] as peripheral, or passed to [`encrypt`] as central. With LE
+    /// Secure Connections, `_master_id` is all zeros, so the peer must be looked up by its identity instead.
+    ///
     /// Must be implemented if [`can_bond`][Self::can_bond] ever returns `true`.
     fn on_bonded(&self, _conn: &Connection, _master_id: MasterId, _key: EncryptionInfo, _peer_id: IdentityKey) {
         panic!("SecurityHandler::on_bonded not implemented")
     }
 
+    /// Returns `true` if a bond exists with the peer of `_conn`.
+    ///
+    /// Used to detect a bonded peer pairing again, see [`on_repairing_request()`][Self::on_repairing_request].
+    fn is_bonded(&self, _conn: &Connection) -> bool {
+        false
+    }
+
+    /// Called when a bonded central starts pairing again, usually because it lost its keys.
+    ///
+    /// Accepting the request overwrites the existing bond once pairing completes, which lets anyone who can
+    /// spoof the peer's address replace its keys. The decision may be taken asynchronously, e.g. after asking
+    /// the user, by keeping `reply` and replying later. The default accepts the request.
+    #[cfg(feature = "ble-peripheral")]
+    fn on_repairing_request(&self, _conn: &Connection, reply: RepairingReply) {
+        if let Err(_err) = reply.reply(true) {
+            warn!("SecurityHandler failed to accept repairing: {:?}", _err);
+        }
+    }
+
+    /// Called when the bond with the peer identified by `_peer_id` has been deleted from the store.
+    ///
+    /// Any data cached for the peer, such as GATT server system attributes, should be dropped as well.
+    fn on_bond_deleted(&self, _peer_id: &IdentityKey) {}
+
     /// Search the store for a known peer identified by `master_id` and return its LTK.
+    ///
+    /// Called when a central requests encryption of the link with the keys of an existing bond.
     fn get_key(&self, _conn: &Connection, _master_id: MasterId) -> Option<EncryptionInfo> {
         None
     }
 
+    /// Return the master id and LTK distributed by the bonded peer of `_conn`, to re-encrypt the link as central.
+    ///
+    /// Used by [`on_security_request()`][Self::on_security_request] to encrypt the link instead of pairing again.
+    #[cfg(feature = "ble-central")]
+    fn get_peer_key(&self, _conn: &Connection) -> Option<(MasterId, EncryptionInfo)> {
+        None
+    }
+
+    /// Decide how to answer a security request from the peripheral of `_conn`.
+    ///
+    /// When accepted, the link is encrypted with the key from [`get_peer_key()`][Self::get_peer_key] if there
+    /// is one, otherwise pairing is started with the given parameters. The outcome is reported to
+    /// [`on_security_update()`][Self::on_security_update] and [`on_bonded()`][Self::on_bonded]. The default
+    /// ignores the request, leaving it to the application to call [`authenticate`] or [`encrypt`].
+    #[cfg(feature = "ble-central")]
+    fn on_security_request(&self, _conn: &Connection, _request: SecurityRequest) -> SecurityRequestAction {
+        SecurityRequestAction::Ignore
+    }
+
     #[cfg(feature = "ble-gatt-server")]
     /// Store the GATTS system attributes for `conn` if a bond exists
     fn save_sys_attrs(&self, _conn: &super::Connection) {}
@@ -92,3 +259,504 @@ pub trait SecurityHandler {
         }
     }
 }
+
+/// P-256 elliptic curve operations needed by LE Secure Connections pairing.
+///
+/// The SoftDevice runs the pairing procedure but leaves the public key cryptography to the application, so it
+/// can be done in software, by the CryptoCell of the nRF52840, or by an external secure element. Keys are in the
+/// SMP format: each coordinate or scalar is little-endian.
+pub trait EcdhBackend {
+    /// Returns the local public key, as the X and Y coordinates.
+    ///
+    /// The key pair is owned by the backend, which generates it before first use. It may be regenerated
+    /// between pairing procedures, but must not change while one is in progress.
+    fn public_key(&self) -> [u8; 64];
+
+    /// Compute the X coordinate of the shared secret from the peer's public key and the local private key.
+    ///
+    /// The computation may be done asynchronously, keeping `reply` until done. Replying with `None` when the
+    /// peer's key is not a valid curve point makes pairing fail.
+    fn compute_shared_secret(&self, peer_public_key: &[u8; 64], reply: DhKeyReply);
+}
+
+/// Security requirements used when pairing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SecurityParams {
+    /// Request bonding, so the keys are kept for future connections.
+    pub bond: bool,
+    /// Request man-in-the-middle protection. Requires IO capabilities allowing passkey or numeric comparison.
+    pub mitm: bool,
+    /// Request LE Secure Connections pairing. Requires the connection's [`SecurityHandler`] to provide
+    /// a [`lesc_public_key`][SecurityHandler::lesc_public_key].
+    pub lesc: bool,
+    /// Exchange keypress notifications during passkey entry.
+    pub keypress: bool,
+    /// Out-of-band authentication data is available.
+    pub oob: bool,
+    pub io_capabilities: IoCapabilities,
+    /// Minimum encryption key size in bytes, from 7 to 16.
+    pub min_key_size: u8,
+    /// Maximum encryption key size in bytes, from `min_key_size` to 16.
+    pub max_key_size: u8,
+    /// Keys the local device offers to distribute.
+    pub own_keys: KeyDistribution,
+    /// Keys the local device requests from the peer.
+    pub peer_keys: KeyDistribution,
+}
+
+impl Default for SecurityParams {
+    fn default() -> Self {
+        Self {
+            bond: false,
+            mitm: false,
+            lesc: false,
+            keypress: false,
+            oob: false,
+            io_capabilities: IoCapabilities::None,
+            min_key_size: 7,
+            max_key_size: 16,
+            own_keys: KeyDistribution::ENC_ID,
+            peer_keys: KeyDistribution::ENC_ID,
+        }
+    }
+}
+
+impl SecurityParams {
+    pub(crate) fn to_raw(&self) -> raw::ble_gap_sec_params_t {
+        let mut sec_params: raw::ble_gap_sec_params_t = unsafe { core::mem::zeroed() };
+
+        sec_params.set_bond(u8::from(self.bond));
+        sec_params.set_mitm(u8::from(self.mitm));
+        sec_params.set_lesc(u8::from(self.lesc));
+        sec_params.set_keypress(u8::from(self.keypress));
+        sec_params.set_oob(u8::from(self.oob));
+        sec_params.set_io_caps(self.io_capabilities.to_io_caps());
+        sec_params.min_key_size = self.min_key_size;
+        sec_params.max_key_size = self.max_key_size;
+
+        sec_params.kdist_own = self.own_keys.into_raw();
+        sec_params.kdist_peer = self.peer_keys.into_raw();
+
+        sec_params
+    }
+}
+
+/// Set of keys distributed during pairing.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct KeyDistribution {
+    /// Long term key and master identification.
+    pub enc: bool,
+    /// Identity resolving key and identity address.
+    pub id: bool,
+    /// Connection signature resolving key.
+    pub sign: bool,
+    /// Derive the BR/EDR link key from the LE long term key.
+    pub link: bool,
+}
+
+impl KeyDistribution {
+    /// Long term key and identity, the keys needed to re-encrypt and recognize bonded peers.
+    pub const ENC_ID: Self = Self {
+        enc: true,
+        id: true,
+        sign: false,
+        link: false,
+    };
+
+    pub(crate) fn into_raw(self) -> raw::ble_gap_sec_kdist_t {
+        raw::ble_gap_sec_kdist_t {
+            _bitfield_1: raw::ble_gap_sec_kdist_t::new_bitfield_1(
+                u8::from(self.enc),
+                u8::from(self.id),
+                u8::from(self.sign),
+                u8::from(self.link),
+            ),
+        }
+    }
+}
+
+/// Outcome of a successful pairing or bonding procedure.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct AuthStatus {
+    /// Security mode of the link once the procedure completed.
+    pub security_mode: SecurityMode,
+    /// The peers are now bonded.
+    pub bonded: bool,
+    /// LE Secure Connections pairing was used.
+    pub lesc: bool,
+    /// Keys distributed by the local device.
+    pub own_keys: KeySet,
+    /// Keys distributed by the peer.
+    pub peer_keys: KeySet,
+}
+
+/// Keys distributed by one side during pairing.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct KeySet {
+    /// Long term key and its master identification.
+    ///
+    /// With LE Secure Connections, the LTK is derived by both sides instead of distributed, and is reported in
+    /// the local device's keys.
+    pub enc: Option<(MasterId, EncryptionInfo)>,
+    /// Identity resolving key and identity address. Never reported for the local device, whose identity is
+    /// set with [`set_privacy`](crate::ble::set_privacy).
+    pub id: Option<IdentityKey>,
+    /// Connection signature resolving key.
+    pub sign: Option<[u8; 16]>,
+}
+
+impl KeySet {
+    /// Collect the keys received in the connection's keyset, as reported by `kdist_own` and `kdist_peer`.
+    pub(crate) fn from_state(
+        state: &EncryptionState,
+        kdist_own: raw::ble_gap_sec_kdist_t,
+        kdist_peer: raw::ble_gap_sec_kdist_t,
+    ) -> (Self, Self) {
+        let enc = |key: &raw::ble_gap_enc_key_t| {
+            (
+                MasterId::from_raw(key.master_id),
+                EncryptionInfo::from_raw(key.enc_info),
+            )
+        };
+
+        let own = Self {
+            enc: (kdist_own.enc() != 0).then(|| enc(&state.own_enc_key)),
+            id: None,
+            sign: (kdist_own.sign() != 0).then_some(state.own_sign_key.csrk),
+        };
+        let peer = Self {
+            enc: (kdist_peer.enc() != 0).then(|| enc(&state.peer_enc_key)),
+            id: (kdist_peer.id() != 0).then(|| IdentityKey::from_raw(state.peer_id)),
+            sign: (kdist_peer.sign() != 0).then_some(state.peer_sign_key.csrk),
+        };
+        (own, peer)
+    }
+}
+
+/// Security requested by a peripheral, see [`SecurityHandler::on_security_request`].
+#[cfg(feature = "ble-central")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SecurityRequest {
+    pub bond: bool,
+    pub mitm: bool,
+    pub lesc: bool,
+    pub keypress: bool,
+}
+
+/// How to answer a security request from a peripheral.
+#[cfg(feature = "ble-central")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SecurityRequestAction {
+    /// Leave the request unanswered.
+    Ignore,
+    /// Reject the request, the peripheral is told that pairing is not supported.
+    Reject,
+    /// Encrypt the link with the stored keys of the peer, or pair with the given parameters.
+    Accept(SecurityParams),
+}
+
+/// Reason of a failed pairing or encryption procedure, from the SMP pairing failed codes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SecurityError {
+    /// The procedure timed out.
+    Timeout,
+    /// An invalid SMP PDU was received.
+    PduInvalid,
+    /// Passkey entry failed or was cancelled by the user.
+    PasskeyEntryFailed,
+    /// Out-of-band data is not available.
+    OobNotAvailable,
+    /// The authentication requirements, such as MITM protection, can't be met with the IO capabilities.
+    AuthRequirements,
+    /// The confirm value doesn't match, usually because of a wrong passkey.
+    ConfirmValue,
+    /// Pairing is not supported by the device.
+    PairingNotSupported,
+    /// The resulting encryption key size is insufficient.
+    EncryptionKeySize,
+    /// The SMP command is not supported.
+    CommandNotSupported,
+    /// Pairing failed for an unspecified reason.
+    Unspecified,
+    /// Too little time has passed since the last pairing request or security request.
+    RepeatedAttempts,
+    /// The pairing parameters are invalid.
+    InvalidParams,
+    /// The LE Secure Connections DHKey check failed.
+    DhKeyCheckFailed,
+    /// The user rejected the numeric comparison.
+    NumericComparisonFailed,
+    /// Pairing over BR/EDR is in progress.
+    BrEdrInProgress,
+    /// Cross-transport key derivation is not allowed.
+    CrossTransportKeyDisallowed,
+    /// Any other `BLE_GAP_SEC_STATUS` code.
+    Other(u8),
+}
+
+impl SecurityError {
+    pub fn from_raw(status: u8) -> Self {
+        match u32::from(status) {
+            raw::BLE_GAP_SEC_STATUS_TIMEOUT => Self::Timeout,
+            raw::BLE_GAP_SEC_STATUS_PDU_INVALID => Self::PduInvalid,
+            raw::BLE_GAP_SEC_STATUS_PASSKEY_ENTRY_FAILED => Self::PasskeyEntryFailed,
+            raw::BLE_GAP_SEC_STATUS_OOB_NOT_AVAILABLE => Self::OobNotAvailable,
+            raw::BLE_GAP_SEC_STATUS_AUTH_REQ => Self::AuthRequirements,
+            raw::BLE_GAP_SEC_STATUS_CONFIRM_VALUE => Self::ConfirmValue,
+            raw::BLE_GAP_SEC_STATUS_PAIRING_NOT_SUPP => Self::PairingNotSupported,
+            raw::BLE_GAP_SEC_STATUS_ENC_KEY_SIZE => Self::EncryptionKeySize,
+            raw::BLE_GAP_SEC_STATUS_SMP_CMD_UNSUPPORTED => Self::CommandNotSupported,
+            raw::BLE_GAP_SEC_STATUS_UNSPECIFIED => Self::Unspecified,
+            raw::BLE_GAP_SEC_STATUS_REPEATED_ATTEMPTS => Self::RepeatedAttempts,
+            raw::BLE_GAP_SEC_STATUS_INVALID_PARAMS => Self::InvalidParams,
+            raw::BLE_GAP_SEC_STATUS_DHKEY_FAILURE => Self::DhKeyCheckFailed,
+            raw::BLE_GAP_SEC_STATUS_NUM_COMP_FAILURE => Self::NumericComparisonFailed,
+            raw::BLE_GAP_SEC_STATUS_BR_EDR_IN_PROG => Self::BrEdrInProgress,
+            raw::BLE_GAP_SEC_STATUS_X_TRANS_KEY_DISALLOWED => Self::CrossTransportKeyDisallowed,
+            _ => Self::Other(status),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum AuthenticateError {
+    Disconnected,
+    /// Another security procedure is already waiting for completion on this connection.
+    Busy,
+    /// The procedure failed. `remote` is `true` if the failure was reported by the peer rather than detected
+    /// locally.
+    Failed {
+        error: SecurityError,
+        remote: bool,
+    },
+    Raw(RawError),
+}
+
+impl AuthenticateError {
+    fn from_auth_status(params: &raw::ble_gap_evt_auth_status_t) -> Self {
+        Self::Failed {
+            error: SecurityError::from_raw(params.auth_status),
+            remote: u32::from(params.error_src()) == raw::BLE_GAP_SEC_STATUS_SOURCE_REMOTE,
+        }
+    }
+}
+
+impl From<DisconnectedError> for AuthenticateError {
+    fn from(_: DisconnectedError) -> Self {
+        Self::Disconnected
+    }
+}
+
+impl From<RawError> for AuthenticateError {
+    fn from(err: RawError) -> Self {
+        Self::Raw(err)
+    }
+}
+
+/// Answer a security request received as central, as decided by the connection's handler.
+#[cfg(feature = "ble-central")]
+pub(crate) unsafe fn on_security_request(conn_handle: u16, params: &raw::ble_gap_evt_sec_request_t) {
+    let Some(conn) = Connection::from_handle(conn_handle) else {
+        return;
+    };
+    let Some(handler) = conn.with_state(|state| state.security.handler) else {
+        return;
+    };
+
+    let request = SecurityRequest {
+        bond: params.bond() != 0,
+        mitm: params.mitm() != 0,
+        lesc: params.lesc() != 0,
+        keypress: params.keypress() != 0,
+    };
+    let ret = match handler.on_security_request(&conn, request) {
+        SecurityRequestAction::Ignore => return,
+        // As central, null params reject the security request.
+        SecurityRequestAction::Reject => raw::sd_ble_gap_authenticate(conn_handle, core::ptr::null()),
+        SecurityRequestAction::Accept(params) => match handler.get_peer_key(&conn) {
+            Some((master_id, key)) => {
+                let master_id = raw::ble_gap_master_id_t {
+                    ediv: master_id.ediv,
+                    rand: master_id.rand,
+                };
+                raw::sd_ble_gap_encrypt(conn_handle, &master_id, key.as_raw())
+            }
+            None => raw::sd_ble_gap_authenticate(conn_handle, &params.to_raw()),
+        },
+    };
+
+    if let Err(_err) = RawError::convert(ret) {
+        warn!("failed to answer security request: {:?}", _err);
+    }
+}
+
+const PORTAL_NEW: Portal<*const raw::ble_evt_t> = Portal::new();
+static PORTALS: [Portal<*const raw::ble_evt_t>; CONNS_MAX] = [PORTAL_NEW; CONNS_MAX];
+pub(crate) fn portal(conn_handle: u16) -> &'static Portal<*const raw::ble_evt_t> {
+    &PORTALS[conn_handle as usize]
+}
+
+/// Start pairing or bonding on the connection, and wait for the procedure to complete.
+///
+/// As central this initiates pairing with `params`. Keys are exchanged through the connection's
+/// [`SecurityHandler`], if any.
+///
+/// As peripheral this sends a security request, but the central may choose to re-encrypt the link with
+/// existing keys instead of pairing, which this doesn't detect. Use [`request_security`] instead.
+///
+/// Fails with [`AuthenticateError::Busy`] if another security procedure is in progress on the connection.
+pub async fn authenticate(conn: &Connection, params: &SecurityParams) -> Result<AuthStatus, AuthenticateError> {
+    let conn_handle = conn.with_state(|state| state.check_connected())?;
+    if portal(conn_handle).is_waiting() {
+        return Err(AuthenticateError::Busy);
+    }
+
+    let sec_params = params.to_raw();
+    let ret = unsafe { raw::sd_ble_gap_authenticate(conn_handle, &sec_params) };
+    RawError::convert(ret).map_err(|err| {
+        warn!("sd_ble_gap_authenticate err {:?}", err);
+        err
+    })?;
+
+    portal(conn_handle)
+        .wait_many(|ble_evt| unsafe {
+            match (*ble_evt).header.evt_id as u32 {
+                raw::BLE_GAP_EVTS_BLE_GAP_EVT_DISCONNECTED => Some(Err(AuthenticateError::Disconnected)),
+                raw::BLE_GAP_EVTS_BLE_GAP_EVT_CONN_SEC_UPDATE => None,
+                raw::BLE_GAP_EVTS_BLE_GAP_EVT_AUTH_STATUS => {
+                    let gap_evt = get_union_field(ble_evt, &(*ble_evt).evt.gap_evt);
+                    let params = &gap_evt.params.auth_status;
+                    if u32::from(params.auth_status) != raw::BLE_GAP_SEC_STATUS_SUCCESS {
+                        return Some(Err(AuthenticateError::from_auth_status(params)));
+                    }
+
+                    let (own_keys, peer_keys) = conn
+                        .with_state(|state| KeySet::from_state(&state.security, params.kdist_own, params.kdist_peer));
+                    Some(Ok(AuthStatus {
+                        security_mode: conn.security_mode(),
+                        bonded: params.bonded() != 0,
+                        lesc: params.lesc() != 0,
+                        own_keys,
+                        peer_keys,
+                    }))
+                }
+                e => panic!("unexpected event {}", e),
+            }
+        })
+        .await
+}
+
+/// Ask the central to secure the link, and wait until it's encrypted.
+///
+/// The central either re-encrypts the link with the keys of an existing bond, or pairs according to `params`.
+/// Returns the resulting security mode. The central may also ignore the request, so consider using a timeout.
+///
+/// This is typically done when the central accesses a characteristic that requires encryption.
+#[cfg(feature = "ble-peripheral")]
+pub async fn request_security(conn: &Connection, params: &SecurityParams) -> Result<SecurityMode, AuthenticateError> {
+    let conn_handle = conn.with_state(|state| state.check_connected())?;
+    if portal(conn_handle).is_waiting() {
+        return Err(AuthenticateError::Busy);
+    }
+    if conn.role() != crate::ble::Role::Peripheral {
+        return Err(AuthenticateError::Raw(RawError::InvalidState));
+    }
+
+    let sec_params = params.to_raw();
+    let ret = unsafe { raw::sd_ble_gap_authenticate(conn_handle, &sec_params) };
+    RawError::convert(ret).map_err(|err| {
+        warn!("sd_ble_gap_authenticate err {:?}", err);
+        err
+    })?;
+
+    portal(conn_handle)
+        .wait_once(|ble_evt| unsafe {
+            match (*ble_evt).header.evt_id as u32 {
+                raw::BLE_GAP_EVTS_BLE_GAP_EVT_DISCONNECTED => Err(AuthenticateError::Disconnected),
+                raw::BLE_GAP_EVTS_BLE_GAP_EVT_CONN_SEC_UPDATE => Ok(conn.security_mode()),
+                raw::BLE_GAP_EVTS_BLE_GAP_EVT_AUTH_STATUS => {
+                    // Only reached if pairing fails, since the link is encrypted before a successful pairing completes.
+                    let gap_evt = get_union_field(ble_evt, &(*ble_evt).evt.gap_evt);
+                    let params = &gap_evt.params.auth_status;
+                    match u32::from(params.auth_status) {
+                        raw::BLE_GAP_SEC_STATUS_SUCCESS => Ok(conn.security_mode()),
+                        _ => Err(AuthenticateError::from_auth_status(params)),
+                    }
+                }
+                e => panic!("unexpected event {}", e),
+            }
+        })
+        .await
+}
+
+/// Set the passkey displayed during passkey entry pairing.
+///
+/// `passkey` must be 6 ASCII digits. If `None`, the SoftDevice generates a random passkey for each pairing,
+/// which is the only secure option unless the passkey is provisioned per device.
+pub fn set_static_passkey(_sd: &Softdevice, passkey: Option<&'static [u8; 6]>) -> Result<(), RawError> {
+    let ret = unsafe {
+        raw::sd_ble_opt_set(
+            raw::BLE_GAP_OPTS_BLE_GAP_OPT_PASSKEY,
+            &raw::ble_opt_t {
+                gap_opt: raw::ble_gap_opt_t {
+                    passkey: raw::ble_gap_opt_passkey_t {
+                        p_passkey: passkey.map_or(core::ptr::null(), |p| p.as_ptr()),
+                    },
+                },
+            },
+        )
+    };
+    RawError::convert(ret).map_err(|err| {
+        warn!("set_static_passkey sd_ble_opt_set err {:?}", err);
+        err
+    })
+}
+
+/// Encrypt the link with the keys of an existing bond, and wait until it's encrypted.
+///
+/// `master_id` and `key` are the ones received in [`SecurityHandler::on_bonded`] when bonding with the peer.
+/// Returns the resulting security mode. If the peer lost its keys, the connection is usually terminated by the
+/// peer, and this returns [`AuthenticateError::Disconnected`].
+#[cfg(feature = "ble-central")]
+pub async fn encrypt(
+    conn: &Connection,
+    master_id: &MasterId,
+    key: &EncryptionInfo,
+) -> Result<SecurityMode, AuthenticateError> {
+    let conn_handle = conn.with_state(|state| state.check_connected())?;
+    if portal(conn_handle).is_waiting() {
+        return Err(AuthenticateError::Busy);
+    }
+
+    let master_id = raw::ble_gap_master_id_t {
+        ediv: master_id.ediv,
+        rand: master_id.rand,
+    };
+    let ret = unsafe { raw::sd_ble_gap_encrypt(conn_handle, &master_id, key.as_raw()) };
+    RawError::convert(ret).map_err(|err| {
+        warn!("sd_ble_gap_encrypt err {:?}", err);
+        err
+    })?;
+
+    portal(conn_handle)
+        .wait_once(|ble_evt| unsafe {
+            match (*ble_evt).header.evt_id as u32 {
+                raw::BLE_GAP_EVTS_BLE_GAP_EVT_DISCONNECTED => Err(AuthenticateError::Disconnected),
+                raw::BLE_GAP_EVTS_BLE_GAP_EVT_CONN_SEC_UPDATE => Ok(conn.security_mode()),
+                raw::BLE_GAP_EVTS_BLE_GAP_EVT_AUTH_STATUS => {
+                    let gap_evt = get_union_field(ble_evt, &(*ble_evt).evt.gap_evt);
+                    Err(AuthenticateError::from_auth_status(&gap_evt.params.auth_status))
+                }
+                e => panic!("unexpected event {}", e),
+            }
+        })
+        .await
+}
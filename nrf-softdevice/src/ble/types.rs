@@ -1,7 +1,8 @@
 use core::mem;
 use core::num::NonZeroU16;
+use core::sync::atomic::{AtomicU8, Ordering};
 
-use crate::{raw, RawError};
+use crate::{raw, RawError, Softdevice};
 
 #[repr(transparent)]
 #[derive(Copy, Clone)]
@@ -32,18 +33,33 @@ impl Uuid {
     // Note that `uuid` needs to be in little-endian format, i.e. opposite to what you would
     // normally write UUIDs.
     pub fn new_128(uuid: &[u8; 16]) -> Self {
-        let mut uuid_type: u8 = 0;
-        let ret = unsafe { raw::sd_ble_uuid_vs_add(uuid.as_ptr() as _, &mut uuid_type as _) };
-        match RawError::convert(ret) {
-            Ok(()) => {}
+        match Self::try_new_128(uuid) {
+            Ok(uuid) => uuid,
             Err(e) => panic!("sd_ble_uuid_vs_add err {:?}", e),
         }
+    }
 
-        Self {
+    // Like `new_128`, but returns an error instead of panicking if the UUID base can't be
+    // added to the SoftDevice, e.g. because there are no vendor-specific UUID slots left.
+    pub fn try_new_128(uuid: &[u8; 16]) -> Result<Self, RawError> {
+        let uuid_type = vs_uuid_add(uuid)?;
+
+        Ok(Self {
             inner: raw::ble_uuid_t {
                 type_: uuid_type,
                 uuid: ((uuid[13] as u16) << 8) | (uuid[12] as u16),
             },
+        })
+    }
+
+    /// Returns the vendor-specific UUID base of this UUID, if it's a 128-bit UUID.
+    pub fn vendor(&self) -> Option<VendorUuid> {
+        if self.inner.type_ >= raw::BLE_UUID_TYPE_VENDOR_BEGIN as u8 {
+            Some(VendorUuid {
+                uuid_type: self.inner.type_,
+            })
+        } else {
+            None
         }
     }
 
@@ -56,6 +72,64 @@ impl Uuid {
     }
 }
 
+/// Number of vendor-specific UUID bases registered in the softdevice.
+static VS_UUID_USED: AtomicU8 = AtomicU8::new(0);
+
+fn vs_uuid_add(base: &[u8; 16]) -> Result<u8, RawError> {
+    let mut uuid_type: u8 = 0;
+    let ret = unsafe { raw::sd_ble_uuid_vs_add(base.as_ptr() as _, &mut uuid_type as _) };
+    RawError::convert(ret)?;
+
+    // The softdevice returns the existing index for bases that are already registered,
+    // and allocates indexes in increasing order otherwise.
+    let used = uuid_type - raw::BLE_UUID_TYPE_VENDOR_BEGIN as u8 + 1;
+    VS_UUID_USED.fetch_max(used, Ordering::Relaxed);
+    Ok(uuid_type)
+}
+
+/// A vendor-specific 128-bit UUID base registered in the softdevice.
+///
+/// The softdevice stores 128-bit UUIDs as a base and a 16-bit alias, which replaces bytes 12 and 13
+/// of the base. The number of bases that can be registered is limited by
+/// [`Config::common_vs_uuid`][crate::Config::common_vs_uuid].
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct VendorUuid {
+    uuid_type: u8,
+}
+
+impl VendorUuid {
+    /// Register a 128-bit UUID base in the softdevice.
+    ///
+    /// Registering a base that is already registered returns the existing one, without using a new slot.
+    ///
+    /// Note that `base` needs to be in little-endian format, like in [`Uuid::new_128`].
+    pub fn new(_sd: &Softdevice, base: &[u8; 16]) -> Result<Self, RawError> {
+        let uuid_type = vs_uuid_add(base)?;
+        Ok(Self { uuid_type })
+    }
+
+    /// Returns the 128-bit UUID made of this base and the 16-bit `alias`.
+    pub const fn uuid(&self, alias: u16) -> Uuid {
+        Uuid {
+            inner: raw::ble_uuid_t {
+                type_: self.uuid_type,
+                uuid: alias,
+            },
+        }
+    }
+
+    /// Returns the index of this base in the softdevice's table, as used in [`raw::ble_uuid_t`].
+    pub fn uuid_type(&self) -> u8 {
+        self.uuid_type
+    }
+
+    /// Returns how many more vendor-specific UUID bases can be registered.
+    pub fn slots_left(sd: &Softdevice) -> u8 {
+        sd.vs_uuid_count.saturating_sub(VS_UUID_USED.load(Ordering::Relaxed))
+    }
+}
+
 impl Eq for Uuid {}
 impl PartialEq for Uuid {
     fn eq(&self, other: &Uuid) -> bool {
@@ -84,15 +158,26 @@ impl Role {
     }
 }
 
+/// Security required to access an attribute, or achieved by a link.
+///
+/// When used as an attribute permission, the SoftDevice rejects accesses over links that don't meet it with an
+/// insufficient authentication or encryption error, so peers know to pair first.
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum SecurityMode {
+    /// No access is allowed.
     NoAccess,
+    /// No security is required.
     Open,
+    /// Encryption is required, unauthenticated pairing (Just Works) is enough.
     JustWorks,
+    /// Encryption with man-in-the-middle protection is required.
     Mitm,
+    /// Encryption with man-in-the-middle protection and LE Secure Connections pairing is required.
     LescMitm,
+    /// Data signing is required, unauthenticated pairing is enough.
     Signed,
+    /// Data signing with man-in-the-middle protection is required.
     SignedMitm,
 }
 
@@ -133,6 +218,34 @@ impl SecurityMode {
     }
 }
 
+/// Security of an established link, see [`Connection::security`](crate::ble::Connection::security).
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ConnectionSecurity {
+    pub mode: SecurityMode,
+    /// Length of the encryption key in bytes, 0 if the link is not encrypted.
+    pub key_size: u8,
+}
+
+impl ConnectionSecurity {
+    /// Security level of the mode, from 1 (no security) to 4 (authenticated LE Secure Connections).
+    pub fn level(&self) -> u8 {
+        self.mode.into_raw().lv()
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.key_size != 0
+    }
+
+    /// Returns `true` if the link is encrypted with a key of at least `min_key_size` bytes and the pairing was
+    /// protected against man-in-the-middle attacks.
+    pub fn is_authenticated(&self, min_key_size: u8) -> bool {
+        self.is_encrypted()
+            && self.key_size >= min_key_size
+            && matches!(self.mode, SecurityMode::Mitm | SecurityMode::LescMitm)
+    }
+}
+
 #[repr(u8)]
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
@@ -205,6 +318,29 @@ impl Address {
         unwrap!((self.flags >> 1).try_into())
     }
 
+    /// Check that the address follows the rules for its type.
+    ///
+    /// Random addresses encode their sub-type in the two most significant bits, and the remaining random
+    /// part may not be all zeros or all ones.
+    pub fn is_valid(&self) -> bool {
+        let Ok(address_type) = AddressType::try_from(self.flags >> 1) else {
+            return false;
+        };
+
+        let msb = self.bytes[5] >> 6;
+        let mut random = self.bytes;
+        random[5] &= 0x3f;
+        let random_ok = random != [0; 6] && random != [0xff, 0xff, 0xff, 0xff, 0xff, 0x3f];
+
+        match address_type {
+            AddressType::Public => true,
+            AddressType::RandomStatic => msb == 0b11 && random_ok,
+            AddressType::RandomPrivateResolvable => msb == 0b01,
+            AddressType::RandomPrivateNonResolvable => msb == 0b00 && random_ok,
+            AddressType::Anonymous => false,
+        }
+    }
+
     pub fn is_resolved_peer_id(&self) -> bool {
         (self.flags & 1) != 0
     }
@@ -260,6 +396,117 @@ pub enum TxPower {
     Plus8dBm = 8,
 }
 
+/// External appearance of the device, as assigned by the Bluetooth SIG.
+///
+/// The upper 10 bits are the category, the lower 6 bits the subcategory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Appearance(u16);
+
+impl Appearance {
+    pub const UNKNOWN: Appearance = Appearance(0x0000);
+    pub const GENERIC_PHONE: Appearance = Appearance(0x0040);
+    pub const GENERIC_COMPUTER: Appearance = Appearance(0x0080);
+    pub const GENERIC_WATCH: Appearance = Appearance(0x00C0);
+    pub const WATCH_SPORTS_WATCH: Appearance = Appearance(0x00C1);
+    pub const GENERIC_CLOCK: Appearance = Appearance(0x0100);
+    pub const GENERIC_DISPLAY: Appearance = Appearance(0x0140);
+    pub const GENERIC_REMOTE_CONTROL: Appearance = Appearance(0x0180);
+    pub const GENERIC_EYE_GLASSES: Appearance = Appearance(0x01C0);
+    pub const GENERIC_TAG: Appearance = Appearance(0x0200);
+    pub const GENERIC_KEYRING: Appearance = Appearance(0x0240);
+    pub const GENERIC_MEDIA_PLAYER: Appearance = Appearance(0x0280);
+    pub const GENERIC_BARCODE_SCANNER: Appearance = Appearance(0x02C0);
+    pub const GENERIC_THERMOMETER: Appearance = Appearance(0x0300);
+    pub const THERMOMETER_EAR: Appearance = Appearance(0x0301);
+    pub const GENERIC_HEART_RATE_SENSOR: Appearance = Appearance(0x0340);
+    pub const HEART_RATE_SENSOR_HEART_RATE_BELT: Appearance = Appearance(0x0341);
+    pub const GENERIC_BLOOD_PRESSURE: Appearance = Appearance(0x0380);
+    pub const BLOOD_PRESSURE_ARM: Appearance = Appearance(0x0381);
+    pub const BLOOD_PRESSURE_WRIST: Appearance = Appearance(0x0382);
+    pub const GENERIC_HID: Appearance = Appearance(0x03C0);
+    pub const HID_KEYBOARD: Appearance = Appearance(0x03C1);
+    pub const HID_MOUSE: Appearance = Appearance(0x03C2);
+    pub const HID_JOYSTICK: Appearance = Appearance(0x03C3);
+    pub const HID_GAMEPAD: Appearance = Appearance(0x03C4);
+    pub const HID_DIGITIZERSUBTYPE: Appearance = Appearance(0x03C5);
+    pub const HID_CARD_READER: Appearance = Appearance(0x03C6);
+    pub const HID_DIGITAL_PEN: Appearance = Appearance(0x03C7);
+    pub const HID_BARCODE: Appearance = Appearance(0x03C8);
+    pub const GENERIC_GLUCOSE_METER: Appearance = Appearance(0x0400);
+    pub const GENERIC_RUNNING_WALKING_SENSOR: Appearance = Appearance(0x0440);
+    pub const RUNNING_WALKING_SENSOR_IN_SHOE: Appearance = Appearance(0x0441);
+    pub const RUNNING_WALKING_SENSOR_ON_SHOE: Appearance = Appearance(0x0442);
+    pub const RUNNING_WALKING_SENSOR_ON_HIP: Appearance = Appearance(0x0443);
+    pub const GENERIC_CYCLING: Appearance = Appearance(0x0480);
+    pub const CYCLING_CYCLING_COMPUTER: Appearance = Appearance(0x0481);
+    pub const CYCLING_SPEED_SENSOR: Appearance = Appearance(0x0482);
+    pub const CYCLING_CADENCE_SENSOR: Appearance = Appearance(0x0483);
+    pub const CYCLING_POWER_SENSOR: Appearance = Appearance(0x0484);
+    pub const CYCLING_SPEED_CADENCE_SENSOR: Appearance = Appearance(0x0485);
+    pub const GENERIC_PULSE_OXIMETER: Appearance = Appearance(0x0C40);
+    pub const PULSE_OXIMETER_FINGERTIP: Appearance = Appearance(0x0C41);
+    pub const PULSE_OXIMETER_WRIST_WORN: Appearance = Appearance(0x0C42);
+    pub const GENERIC_WEIGHT_SCALE: Appearance = Appearance(0x0C80);
+    pub const GENERIC_OUTDOOR_SPORTS_ACT: Appearance = Appearance(0x1440);
+    pub const OUTDOOR_SPORTS_ACT_LOC_DISP: Appearance = Appearance(0x1441);
+    pub const OUTDOOR_SPORTS_ACT_LOC_AND_NAV_DISP: Appearance = Appearance(0x1442);
+    pub const OUTDOOR_SPORTS_ACT_LOC_POD: Appearance = Appearance(0x1443);
+    pub const OUTDOOR_SPORTS_ACT_LOC_AND_NAV_POD: Appearance = Appearance(0x1444);
+
+    pub const fn from_u16(value: u16) -> Self {
+        Appearance(value)
+    }
+
+    pub const fn to_u16(self) -> u16 {
+        self.0
+    }
+}
+
+impl From<u16> for Appearance {
+    fn from(value: u16) -> Self {
+        Appearance(value)
+    }
+}
+
+impl From<Appearance> for u16 {
+    fn from(value: Appearance) -> Self {
+        value.0
+    }
+}
+
+/// Set of radio channels excluded from use.
+///
+/// Channels 0 to 36 are data channels, 37 to 39 are the primary advertising channels.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ChannelMask([u8; 5]);
+
+impl ChannelMask {
+    /// No channels excluded.
+    pub const NONE: Self = Self([0; 5]);
+
+    /// Exclude a channel. Panics if `channel` is greater than 39.
+    pub const fn disable(mut self, channel: u8) -> Self {
+        // Use core::assert! even if defmt is enabled because it is const
+        core::assert!(channel < 40, "invalid channel");
+        self.0[channel as usize / 8] |= 1 << (channel % 8);
+        self
+    }
+
+    pub const fn is_disabled(&self, channel: u8) -> bool {
+        channel < 40 && self.0[channel as usize / 8] & (1 << (channel % 8)) != 0
+    }
+
+    pub const fn from_raw(raw: raw::ble_gap_ch_mask_t) -> Self {
+        Self(raw)
+    }
+
+    pub const fn into_raw(self) -> raw::ble_gap_ch_mask_t {
+        self.0
+    }
+}
+
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[derive(Eq, PartialEq, Copy, Clone)]
 #[repr(u8)]
@@ -273,6 +520,17 @@ pub enum Phy {
     Coded = 4,
 }
 
+impl Phy {
+    pub(crate) fn from_raw(raw: u8) -> Self {
+        match raw as u32 {
+            raw::BLE_GAP_PHY_2MBPS => Phy::M2,
+            #[cfg(feature = "s140")]
+            raw::BLE_GAP_PHY_CODED => Phy::Coded,
+            _ => Phy::M1,
+        }
+    }
+}
+
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 #[derive(Eq, PartialEq, Copy, Clone)]
 #[repr(u8)]
@@ -347,6 +605,16 @@ pub struct IdentityResolutionKey {
 }
 
 impl IdentityResolutionKey {
+    pub const fn new(irk: [u8; 16]) -> Self {
+        Self { irk }
+    }
+
+    /// Returns `true` if `addr` is a resolvable private address generated from this key.
+    pub fn resolves(&self, addr: Address) -> bool {
+        addr.address_type() == AddressType::RandomPrivateResolvable
+            && addr.bytes()[..3] == random_address_hash(*self, addr.bytes()[3..].try_into().unwrap())
+    }
+
     pub fn from_raw(raw: raw::ble_gap_irk_t) -> Self {
         Self { irk: raw.irk }
     }
@@ -369,13 +637,11 @@ pub struct IdentityKey {
 }
 
 impl IdentityKey {
+    /// Returns `true` if `addr` is this identity address, or a resolvable private address generated from the IRK.
     pub fn is_match(&self, addr: Address) -> bool {
         match addr.address_type() {
             AddressType::Public | AddressType::RandomStatic => self.addr == addr,
-            AddressType::RandomPrivateResolvable => {
-                let local_hash = random_address_hash(self.irk, addr.bytes()[3..].try_into().unwrap());
-                addr.bytes()[..3] == local_hash
-            }
+            AddressType::RandomPrivateResolvable => self.irk.resolves(addr),
             AddressType::RandomPrivateNonResolvable | AddressType::Anonymous => false,
         }
     }
@@ -400,6 +666,18 @@ impl IdentityKey {
     }
 }
 
+/// Find the identity a peer address belongs to.
+///
+/// Resolvable private addresses are resolved in software against the IRK of each identity, so this works on
+/// addresses from scan reports and connections that the SoftDevice did not resolve itself, e.g. when the
+/// identities are not in the list set with [`set_device_identities_list`](crate::ble::set_device_identities_list).
+pub fn resolve_address<'a>(
+    addr: Address,
+    identities: impl IntoIterator<Item = &'a IdentityKey>,
+) -> Option<&'a IdentityKey> {
+    identities.into_iter().find(|id| id.is_match(addr))
+}
+
 fn random_address_hash(key: IdentityResolutionKey, r: [u8; 3]) -> [u8; 3] {
     let mut cleartext = [0; 16];
     cleartext[13..].copy_from_slice(&r);
@@ -1,3 +1,10 @@
+//! `critical-section` implementation that leaves the SoftDevice's interrupts enabled.
+//!
+//! This is the equivalent of `sd_nvic_critical_region_enter` and `sd_nvic_critical_region_exit`, which are inline
+//! functions of the SoftDevice headers and so aren't part of the bindings. Instead of disabling all interrupts, it
+//! masks only the application's ones in the NVIC, so taking a critical section can't delay the SoftDevice and trip
+//! its timing assertions.
+
 use core::arch::asm;
 use core::sync::atomic::{compiler_fence, AtomicBool, Ordering};
 
@@ -28,11 +35,27 @@ const RESERVED_IRQS: u32 = (1 << (Interrupt::POWER_CLOCK as u8))
 static CS_FLAG: AtomicBool = AtomicBool::new(false);
 static mut CS_MASK: [u32; 2] = [0; 2];
 
+/// Check that critical sections can be taken from the current thread mode.
+///
+/// Disabling irqs in non-privileged level is a noop, which would break safety.
+/// Handlers always run privileged, thread mode only if CONTROL.nPRIV is clear.
+/// This is checked once when enabling the softdevice, instead of on every critical section,
+/// so thread mode must not be switched to unprivileged afterwards.
+pub(crate) fn check_privileged() {
+    let ipsr: u32;
+    let control: u32;
+    unsafe {
+        asm!("mrs {}, IPSR", out(reg) ipsr);
+        asm!("mrs {}, CONTROL", out(reg) control);
+    }
+    if ipsr & 0x1FF == 0 && control & 1 != 0 {
+        // Fault without formatting a panic, as panic handlers take critical sections themselves.
+        unsafe { asm!("udf #0", options(noreturn)) };
+    }
+}
+
 #[inline]
 unsafe fn raw_critical_section<R>(f: impl FnOnce() -> R) -> R {
-    // TODO: assert that we're in privileged level
-    // Needed because disabling irqs in non-privileged level is a noop, which would break safety.
-
     let primask: u32;
     asm!("mrs {}, PRIMASK", out(reg) primask);
 
@@ -38,7 +38,28 @@ fn on_soc_evt<F: FnMut(SocEvent)>(evt: u32, evt_handler: &mut F) {
     match evt {
         raw::NRF_SOC_EVTS_NRF_EVT_FLASH_OPERATION_ERROR => crate::flash::on_flash_error(),
         raw::NRF_SOC_EVTS_NRF_EVT_FLASH_OPERATION_SUCCESS => crate::flash::on_flash_success(),
+        raw::NRF_SOC_EVTS_NRF_EVT_POWER_FAILURE_WARNING => {
+            crate::power::on_power_failure_warning();
+            evt_handler(SocEvent::PowerFailureWarning)
+        }
+        #[cfg(any(feature = "s113", feature = "s122", feature = "s140"))]
+        raw::NRF_SOC_EVTS_NRF_EVT_POWER_USB_DETECTED => {
+            crate::power::on_usb_event(crate::power::UsbEvent::Detected);
+            evt_handler(SocEvent::PowerUsbDetected)
+        }
+        #[cfg(any(feature = "s113", feature = "s122", feature = "s140"))]
+        raw::NRF_SOC_EVTS_NRF_EVT_POWER_USB_REMOVED => {
+            crate::power::on_usb_event(crate::power::UsbEvent::Removed);
+            evt_handler(SocEvent::PowerUsbRemoved)
+        }
+        #[cfg(any(feature = "s113", feature = "s122", feature = "s140"))]
+        raw::NRF_SOC_EVTS_NRF_EVT_POWER_USB_POWER_READY => {
+            crate::power::on_usb_event(crate::power::UsbEvent::PowerReady);
+            evt_handler(SocEvent::PowerUsbPowerReady)
+        }
         _ => {
+            crate::timeslot::on_soc_evt(evt);
+
             let evt = match SocEvent::try_from(evt) {
                 Ok(evt) => evt,
                 Err(_) => panic!("Unknown soc evt {:?}", evt),
@@ -1,4 +1,5 @@
 use core::marker::PhantomData;
+use core::ptr;
 use core::sync::atomic::{AtomicBool, Ordering};
 
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
@@ -18,6 +19,7 @@ pub enum FlashError {
     Failed,
     AddressMisaligned,
     BufferMisaligned,
+    OutOfBounds,
 }
 
 impl NorFlashError for FlashError {
@@ -26,6 +28,7 @@ impl NorFlashError for FlashError {
             Self::Failed => NorFlashErrorKind::Other,
             Self::AddressMisaligned => NorFlashErrorKind::NotAligned,
             Self::BufferMisaligned => NorFlashErrorKind::NotAligned,
+            Self::OutOfBounds => NorFlashErrorKind::OutOfBounds,
         }
     }
 }
@@ -38,8 +41,15 @@ pub struct Flash {
 
 static FLASH_TAKEN: AtomicBool = AtomicBool::new(false);
 
+/// Size of a flash page, in bytes.
+const FICR_CODEPAGESIZE: *const u32 = 0x1000_0010 as *const u32;
+/// Number of flash pages.
+const FICR_CODESIZE: *const u32 = 0x1000_0014 as *const u32;
+
 impl Flash {
     const PAGE_SIZE: usize = 4096;
+    /// Number of times an operation is retried when the SoftDevice reports it failed.
+    const RETRIES_MAX: usize = 3;
 
     /// Takes the Flash instance from the softdevice.
     ///
@@ -68,6 +78,32 @@ pub(crate) fn on_flash_error() {
     SIGNAL.signal(Err(FlashError::Failed))
 }
 
+/// Start a flash operation with `op` and wait for its completion event.
+///
+/// The SoftDevice schedules flash operations between radio activity, and reports an error if it couldn't find
+/// the time to do it, so failed operations are retried as long as `can_retry` returns `true`. `NRF_ERROR_BUSY`
+/// means another operation is still ongoing, in which case we yield and try again.
+async fn flash_op(mut op: impl FnMut() -> u32, can_retry: impl Fn() -> bool) -> Result<(), FlashError> {
+    let mut retries = 0;
+    loop {
+        match RawError::convert(op()) {
+            Ok(()) => match SIGNAL.wait().await {
+                Ok(()) => return Ok(()),
+                Err(e) if retries >= Flash::RETRIES_MAX || !can_retry() => return Err(e),
+                Err(_) => {
+                    retries += 1;
+                    debug!("flash operation failed, retrying");
+                }
+            },
+            Err(RawError::Busy) => embassy_futures::yield_now().await,
+            Err(_e) => {
+                warn!("flash operation err {:?}", _e);
+                return Err(FlashError::Failed);
+            }
+        }
+    }
+}
+
 impl ErrorType for Flash {
     type Error = FlashError;
 }
@@ -77,7 +113,9 @@ impl ReadNorFlash for Flash {
 
     fn read(&mut self, address: u32, data: &mut [u8]) -> Result<(), Self::Error> {
         // Reading is simple since SoC flash is memory-mapped :)
-        // TODO check addr/len is in bounds.
+        if address as usize + data.len() > ReadNorFlash::capacity(self) {
+            return Err(FlashError::OutOfBounds);
+        }
 
         data.copy_from_slice(unsafe { core::slice::from_raw_parts(address as *const u8, data.len()) });
 
@@ -85,7 +123,12 @@ impl ReadNorFlash for Flash {
     }
 
     fn capacity(&self) -> usize {
-        256 * 4096
+        // Safety: FICR.CODEPAGESIZE and FICR.CODESIZE are read-only registers, valid on all nRF52s.
+        unsafe {
+            let page_size = ptr::read_volatile(FICR_CODEPAGESIZE) as usize;
+            let pages = ptr::read_volatile(FICR_CODESIZE) as usize;
+            page_size * pages
+        }
     }
 }
 
@@ -113,6 +156,9 @@ impl AsyncNorFlash for Flash {
         if address % 4 != 0 {
             return Err(FlashError::AddressMisaligned);
         }
+        if address + data.len() > ReadNorFlash::capacity(self) {
+            return Err(FlashError::OutOfBounds);
+        }
         if (data_ptr as u32) % 4 != 0 || data_len % 4 != 0 {
             return Err(FlashError::BufferMisaligned);
         }
@@ -121,16 +167,24 @@ impl AsyncNorFlash for Flash {
         let words_ptr = data_ptr as *const u32;
         let words_len = data_len / 4;
 
-        let bomb = DropBomb::new();
-        let ret = unsafe { raw::sd_flash_write(address as _, words_ptr, words_len) };
-        let ret = match RawError::convert(ret) {
-            Ok(()) => SIGNAL.wait().await,
-            Err(_e) => {
-                warn!("sd_flash_write err {:?}", _e);
-                Err(FlashError::Failed)
-            }
+        // A failed write may have programmed some words already, and a word must not be written twice without an
+        // erase in between, so it's only retried if nothing was written.
+        let is_erased = || {
+            unsafe { core::slice::from_raw_parts(address as *const u32, words_len as usize) }
+                .iter()
+                .all(|word| *word == u32::MAX)
         };
 
+        let bomb = DropBomb::new();
+        let ret = flash_op(
+            || unsafe { raw::sd_flash_write(address as _, words_ptr, words_len) },
+            is_erased,
+        )
+        .await;
+        if let Err(_e) = ret {
+            warn!("sd_flash_write err {:?}", _e);
+        }
+
         bomb.defuse();
         ret
     }
@@ -142,25 +196,17 @@ impl AsyncNorFlash for Flash {
         if to as usize % Self::PAGE_SIZE != 0 {
             return Err(FlashError::AddressMisaligned);
         }
+        if from > to || to as usize > ReadNorFlash::capacity(self) {
+            return Err(FlashError::OutOfBounds);
+        }
 
         let bomb = DropBomb::new();
         for address in (from as usize..to as usize).step_by(Self::PAGE_SIZE) {
             let page_number = (address / Self::PAGE_SIZE) as u32;
-            let ret = unsafe { raw::sd_flash_page_erase(page_number) };
-            match RawError::convert(ret) {
-                Ok(()) => match SIGNAL.wait().await {
-                    Err(_e) => {
-                        warn!("sd_flash_page_erase err {:?}", _e);
-                        bomb.defuse();
-                        return Err(_e);
-                    }
-                    _ => {}
-                },
-                Err(_e) => {
-                    warn!("sd_flash_page_erase err {:?}", _e);
-                    bomb.defuse();
-                    return Err(FlashError::Failed);
-                }
+            if let Err(_e) = flash_op(|| unsafe { raw::sd_flash_page_erase(page_number) }, || true).await {
+                warn!("sd_flash_page_erase err {:?}", _e);
+                bomb.defuse();
+                return Err(_e);
             }
         }
 
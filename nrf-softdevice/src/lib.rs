@@ -163,9 +163,20 @@ pub mod ble;
 mod softdevice;
 pub use softdevice::*;
 
+#[cfg(feature = "radio-notification")]
+mod radio_notification;
+#[cfg(feature = "radio-notification")]
+pub use radio_notification::*;
+
+pub mod mbr;
+pub mod power;
+pub mod timeslot;
+
 mod temperature;
-pub use temperature::temperature_celsius;
+pub use temperature::{temperature_celsius, TempError};
 
 mod random;
 pub use nrf_softdevice_macro::*;
-pub use random::random_bytes;
+#[cfg(feature = "rand_core")]
+pub use random::SoftdeviceRng;
+pub use random::{random_bytes, random_bytes_available, random_bytes_wait, random_pool_capacity, RandomError};
@@ -0,0 +1,131 @@
+//! Commands of the Master Boot Record, for bootloaders and DFU.
+//!
+//! The MBR is flashed together with the SoftDevice, and can be used whether the SoftDevice is enabled or not.
+//! [`copy_bl`] and [`vector_table_base_set`] need the MBR parameter page, whose address is set in the UICR.
+
+use crate::{raw, RawError};
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum MbrError {
+    Raw(RawError),
+}
+
+impl From<RawError> for MbrError {
+    fn from(err: RawError) -> Self {
+        Self::Raw(err)
+    }
+}
+
+unsafe fn command(command: u32, params: raw::sd_mbr_command_t__bindgen_ty_1) -> Result<(), RawError> {
+    let mut cmd = raw::sd_mbr_command_t { command, params };
+    RawError::convert(raw::sd_mbr_command(&mut cmd))
+}
+
+/// Forward interrupts to the SoftDevice and run its reset function.
+///
+/// Bootloaders call this before starting an application that enables the SoftDevice.
+///
+/// # Safety
+///
+/// The SoftDevice must not be enabled.
+pub unsafe fn init_sd() -> Result<(), MbrError> {
+    let params = raw::sd_mbr_command_t__bindgen_ty_1 {
+        irq_forward_address_set: raw::sd_mbr_command_irq_forward_address_set_t { address: 0 },
+    };
+    command(raw::NRF_MBR_COMMANDS_SD_MBR_COMMAND_INIT_SD, params).map_err(|err| {
+        warn!("SD_MBR_COMMAND_INIT_SD err {:?}", err);
+        err.into()
+    })
+}
+
+/// Forward all interrupts to the vector table at `address`, for example to start an application from a
+/// bootloader without the SoftDevice.
+///
+/// # Safety
+///
+/// `address` must point to a valid vector table, whose handlers will be called for all interrupts.
+pub unsafe fn irq_forward_address_set(address: u32) -> Result<(), MbrError> {
+    let params = raw::sd_mbr_command_t__bindgen_ty_1 {
+        irq_forward_address_set: raw::sd_mbr_command_irq_forward_address_set_t { address },
+    };
+    command(raw::NRF_MBR_COMMANDS_SD_MBR_COMMAND_IRQ_FORWARD_ADDRESS_SET, params).map_err(|err| {
+        warn!("SD_MBR_COMMAND_IRQ_FORWARD_ADDRESS_SET err {:?}", err);
+        err.into()
+    })
+}
+
+/// Set the address the MBR starts after a reset, which is the bootloader's. Resets the chip on success.
+///
+/// # Safety
+///
+/// `address` must point to a valid vector table, which will be started on every reset.
+pub unsafe fn vector_table_base_set(address: u32) -> Result<(), MbrError> {
+    let params = raw::sd_mbr_command_t__bindgen_ty_1 {
+        base_set: raw::sd_mbr_command_vector_table_base_set_t { address },
+    };
+    command(raw::NRF_MBR_COMMANDS_SD_MBR_COMMAND_VECTOR_TABLE_BASE_SET, params).map_err(|err| {
+        warn!("SD_MBR_COMMAND_VECTOR_TABLE_BASE_SET err {:?}", err);
+        err.into()
+    })
+}
+
+/// Replace the bootloader with the one in `src`, and start it. Only returns on error.
+///
+/// # Safety
+///
+/// `src` must hold a valid bootloader, and must not overlap the bootloader region.
+pub unsafe fn copy_bl(src: &[u32]) -> Result<(), MbrError> {
+    let params = raw::sd_mbr_command_t__bindgen_ty_1 {
+        copy_bl: raw::sd_mbr_command_copy_bl_t {
+            bl_src: src.as_ptr() as *mut u32,
+            bl_len: src.len() as u32,
+        },
+    };
+    command(raw::NRF_MBR_COMMANDS_SD_MBR_COMMAND_COPY_BL, params).map_err(|err| {
+        warn!("SD_MBR_COMMAND_COPY_BL err {:?}", err);
+        err.into()
+    })
+}
+
+/// Copy `src` to flash at `dst`, such as a new SoftDevice. The copy is verified, and restarted in case of a reset.
+///
+/// # Safety
+///
+/// The SoftDevice must not be enabled, and `dst` must be a flash region that can be overwritten, which may be the
+/// SoftDevice's own.
+pub unsafe fn copy_sd(src: &[u32], dst: *mut u32) -> Result<(), MbrError> {
+    let params = raw::sd_mbr_command_t__bindgen_ty_1 {
+        copy_sd: raw::sd_mbr_command_copy_sd_t {
+            src: src.as_ptr() as *mut u32,
+            dst,
+            len: src.len() as u32,
+        },
+    };
+    command(raw::NRF_MBR_COMMANDS_SD_MBR_COMMAND_COPY_SD, params).map_err(|err| {
+        warn!("SD_MBR_COMMAND_COPY_SD err {:?}", err);
+        err.into()
+    })
+}
+
+/// Compare two word slices of the same length, for example to verify a copy.
+///
+/// Fails with `Raw(RawError::InvalidLength)` if the lengths differ.
+pub fn compare(a: &[u32], b: &[u32]) -> Result<bool, MbrError> {
+    if a.len() != b.len() {
+        return Err(RawError::InvalidLength.into());
+    }
+    let params = raw::sd_mbr_command_t__bindgen_ty_1 {
+        compare: raw::sd_mbr_command_compare_t {
+            ptr1: a.as_ptr() as *mut u32,
+            ptr2: b.as_ptr() as *mut u32,
+            len: a.len() as u32,
+        },
+    };
+    match unsafe { command(raw::NRF_MBR_COMMANDS_SD_MBR_COMMAND_COMPARE, params) } {
+        Ok(()) => Ok(true),
+        // The MBR reports differing memory as NRF_ERROR_NULL.
+        Err(RawError::Null) => Ok(false),
+        Err(err) => Err(err.into()),
+    }
+}
@@ -0,0 +1,355 @@
+//! Power management through the SoftDevice, which owns the POWER peripheral while it's enabled.
+
+use core::convert::Infallible;
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+
+use crate::{raw, RawError, Softdevice};
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum PowerError {
+    Raw(RawError),
+}
+
+impl From<RawError> for PowerError {
+    fn from(err: RawError) -> Self {
+        Self::Raw(err)
+    }
+}
+
+/// Power-fail comparator threshold on VDD, in tenths of a volt.
+#[repr(u8)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum PofThreshold {
+    V17 = raw::NRF_POWER_THRESHOLDS_NRF_POWER_THRESHOLD_V17 as u8,
+    V18 = raw::NRF_POWER_THRESHOLDS_NRF_POWER_THRESHOLD_V18 as u8,
+    V19 = raw::NRF_POWER_THRESHOLDS_NRF_POWER_THRESHOLD_V19 as u8,
+    V20 = raw::NRF_POWER_THRESHOLDS_NRF_POWER_THRESHOLD_V20 as u8,
+    V21 = raw::NRF_POWER_THRESHOLDS_NRF_POWER_THRESHOLD_V21 as u8,
+    V22 = raw::NRF_POWER_THRESHOLDS_NRF_POWER_THRESHOLD_V22 as u8,
+    V23 = raw::NRF_POWER_THRESHOLDS_NRF_POWER_THRESHOLD_V23 as u8,
+    V24 = raw::NRF_POWER_THRESHOLDS_NRF_POWER_THRESHOLD_V24 as u8,
+    V25 = raw::NRF_POWER_THRESHOLDS_NRF_POWER_THRESHOLD_V25 as u8,
+    V26 = raw::NRF_POWER_THRESHOLDS_NRF_POWER_THRESHOLD_V26 as u8,
+    V27 = raw::NRF_POWER_THRESHOLDS_NRF_POWER_THRESHOLD_V27 as u8,
+    V28 = raw::NRF_POWER_THRESHOLDS_NRF_POWER_THRESHOLD_V28 as u8,
+}
+
+/// Power-fail comparator threshold on VDDH, in tenths of a volt.
+#[cfg(feature = "s140")]
+#[repr(u8)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum PofThresholdVddh {
+    V27 = raw::NRF_POWER_THRESHOLDVDDHS_NRF_POWER_THRESHOLDVDDH_V27 as u8,
+    V28 = raw::NRF_POWER_THRESHOLDVDDHS_NRF_POWER_THRESHOLDVDDH_V28 as u8,
+    V29 = raw::NRF_POWER_THRESHOLDVDDHS_NRF_POWER_THRESHOLDVDDH_V29 as u8,
+    V30 = raw::NRF_POWER_THRESHOLDVDDHS_NRF_POWER_THRESHOLDVDDH_V30 as u8,
+    V31 = raw::NRF_POWER_THRESHOLDVDDHS_NRF_POWER_THRESHOLDVDDH_V31 as u8,
+    V32 = raw::NRF_POWER_THRESHOLDVDDHS_NRF_POWER_THRESHOLDVDDH_V32 as u8,
+    V33 = raw::NRF_POWER_THRESHOLDVDDHS_NRF_POWER_THRESHOLDVDDH_V33 as u8,
+    V34 = raw::NRF_POWER_THRESHOLDVDDHS_NRF_POWER_THRESHOLDVDDH_V34 as u8,
+    V35 = raw::NRF_POWER_THRESHOLDVDDHS_NRF_POWER_THRESHOLDVDDH_V35 as u8,
+    V36 = raw::NRF_POWER_THRESHOLDVDDHS_NRF_POWER_THRESHOLDVDDH_V36 as u8,
+    V37 = raw::NRF_POWER_THRESHOLDVDDHS_NRF_POWER_THRESHOLDVDDH_V37 as u8,
+    V38 = raw::NRF_POWER_THRESHOLDVDDHS_NRF_POWER_THRESHOLDVDDH_V38 as u8,
+    V39 = raw::NRF_POWER_THRESHOLDVDDHS_NRF_POWER_THRESHOLDVDDH_V39 as u8,
+    V40 = raw::NRF_POWER_THRESHOLDVDDHS_NRF_POWER_THRESHOLDVDDH_V40 as u8,
+    V41 = raw::NRF_POWER_THRESHOLDVDDHS_NRF_POWER_THRESHOLDVDDH_V41 as u8,
+    V42 = raw::NRF_POWER_THRESHOLDVDDHS_NRF_POWER_THRESHOLDVDDH_V42 as u8,
+}
+
+static POF_SIGNAL: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+pub(crate) fn on_power_failure_warning() {
+    POF_SIGNAL.signal(())
+}
+
+/// Enable the power-fail comparator, warning when VDD drops below `threshold`.
+///
+/// Wait for the warning with [`wait_power_failure_warning`], for example to save state to flash before a brown-out.
+pub fn pof_enable(_sd: &Softdevice, threshold: PofThreshold) -> Result<(), PowerError> {
+    let ret = unsafe { raw::sd_power_pof_threshold_set(threshold as u8) };
+    if let Err(err) = RawError::convert(ret) {
+        warn!("sd_power_pof_threshold_set err {:?}", err);
+        return Err(err.into());
+    }
+
+    POF_SIGNAL.reset();
+    let ret = unsafe { raw::sd_power_pof_enable(1) };
+    if let Err(err) = RawError::convert(ret) {
+        warn!("sd_power_pof_enable err {:?}", err);
+        return Err(err.into());
+    }
+    Ok(())
+}
+
+/// Set the power-fail comparator threshold for VDDH, when it's enabled with [`pof_enable`].
+#[cfg(feature = "s140")]
+pub fn pof_threshold_vddh_set(_sd: &Softdevice, threshold: PofThresholdVddh) -> Result<(), PowerError> {
+    let ret = unsafe { raw::sd_power_pof_thresholdvddh_set(threshold as u8) };
+    if let Err(err) = RawError::convert(ret) {
+        warn!("sd_power_pof_thresholdvddh_set err {:?}", err);
+        return Err(err.into());
+    }
+    Ok(())
+}
+
+/// Disable the power-fail comparator.
+pub fn pof_disable(_sd: &Softdevice) -> Result<(), PowerError> {
+    let ret = unsafe { raw::sd_power_pof_enable(0) };
+    if let Err(err) = RawError::convert(ret) {
+        warn!("sd_power_pof_enable err {:?}", err);
+        return Err(err.into());
+    }
+    Ok(())
+}
+
+/// Wait until the supply voltage drops below the power-fail comparator threshold.
+///
+/// The warning is also passed to the SoC event handler as [`SocEvent::PowerFailureWarning`](crate::SocEvent).
+pub async fn wait_power_failure_warning() {
+    POF_SIGNAL.wait().await
+}
+
+/// Power and retention state of the sections of a RAM block, one bit per section.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct RamPower {
+    /// Sections powered in System ON.
+    pub on: u16,
+    /// Sections retained in System OFF.
+    pub retention: u16,
+}
+
+/// Get the power and retention state of RAM block `block`.
+pub fn ram_power(_sd: &Softdevice, block: u8) -> Result<RamPower, PowerError> {
+    let mut power = 0;
+    let ret = unsafe { raw::sd_power_ram_power_get(block, &mut power) };
+    if let Err(err) = RawError::convert(ret) {
+        warn!("sd_power_ram_power_get err {:?}", err);
+        return Err(err.into());
+    }
+    Ok(RamPower {
+        on: power as u16,
+        retention: (power >> 16) as u16,
+    })
+}
+
+/// Retain the given `sections` of RAM block `block` in System OFF. Other sections are left unchanged.
+///
+/// Retained RAM keeps its content through System OFF, at the cost of a higher sleep current.
+pub fn ram_retention_enable(_sd: &Softdevice, block: u8, sections: u16) -> Result<(), PowerError> {
+    let ret = unsafe { raw::sd_power_ram_power_set(block, (sections as u32) << 16) };
+    if let Err(err) = RawError::convert(ret) {
+        warn!("sd_power_ram_power_set err {:?}", err);
+        return Err(err.into());
+    }
+    Ok(())
+}
+
+/// Stop retaining the given `sections` of RAM block `block` in System OFF. Other sections are left unchanged.
+pub fn ram_retention_disable(_sd: &Softdevice, block: u8, sections: u16) -> Result<(), PowerError> {
+    let ret = unsafe { raw::sd_power_ram_power_clr(block, (sections as u32) << 16) };
+    if let Err(err) = RawError::convert(ret) {
+        warn!("sd_power_ram_power_clr err {:?}", err);
+        return Err(err.into());
+    }
+    Ok(())
+}
+
+/// Enter System OFF, the deepest sleep state. The chip is woken up by a reset, for example from a GPIO
+/// configured to sense a level, and starts over from the reset vector.
+///
+/// Only RAM sections retained with [`ram_retention_enable`] keep their content. With a debugger attached the
+/// chip enters an emulated System OFF, in which code keeps running, so this loops instead of returning.
+pub fn system_off(_sd: &Softdevice) -> ! {
+    let ret = unsafe { raw::sd_power_system_off() };
+    if let Err(_err) = RawError::convert(ret) {
+        warn!("sd_power_system_off err {:?}", _err);
+    }
+
+    loop {
+        cortex_m::asm::wfe();
+    }
+}
+
+fn dcdc_mode(enable: bool) -> u8 {
+    match enable {
+        true => raw::NRF_POWER_DCDC_MODES_NRF_POWER_DCDC_ENABLE as u8,
+        false => raw::NRF_POWER_DCDC_MODES_NRF_POWER_DCDC_DISABLE as u8,
+    }
+}
+
+/// Enable or disable the DC/DC regulator of the main (REG1) supply stage.
+///
+/// The DC/DC regulator greatly reduces the current consumption compared to the default LDO, especially while
+/// the radio is active. It requires the external inductors to be mounted, the chip won't run otherwise.
+pub fn dcdc_enable(_sd: &Softdevice, enable: bool) -> Result<(), PowerError> {
+    let ret = unsafe { raw::sd_power_dcdc_mode_set(dcdc_mode(enable)) };
+    if let Err(err) = RawError::convert(ret) {
+        warn!("sd_power_dcdc_mode_set err {:?}", err);
+        return Err(err.into());
+    }
+    Ok(())
+}
+
+/// Enable or disable the DC/DC regulator of the high voltage (REG0) supply stage, used when the chip is
+/// supplied through VDDH.
+///
+/// It requires the external inductor to be mounted, the chip won't run otherwise.
+#[cfg(feature = "s140")]
+pub fn dcdc0_enable(_sd: &Softdevice, enable: bool) -> Result<(), PowerError> {
+    let ret = unsafe { raw::sd_power_dcdc0_mode_set(dcdc_mode(enable)) };
+    if let Err(err) = RawError::convert(ret) {
+        warn!("sd_power_dcdc0_mode_set err {:?}", err);
+        return Err(err.into());
+    }
+    Ok(())
+}
+
+/// General purpose retention registers, which keep their value through resets other than power-on and brown-out.
+#[repr(u32)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Gpregret {
+    Gpregret = 0,
+    Gpregret2 = 1,
+}
+
+/// Value of [`Gpregret::Gpregret`] making the Nordic bootloader enter DFU mode after a reset.
+pub const GPREGRET_BOOTLOADER_DFU_START: u8 = 0xB1;
+/// Bit of [`Gpregret::Gpregret2`] making the Nordic bootloader skip validating the application's CRC.
+pub const GPREGRET2_BOOTLOADER_SKIP_CRC: u8 = 0x01;
+
+/// Get the value of a retention register.
+pub fn gpregret_get(_sd: &Softdevice, reg: Gpregret) -> Result<u8, PowerError> {
+    let mut value = 0;
+    let ret = unsafe { raw::sd_power_gpregret_get(reg as u32, &mut value) };
+    if let Err(err) = RawError::convert(ret) {
+        warn!("sd_power_gpregret_get err {:?}", err);
+        return Err(err.into());
+    }
+    Ok(value as u8)
+}
+
+/// Set the bits in `mask` of a retention register, leaving the others unchanged.
+pub fn gpregret_set(_sd: &Softdevice, reg: Gpregret, mask: u8) -> Result<(), PowerError> {
+    let ret = unsafe { raw::sd_power_gpregret_set(reg as u32, mask as u32) };
+    if let Err(err) = RawError::convert(ret) {
+        warn!("sd_power_gpregret_set err {:?}", err);
+        return Err(err.into());
+    }
+    Ok(())
+}
+
+/// Clear the bits in `mask` of a retention register, leaving the others unchanged.
+pub fn gpregret_clear(_sd: &Softdevice, reg: Gpregret, mask: u8) -> Result<(), PowerError> {
+    let ret = unsafe { raw::sd_power_gpregret_clr(reg as u32, mask as u32) };
+    if let Err(err) = RawError::convert(ret) {
+        warn!("sd_power_gpregret_clr err {:?}", err);
+        return Err(err.into());
+    }
+    Ok(())
+}
+
+/// Write the whole value of a retention register.
+pub fn gpregret_write(sd: &Softdevice, reg: Gpregret, value: u8) -> Result<(), PowerError> {
+    gpregret_clear(sd, reg, !value)?;
+    gpregret_set(sd, reg, value)
+}
+
+/// Reset into the DFU mode of the Nordic bootloader. Only returns if setting the retention register failed.
+pub fn enter_dfu_bootloader(sd: &Softdevice) -> Result<Infallible, PowerError> {
+    gpregret_write(sd, Gpregret::Gpregret, GPREGRET_BOOTLOADER_DFU_START)?;
+    cortex_m::peripheral::SCB::sys_reset()
+}
+
+/// USB power events, see [`wait_usb_event`].
+#[cfg(any(feature = "s113", feature = "s122", feature = "s140"))]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum UsbEvent {
+    /// VBUS rose above its valid threshold.
+    Detected,
+    /// VBUS fell below its valid threshold.
+    Removed,
+    /// The USB 3.3 V regulator output is ready.
+    PowerReady,
+}
+
+/// State of the USB supply, read from the USBREGSTATUS register.
+#[cfg(any(feature = "s113", feature = "s122", feature = "s140"))]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct UsbRegStatus {
+    /// VBUS is present.
+    pub vbus_detected: bool,
+    /// The USB 3.3 V regulator output is ready.
+    pub output_ready: bool,
+}
+
+#[cfg(any(feature = "s113", feature = "s122", feature = "s140"))]
+static USB_SIGNAL: Signal<CriticalSectionRawMutex, UsbEvent> = Signal::new();
+
+#[cfg(any(feature = "s113", feature = "s122", feature = "s140"))]
+pub(crate) fn on_usb_event(evt: UsbEvent) {
+    USB_SIGNAL.signal(evt)
+}
+
+/// Enable or disable the USB power events.
+///
+/// The SoftDevice owns the POWER peripheral, so a USB driver can't enable these events itself. Enable them and
+/// forward [`wait_usb_event`] to the driver instead, for example to `embassy_nrf::usb::vbus_detect::SoftwareVbusDetect`.
+#[cfg(any(feature = "s113", feature = "s122", feature = "s140"))]
+pub fn usb_events_enable(_sd: &Softdevice, enable: bool) -> Result<(), PowerError> {
+    if enable {
+        USB_SIGNAL.reset();
+    }
+
+    let ret = unsafe { raw::sd_power_usbdetected_enable(enable as u8) };
+    if let Err(err) = RawError::convert(ret) {
+        warn!("sd_power_usbdetected_enable err {:?}", err);
+        return Err(err.into());
+    }
+    let ret = unsafe { raw::sd_power_usbremoved_enable(enable as u8) };
+    if let Err(err) = RawError::convert(ret) {
+        warn!("sd_power_usbremoved_enable err {:?}", err);
+        return Err(err.into());
+    }
+    let ret = unsafe { raw::sd_power_usbpwrrdy_enable(enable as u8) };
+    if let Err(err) = RawError::convert(ret) {
+        warn!("sd_power_usbpwrrdy_enable err {:?}", err);
+        return Err(err.into());
+    }
+    Ok(())
+}
+
+/// Get the current state of the USB supply.
+///
+/// Events that happened before [`usb_events_enable`] aren't reported, so check this once after enabling them.
+#[cfg(any(feature = "s113", feature = "s122", feature = "s140"))]
+pub fn usb_reg_status(_sd: &Softdevice) -> Result<UsbRegStatus, PowerError> {
+    let mut status = 0;
+    let ret = unsafe { raw::sd_power_usbregstatus_get(&mut status) };
+    if let Err(err) = RawError::convert(ret) {
+        warn!("sd_power_usbregstatus_get err {:?}", err);
+        return Err(err.into());
+    }
+    Ok(UsbRegStatus {
+        vbus_detected: status & 0b01 != 0,
+        output_ready: status & 0b10 != 0,
+    })
+}
+
+/// Wait for the next USB power event, once enabled with [`usb_events_enable`].
+///
+/// Only the latest event is kept, so use [`usb_reg_status`] when the current state matters. The events are also
+/// passed to the SoC event handler.
+#[cfg(any(feature = "s113", feature = "s122", feature = "s140"))]
+pub async fn wait_usb_event() -> UsbEvent {
+    USB_SIGNAL.wait().await
+}
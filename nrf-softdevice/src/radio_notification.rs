@@ -0,0 +1,159 @@
+//! Radio notifications, signalled by the SoftDevice around its radio activity.
+//!
+//! They can be used to pause noise-sensitive work like ADC sampling, or to keep other peak currents from
+//! overlapping with the radio's. The notification interrupt (SWI1) is handled by this module, so the
+//! application can't use it.
+
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+
+use crate::pac::interrupt;
+use crate::{pac, raw, RawError, Softdevice};
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum RadioNotificationError {
+    Raw(RawError),
+}
+
+impl From<RawError> for RadioNotificationError {
+    fn from(err: RawError) -> Self {
+        Self::Raw(err)
+    }
+}
+
+/// Which radio activity transitions are notified.
+#[repr(u8)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum RadioNotificationType {
+    /// Before the radio becomes active.
+    Active = raw::NRF_RADIO_NOTIFICATION_TYPES_NRF_RADIO_NOTIFICATION_TYPE_INT_ON_ACTIVE as u8,
+    /// After the radio becomes inactive.
+    Inactive = raw::NRF_RADIO_NOTIFICATION_TYPES_NRF_RADIO_NOTIFICATION_TYPE_INT_ON_INACTIVE as u8,
+    /// Both before the radio becomes active and after it becomes inactive.
+    Both = raw::NRF_RADIO_NOTIFICATION_TYPES_NRF_RADIO_NOTIFICATION_TYPE_INT_ON_BOTH as u8,
+}
+
+/// Time between the active notification and the start of radio activity.
+#[repr(u8)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum RadioNotificationDistance {
+    None = raw::NRF_RADIO_NOTIFICATION_DISTANCES_NRF_RADIO_NOTIFICATION_DISTANCE_NONE as u8,
+    Us800 = raw::NRF_RADIO_NOTIFICATION_DISTANCES_NRF_RADIO_NOTIFICATION_DISTANCE_800US as u8,
+    Us1740 = raw::NRF_RADIO_NOTIFICATION_DISTANCES_NRF_RADIO_NOTIFICATION_DISTANCE_1740US as u8,
+    Us2680 = raw::NRF_RADIO_NOTIFICATION_DISTANCES_NRF_RADIO_NOTIFICATION_DISTANCE_2680US as u8,
+    Us3620 = raw::NRF_RADIO_NOTIFICATION_DISTANCES_NRF_RADIO_NOTIFICATION_DISTANCE_3620US as u8,
+    Us4560 = raw::NRF_RADIO_NOTIFICATION_DISTANCES_NRF_RADIO_NOTIFICATION_DISTANCE_4560US as u8,
+    Us5500 = raw::NRF_RADIO_NOTIFICATION_DISTANCES_NRF_RADIO_NOTIFICATION_DISTANCE_5500US as u8,
+}
+
+/// A radio notification, see [`wait_radio_notification`].
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum RadioNotification {
+    /// The radio is about to become active.
+    Active,
+    /// The radio became inactive.
+    Inactive,
+}
+
+/// Lowest interrupt priority of the nRF52, which is available to the application.
+const IRQ_PRIORITY: u8 = 7 << 5;
+
+/// Configured `RadioNotificationType`, or 0 if disabled.
+static TYPE: AtomicU8 = AtomicU8::new(0);
+/// Whether the radio is active, tracked when both transitions are notified.
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+static SIGNAL: Signal<CriticalSectionRawMutex, RadioNotification> = Signal::new();
+
+/// Enable radio notifications.
+///
+/// The SoftDevice only accepts this while there is no radio activity, so call it before advertising, scanning
+/// or connecting.
+pub fn radio_notification_enable(
+    _sd: &Softdevice,
+    notification_type: RadioNotificationType,
+    distance: RadioNotificationDistance,
+) -> Result<(), RadioNotificationError> {
+    TYPE.store(notification_type as u8, Ordering::Release);
+    ACTIVE.store(false, Ordering::Release);
+    SIGNAL.reset();
+
+    let ret = unsafe { raw::sd_radio_notification_cfg_set(notification_type as u8, distance as u8) };
+    if let Err(err) = RawError::convert(ret) {
+        warn!("sd_radio_notification_cfg_set err {:?}", err);
+        TYPE.store(0, Ordering::Release);
+        return Err(err.into());
+    }
+
+    unsafe {
+        let mut nvic = cortex_m::Peripherals::steal().NVIC;
+        #[cfg(any(feature = "nrf52805", feature = "nrf52810", feature = "nrf52811"))]
+        {
+            nvic.set_priority(pac::interrupt::SWI1, IRQ_PRIORITY);
+            pac::NVIC::unmask(pac::interrupt::SWI1);
+        }
+        #[cfg(not(any(feature = "nrf52805", feature = "nrf52810", feature = "nrf52811")))]
+        {
+            nvic.set_priority(pac::interrupt::SWI1_EGU1, IRQ_PRIORITY);
+            pac::NVIC::unmask(pac::interrupt::SWI1_EGU1);
+        }
+    }
+
+    Ok(())
+}
+
+/// Disable radio notifications.
+pub fn radio_notification_disable(_sd: &Softdevice) -> Result<(), RadioNotificationError> {
+    let ret = unsafe {
+        raw::sd_radio_notification_cfg_set(
+            raw::NRF_RADIO_NOTIFICATION_TYPES_NRF_RADIO_NOTIFICATION_TYPE_NONE as u8,
+            raw::NRF_RADIO_NOTIFICATION_DISTANCES_NRF_RADIO_NOTIFICATION_DISTANCE_NONE as u8,
+        )
+    };
+    if let Err(err) = RawError::convert(ret) {
+        warn!("sd_radio_notification_cfg_set err {:?}", err);
+        return Err(err.into());
+    }
+
+    TYPE.store(0, Ordering::Release);
+    Ok(())
+}
+
+/// Wait for the next radio notification.
+///
+/// Only the latest notification is kept, so notifications received while nobody is waiting are dropped
+/// except for the last one.
+pub async fn wait_radio_notification() -> RadioNotification {
+    SIGNAL.wait().await
+}
+
+fn on_radio_notification() {
+    let notification = match TYPE.load(Ordering::Acquire) {
+        0 => return,
+        t if t == RadioNotificationType::Active as u8 => RadioNotification::Active,
+        t if t == RadioNotificationType::Inactive as u8 => RadioNotification::Inactive,
+        // The notifications alternate, starting with the radio becoming active.
+        _ => match ACTIVE.fetch_xor(true, Ordering::AcqRel) {
+            false => RadioNotification::Active,
+            true => RadioNotification::Inactive,
+        },
+    };
+    SIGNAL.signal(notification);
+}
+
+#[cfg(any(feature = "nrf52805", feature = "nrf52810", feature = "nrf52811"))]
+#[interrupt]
+unsafe fn SWI1() {
+    on_radio_notification();
+}
+
+#[cfg(not(any(feature = "nrf52805", feature = "nrf52810", feature = "nrf52811")))]
+#[interrupt]
+unsafe fn SWI1_EGU1() {
+    on_radio_notification();
+}
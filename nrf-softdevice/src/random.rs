@@ -1,3 +1,6 @@
+#[cfg(feature = "rand_core")]
+use core::num::NonZeroU32;
+
 use crate::{raw, RawError, Softdevice};
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
@@ -14,7 +17,10 @@ impl From<RawError> for RandomError {
     }
 }
 
-/// Get cryptographically-securerandom bytes.
+/// Get cryptographically-secure random bytes.
+///
+/// Fails with `NotEnoughEntropy` if the SoftDevice's pool doesn't hold `buf.len()` bytes, see
+/// [`random_bytes_wait`] to wait for them instead.
 pub fn random_bytes(_sd: &Softdevice, buf: &mut [u8]) -> Result<(), RandomError> {
     if buf.len() > u8::MAX as usize {
         return Err(RandomError::BufferTooBig);
@@ -27,3 +33,110 @@ pub fn random_bytes(_sd: &Softdevice, buf: &mut [u8]) -> Result<(), RandomError>
         Err(e) => Err(e.into()),
     }
 }
+
+/// Get the number of random bytes currently available in the SoftDevice's pool.
+pub fn random_bytes_available(_sd: &Softdevice) -> Result<u8, RandomError> {
+    let mut available = 0;
+    let ret = unsafe { raw::sd_rand_application_bytes_available_get(&mut available) };
+    RawError::convert(ret)?;
+    Ok(available)
+}
+
+/// Get the capacity of the SoftDevice's pool of random bytes.
+pub fn random_pool_capacity(_sd: &Softdevice) -> Result<u8, RandomError> {
+    let mut capacity = 0;
+    let ret = unsafe { raw::sd_rand_application_pool_capacity_get(&mut capacity) };
+    RawError::convert(ret)?;
+    Ok(capacity)
+}
+
+/// Take as many random bytes as are available in the pool, up to `buf.len()`, returning how many were taken.
+fn take_available(sd: &Softdevice, buf: &mut [u8]) -> Result<usize, RandomError> {
+    let n = buf.len().min(random_bytes_available(sd)? as usize);
+    if n > 0 {
+        random_bytes(sd, &mut buf[..n])?;
+    }
+    Ok(n)
+}
+
+/// Interval between polls of the random pool while it's empty, when the `embassy-time` feature is enabled.
+#[cfg(feature = "embassy-time")]
+const POLL_INTERVAL_US: u64 = 100;
+
+/// Get cryptographically-secure random bytes, waiting for the SoftDevice to generate more when its pool is empty.
+///
+/// Unlike [`random_bytes`], `buf` can be of any length.
+///
+/// The SoftDevice has no event for the pool being refilled, so this polls it. By default it yields to the
+/// executor between polls, which keeps the executor busy (and the CPU awake) until enough bytes are generated.
+/// With the `embassy-time` feature it sleeps for a while between polls instead.
+pub async fn random_bytes_wait(sd: &Softdevice, mut buf: &mut [u8]) -> Result<(), RandomError> {
+    while !buf.is_empty() {
+        let n = take_available(sd, buf)?;
+        buf = &mut buf[n..];
+        if n == 0 {
+            // Generating a byte takes a few us.
+            #[cfg(feature = "embassy-time")]
+            embassy_time::Timer::after_micros(POLL_INTERVAL_US).await;
+            #[cfg(not(feature = "embassy-time"))]
+            embassy_futures::yield_now().await;
+        }
+    }
+    Ok(())
+}
+
+/// Random number generator backed by the SoftDevice, implementing the `rand_core` traits.
+///
+/// The RNG peripheral is owned by the SoftDevice while it's enabled, so this is the way to get hardware
+/// random numbers. Generating busy-waits while the SoftDevice's pool is empty, so prefer [`random_bytes_wait`]
+/// in async code when asking for more bytes than the pool holds.
+#[cfg(feature = "rand_core")]
+pub struct SoftdeviceRng<'a> {
+    sd: &'a Softdevice,
+}
+
+#[cfg(feature = "rand_core")]
+impl<'a> SoftdeviceRng<'a> {
+    pub fn new(sd: &'a Softdevice) -> Self {
+        Self { sd }
+    }
+
+    fn fill(&self, mut dest: &mut [u8]) -> Result<(), RandomError> {
+        while !dest.is_empty() {
+            let n = take_available(self.sd, dest)?;
+            dest = &mut dest[n..];
+            if n == 0 {
+                core::hint::spin_loop();
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "rand_core")]
+impl<'a> rand_core::RngCore for SoftdeviceRng<'a> {
+    fn next_u32(&mut self) -> u32 {
+        rand_core::impls::next_u32_via_fill(self)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        rand_core::impls::next_u64_via_fill(self)
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        unwrap!(self.fill(dest))
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+        self.fill(dest).map_err(|err| {
+            let code = match err {
+                RandomError::Raw(err) => err.into(),
+                _ => 0,
+            };
+            rand_core::Error::from(unwrap!(NonZeroU32::new(rand_core::Error::CUSTOM_START | code)))
+        })
+    }
+}
+
+#[cfg(feature = "rand_core")]
+impl<'a> rand_core::CryptoRng for SoftdeviceRng<'a> {}
@@ -40,6 +40,11 @@ pub struct Softdevice {
     pub(crate) att_mtu: u16,
     #[cfg(feature = "ble-l2cap")]
     pub(crate) l2cap_rx_mps: u16,
+    pub(crate) vs_uuid_count: u8,
+    #[cfg(feature = "ble-gatt-server")]
+    pub(crate) hvn_tx_queue_size: u8,
+    #[cfg(feature = "ble-gatt-server")]
+    pub(crate) attr_tab_size: u32,
 }
 
 /// Softdevice configuration.
@@ -59,8 +64,15 @@ pub struct Config {
     pub gap_device_name: Option<raw::ble_gap_cfg_device_name_t>,
     pub gap_ppcp_incl: Option<raw::ble_gap_cfg_ppcp_incl_cfg_t>,
     pub gap_car_incl: Option<raw::ble_gap_cfg_car_incl_cfg_t>,
+    /// Include the Service Changed characteristic in the GATT service, needed for
+    /// `gatt_server::indicate_services_changed`.
     pub gatts_service_changed: Option<raw::ble_gatts_cfg_service_changed_t>,
     pub gatts_attr_tab_size: Option<raw::ble_gatts_cfg_attr_tab_size_t>,
+    /// Value of the Appearance characteristic in the GAP service.
+    pub gap_appearance: Option<crate::ble::Appearance>,
+    /// Value of the Peripheral Preferred Connection Parameters characteristic in the GAP service.
+    #[cfg(feature = "ble-peripheral")]
+    pub gap_ppcp: Option<raw::ble_gap_conn_params_t>,
 }
 
 const APP_CONN_CFG_TAG: u8 = 1;
@@ -101,6 +113,9 @@ impl Softdevice {
             panic!("nrf_softdevice::enable() called multiple times.")
         }
 
+        #[cfg(feature = "critical-section-impl")]
+        crate::critical_section_impl::check_privileged();
+
         let p_clock_lf_cfg = config.clock.as_ref().map(|x| x as _).unwrap_or(ptr::null());
         let ret = unsafe { raw::sd_softdevice_enable(p_clock_lf_cfg, Some(fault_handler)) };
         match RawError::convert(ret) {
@@ -258,6 +273,17 @@ impl Softdevice {
             warn!("You're giving more RAM to the softdevice than needed. You can change your app's RAM start address to {:x}", wanted_app_ram_base);
         }
 
+        if let Some(appearance) = config.gap_appearance {
+            let ret = unsafe { raw::sd_ble_gap_appearance_set(appearance.to_u16()) };
+            unwrap!(RawError::convert(ret), "sd_ble_gap_appearance_set");
+        }
+
+        #[cfg(feature = "ble-peripheral")]
+        if let Some(ppcp) = config.gap_ppcp {
+            let ret = unsafe { raw::sd_ble_gap_ppcp_set(&ppcp) };
+            unwrap!(RawError::convert(ret), "sd_ble_gap_ppcp_set");
+        }
+
         unsafe {
             #[cfg(any(feature = "nrf52805", feature = "nrf52810", feature = "nrf52811"))]
             pac::NVIC::unmask(pac::interrupt::SWI2);
@@ -277,6 +303,23 @@ impl Softdevice {
             .map(|x| x.rx_mps)
             .unwrap_or(raw::BLE_L2CAP_MPS_MIN as u16);
 
+        #[cfg(feature = "ble-gatt-server")]
+        let hvn_tx_queue_size = config
+            .conn_gatts
+            .map(|x| x.hvn_tx_queue_size)
+            .unwrap_or(raw::BLE_GATTS_HVN_TX_QUEUE_SIZE_DEFAULT as u8);
+
+        #[cfg(feature = "ble-gatt-server")]
+        let attr_tab_size = config
+            .gatts_attr_tab_size
+            .map(|x| x.attr_tab_size)
+            .unwrap_or(raw::BLE_GATTS_ATTR_TAB_SIZE_DEFAULT);
+
+        let vs_uuid_count = config
+            .common_vs_uuid
+            .map(|x| x.vs_uuid_count)
+            .unwrap_or(raw::BLE_UUID_VS_COUNT_DEFAULT as u8);
+
         let sd = Softdevice {
             _private: PhantomData,
 
@@ -285,6 +328,13 @@ impl Softdevice {
 
             #[cfg(feature = "ble-l2cap")]
             l2cap_rx_mps,
+
+            vs_uuid_count,
+
+            #[cfg(feature = "ble-gatt-server")]
+            hvn_tx_queue_size,
+            #[cfg(feature = "ble-gatt-server")]
+            attr_tab_size,
         };
 
         unsafe {
@@ -14,9 +14,10 @@ impl From<RawError> for TempError {
     }
 }
 
-/// Get temperature reading in Celsius
+/// Get the die temperature in degrees Celsius, with a resolution of 0.25 degrees.
 ///
-/// Note this blocks for ~50us
+/// The TEMP peripheral is owned by the SoftDevice while it's enabled, so this is the way to read it. The
+/// measurement blocks for ~50us, which is short enough to call from async code.
 pub fn temperature_celsius(_sd: &Softdevice) -> Result<I30F2, TempError> {
     let mut temp: i32 = 0;
     let ret = unsafe { raw::sd_temp_get(&mut temp) };
@@ -0,0 +1,388 @@
+//! Radio timeslots, to run other radio protocols concurrently with Bluetooth.
+//!
+//! A [`Session`] requests timeslots from the SoftDevice, during which the application has full access to the
+//! RADIO and TIMER0 peripherals. The timeslot is driven by a [`Handler`], which is called by the SoftDevice in
+//! the highest priority interrupt when the timeslot starts and for each RADIO and TIMER0 interrupt during it.
+//!
+//! ```ignore
+//! struct Esb;
+//!
+//! impl timeslot::Handler for Esb {
+//!     fn on_signal(&self, signal: timeslot::SignalType) -> timeslot::Action {
+//!         match signal {
+//!             timeslot::SignalType::Start => {
+//!                 // set up RADIO, and TIMER0 to fire before the end of the timeslot
+//!                 timeslot::Action::None
+//!             }
+//!             timeslot::SignalType::Timer0 => timeslot::Action::RequestAndEnd(timeslot::Request::normal(10_000, 5_000)),
+//!             _ => timeslot::Action::None,
+//!         }
+//!     }
+//! }
+//!
+//! static ESB: Esb = Esb;
+//!
+//! let session = timeslot::Session::open(sd, &ESB)?;
+//! session.request(&timeslot::Request::earliest(5_000))?;
+//! ```
+
+use core::marker::PhantomData;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+
+use crate::{raw, RawError, Softdevice};
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum TimeslotError {
+    Raw(RawError),
+}
+
+impl From<RawError> for TimeslotError {
+    fn from(err: RawError) -> Self {
+        Self::Raw(err)
+    }
+}
+
+/// Priority of a timeslot request against the SoftDevice's own radio activity.
+#[repr(u8)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Priority {
+    High = raw::NRF_RADIO_PRIORITY_NRF_RADIO_PRIORITY_HIGH as u8,
+    Normal = raw::NRF_RADIO_PRIORITY_NRF_RADIO_PRIORITY_NORMAL as u8,
+}
+
+/// High frequency clock configuration during the timeslot.
+#[repr(u8)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Hfclk {
+    /// The external crystal is running from the start of the timeslot, as needed to use the radio.
+    XtalGuaranteed = raw::NRF_RADIO_HFCLK_CFG_NRF_RADIO_HFCLK_CFG_XTAL_GUARANTEED as u8,
+    /// The external crystal may not be running. Use for timeslots that don't use the radio.
+    NoGuarantee = raw::NRF_RADIO_HFCLK_CFG_NRF_RADIO_HFCLK_CFG_NO_GUARANTEE as u8,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+enum RequestKind {
+    Earliest { timeout_us: u32 },
+    Normal { distance_us: u32 },
+}
+
+/// A timeslot request.
+///
+/// Timeslot lengths are between 100us and 100ms.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Request {
+    kind: RequestKind,
+    length_us: u32,
+    priority: Priority,
+    hfclk: Hfclk,
+}
+
+impl Request {
+    /// Request a timeslot of `length_us` as early as possible, waiting up to the longest timeout allowed.
+    ///
+    /// The first request of a session must be of this kind.
+    pub const fn earliest(length_us: u32) -> Self {
+        Self {
+            kind: RequestKind::Earliest {
+                timeout_us: raw::NRF_RADIO_EARLIEST_TIMEOUT_MAX_US,
+            },
+            length_us,
+            priority: Priority::Normal,
+            hfclk: Hfclk::XtalGuaranteed,
+        }
+    }
+
+    /// Request a timeslot of `length_us`, starting `distance_us` after the start of the previous timeslot.
+    pub const fn normal(distance_us: u32, length_us: u32) -> Self {
+        Self {
+            kind: RequestKind::Normal { distance_us },
+            length_us,
+            priority: Priority::Normal,
+            hfclk: Hfclk::XtalGuaranteed,
+        }
+    }
+
+    /// Set the longest acceptable delay until the start of an [`earliest`](Self::earliest) timeslot. Ignored
+    /// for normal requests.
+    pub const fn timeout_us(mut self, timeout_us: u32) -> Self {
+        if let RequestKind::Earliest { .. } = self.kind {
+            self.kind = RequestKind::Earliest { timeout_us };
+        }
+        self
+    }
+
+    pub const fn priority(mut self, priority: Priority) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    pub const fn hfclk(mut self, hfclk: Hfclk) -> Self {
+        self.hfclk = hfclk;
+        self
+    }
+
+    fn to_raw(&self) -> raw::nrf_radio_request_t {
+        match self.kind {
+            RequestKind::Earliest { timeout_us } => raw::nrf_radio_request_t {
+                request_type: raw::NRF_RADIO_REQUEST_TYPE_NRF_RADIO_REQ_TYPE_EARLIEST as u8,
+                params: raw::nrf_radio_request_t__bindgen_ty_1 {
+                    earliest: raw::nrf_radio_request_earliest_t {
+                        hfclk: self.hfclk as u8,
+                        priority: self.priority as u8,
+                        length_us: self.length_us,
+                        timeout_us,
+                    },
+                },
+            },
+            RequestKind::Normal { distance_us } => raw::nrf_radio_request_t {
+                request_type: raw::NRF_RADIO_REQUEST_TYPE_NRF_RADIO_REQ_TYPE_NORMAL as u8,
+                params: raw::nrf_radio_request_t__bindgen_ty_1 {
+                    normal: raw::nrf_radio_request_normal_t {
+                        hfclk: self.hfclk as u8,
+                        priority: self.priority as u8,
+                        distance_us,
+                        length_us: self.length_us,
+                    },
+                },
+            },
+        }
+    }
+}
+
+/// Why the [`Handler`] is called.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SignalType {
+    /// The timeslot started. TIMER0 is reset and running at 1MHz.
+    Start,
+    /// TIMER0 interrupt.
+    Timer0,
+    /// RADIO interrupt.
+    Radio,
+    /// The timeslot couldn't be extended.
+    ExtendFailed,
+    /// The timeslot was extended.
+    ExtendSucceeded,
+}
+
+/// What to do when the [`Handler`] returns.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Action {
+    /// Continue the timeslot.
+    None,
+    /// Try to extend the timeslot by the given number of us, at least 200us. The result is signalled with
+    /// [`SignalType::ExtendSucceeded`] or [`SignalType::ExtendFailed`].
+    Extend(u32),
+    /// End the timeslot.
+    End,
+    /// End the timeslot and request the next one.
+    RequestAndEnd(Request),
+}
+
+/// Drives the timeslots of a [`Session`].
+pub trait Handler: Sync {
+    /// Called at the start of the timeslot, and for each RADIO and TIMER0 interrupt during it.
+    ///
+    /// This runs in the SoftDevice's highest priority interrupt, and must return before the end of the timeslot,
+    /// so keep it short. The RADIO and TIMER0 peripherals must not be used after the timeslot ends.
+    fn on_signal(&self, signal: SignalType) -> Action;
+}
+
+/// Events of a session, reported by the SoftDevice outside of timeslots.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Event {
+    /// The requested timeslot couldn't be scheduled, because of a conflict with other radio activity.
+    Blocked,
+    /// The requested timeslot was cancelled in favour of higher priority radio activity.
+    Canceled,
+    /// The handler returned an invalid action, and the timeslot was ended.
+    InvalidReturn,
+    /// The session has no remaining timeslot requests.
+    Idle,
+    /// The session was closed.
+    Closed,
+}
+
+static SESSION_OPEN: AtomicBool = AtomicBool::new(false);
+static mut HANDLER: Option<&'static dyn Handler> = None;
+// Read by the SoftDevice after the signal callback returns.
+static mut RETURN_PARAM: raw::nrf_radio_signal_callback_return_param_t =
+    raw::nrf_radio_signal_callback_return_param_t {
+        callback_action: 0,
+        params: raw::nrf_radio_signal_callback_return_param_t__bindgen_ty_1 {
+            extend: raw::nrf_radio_signal_callback_return_param_t__bindgen_ty_1__bindgen_ty_2 { length_us: 0 },
+        },
+    };
+static mut NEXT_REQUEST: raw::nrf_radio_request_t = raw::nrf_radio_request_t {
+    request_type: 0,
+    params: raw::nrf_radio_request_t__bindgen_ty_1 {
+        normal: raw::nrf_radio_request_normal_t {
+            hfclk: 0,
+            priority: 0,
+            distance_us: 0,
+            length_us: 0,
+        },
+    },
+};
+static EVENTS: Channel<CriticalSectionRawMutex, Event, 4> = Channel::new();
+
+unsafe extern "C" fn signal_callback(signal_type: u8) -> *mut raw::nrf_radio_signal_callback_return_param_t {
+    let signal = match signal_type as u32 {
+        raw::NRF_RADIO_CALLBACK_SIGNAL_TYPE_NRF_RADIO_CALLBACK_SIGNAL_TYPE_START => SignalType::Start,
+        raw::NRF_RADIO_CALLBACK_SIGNAL_TYPE_NRF_RADIO_CALLBACK_SIGNAL_TYPE_TIMER0 => SignalType::Timer0,
+        raw::NRF_RADIO_CALLBACK_SIGNAL_TYPE_NRF_RADIO_CALLBACK_SIGNAL_TYPE_RADIO => SignalType::Radio,
+        raw::NRF_RADIO_CALLBACK_SIGNAL_TYPE_NRF_RADIO_CALLBACK_SIGNAL_TYPE_EXTEND_FAILED => SignalType::ExtendFailed,
+        raw::NRF_RADIO_CALLBACK_SIGNAL_TYPE_NRF_RADIO_CALLBACK_SIGNAL_TYPE_EXTEND_SUCCEEDED => {
+            SignalType::ExtendSucceeded
+        }
+        _ => panic!("unknown timeslot signal {:?}", signal_type),
+    };
+
+    let action = match HANDLER {
+        Some(handler) => handler.on_signal(signal),
+        None => Action::End,
+    };
+
+    let ret = &mut *core::ptr::addr_of_mut!(RETURN_PARAM);
+    match action {
+        Action::None => {
+            ret.callback_action = raw::NRF_RADIO_SIGNAL_CALLBACK_ACTION_NRF_RADIO_SIGNAL_CALLBACK_ACTION_NONE as u8;
+        }
+        Action::Extend(length_us) => {
+            ret.callback_action = raw::NRF_RADIO_SIGNAL_CALLBACK_ACTION_NRF_RADIO_SIGNAL_CALLBACK_ACTION_EXTEND as u8;
+            ret.params.extend.length_us = length_us;
+        }
+        Action::End => {
+            ret.callback_action = raw::NRF_RADIO_SIGNAL_CALLBACK_ACTION_NRF_RADIO_SIGNAL_CALLBACK_ACTION_END as u8;
+        }
+        Action::RequestAndEnd(request) => {
+            NEXT_REQUEST = request.to_raw();
+            ret.callback_action =
+                raw::NRF_RADIO_SIGNAL_CALLBACK_ACTION_NRF_RADIO_SIGNAL_CALLBACK_ACTION_REQUEST_AND_END as u8;
+            ret.params.request.p_next = core::ptr::addr_of_mut!(NEXT_REQUEST);
+        }
+    }
+    ret
+}
+
+pub(crate) fn on_soc_evt(evt: u32) {
+    let event = match evt {
+        raw::NRF_SOC_EVTS_NRF_EVT_RADIO_BLOCKED => Event::Blocked,
+        raw::NRF_SOC_EVTS_NRF_EVT_RADIO_CANCELED => Event::Canceled,
+        raw::NRF_SOC_EVTS_NRF_EVT_RADIO_SIGNAL_CALLBACK_INVALID_RETURN => Event::InvalidReturn,
+        raw::NRF_SOC_EVTS_NRF_EVT_RADIO_SESSION_IDLE => Event::Idle,
+        raw::NRF_SOC_EVTS_NRF_EVT_RADIO_SESSION_CLOSED => Event::Closed,
+        _ => return,
+    };
+
+    if !SESSION_OPEN.load(Ordering::Acquire) {
+        return;
+    }
+    // Keep the latest events if nobody is waiting for them.
+    if EVENTS.try_send(event).is_err() {
+        let _ = EVENTS.try_receive();
+        let _ = EVENTS.try_send(event);
+    }
+
+    // The session is closed even if its `Session` was dropped, so a new one can be opened.
+    if event == Event::Closed {
+        unsafe { HANDLER = None };
+        SESSION_OPEN.store(false, Ordering::Release);
+    }
+}
+
+/// A radio timeslot session. Only one session can be open at a time.
+///
+/// Dropping the session closes it, without waiting for the SoftDevice to do so.
+pub struct Session {
+    // Prevent Send, Sync
+    _private: PhantomData<*mut ()>,
+    /// Whether `sd_radio_session_close` was called already.
+    closing: bool,
+}
+
+impl Session {
+    /// Open a session, with `handler` driving its timeslots.
+    ///
+    /// Fails with [`RawError::Busy`] if a session is already open.
+    pub fn open(_sd: &Softdevice, handler: &'static dyn Handler) -> Result<Self, TimeslotError> {
+        if SESSION_OPEN
+            .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+            .is_err()
+        {
+            return Err(RawError::Busy.into());
+        }
+
+        while EVENTS.try_receive().is_ok() {}
+        unsafe { HANDLER = Some(handler) };
+
+        let ret = unsafe { raw::sd_radio_session_open(Some(signal_callback)) };
+        if let Err(err) = RawError::convert(ret) {
+            warn!("sd_radio_session_open err {:?}", err);
+            SESSION_OPEN.store(false, Ordering::Release);
+            return Err(err.into());
+        }
+
+        Ok(Self {
+            _private: PhantomData,
+            closing: false,
+        })
+    }
+
+    /// Request a timeslot. Further timeslots can be requested from the handler with [`Action::RequestAndEnd`].
+    pub fn request(&self, request: &Request) -> Result<(), TimeslotError> {
+        let request = request.to_raw();
+        let ret = unsafe { raw::sd_radio_request(&request) };
+        if let Err(err) = RawError::convert(ret) {
+            warn!("sd_radio_request err {:?}", err);
+            return Err(err.into());
+        }
+        Ok(())
+    }
+
+    /// Wait for the next event of the session.
+    ///
+    /// After [`Event::Blocked`] or [`Event::Canceled`] no timeslot is pending, so a new one has to be requested
+    /// to continue.
+    pub async fn next_event(&self) -> Event {
+        EVENTS.receive().await
+    }
+
+    /// Close the session, ending the current timeslot and waiting until the SoftDevice has closed it.
+    ///
+    /// If this is cancelled, the session still closes in the background.
+    pub async fn close(mut self) -> Result<(), TimeslotError> {
+        let ret = unsafe { raw::sd_radio_session_close() };
+        if let Err(err) = RawError::convert(ret) {
+            warn!("sd_radio_session_close err {:?}", err);
+            return Err(err.into());
+        }
+        self.closing = true;
+
+        while EVENTS.receive().await != Event::Closed {}
+        Ok(())
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        if self.closing {
+            return;
+        }
+
+        let ret = unsafe { raw::sd_radio_session_close() };
+        if let Err(_err) = RawError::convert(ret) {
+            warn!("sd_radio_session_close err {:?}", _err);
+        }
+    }
+}
@@ -52,6 +52,11 @@ impl<T> Portal<T> {
         })
     }
 
+    /// Whether a task is currently waiting on this portal.
+    pub fn is_waiting(&self) -> bool {
+        self.state.lock(|state| state.borrow().0.is_some())
+    }
+
     /// Wait until the portal is called once using the [Portal::call()] function.
     ///
     /// The closure will be called with the parameter provided to [Portal::call()].